@@ -0,0 +1,287 @@
+// src/auth.rs
+//! Access tokens, refresh sessions and revocation.
+//!
+//! Logging in opens a row in `sessions` and returns two tokens: a short-lived
+//! JWT access token carrying the session id (`sid`), and an opaque refresh
+//! token, stored only as a SHA-256 hash. `POST /refresh` swaps a refresh token
+//! for a new pair and retires the old one; presenting a retired refresh token
+//! again means it was copied, so the whole session is revoked.
+//!
+//! Revoked session ids are also kept in memory until any access token issued
+//! for them has expired, so [`verify_jwt`] can reject them without a database
+//! round trip.
+//!
+//! Configuration comes from the environment:
+//! `JWT_SECRET`, `JWT_ISSUER`, `JWT_ACCESS_TTL_SECS` and `JWT_REFRESH_TTL_SECS`.
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqlitePool};
+
+const DEFAULT_ISSUER: &str = "rustmessenger";
+const DEFAULT_ACCESS_TTL_SECS: i64 = 15 * 60;
+const DEFAULT_REFRESH_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+// jsonwebtoken accepts tokens this long past `exp` by default
+const EXP_LEEWAY_SECS: i64 = 60;
+
+pub struct AuthConfig {
+    secret: Vec<u8>,
+    pub issuer: String,
+    pub access_ttl_secs: i64,
+    pub refresh_ttl_secs: i64,
+}
+
+fn env_secs(name: &str, default: i64) -> i64 {
+    match std::env::var(name) {
+        Ok(value) => match value.trim().parse::<i64>() {
+            Ok(secs) if secs > 0 => secs,
+            _ => {
+                eprintln!("Warning: {} must be a positive number of seconds; using {}", name, default);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+impl AuthConfig {
+    fn from_env() -> Self {
+        let secret = match std::env::var("JWT_SECRET") {
+            Ok(secret) if !secret.is_empty() => {
+                if secret.len() < 32 {
+                    eprintln!("Warning: JWT_SECRET is shorter than 32 bytes");
+                }
+                secret.into_bytes()
+            }
+            _ => {
+                // Safe but inconvenient: access tokens die with the process,
+                // and clients fall back to their refresh token
+                eprintln!("Warning: JWT_SECRET is not set; using a random secret for this run");
+                let mut secret = vec![0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+                secret
+            }
+        };
+        AuthConfig {
+            secret,
+            issuer: std::env::var("JWT_ISSUER").unwrap_or_else(|_| DEFAULT_ISSUER.to_string()),
+            access_ttl_secs: env_secs("JWT_ACCESS_TTL_SECS", DEFAULT_ACCESS_TTL_SECS),
+            refresh_ttl_secs: env_secs("JWT_REFRESH_TTL_SECS", DEFAULT_REFRESH_TTL_SECS),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref CONFIG: AuthConfig = AuthConfig::from_env();
+    // Revoked session id -> unix time after which its access tokens are all expired
+    static ref REVOKED: RwLock<HashMap<String, i64>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub iss: String,
+    pub sid: String,
+}
+
+/// What login and refresh hand back to the client.
+#[derive(Debug, Serialize)]
+pub struct TokenPair {
+    pub token: String,
+    pub refresh_token: String,
+    // Seconds until `token` expires
+    pub expires_in: i64,
+}
+
+#[derive(Debug)]
+pub enum RefreshError {
+    /// Unknown, expired or revoked refresh token.
+    Invalid,
+    /// A refresh token that was already exchanged; its session is now revoked.
+    Reused { username: String, session_id: String },
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for RefreshError {
+    fn from(e: sqlx::Error) -> Self {
+        RefreshError::Database(e)
+    }
+}
+
+/// `bytes` random bytes, hex encoded.
+pub fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn issue_access_token(username: &str, session_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    let now = Utc::now().timestamp();
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: (now + CONFIG.access_ttl_secs) as usize,
+        iat: now as usize,
+        iss: CONFIG.issuer.clone(),
+        sid: session_id.to_string(),
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(&CONFIG.secret))
+}
+
+/// Decode and check an access token, including that its session is live.
+pub fn verify_access_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_issuer(&[CONFIG.issuer.as_str()]);
+    validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+    let claims = decode::<Claims>(token, &DecodingKey::from_secret(&CONFIG.secret), &validation)?.claims;
+    if REVOKED.read().unwrap().contains_key(&claims.sid) {
+        return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
+/// The username an access token was issued to.
+pub fn verify_jwt(token: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    verify_access_token(token).map(|claims| claims.sub)
+}
+
+fn remember_revoked(session_ids: &[String]) {
+    let now = Utc::now().timestamp();
+    let forget_after = now + CONFIG.access_ttl_secs + EXP_LEEWAY_SECS;
+    let mut revoked = REVOKED.write().unwrap();
+    revoked.retain(|_, until| *until > now);
+    for sid in session_ids {
+        revoked.insert(sid.clone(), forget_after);
+    }
+}
+
+/// Load recently revoked sessions whose access tokens may still be
+/// unexpired, and drop sessions that have long expired. Run at startup.
+pub async fn init(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let now = Utc::now();
+    let window = chrono::Duration::seconds(CONFIG.access_ttl_secs + EXP_LEEWAY_SECS);
+    let recent: Vec<String> = sqlx::query_scalar("SELECT id FROM sessions WHERE revoked_at > ?")
+        .bind((now - window).to_rfc3339())
+        .fetch_all(pool)
+        .await?;
+    remember_revoked(&recent);
+
+    sqlx::query("DELETE FROM sessions WHERE expires_at < ?")
+        .bind((now - chrono::Duration::days(1)).to_rfc3339())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Open a session for a user who just proved their password.
+pub async fn create_session(pool: &SqlitePool, username: &str, user_agent: Option<&str>) -> Result<TokenPair, RefreshError> {
+    let session_id = random_hex(16);
+    let refresh_token = random_hex(32);
+    let now = Utc::now();
+    sqlx::query(
+        "INSERT INTO sessions (id, username, refresh_hash, created_at, last_used_at, expires_at, user_agent)
+         VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&session_id)
+    .bind(username)
+    .bind(hash_token(&refresh_token))
+    .bind(now.to_rfc3339())
+    .bind(now.to_rfc3339())
+    .bind((now + chrono::Duration::seconds(CONFIG.refresh_ttl_secs)).to_rfc3339())
+    .bind(user_agent)
+    .execute(pool)
+    .await?;
+
+    let token = issue_access_token(username, &session_id).map_err(|_| RefreshError::Invalid)?;
+    Ok(TokenPair { token, refresh_token, expires_in: CONFIG.access_ttl_secs })
+}
+
+/// Exchange a refresh token for a new pair, rotating the refresh token.
+pub async fn refresh(pool: &SqlitePool, refresh_token: &str) -> Result<(String, TokenPair), RefreshError> {
+    let hash = hash_token(refresh_token);
+    let now = Utc::now();
+
+    let row = sqlx::query("SELECT id, username, expires_at, revoked_at FROM sessions WHERE refresh_hash = ?")
+        .bind(&hash)
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row else {
+        // A token that was already rotated away is being replayed
+        let reused: Option<(String, String)> = sqlx::query_as(
+            "SELECT id, username FROM sessions WHERE previous_refresh_hash = ? AND revoked_at IS NULL"
+        )
+        .bind(&hash)
+        .fetch_optional(pool)
+        .await?;
+        if let Some((session_id, username)) = reused {
+            println!("DEBUG: Refresh token reuse for {}; revoking session {}", username, session_id);
+            revoke_session(pool, &session_id).await?;
+            return Err(RefreshError::Reused { username, session_id });
+        }
+        return Err(RefreshError::Invalid);
+    };
+
+    let session_id: String = row.get("id");
+    let username: String = row.get("username");
+    let expires_at: String = row.get("expires_at");
+    let revoked_at: Option<String> = row.get("revoked_at");
+    if revoked_at.is_some() || expires_at <= now.to_rfc3339() {
+        return Err(RefreshError::Invalid);
+    }
+
+    let new_refresh = random_hex(32);
+    // Only one of two concurrent refreshes with the same token can win
+    let updated = sqlx::query(
+        "UPDATE sessions
+         SET previous_refresh_hash = refresh_hash, refresh_hash = ?, last_used_at = ?, expires_at = ?
+         WHERE id = ? AND refresh_hash = ? AND revoked_at IS NULL"
+    )
+    .bind(hash_token(&new_refresh))
+    .bind(now.to_rfc3339())
+    .bind((now + chrono::Duration::seconds(CONFIG.refresh_ttl_secs)).to_rfc3339())
+    .bind(&session_id)
+    .bind(&hash)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Err(RefreshError::Invalid);
+    }
+
+    let token = issue_access_token(&username, &session_id).map_err(|_| RefreshError::Invalid)?;
+    Ok((username, TokenPair { token, refresh_token: new_refresh, expires_in: CONFIG.access_ttl_secs }))
+}
+
+pub async fn revoke_session(pool: &SqlitePool, session_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE sessions SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL")
+        .bind(Utc::now().to_rfc3339())
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+    remember_revoked(&[session_id.to_string()]);
+    Ok(())
+}
+
+/// Revoke every live session of a user, returning how many there were.
+pub async fn revoke_all_sessions(pool: &SqlitePool, username: &str) -> Result<usize, sqlx::Error> {
+    let session_ids: Vec<String> = sqlx::query_scalar(
+        "UPDATE sessions SET revoked_at = ? WHERE username = ? AND revoked_at IS NULL RETURNING id"
+    )
+    .bind(Utc::now().to_rfc3339())
+    .bind(username)
+    .fetch_all(pool)
+    .await?;
+    remember_revoked(&session_ids);
+    Ok(session_ids.len())
+}
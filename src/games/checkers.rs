@@ -0,0 +1,351 @@
+// src/games/checkers.rs
+//! Checkers (English draughts). The game's creator plays black, starts on
+//! the bottom three rows and moves first.
+//!
+//! `board` is laid out top row first, each cell `"b"` or `"w"` for a man,
+//! `"B"` or `"W"` for a king, or `""`. Pieces stand on the dark squares,
+//! where row + col is odd. A move is one step, `{"from": [5, 0], "to":
+//! [4, 1]}`. Captures are compulsory; after a capture, if the same piece can
+//! capture again, its owner keeps the move and must go on with that piece
+//! (`continue_from`). A man reaching the far row is crowned, which ends the
+//! move. A player with no legal move loses, and forty moves each without a
+//! capture or a man moving is a draw.
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use super::{GameEngine, Outcome, Players, Seat};
+
+// Plies without a capture or a man moving before the game is drawn
+const DRAW_PLIES: usize = 80;
+
+type Square = [usize; 2];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Step {
+    from: Square,
+    to: Square,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LastMove {
+    from: Square,
+    to: Square,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    captured: Option<Square>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    board: Vec<Vec<String>>,
+    // "b" or "w"
+    turn: String,
+    // The piece that must keep capturing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    continue_from: Option<Square>,
+    #[serde(default)]
+    quiet_plies: usize,
+    // For the side to move, so clients can show where a piece may go
+    #[serde(default)]
+    legal_moves: Vec<Step>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_move: Option<LastMove>,
+}
+
+fn color(seat: Seat) -> &'static str {
+    match seat {
+        Seat::First => "b",
+        Seat::Second => "w",
+    }
+}
+
+fn owner(cell: &str) -> Option<Seat> {
+    match cell {
+        "b" | "B" => Some(Seat::First),
+        "w" | "W" => Some(Seat::Second),
+        _ => None,
+    }
+}
+
+fn is_king(cell: &str) -> bool {
+    cell == "B" || cell == "W"
+}
+
+fn read(state: &str) -> Result<State, String> {
+    let state: State = super::parse_state(state)?;
+    if state.board.len() != 8 || state.board.iter().any(|row| row.len() != 8) {
+        return Err("Invalid board".to_string());
+    }
+    Ok(state)
+}
+
+impl State {
+    fn turn(&self) -> Seat {
+        if self.turn == color(Seat::First) { Seat::First } else { Seat::Second }
+    }
+
+    fn at(&self, [row, col]: Square) -> &str {
+        &self.board[row][col]
+    }
+
+    /// The square `n` diagonal steps from `from`, if it is on the board.
+    fn offset(from: Square, (dr, dc): (isize, isize), n: isize) -> Option<Square> {
+        let row = from[0] as isize + dr * n;
+        let col = from[1] as isize + dc * n;
+        ((0..8).contains(&row) && (0..8).contains(&col)).then_some([row as usize, col as usize])
+    }
+
+    fn directions(&self, from: Square) -> Vec<(isize, isize)> {
+        let cell = self.at(from);
+        let forward = if owner(cell) == Some(Seat::First) { -1 } else { 1 };
+        if is_king(cell) {
+            vec![(-1, -1), (-1, 1), (1, -1), (1, 1)]
+        } else {
+            vec![(forward, -1), (forward, 1)]
+        }
+    }
+
+    fn captures_from(&self, from: Square) -> Vec<Step> {
+        let Some(seat) = owner(self.at(from)) else {
+            return Vec::new();
+        };
+        self.directions(from)
+            .into_iter()
+            .filter_map(|direction| {
+                let over = Self::offset(from, direction, 1)?;
+                let to = Self::offset(from, direction, 2)?;
+                (owner(self.at(over)) == Some(seat.other()) && self.at(to).is_empty()).then_some(Step { from, to })
+            })
+            .collect()
+    }
+
+    fn legal_moves(&self) -> Vec<Step> {
+        if let Some(from) = self.continue_from {
+            return self.captures_from(from);
+        }
+        let seat = self.turn();
+        let pieces: Vec<Square> = (0..8)
+            .flat_map(|row| (0..8).map(move |col| [row, col]))
+            .filter(|&square| owner(self.at(square)) == Some(seat))
+            .collect();
+
+        let captures: Vec<Step> = pieces.iter().flat_map(|&from| self.captures_from(from)).collect();
+        if !captures.is_empty() {
+            return captures;
+        }
+        pieces
+            .iter()
+            .flat_map(|&from| {
+                self.directions(from)
+                    .into_iter()
+                    .filter_map(move |direction| Self::offset(from, direction, 1))
+                    .filter(|&to| self.at(to).is_empty())
+                    .map(move |to| Step { from, to })
+            })
+            .collect()
+    }
+
+    fn play(&mut self, step: Step) {
+        let piece = self.at(step.from).to_string();
+        let seat = owner(&piece).unwrap_or(Seat::First);
+        self.board[step.from[0]][step.from[1]].clear();
+
+        let jumped = step.from[0].abs_diff(step.to[0]) == 2;
+        let captured = jumped.then(|| [(step.from[0] + step.to[0]) / 2, (step.from[1] + step.to[1]) / 2]);
+        if let Some([row, col]) = captured {
+            self.board[row][col].clear();
+        }
+
+        let far_row = if seat == Seat::First { 0 } else { 7 };
+        let crowned = !is_king(&piece) && step.to[0] == far_row;
+        self.board[step.to[0]][step.to[1]] = if crowned { piece.to_uppercase() } else { piece.clone() };
+
+        self.quiet_plies = if jumped || !is_king(&piece) { 0 } else { self.quiet_plies + 1 };
+        self.last_move = Some(LastMove { from: step.from, to: step.to, captured });
+        self.continue_from = None;
+        if jumped && !crowned && !self.captures_from(step.to).is_empty() {
+            self.continue_from = Some(step.to);
+        } else {
+            self.turn = color(seat.other()).to_string();
+        }
+        self.legal_moves = self.legal_moves();
+    }
+}
+
+/// The `checkers` engine.
+pub struct Checkers;
+
+impl GameEngine for Checkers {
+    fn game_type(&self) -> &'static str {
+        "checkers"
+    }
+
+    fn label(&self) -> &'static str {
+        "⛀ Checkers"
+    }
+
+    fn initial_state<'a>(&'a self, _pool: &'a SqlitePool) -> BoxFuture<'a, Result<String, String>> {
+        let board = (0..8)
+            .map(|row| {
+                (0..8)
+                    .map(|col| match row {
+                        _ if (row + col) % 2 == 0 => "",
+                        0..=2 => "w",
+                        5..=7 => "b",
+                        _ => "",
+                    })
+                    .map(str::to_string)
+                    .collect()
+            })
+            .collect();
+        let mut state = State {
+            board,
+            turn: color(Seat::First).to_string(),
+            continue_from: None,
+            quiet_plies: 0,
+            legal_moves: Vec::new(),
+            last_move: None,
+        };
+        state.legal_moves = state.legal_moves();
+        super::ready(super::to_state(&state))
+    }
+
+    fn to_move(&self, state: &str) -> Result<Option<Seat>, String> {
+        Ok(Some(read(state)?.turn()))
+    }
+
+    fn apply_move(&self, state: &str, _players: &Players, _seat: Seat, move_data: &str) -> Result<String, String> {
+        let mut state = read(state)?;
+        let step: Step = super::parse_move(move_data)?;
+        if step.from.iter().chain(&step.to).any(|&i| i >= 8) {
+            return Err("Square out of bounds".to_string());
+        }
+
+        let legal = state.legal_moves();
+        if !legal.contains(&step) {
+            let from = step.from;
+            return Err(if owner(state.at(from)) != Some(state.turn()) {
+                "That is not your piece".to_string()
+            } else if state.continue_from.is_some_and(|square| square != from) {
+                "You must keep capturing with the same piece".to_string()
+            } else if legal.iter().any(|m| m.from[0].abs_diff(m.to[0]) == 2) {
+                "You must capture".to_string()
+            } else {
+                "Illegal move".to_string()
+            });
+        }
+        state.play(step);
+        Ok(super::to_state(&state))
+    }
+
+    fn outcome(&self, state: &str, _players: &Players) -> Result<Option<Outcome>, String> {
+        let state = read(state)?;
+        Ok(if state.legal_moves().is_empty() {
+            Some(Outcome::Winner(state.turn().other()))
+        } else if state.quiet_plies >= DRAW_PLIES {
+            Some(Outcome::Draw)
+        } else {
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{new_game, PLAYERS};
+    use super::*;
+
+    /// A state with only `pieces` on the board and `turn` to move.
+    fn board(pieces: &[(Square, &str)], turn: &str, quiet_plies: usize) -> String {
+        let mut state = State {
+            board: vec![vec![String::new(); 8]; 8],
+            turn: turn.to_string(),
+            continue_from: None,
+            quiet_plies,
+            legal_moves: Vec::new(),
+            last_move: None,
+        };
+        for &([row, col], piece) in pieces {
+            state.board[row][col] = piece.to_string();
+        }
+        state.legal_moves = state.legal_moves();
+        super::super::to_state(&state)
+    }
+
+    fn step(state: &str, seat: Seat, from: Square, to: Square) -> Result<String, String> {
+        let move_data = serde_json::to_string(&Step { from, to }).unwrap();
+        Checkers.apply_move(state, &PLAYERS, seat, &move_data)
+    }
+
+    #[test]
+    fn black_opens_with_seven_moves() {
+        let state = read(&new_game(&Checkers)).unwrap();
+        assert_eq!(state.turn(), Seat::First);
+        assert_eq!(state.legal_moves.len(), 7);
+        assert_eq!(step(&new_game(&Checkers), Seat::First, [5, 0], [3, 2]).unwrap_err(), "Illegal move");
+    }
+
+    #[test]
+    fn captures_are_compulsory() {
+        let state = board(&[([5, 0], "b"), ([5, 4], "b"), ([4, 1], "w")], "b", 0);
+        assert_eq!(step(&state, Seat::First, [5, 4], [4, 5]).unwrap_err(), "You must capture");
+        assert_eq!(step(&state, Seat::First, [4, 1], [5, 2]).unwrap_err(), "That is not your piece");
+    }
+
+    #[test]
+    fn a_capture_chain_keeps_the_move_with_the_same_piece() {
+        let state = board(&[([5, 0], "b"), ([7, 6], "b"), ([4, 1], "w"), ([2, 3], "w"), ([0, 7], "w")], "b", 0);
+        let state = step(&state, Seat::First, [5, 0], [3, 2]).unwrap();
+        let read_back = read(&state).unwrap();
+        assert_eq!(read_back.continue_from, Some([3, 2]));
+        assert_eq!(read_back.turn(), Seat::First);
+        assert!(read_back.board[4][1].is_empty());
+        assert_eq!(read_back.legal_moves, vec![Step { from: [3, 2], to: [1, 4] }]);
+        assert_eq!(Checkers.to_move(&state).unwrap(), Some(Seat::First));
+
+        assert_eq!(
+            step(&state, Seat::First, [7, 6], [6, 5]).unwrap_err(),
+            "You must keep capturing with the same piece"
+        );
+
+        let state = read(&step(&state, Seat::First, [3, 2], [1, 4]).unwrap()).unwrap();
+        assert_eq!(state.continue_from, None);
+        assert_eq!(state.turn(), Seat::Second);
+        assert!(state.board[2][3].is_empty());
+        assert_eq!(state.board[1][4], "b");
+    }
+
+    #[test]
+    fn crowning_ends_the_move() {
+        // The new king could jump (1, 4) at once, but must wait a turn
+        let state = board(&[([2, 1], "b"), ([1, 2], "w"), ([1, 4], "w")], "b", 0);
+        let state = read(&step(&state, Seat::First, [2, 1], [0, 3]).unwrap()).unwrap();
+        assert_eq!(state.board[0][3], "B");
+        assert_eq!(state.continue_from, None);
+        assert_eq!(state.turn(), Seat::Second);
+    }
+
+    #[test]
+    fn forty_quiet_moves_each_is_a_draw() {
+        let state = board(&[([7, 0], "B"), ([0, 7], "W")], "b", DRAW_PLIES - 2);
+        let state = step(&state, Seat::First, [7, 0], [6, 1]).unwrap();
+        assert_eq!(Checkers.outcome(&state, &PLAYERS).unwrap(), None);
+        let state = step(&state, Seat::Second, [0, 7], [1, 6]).unwrap();
+        assert_eq!(Checkers.outcome(&state, &PLAYERS).unwrap(), Some(Outcome::Draw));
+    }
+
+    #[test]
+    fn a_man_moving_resets_the_draw_count() {
+        let state = board(&[([7, 0], "B"), ([5, 2], "b"), ([0, 7], "W")], "b", DRAW_PLIES - 1);
+        let state = step(&state, Seat::First, [5, 2], [4, 3]).unwrap();
+        assert_eq!(read(&state).unwrap().quiet_plies, 0);
+        assert_eq!(Checkers.outcome(&state, &PLAYERS).unwrap(), None);
+    }
+
+    #[test]
+    fn no_legal_move_loses() {
+        let state = board(&[([5, 0], "b"), ([4, 1], "w")], "b", 0);
+        let state = step(&state, Seat::First, [5, 0], [3, 2]).unwrap();
+        assert_eq!(Checkers.outcome(&state, &PLAYERS).unwrap(), Some(Outcome::Winner(Seat::First)));
+    }
+}
@@ -0,0 +1,1215 @@
+// src/games/chess.rs
+//! Chess rules for the `chess` game type.
+//!
+//! A game's state is a FEN position plus what FEN leaves out: the position
+//! the game started from, the moves so far in SAN, and the positions seen
+//! since the last capture or pawn move for threefold repetition. Moves are checked against the legal moves of
+//! the position, castling, en passant and promotion included, and after each
+//! one the game ends on checkmate, stalemate, threefold repetition, the
+//! fifty-move rule or insufficient material.
+//!
+//! The stored state keeps the `board` and `turn` fields that clients have
+//! always drawn from, derived from the FEN. States saved before this module
+//! existed have only those, and are read from them.
+//!
+//! Squares count from a1 = 0 to h8 = 63. Clients name them either as
+//! algebraic `"e2"` or as `[row, col]` with row 0 the eighth rank, the way
+//! `board` is laid out.
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use super::{GameEngine, Players, Seat};
+
+pub const START_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    White,
+    Black,
+}
+
+impl Color {
+    pub fn opponent(self) -> Color {
+        match self {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Color::White => "white",
+            Color::Black => "black",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Pawn,
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+    King,
+}
+
+impl Kind {
+    fn from_char(c: char) -> Option<Kind> {
+        match c.to_ascii_lowercase() {
+            'p' => Some(Kind::Pawn),
+            'n' => Some(Kind::Knight),
+            'b' => Some(Kind::Bishop),
+            'r' => Some(Kind::Rook),
+            'q' => Some(Kind::Queen),
+            'k' => Some(Kind::King),
+            _ => None,
+        }
+    }
+
+    fn letter(self) -> char {
+        match self {
+            Kind::Pawn => 'P',
+            Kind::Knight => 'N',
+            Kind::Bishop => 'B',
+            Kind::Rook => 'R',
+            Kind::Queen => 'Q',
+            Kind::King => 'K',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Piece {
+    color: Color,
+    kind: Kind,
+}
+
+impl Piece {
+    fn from_fen(c: char) -> Option<Piece> {
+        let color = if c.is_ascii_uppercase() { Color::White } else { Color::Black };
+        Kind::from_char(c).map(|kind| Piece { color, kind })
+    }
+
+    fn to_fen(self) -> char {
+        match self.color {
+            Color::White => self.kind.letter(),
+            Color::Black => self.kind.letter().to_ascii_lowercase(),
+        }
+    }
+}
+
+type Square = usize;
+
+fn file(sq: Square) -> usize {
+    sq % 8
+}
+
+fn rank(sq: Square) -> usize {
+    sq / 8
+}
+
+fn square_name(sq: Square) -> String {
+    format!("{}{}", (b'a' + file(sq) as u8) as char, rank(sq) + 1)
+}
+
+fn parse_square(s: &str) -> Option<Square> {
+    let mut chars = s.chars();
+    let (f, r) = (chars.next()?, chars.next()?);
+    if chars.next().is_some() || !('a'..='h').contains(&f) || !('1'..='8').contains(&r) {
+        return None;
+    }
+    Some((r as usize - '1' as usize) * 8 + (f as usize - 'a' as usize))
+}
+
+/// The square `df` files and `dr` ranks away, if it is on the board.
+fn offset(sq: Square, df: i32, dr: i32) -> Option<Square> {
+    let (f, r) = (file(sq) as i32 + df, rank(sq) as i32 + dr);
+    ((0..8).contains(&f) && (0..8).contains(&r)).then(|| (r * 8 + f) as Square)
+}
+
+const KNIGHT_JUMPS: [(i32, i32); 8] = [(1, 2), (2, 1), (2, -1), (1, -2), (-1, -2), (-2, -1), (-2, 1), (-1, 2)];
+const KING_STEPS: [(i32, i32); 8] = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+const ROOK_LINES: [(i32, i32); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+const BISHOP_LINES: [(i32, i32); 4] = [(1, 1), (1, -1), (-1, 1), (-1, -1)];
+const PROMOTIONS: [Kind; 4] = [Kind::Queen, Kind::Rook, Kind::Bishop, Kind::Knight];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    from: Square,
+    to: Square,
+    promotion: Option<Kind>,
+}
+
+impl Move {
+    /// The move in UCI notation, e.g. `e7e8q`.
+    pub fn uci(self) -> String {
+        let promotion = self.promotion.map(|kind| kind.letter().to_ascii_lowercase().to_string()).unwrap_or_default();
+        format!("{}{}{}", square_name(self.from), square_name(self.to), promotion)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Castling {
+    white_king: bool,
+    white_queen: bool,
+    black_king: bool,
+    black_queen: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    board: [Option<Piece>; 64],
+    turn: Color,
+    castling: Castling,
+    // Set after any double pawn push, as FEN has it
+    en_passant: Option<Square>,
+    halfmove_clock: u32,
+    fullmove_number: u32,
+}
+
+impl Position {
+    pub fn start() -> Position {
+        Position::from_fen(START_FEN).expect("START_FEN is valid")
+    }
+
+    pub fn from_fen(fen: &str) -> Result<Position, String> {
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        if fields.len() < 4 || fields.len() > 6 {
+            return Err(format!("Invalid FEN `{}`", fen));
+        }
+        let invalid = |what: &str| format!("Invalid FEN {}: `{}`", what, fen);
+
+        let mut board = [None; 64];
+        let ranks: Vec<&str> = fields[0].split('/').collect();
+        if ranks.len() != 8 {
+            return Err(invalid("board"));
+        }
+        for (i, row) in ranks.iter().enumerate() {
+            let r = 7 - i;
+            let mut f = 0;
+            for c in row.chars() {
+                if let Some(empty) = c.to_digit(10).filter(|d| (1..=8).contains(d)) {
+                    f += empty as usize;
+                } else {
+                    let piece = Piece::from_fen(c).ok_or_else(|| invalid("board"))?;
+                    if f >= 8 {
+                        return Err(invalid("board"));
+                    }
+                    board[r * 8 + f] = Some(piece);
+                    f += 1;
+                }
+            }
+            if f != 8 {
+                return Err(invalid("board"));
+            }
+        }
+
+        let turn = match fields[1] {
+            "w" => Color::White,
+            "b" => Color::Black,
+            _ => return Err(invalid("side to move")),
+        };
+        let mut castling = Castling::default();
+        if fields[2] != "-" {
+            for c in fields[2].chars() {
+                match c {
+                    'K' => castling.white_king = true,
+                    'Q' => castling.white_queen = true,
+                    'k' => castling.black_king = true,
+                    'q' => castling.black_queen = true,
+                    _ => return Err(invalid("castling")),
+                }
+            }
+        }
+        let en_passant = match fields[3] {
+            "-" => None,
+            square => Some(parse_square(square).ok_or_else(|| invalid("en passant square"))?),
+        };
+        let halfmove_clock = fields.get(4).map_or(Ok(0), |n| n.parse()).map_err(|_| invalid("halfmove clock"))?;
+        let fullmove_number = fields.get(5).map_or(Ok(1), |n| n.parse()).map_err(|_| invalid("move number"))?;
+
+        let position = Position { board, turn, castling, en_passant, halfmove_clock, fullmove_number };
+        for color in [Color::White, Color::Black] {
+            let kings = board.iter().flatten().filter(|p| p.color == color && p.kind == Kind::King).count();
+            if kings != 1 {
+                return Err(format!("Invalid FEN: {} needs exactly one king", color.name()));
+            }
+        }
+        Ok(position)
+    }
+
+    pub fn to_fen(&self) -> String {
+        let mut fen = String::new();
+        for r in (0..8).rev() {
+            let mut empty = 0;
+            for f in 0..8 {
+                match self.board[r * 8 + f] {
+                    Some(piece) => {
+                        if empty > 0 {
+                            fen.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        fen.push(piece.to_fen());
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+            }
+            if r > 0 {
+                fen.push('/');
+            }
+        }
+        format!(
+            "{} {} {} {} {} {}",
+            fen,
+            if self.turn == Color::White { "w" } else { "b" },
+            self.castling_field(),
+            self.en_passant.map_or("-".to_string(), square_name),
+            self.halfmove_clock,
+            self.fullmove_number
+        )
+    }
+
+    fn castling_field(&self) -> String {
+        let rights = [
+            (self.castling.white_king, 'K'),
+            (self.castling.white_queen, 'Q'),
+            (self.castling.black_king, 'k'),
+            (self.castling.black_queen, 'q'),
+        ];
+        let field: String = rights.iter().filter(|(has, _)| *has).map(|(_, c)| *c).collect();
+        if field.is_empty() { "-".to_string() } else { field }
+    }
+
+    /// What makes two positions the same for repetition: the pieces, the
+    /// side to move, castling rights, and an en passant square only when a
+    /// capture there is actually possible.
+    fn repetition_key(&self) -> String {
+        let en_passant = self
+            .en_passant
+            .filter(|&ep| self.legal_moves().iter().any(|m| m.to == ep && self.is_pawn(m.from)))
+            .map_or("-".to_string(), square_name);
+        let fen = self.to_fen();
+        let placement = fen.split(' ').next().unwrap_or_default();
+        format!("{} {} {} {}", placement, if self.turn == Color::White { "w" } else { "b" }, self.castling_field(), en_passant)
+    }
+
+    pub fn turn(&self) -> Color {
+        self.turn
+    }
+
+    fn is_pawn(&self, sq: Square) -> bool {
+        self.board[sq].is_some_and(|p| p.kind == Kind::Pawn)
+    }
+
+    fn king(&self, color: Color) -> Option<Square> {
+        (0..64).find(|&sq| self.board[sq] == Some(Piece { color, kind: Kind::King }))
+    }
+
+    /// Whether a piece of `by` attacks `sq`.
+    fn is_attacked(&self, sq: Square, by: Color) -> bool {
+        let holds = |sq: Option<Square>, kinds: &[Kind]| {
+            sq.and_then(|sq| self.board[sq]).is_some_and(|p| p.color == by && kinds.contains(&p.kind))
+        };
+        // A pawn attacks diagonally forward, so look back from its target
+        let pawn_rank = if by == Color::White { -1 } else { 1 };
+        if [-1, 1].iter().any(|&df| holds(offset(sq, df, pawn_rank), &[Kind::Pawn])) {
+            return true;
+        }
+        if KNIGHT_JUMPS.iter().any(|&(df, dr)| holds(offset(sq, df, dr), &[Kind::Knight])) {
+            return true;
+        }
+        if KING_STEPS.iter().any(|&(df, dr)| holds(offset(sq, df, dr), &[Kind::King])) {
+            return true;
+        }
+        let slides = |lines: &[(i32, i32)], kinds: &[Kind]| {
+            lines.iter().any(|&(df, dr)| {
+                let mut next = offset(sq, df, dr);
+                while let Some(at) = next {
+                    if self.board[at].is_some() {
+                        return holds(Some(at), kinds);
+                    }
+                    next = offset(at, df, dr);
+                }
+                false
+            })
+        };
+        slides(&ROOK_LINES, &[Kind::Rook, Kind::Queen]) || slides(&BISHOP_LINES, &[Kind::Bishop, Kind::Queen])
+    }
+
+    pub fn in_check(&self) -> bool {
+        self.king(self.turn).is_some_and(|king| self.is_attacked(king, self.turn.opponent()))
+    }
+
+    /// Moves that follow how the pieces move, without checking whether they
+    /// leave the mover's king attacked. Castling is checked in full here,
+    /// since it depends on the squares the king passes.
+    fn pseudo_moves(&self) -> Vec<Move> {
+        let mut moves = Vec::new();
+        let us = self.turn;
+        let target_ok = |to: Square| self.board[to].is_none_or(|p| p.color != us);
+
+        for from in 0..64 {
+            let Some(piece) = self.board[from].filter(|p| p.color == us) else {
+                continue;
+            };
+            match piece.kind {
+                Kind::Pawn => {
+                    let (dir, start_rank, last_rank) = if us == Color::White { (1, 1, 7) } else { (-1, 6, 0) };
+                    let mut push = |to: Square| {
+                        if rank(to) == last_rank {
+                            moves.extend(PROMOTIONS.iter().map(|&kind| Move { from, to, promotion: Some(kind) }));
+                        } else {
+                            moves.push(Move { from, to, promotion: None });
+                        }
+                    };
+                    if let Some(one) = offset(from, 0, dir).filter(|&to| self.board[to].is_none()) {
+                        push(one);
+                        if rank(from) == start_rank {
+                            if let Some(two) = offset(from, 0, 2 * dir).filter(|&to| self.board[to].is_none()) {
+                                push(two);
+                            }
+                        }
+                    }
+                    for df in [-1, 1] {
+                        if let Some(to) = offset(from, df, dir) {
+                            let captures = self.board[to].is_some_and(|p| p.color != us);
+                            if captures || self.en_passant == Some(to) {
+                                push(to);
+                            }
+                        }
+                    }
+                }
+                Kind::Knight | Kind::King => {
+                    let steps = if piece.kind == Kind::Knight { &KNIGHT_JUMPS } else { &KING_STEPS };
+                    for &(df, dr) in steps {
+                        if let Some(to) = offset(from, df, dr).filter(|&to| target_ok(to)) {
+                            moves.push(Move { from, to, promotion: None });
+                        }
+                    }
+                }
+                Kind::Bishop | Kind::Rook | Kind::Queen => {
+                    let lines: Vec<(i32, i32)> = match piece.kind {
+                        Kind::Bishop => BISHOP_LINES.to_vec(),
+                        Kind::Rook => ROOK_LINES.to_vec(),
+                        _ => ROOK_LINES.iter().chain(BISHOP_LINES.iter()).copied().collect(),
+                    };
+                    for (df, dr) in lines {
+                        let mut next = offset(from, df, dr);
+                        while let Some(to) = next {
+                            if target_ok(to) {
+                                moves.push(Move { from, to, promotion: None });
+                            }
+                            if self.board[to].is_some() {
+                                break;
+                            }
+                            next = offset(to, df, dr);
+                        }
+                    }
+                }
+            }
+        }
+        moves.extend(self.castling_moves());
+        moves
+    }
+
+    fn castling_moves(&self) -> Vec<Move> {
+        let us = self.turn;
+        let them = us.opponent();
+        let (home, king_side, queen_side) = match us {
+            Color::White => (0, self.castling.white_king, self.castling.white_queen),
+            Color::Black => (56, self.castling.black_king, self.castling.black_queen),
+        };
+        let king = home + 4;
+        if self.board[king] != Some(Piece { color: us, kind: Kind::King }) || self.is_attacked(king, them) {
+            return Vec::new();
+        }
+        let rook = Some(Piece { color: us, kind: Kind::Rook });
+        let empty = |squares: &[Square]| squares.iter().all(|&sq| self.board[sq].is_none());
+        let safe = |squares: &[Square]| squares.iter().all(|&sq| !self.is_attacked(sq, them));
+
+        let mut moves = Vec::new();
+        if king_side && self.board[home + 7] == rook && empty(&[home + 5, home + 6]) && safe(&[home + 5, home + 6]) {
+            moves.push(Move { from: king, to: home + 6, promotion: None });
+        }
+        if queen_side && self.board[home] == rook && empty(&[home + 1, home + 2, home + 3]) && safe(&[home + 2, home + 3]) {
+            moves.push(Move { from: king, to: home + 2, promotion: None });
+        }
+        moves
+    }
+
+    pub fn legal_moves(&self) -> Vec<Move> {
+        self.pseudo_moves()
+            .into_iter()
+            .filter(|&mv| {
+                let mut next = self.clone();
+                next.apply(mv);
+                next.king(self.turn).is_some_and(|king| !next.is_attacked(king, self.turn.opponent()))
+            })
+            .collect()
+    }
+
+    /// Play `mv`, which must be one of [`Position::pseudo_moves`].
+    fn apply(&mut self, mv: Move) {
+        let Some(piece) = self.board[mv.from].take() else {
+            return;
+        };
+        let mut captured = self.board[mv.to].is_some();
+
+        if piece.kind == Kind::Pawn && Some(mv.to) == self.en_passant && file(mv.from) != file(mv.to) && !captured {
+            let victim = if piece.color == Color::White { mv.to - 8 } else { mv.to + 8 };
+            self.board[victim] = None;
+            captured = true;
+        }
+        if piece.kind == Kind::King && file(mv.from).abs_diff(file(mv.to)) == 2 {
+            let (rook_from, rook_to) = if file(mv.to) == 6 { (mv.from + 3, mv.from + 1) } else { (mv.from - 4, mv.from - 1) };
+            self.board[rook_to] = self.board[rook_from].take();
+        }
+        self.board[mv.to] = Some(match mv.promotion {
+            Some(kind) => Piece { color: piece.color, kind },
+            None => piece,
+        });
+
+        if piece.kind == Kind::King {
+            match piece.color {
+                Color::White => (self.castling.white_king, self.castling.white_queen) = (false, false),
+                Color::Black => (self.castling.black_king, self.castling.black_queen) = (false, false),
+            }
+        }
+        // A rook leaving its corner, or captured there
+        for sq in [mv.from, mv.to] {
+            match sq {
+                0 => self.castling.white_queen = false,
+                7 => self.castling.white_king = false,
+                56 => self.castling.black_queen = false,
+                63 => self.castling.black_king = false,
+                _ => {}
+            }
+        }
+
+        self.en_passant =
+            (piece.kind == Kind::Pawn && rank(mv.from).abs_diff(rank(mv.to)) == 2).then_some((mv.from + mv.to) / 2);
+        self.halfmove_clock = if piece.kind == Kind::Pawn || captured { 0 } else { self.halfmove_clock + 1 };
+        if self.turn == Color::Black {
+            self.fullmove_number += 1;
+        }
+        self.turn = self.turn.opponent();
+    }
+
+    /// `mv` in standard algebraic notation; it must be legal here.
+    fn san(&self, mv: Move) -> String {
+        let Some(piece) = self.board[mv.from] else {
+            return mv.uci();
+        };
+        let mut san = String::new();
+        if piece.kind == Kind::King && file(mv.from).abs_diff(file(mv.to)) == 2 {
+            san.push_str(if file(mv.to) == 6 { "O-O" } else { "O-O-O" });
+        } else {
+            let capture = self.board[mv.to].is_some() || (piece.kind == Kind::Pawn && file(mv.from) != file(mv.to));
+            if piece.kind == Kind::Pawn {
+                if capture {
+                    san.push((b'a' + file(mv.from) as u8) as char);
+                }
+            } else {
+                san.push(piece.kind.letter());
+                let rivals: Vec<Move> = self
+                    .legal_moves()
+                    .into_iter()
+                    .filter(|m| m.to == mv.to && m.from != mv.from && self.board[m.from] == Some(piece))
+                    .collect();
+                if !rivals.is_empty() {
+                    let name = square_name(mv.from);
+                    if rivals.iter().all(|m| file(m.from) != file(mv.from)) {
+                        san.push_str(&name[..1]);
+                    } else if rivals.iter().all(|m| rank(m.from) != rank(mv.from)) {
+                        san.push_str(&name[1..]);
+                    } else {
+                        san.push_str(&name);
+                    }
+                }
+            }
+            if capture {
+                san.push('x');
+            }
+            san.push_str(&square_name(mv.to));
+            if let Some(kind) = mv.promotion {
+                san.push('=');
+                san.push(kind.letter());
+            }
+        }
+
+        let mut next = self.clone();
+        next.apply(mv);
+        if next.in_check() {
+            san.push(if next.legal_moves().is_empty() { '#' } else { '+' });
+        }
+        san
+    }
+
+    /// Neither side can ever mate: bare kings, a single minor piece, or only
+    /// bishops that all stand on one colour of square.
+    fn insufficient_material(&self) -> bool {
+        let pieces: Vec<(Square, Piece)> =
+            (0..64).filter_map(|sq| self.board[sq].map(|p| (sq, p))).filter(|(_, p)| p.kind != Kind::King).collect();
+        match pieces.as_slice() {
+            [] => true,
+            [(_, piece)] => matches!(piece.kind, Kind::Knight | Kind::Bishop),
+            _ => {
+                pieces.iter().all(|(_, p)| p.kind == Kind::Bishop)
+                    && pieces.iter().all(|(sq, _)| (file(*sq) + rank(*sq)) % 2 == (file(pieces[0].0) + rank(pieces[0].0)) % 2)
+            }
+        }
+    }
+}
+
+/// How a game ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Checkmate { winner: Color },
+    Stalemate,
+    ThreefoldRepetition,
+    FiftyMoveRule,
+    InsufficientMaterial,
+}
+
+impl Outcome {
+    pub fn winner(self) -> Option<Color> {
+        match self {
+            Outcome::Checkmate { winner } => Some(winner),
+            _ => None,
+        }
+    }
+
+    /// The PGN result: `1-0`, `0-1` or `1/2-1/2`.
+    pub fn result(self) -> &'static str {
+        match self.winner() {
+            Some(Color::White) => "1-0",
+            Some(Color::Black) => "0-1",
+            None => "1/2-1/2",
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            Outcome::Checkmate { .. } => "checkmate",
+            Outcome::Stalemate => "stalemate",
+            Outcome::ThreefoldRepetition => "threefold_repetition",
+            Outcome::FiftyMoveRule => "fifty_move_rule",
+            Outcome::InsufficientMaterial => "insufficient_material",
+        }
+    }
+}
+
+/// Read a move frame: `{"from": "e7", "to": "e8", "promotion": "q"}`, with
+/// squares also accepted as `[row, col]`, or `{"uci": "e7e8q"}`. A pawn
+/// reaching the last rank without a `promotion` becomes a queen.
+pub fn parse_move(move_data: &str) -> Result<Move, String> {
+    let value: serde_json::Value = serde_json::from_str(move_data).map_err(|_| "Invalid move format")?;
+    if let Some(uci) = value["uci"].as_str() {
+        let uci = uci.trim();
+        let from = uci.get(0..2).and_then(parse_square);
+        let to = uci.get(2..4).and_then(parse_square);
+        let (Some(from), Some(to)) = (from, to) else {
+            return Err(format!("Invalid move `{}`", uci));
+        };
+        let promotion = match uci.get(4..) {
+            None | Some("") => None,
+            Some(p) => Some(parse_promotion(p)?),
+        };
+        return Ok(Move { from, to, promotion });
+    }
+
+    let square = |value: &serde_json::Value| match value {
+        serde_json::Value::String(name) => parse_square(name.trim()),
+        serde_json::Value::Array(rc) if rc.len() == 2 => {
+            let (row, col) = (rc[0].as_u64()? as usize, rc[1].as_u64()? as usize);
+            (row < 8 && col < 8).then(|| (7 - row) * 8 + col)
+        }
+        _ => None,
+    };
+    let from = square(&value["from"]).ok_or("Invalid from position")?;
+    let to = square(&value["to"]).ok_or("Invalid to position")?;
+    let promotion = value["promotion"].as_str().map(parse_promotion).transpose()?;
+    Ok(Move { from, to, promotion })
+}
+
+fn parse_promotion(p: &str) -> Result<Kind, String> {
+    match Kind::from_char(p.chars().next().unwrap_or(' ')) {
+        Some(kind) if p.chars().count() == 1 && PROMOTIONS.contains(&kind) => Ok(kind),
+        _ => Err(format!("Invalid promotion `{}`; use q, r, b or n", p)),
+    }
+}
+
+/// The last move, for clients to highlight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LastMove {
+    from: [usize; 2],
+    to: [usize; 2],
+    san: String,
+}
+
+/// `game_state` of a chess game, as stored and sent to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct State {
+    fen: String,
+    // The position `moves` start from; missing in states saved before it
+    // was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    start_fen: Option<String>,
+    // Derived from `fen`: rank 8 first, "." for an empty square
+    board: Vec<Vec<String>>,
+    turn: String,
+    // In SAN
+    moves: Vec<String>,
+    // Repetition keys since the last capture or pawn move
+    #[serde(default)]
+    positions: Vec<String>,
+    #[serde(default)]
+    check: bool,
+    // In UCI, so clients can show where a piece may go
+    #[serde(default)]
+    legal_moves: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_move: Option<LastMove>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end_reason: Option<String>,
+}
+
+fn row_col(sq: Square) -> [usize; 2] {
+    [7 - rank(sq), file(sq)]
+}
+
+/// A game in progress: the position and the history rules need.
+#[derive(Debug, Clone)]
+pub struct ChessGame {
+    position: Position,
+    start_fen: Option<String>,
+    moves: Vec<String>,
+    positions: Vec<String>,
+    last_move: Option<LastMove>,
+    outcome: Option<Outcome>,
+}
+
+impl Default for ChessGame {
+    fn default() -> Self {
+        ChessGame::new()
+    }
+}
+
+impl ChessGame {
+    pub fn new() -> ChessGame {
+        let position = Position::start();
+        let positions = vec![position.repetition_key()];
+        ChessGame {
+            position,
+            start_fen: Some(START_FEN.to_string()),
+            moves: Vec::new(),
+            positions,
+            last_move: None,
+            outcome: None,
+        }
+    }
+
+    /// Read a stored `game_state`, including ones saved before FEN was kept.
+    pub fn from_state(state: &str) -> Result<ChessGame, String> {
+        let value: serde_json::Value = serde_json::from_str(state).map_err(|_| "Invalid game state")?;
+        let mut game = match value.get("fen").and_then(|fen| fen.as_str()) {
+            Some(fen) => {
+                let state: State = serde_json::from_value(value.clone()).map_err(|_| "Invalid game state")?;
+                ChessGame {
+                    position: Position::from_fen(fen)?,
+                    start_fen: state.start_fen,
+                    moves: state.moves,
+                    positions: state.positions,
+                    last_move: state.last_move,
+                    outcome: None,
+                }
+            }
+            None => {
+                // Moves before now were never recorded against a position, so
+                // the game's history starts here
+                let position = Position::from_legacy(&value)?;
+                let positions = vec![position.repetition_key()];
+                let start_fen = Some(position.to_fen());
+                ChessGame { position, start_fen, moves: Vec::new(), positions, last_move: None, outcome: None }
+            }
+        };
+        game.outcome = game.evaluate();
+        Ok(game)
+    }
+
+    pub fn turn(&self) -> Color {
+        self.position.turn()
+    }
+
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.outcome
+    }
+
+    /// Play `mv` if it is legal, returning how the game ended if it did.
+    pub fn play(&mut self, mv: Move) -> Result<Option<Outcome>, String> {
+        if self.outcome.is_some() {
+            return Err("The game is over".to_string());
+        }
+        let legal = self.position.legal_moves();
+        let candidates: Vec<&Move> = legal.iter().filter(|m| m.from == mv.from && m.to == mv.to).collect();
+        let Some(first) = candidates.first() else {
+            return Err(self.illegal_reason(mv));
+        };
+        let chosen = match (first.promotion.is_some(), mv.promotion) {
+            (true, promotion) => {
+                let kind = promotion.unwrap_or(Kind::Queen);
+                **candidates.iter().find(|m| m.promotion == Some(kind)).ok_or("Invalid promotion")?
+            }
+            (false, None) => **first,
+            (false, Some(_)) => return Err("Only a pawn reaching the last rank can promote".to_string()),
+        };
+
+        let san = self.position.san(chosen);
+        self.position.apply(chosen);
+        if self.position.halfmove_clock == 0 {
+            // Nothing before a capture or pawn move can come back
+            self.positions.clear();
+        }
+        self.positions.push(self.position.repetition_key());
+        self.last_move = Some(LastMove { from: row_col(chosen.from), to: row_col(chosen.to), san: san.clone() });
+        self.moves.push(san);
+        self.outcome = self.evaluate();
+        Ok(self.outcome)
+    }
+
+    fn illegal_reason(&self, mv: Move) -> String {
+        match self.position.board[mv.from] {
+            None => format!("There is no piece on {}", square_name(mv.from)),
+            Some(piece) if piece.color != self.position.turn => "That is not your piece".to_string(),
+            Some(_) if self.position.pseudo_moves().iter().any(|m| m.from == mv.from && m.to == mv.to) => {
+                "That move would leave your king in check".to_string()
+            }
+            Some(_) => format!("Illegal move {}{}", square_name(mv.from), square_name(mv.to)),
+        }
+    }
+
+    fn evaluate(&self) -> Option<Outcome> {
+        if self.position.legal_moves().is_empty() {
+            return Some(if self.position.in_check() {
+                Outcome::Checkmate { winner: self.position.turn.opponent() }
+            } else {
+                Outcome::Stalemate
+            });
+        }
+        if self.position.insufficient_material() {
+            return Some(Outcome::InsufficientMaterial);
+        }
+        let key = self.position.repetition_key();
+        if self.positions.iter().filter(|k| **k == key).count() >= 3 {
+            return Some(Outcome::ThreefoldRepetition);
+        }
+        if self.position.halfmove_clock >= 100 {
+            return Some(Outcome::FiftyMoveRule);
+        }
+        None
+    }
+
+    /// The game as `game_state` JSON.
+    pub fn state(&self) -> String {
+        let board = (0..8)
+            .map(|row| {
+                (0..8)
+                    .map(|col| self.position.board[(7 - row) * 8 + col].map_or(".".to_string(), |p| p.to_fen().to_string()))
+                    .collect()
+            })
+            .collect();
+        let finished = self.outcome.is_some();
+        let state = State {
+            fen: self.position.to_fen(),
+            start_fen: self.start_fen.clone(),
+            board,
+            turn: self.position.turn.name().to_string(),
+            moves: self.moves.clone(),
+            positions: self.positions.clone(),
+            check: self.position.in_check(),
+            legal_moves: if finished { Vec::new() } else { self.position.legal_moves().into_iter().map(Move::uci).collect() },
+            last_move: self.last_move.clone(),
+            result: self.outcome.map(|o| o.result().to_string()),
+            end_reason: self.outcome.map(|o| o.reason().to_string()),
+        };
+        serde_json::to_string(&state).unwrap_or_default()
+    }
+}
+
+impl Position {
+    /// A state from before FEN was stored: just `board` and `turn`. Castling
+    /// is allowed wherever king and rook still stand on their home squares.
+    fn from_legacy(value: &serde_json::Value) -> Result<Position, String> {
+        let rows = value["board"].as_array().filter(|rows| rows.len() == 8).ok_or("Invalid board")?;
+        let mut board = [None; 64];
+        for (row, cells) in rows.iter().enumerate() {
+            let cells = cells.as_array().filter(|cells| cells.len() == 8).ok_or("Invalid board")?;
+            for (col, cell) in cells.iter().enumerate() {
+                board[(7 - row) * 8 + col] = cell.as_str().and_then(|s| s.chars().next()).and_then(Piece::from_fen);
+            }
+        }
+        let has = |sq: Square, c: char| board[sq] == Piece::from_fen(c);
+        let castling = Castling {
+            white_king: has(4, 'K') && has(7, 'R'),
+            white_queen: has(4, 'K') && has(0, 'R'),
+            black_king: has(60, 'k') && has(63, 'r'),
+            black_queen: has(60, 'k') && has(56, 'r'),
+        };
+        let turn = if value["turn"].as_str() == Some("black") { Color::Black } else { Color::White };
+        let position = Position { board, turn, castling, en_passant: None, halfmove_clock: 0, fullmove_number: 1 };
+        // Run it through FEN so a board missing a king is refused the same way
+        Position::from_fen(&position.to_fen())
+    }
+}
+
+fn pgn_tag(name: &str, value: &str) -> String {
+    format!("[{} \"{}\"]\n", name, value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Write the game in `state` as PGN, replaying its moves from `move_data`,
+/// as stored in `game_moves`, from the position they started from. `date`
+/// is `YYYY.MM.DD`.
+///
+/// Games converted from the old board-only state start from the position
+/// they were converted at, with `SetUp` and `FEN` tags. A replay that does
+/// not reach the stored position is refused rather than exported wrong.
+pub fn pgn(event: &str, date: &str, white: &str, black: &str, state: &str, move_data: &[String]) -> Result<String, String> {
+    let stored = ChessGame::from_state(state)?;
+    let start_fen = stored.start_fen.as_deref().unwrap_or(START_FEN);
+    let start = Position::from_fen(start_fen)?;
+    let (first_move, black_first) = (start.fullmove_number, start.turn == Color::Black);
+    let mut game = ChessGame {
+        positions: vec![start.repetition_key()],
+        position: start,
+        start_fen: Some(start_fen.to_string()),
+        moves: Vec::new(),
+        last_move: None,
+        outcome: None,
+    };
+    // The moves played since the start position are the last ones stored
+    let Some(skipped) = move_data.len().checked_sub(stored.moves.len()) else {
+        return Err("This game's moves cannot be replayed".to_string());
+    };
+    for (index, data) in move_data.iter().enumerate().skip(skipped) {
+        parse_move(data)
+            .and_then(|mv| game.play(mv))
+            .map_err(|e| format!("Move {} cannot be replayed: {}", index + 1, e))?;
+    }
+    if game.position.to_fen() != stored.position.to_fen() {
+        return Err("This game was saved in an older format and cannot be exported as PGN".to_string());
+    }
+    let result = game.outcome.map_or("*", Outcome::result);
+
+    let mut pgn = String::new();
+    for (name, value) in [("Event", event), ("Site", "?"), ("Date", date), ("Round", "-"), ("White", white), ("Black", black), ("Result", result)] {
+        pgn.push_str(&pgn_tag(name, value));
+    }
+    if start_fen != START_FEN {
+        pgn.push_str(&pgn_tag("SetUp", "1"));
+        pgn.push_str(&pgn_tag("FEN", start_fen));
+    }
+    if let Some(outcome) = game.outcome.filter(|o| o.winner().is_none()) {
+        pgn.push_str(&pgn_tag("Termination", &outcome.reason().replace('_', " ")));
+    }
+    pgn.push('\n');
+
+    // Movetext, wrapped under 80 columns
+    let mut tokens = Vec::new();
+    for (index, san) in game.moves.iter().enumerate() {
+        let ply = index + usize::from(black_first);
+        let number = first_move as usize + ply / 2;
+        if ply % 2 == 0 {
+            tokens.push(format!("{}.", number));
+        } else if index == 0 {
+            tokens.push(format!("{}...", number));
+        }
+        tokens.push(san.clone());
+    }
+    tokens.push(result.to_string());
+    let mut line = String::new();
+    for token in tokens {
+        if !line.is_empty() && line.len() + 1 + token.len() > 79 {
+            pgn.push_str(&line);
+            pgn.push('\n');
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&token);
+    }
+    pgn.push_str(&line);
+    pgn.push('\n');
+    Ok(pgn)
+}
+
+/// The `chess` engine. The game's creator has white.
+pub struct Chess;
+
+fn seat_of(color: Color) -> Seat {
+    match color {
+        Color::White => Seat::First,
+        Color::Black => Seat::Second,
+    }
+}
+
+impl GameEngine for Chess {
+    fn game_type(&self) -> &'static str {
+        "chess"
+    }
+
+    fn label(&self) -> &'static str {
+        "♟️ Chess"
+    }
+
+    fn initial_state<'a>(&'a self, _pool: &'a SqlitePool) -> BoxFuture<'a, Result<String, String>> {
+        super::ready(ChessGame::new().state())
+    }
+
+    fn to_move(&self, state: &str) -> Result<Option<Seat>, String> {
+        Ok(Some(seat_of(ChessGame::from_state(state)?.turn())))
+    }
+
+    fn apply_move(&self, state: &str, _players: &Players, _seat: Seat, move_data: &str) -> Result<String, String> {
+        let mut game = ChessGame::from_state(state)?;
+        game.play(parse_move(move_data)?)?;
+        Ok(game.state())
+    }
+
+    fn outcome(&self, state: &str, _players: &Players) -> Result<Option<super::Outcome>, String> {
+        Ok(ChessGame::from_state(state)?.outcome().map(|outcome| match outcome.winner() {
+            Some(color) => super::Outcome::Winner(seat_of(color)),
+            None => super::Outcome::Draw,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn perft(position: &Position, depth: u32) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        position
+            .legal_moves()
+            .into_iter()
+            .map(|mv| {
+                let mut next = position.clone();
+                next.apply(mv);
+                perft(&next, depth - 1)
+            })
+            .sum()
+    }
+
+    fn assert_perft(fen: &str, counts: &[u64]) {
+        let position = Position::from_fen(fen).unwrap();
+        for (depth, &expected) in (1..).zip(counts) {
+            assert_eq!(perft(&position, depth), expected, "perft({}) of {}", depth, fen);
+        }
+    }
+
+    fn uci(mv: &str) -> String {
+        format!(r#"{{"uci": "{}"}}"#, mv)
+    }
+
+    fn game_at(fen: &str) -> ChessGame {
+        let position = Position::from_fen(fen).unwrap();
+        let positions = vec![position.repetition_key()];
+        ChessGame { position, start_fen: Some(fen.to_string()), moves: Vec::new(), positions, last_move: None, outcome: None }
+    }
+
+    /// Play space-separated UCI moves, returning the outcome after the last.
+    fn play(game: &mut ChessGame, moves: &str) -> Option<Outcome> {
+        let mut outcome = None;
+        for mv in moves.split_whitespace() {
+            outcome = game.play(parse_move(&uci(mv)).unwrap()).unwrap_or_else(|e| panic!("{}: {}", mv, e));
+        }
+        outcome
+    }
+
+    fn play_err(game: &mut ChessGame, mv: &str) -> String {
+        game.play(parse_move(&uci(mv)).unwrap()).unwrap_err()
+    }
+
+    #[test]
+    fn perft_start_position() {
+        assert_perft(START_FEN, &[20, 400, 8_902, 197_281]);
+    }
+
+    #[test]
+    fn perft_kiwipete() {
+        assert_perft("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", &[48, 2_039, 97_862]);
+    }
+
+    #[test]
+    fn perft_en_passant_pins() {
+        assert_perft("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", &[14, 191, 2_812]);
+    }
+
+    #[test]
+    fn perft_promotions() {
+        assert_perft("r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1", &[6, 264, 9_467]);
+    }
+
+    #[test]
+    fn fen_round_trips() {
+        for fen in [
+            START_FEN,
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "rnbqkbnr/ppp1pppp/8/3pP3/8/8/PPPP1PPP/RNBQKBNR w KQkq d6 0 3",
+            "8/8/8/8/8/8/6k1/4K2R b K - 12 47",
+        ] {
+            assert_eq!(Position::from_fen(fen).unwrap().to_fen(), fen);
+        }
+        assert!(Position::from_fen("8/8/8/8/8/8/8/8 w - - 0 1").is_err());
+    }
+
+    #[test]
+    fn castling() {
+        let mut game = game_at("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+        play(&mut game, "e1g1 e8c8");
+        assert_eq!(game.position.to_fen(), "2kr3r/8/8/8/8/8/8/R4RK1 w - - 2 2");
+        assert_eq!(game.moves, ["O-O", "O-O-O"]);
+    }
+
+    #[test]
+    fn no_castling_through_check_or_after_the_king_moved() {
+        let mut game = game_at("r3k2r/8/8/8/8/5r2/8/R3K2R w KQkq - 0 1");
+        assert!(play_err(&mut game, "e1g1").starts_with("Illegal move"));
+        play(&mut game, "e1c1");
+
+        let mut game = game_at("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+        play(&mut game, "e1f1 e8f8 f1e1 f8e8");
+        assert!(play_err(&mut game, "e1g1").starts_with("Illegal move"));
+    }
+
+    #[test]
+    fn en_passant() {
+        let mut game = ChessGame::new();
+        play(&mut game, "e2e4 a7a6 e4e5 d7d5 e5d6");
+        assert_eq!(game.moves.last().unwrap(), "exd6");
+        assert_eq!(game.position.to_fen(), "rnbqkbnr/1pp1pppp/p2P4/8/8/8/PPPP1PPP/RNBQKBNR b KQkq - 0 3");
+
+        // Only on the move right after the double push
+        let mut game = ChessGame::new();
+        play(&mut game, "e2e4 a7a6 e4e5 d7d5 h2h3 h7h6");
+        assert!(play_err(&mut game, "e5d6").starts_with("Illegal move"));
+    }
+
+    #[test]
+    fn promotion() {
+        let mut game = game_at("8/P7/8/8/8/8/8/k6K w - - 0 1");
+        play(&mut game, "a7a8n");
+        assert_eq!(game.moves, ["a8=N"]);
+        assert_eq!(game.position.to_fen(), "N7/8/8/8/8/8/8/k6K b - - 0 1");
+
+        // A queen unless told otherwise
+        let mut game = game_at("8/P7/8/8/8/8/8/k6K w - - 0 1");
+        play(&mut game, "a7a8");
+        assert_eq!(game.moves, ["a8=Q+"]);
+
+        assert!(parse_move(&uci("a7a8k")).is_err());
+        assert!(play_err(&mut game_at("8/8/8/8/8/8/P7/k6K w - - 0 1"), "a2a3q").contains("promote"));
+    }
+
+    #[test]
+    fn moving_into_check_is_refused() {
+        let mut game = game_at("4k3/8/8/8/8/8/4r3/4K3 w - - 0 1");
+        assert_eq!(play_err(&mut game, "e1d2"), "That move would leave your king in check");
+        assert_eq!(play_err(&mut game, "e8e7"), "That is not your piece");
+    }
+
+    #[test]
+    fn checkmate() {
+        let mut game = ChessGame::new();
+        let outcome = play(&mut game, "f2f3 e7e5 g2g4 d8h4");
+        assert_eq!(outcome, Some(Outcome::Checkmate { winner: Color::Black }));
+        assert_eq!(game.moves.last().unwrap(), "Qh4#");
+        assert_eq!(play_err(&mut game, "a2a3"), "The game is over");
+    }
+
+    #[test]
+    fn stalemate() {
+        let mut game = game_at("k7/8/1Q6/8/8/8/8/7K w - - 0 1");
+        assert_eq!(play(&mut game, "b6c7"), Some(Outcome::Stalemate));
+    }
+
+    #[test]
+    fn threefold_repetition() {
+        let mut game = ChessGame::new();
+        assert_eq!(play(&mut game, "g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1"), None);
+        assert_eq!(play(&mut game, "f6g8"), Some(Outcome::ThreefoldRepetition));
+    }
+
+    #[test]
+    fn fifty_move_rule() {
+        let mut game = game_at("k7/8/8/8/8/8/8/KR6 w - - 98 80");
+        assert_eq!(play(&mut game, "b1b2"), None);
+        assert_eq!(play(&mut game, "a8a7"), Some(Outcome::FiftyMoveRule));
+    }
+
+    #[test]
+    fn insufficient_material() {
+        let mut game = game_at("k7/8/8/8/8/8/8/Kr6 w - - 0 1");
+        assert_eq!(play(&mut game, "a1b1"), Some(Outcome::InsufficientMaterial));
+        for fen in ["k7/8/8/8/8/8/8/KN6 w - - 0 1", "k7/8/8/8/8/8/8/KB5b w - - 0 1"] {
+            assert!(Position::from_fen(fen).unwrap().insufficient_material(), "{}", fen);
+        }
+        for fen in ["k7/8/8/8/8/8/8/KB4b1 w - - 0 1", "k7/8/8/8/8/8/8/KNN5 w - - 0 1", "k7/8/8/8/8/8/P7/K7 w - - 0 1"] {
+            assert!(!Position::from_fen(fen).unwrap().insufficient_material(), "{}", fen);
+        }
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let mut game = ChessGame::new();
+        play(&mut game, "e2e4 e7e5");
+        let restored = ChessGame::from_state(&game.state()).unwrap();
+        assert_eq!(restored.position, game.position);
+        assert_eq!(restored.moves, ["e4", "e5"]);
+        assert_eq!(restored.start_fen.as_deref(), Some(START_FEN));
+    }
+
+    #[test]
+    fn pgn_of_a_game_from_the_start() {
+        let mut game = ChessGame::new();
+        let moves = ["f2f3", "e7e5", "g2g4", "d8h4"];
+        play(&mut game, &moves.join(" "));
+        let move_data: Vec<String> = moves.iter().map(|mv| uci(mv)).collect();
+        let exported = pgn("Test", "2024.01.02", "alice", "bob", &game.state(), &move_data).unwrap();
+        assert!(exported.contains("[White \"alice\"]\n[Black \"bob\"]\n[Result \"0-1\"]\n"), "{}", exported);
+        assert!(!exported.contains("[FEN"), "{}", exported);
+        assert!(exported.ends_with("\n1. f3 e5 2. g4 Qh4# 0-1\n"), "{}", exported);
+    }
+
+    #[test]
+    fn pgn_of_a_game_saved_in_the_old_format() {
+        // An old state is just the board and whose turn it is
+        let mut old = ChessGame::new();
+        play(&mut old, "e2e4");
+        let state: serde_json::Value = serde_json::from_str(&old.state()).unwrap();
+        let legacy = serde_json::json!({ "board": state["board"], "turn": state["turn"] }).to_string();
+
+        let mut game = ChessGame::from_state(&legacy).unwrap();
+        play(&mut game, "e7e5");
+        let move_data = [uci("e2e4"), uci("e7e5")];
+        let exported = pgn("Test", "2024.01.02", "alice", "bob", &game.state(), &move_data).unwrap();
+        assert!(exported.contains("[SetUp \"1\"]\n[FEN \"rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1\"]\n"), "{}", exported);
+        assert!(exported.ends_with("\n1... e5 *\n"), "{}", exported);
+
+        // Converted before the start position was kept: the replay from the
+        // standard start does not reach the stored position
+        let mut state: serde_json::Value = serde_json::from_str(&game.state()).unwrap();
+        state.as_object_mut().unwrap().remove("start_fen");
+        assert!(pgn("Test", "2024.01.02", "alice", "bob", &state.to_string(), &move_data).is_err());
+    }
+}
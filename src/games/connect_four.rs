@@ -0,0 +1,215 @@
+// src/games/connect_four.rs
+//! Connect Four on the standard 7-column, 6-row board. The game's creator
+//! plays red and drops first.
+//!
+//! `board` is laid out top row first, each cell `"R"`, `"Y"` or `""`. A move
+//! names a column, `{"column": 3}`, and the disc falls to the lowest empty
+//! cell in it. Four in a row in any direction wins, and a full board with no
+//! line is a draw.
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use super::{GameEngine, Outcome, Players, Seat};
+
+const ROWS: usize = 6;
+const COLUMNS: usize = 7;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    board: Vec<Vec<String>>,
+    // "R" or "Y"
+    turn: String,
+    // Columns dropped into, in order
+    #[serde(default)]
+    moves: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_move: Option<[usize; 2]>,
+    // The four cells of the winning line, once there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    winning_line: Option<Vec<[usize; 2]>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DropMove {
+    column: usize,
+}
+
+fn disc(seat: Seat) -> &'static str {
+    match seat {
+        Seat::First => "R",
+        Seat::Second => "Y",
+    }
+}
+
+fn read(state: &str) -> Result<State, String> {
+    let state: State = super::parse_state(state)?;
+    if state.board.len() != ROWS || state.board.iter().any(|row| row.len() != COLUMNS) {
+        return Err("Invalid board".to_string());
+    }
+    Ok(state)
+}
+
+impl State {
+    /// The line of four or more through `(row, col)`, if its disc made one.
+    fn line_through(&self, row: usize, col: usize) -> Option<Vec<[usize; 2]>> {
+        let disc = &self.board[row][col];
+        if disc.is_empty() {
+            return None;
+        }
+        let same = |r: isize, c: isize| {
+            (0..ROWS as isize).contains(&r)
+                && (0..COLUMNS as isize).contains(&c)
+                && self.board[r as usize][c as usize] == *disc
+        };
+        for (dr, dc) in [(0, 1), (1, 0), (1, 1), (1, -1)] {
+            let mut line = vec![[row, col]];
+            for direction in [1, -1] {
+                let (mut r, mut c) = (row as isize + dr * direction, col as isize + dc * direction);
+                while same(r, c) {
+                    line.push([r as usize, c as usize]);
+                    r += dr * direction;
+                    c += dc * direction;
+                }
+            }
+            if line.len() >= 4 {
+                line.sort();
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    fn winner(&self) -> Option<Seat> {
+        let [row, col] = self.last_move?;
+        self.line_through(row, col)?;
+        Some(if self.board[row][col] == disc(Seat::First) { Seat::First } else { Seat::Second })
+    }
+}
+
+/// The `connect_four` engine.
+pub struct ConnectFour;
+
+impl GameEngine for ConnectFour {
+    fn game_type(&self) -> &'static str {
+        "connect_four"
+    }
+
+    fn label(&self) -> &'static str {
+        "🔴 Connect Four"
+    }
+
+    fn initial_state<'a>(&'a self, _pool: &'a SqlitePool) -> BoxFuture<'a, Result<String, String>> {
+        super::ready(super::to_state(&State {
+            board: vec![vec![String::new(); COLUMNS]; ROWS],
+            turn: disc(Seat::First).to_string(),
+            moves: Vec::new(),
+            last_move: None,
+            winning_line: None,
+        }))
+    }
+
+    fn to_move(&self, state: &str) -> Result<Option<Seat>, String> {
+        let state = read(state)?;
+        Ok(Some(if state.turn == disc(Seat::First) { Seat::First } else { Seat::Second }))
+    }
+
+    fn apply_move(&self, state: &str, _players: &Players, seat: Seat, move_data: &str) -> Result<String, String> {
+        let mut state = read(state)?;
+        if state.winning_line.is_some() {
+            return Err("The game is over".to_string());
+        }
+        let DropMove { column } = super::parse_move(move_data)?;
+        if column >= COLUMNS {
+            return Err(format!("Column must be 0 to {}", COLUMNS - 1));
+        }
+        let row = (0..ROWS).rev().find(|&row| state.board[row][column].is_empty()).ok_or("That column is full")?;
+
+        state.board[row][column] = disc(seat).to_string();
+        state.moves.push(column);
+        state.last_move = Some([row, column]);
+        state.winning_line = state.line_through(row, column);
+        state.turn = disc(seat.other()).to_string();
+        Ok(super::to_state(&state))
+    }
+
+    fn outcome(&self, state: &str, _players: &Players) -> Result<Option<Outcome>, String> {
+        let state = read(state)?;
+        Ok(if let Some(seat) = state.winner() {
+            Some(Outcome::Winner(seat))
+        } else if state.board[0].iter().all(|cell| !cell.is_empty()) {
+            Some(Outcome::Draw)
+        } else {
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{new_game, PLAYERS};
+    use super::*;
+
+    /// Drop discs into `columns` in turn, red first, returning the state.
+    fn play(columns: &str) -> String {
+        let mut state = new_game(&ConnectFour);
+        for (i, column) in columns.chars().enumerate() {
+            let seat = if i % 2 == 0 { Seat::First } else { Seat::Second };
+            let move_data = format!(r#"{{"column": {}}}"#, column);
+            state = ConnectFour.apply_move(&state, &PLAYERS, seat, &move_data).unwrap();
+        }
+        state
+    }
+
+    fn outcome(state: &str) -> Option<Outcome> {
+        ConnectFour.outcome(state, &PLAYERS).unwrap()
+    }
+
+    #[test]
+    fn discs_stack_up_from_the_bottom() {
+        let state = read(&play("33")).unwrap();
+        assert_eq!(state.board[5][3], "R");
+        assert_eq!(state.board[4][3], "Y");
+        assert_eq!(state.turn, "R");
+        assert_eq!(state.last_move, Some([4, 3]));
+    }
+
+    #[test]
+    fn vertical_win() {
+        let state = play("0101010");
+        assert_eq!(outcome(&state), Some(Outcome::Winner(Seat::First)));
+        assert_eq!(read(&state).unwrap().winning_line, Some(vec![[2, 0], [3, 0], [4, 0], [5, 0]]));
+        let err = ConnectFour.apply_move(&state, &PLAYERS, Seat::Second, r#"{"column": 1}"#).unwrap_err();
+        assert_eq!(err, "The game is over");
+    }
+
+    #[test]
+    fn horizontal_win() {
+        assert_eq!(outcome(&play("0102030")), Some(Outcome::Winner(Seat::First)));
+        assert_eq!(outcome(&play("01020364")), Some(Outcome::Winner(Seat::Second)));
+    }
+
+    #[test]
+    fn diagonal_win() {
+        let state = play("01122323363");
+        assert_eq!(outcome(&state), Some(Outcome::Winner(Seat::First)));
+        assert_eq!(read(&state).unwrap().winning_line, Some(vec![[2, 3], [3, 2], [4, 1], [5, 0]]));
+        assert_eq!(outcome(&play("0112232336")), None);
+    }
+
+    #[test]
+    fn full_board_without_a_line_is_a_draw() {
+        let columns = "012345601234560123456103254061234560123456";
+        assert_eq!(outcome(&play(&columns[..41])), None);
+        assert_eq!(outcome(&play(columns)), Some(Outcome::Draw));
+    }
+
+    #[test]
+    fn bad_columns_are_refused() {
+        let state = play("000000");
+        let drop = |column: usize| ConnectFour.apply_move(&state, &PLAYERS, Seat::First, &format!(r#"{{"column": {}}}"#, column));
+        assert_eq!(drop(0).unwrap_err(), "That column is full");
+        assert_eq!(drop(7).unwrap_err(), "Column must be 0 to 6");
+        assert!(drop(6).is_ok());
+    }
+}
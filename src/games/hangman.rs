@@ -0,0 +1,250 @@
+// src/games/hangman.rs
+//! Hangman for two: players take turns guessing at a hidden word, scoring a
+//! point for every letter their guess reveals.
+//!
+//! A guess is a letter, `{"letter": "e"}`, or the whole word, `{"word":
+//! "rocket"}`; a right word scores the letters still hidden. Wrong guesses
+//! of either kind are misses, shared by both players. The game ends when
+//! the word is revealed or after `MAX_MISSES` misses, and the higher score
+//! wins. Views show the word as `pattern`, with `_` for hidden letters,
+//! until the game is over.
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+use super::{GameEngine, Outcome, Players, Seat};
+
+const MAX_MISSES: usize = 6;
+
+const WORDS: &[&str] = &[
+    "anchor", "balloon", "blanket", "bridge", "cactus", "candle", "castle", "compass", "dolphin", "garden",
+    "giraffe", "glacier", "harbor", "helmet", "island", "jigsaw", "kettle", "ladder", "lantern", "meadow",
+    "monster", "orchard", "panther", "pepper", "pirate", "planet", "puzzle", "rainbow", "rocket", "saddle",
+    "scarecrow", "squirrel", "thunder", "tornado", "trumpet", "umbrella", "volcano", "walrus", "whistle", "wizard",
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    // Absent from views until the game is over
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    word: String,
+    // Letters guessed so far, right or wrong
+    guessed: Vec<char>,
+    // Wrong letters and words, in order
+    misses: Vec<String>,
+    max_misses: usize,
+    scores: BTreeMap<String, usize>,
+    // 0 for the game's creator, 1 for the other player
+    turn: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Guess {
+    Letter(String),
+    Word(String),
+}
+
+impl State {
+    fn hidden(&self) -> usize {
+        self.word.chars().filter(|c| !self.guessed.contains(c)).count()
+    }
+
+    fn is_over(&self) -> bool {
+        self.hidden() == 0 || self.misses.len() >= self.max_misses
+    }
+
+    fn pattern(&self) -> String {
+        self.word
+            .chars()
+            .map(|c| if self.guessed.contains(&c) { c } else { '_' })
+            .map(String::from)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// The `hangman` engine.
+pub struct Hangman;
+
+impl GameEngine for Hangman {
+    fn game_type(&self) -> &'static str {
+        "hangman"
+    }
+
+    fn label(&self) -> &'static str {
+        "🪢 Hangman"
+    }
+
+    fn initial_state<'a>(&'a self, _pool: &'a SqlitePool) -> BoxFuture<'a, Result<String, String>> {
+        let word = WORDS[OsRng.next_u32() as usize % WORDS.len()];
+        super::ready(super::to_state(&State {
+            word: word.to_string(),
+            guessed: Vec::new(),
+            misses: Vec::new(),
+            max_misses: MAX_MISSES,
+            scores: BTreeMap::new(),
+            turn: Seat::First.index(),
+            pattern: None,
+        }))
+    }
+
+    fn to_move(&self, state: &str) -> Result<Option<Seat>, String> {
+        let state: State = super::parse_state(state)?;
+        Ok(Some(if state.turn == Seat::First.index() { Seat::First } else { Seat::Second }))
+    }
+
+    fn apply_move(&self, state: &str, players: &Players, seat: Seat, move_data: &str) -> Result<String, String> {
+        let mut state: State = super::parse_state(state)?;
+        if state.is_over() {
+            return Err("The game is over".to_string());
+        }
+        let hidden_before = state.hidden();
+        match super::parse_move(move_data)? {
+            Guess::Letter(letter) => {
+                let mut chars = letter.trim().chars();
+                let letter = match (chars.next(), chars.next()) {
+                    (Some(c), None) if c.is_ascii_alphabetic() => c.to_ascii_lowercase(),
+                    _ => return Err("Guess a single letter".to_string()),
+                };
+                if state.guessed.contains(&letter) {
+                    return Err(format!("'{}' has already been guessed", letter));
+                }
+                state.guessed.push(letter);
+                if !state.word.contains(letter) {
+                    state.misses.push(letter.to_string());
+                }
+            }
+            Guess::Word(word) => {
+                let word = word.trim().to_ascii_lowercase();
+                if word.is_empty() {
+                    return Err("Guess a word".to_string());
+                }
+                if word == state.word {
+                    for c in word.chars() {
+                        if !state.guessed.contains(&c) {
+                            state.guessed.push(c);
+                        }
+                    }
+                } else {
+                    state.misses.push(word);
+                }
+            }
+        }
+
+        let revealed = hidden_before - state.hidden();
+        *state.scores.entry(players.name(seat).to_string()).or_insert(0) += revealed;
+        state.turn = seat.other().index();
+        Ok(super::to_state(&state))
+    }
+
+    fn outcome(&self, state: &str, players: &Players) -> Result<Option<Outcome>, String> {
+        let state: State = super::parse_state(state)?;
+        if !state.is_over() {
+            return Ok(None);
+        }
+        let score = |seat| state.scores.get(players.name(seat)).copied().unwrap_or(0);
+        Ok(Some(super::by_score(score(Seat::First), score(Seat::Second))))
+    }
+
+    fn view(&self, state: &str, _viewer: Option<Seat>) -> String {
+        let Ok(mut state) = super::parse_state::<State>(state) else {
+            return state.to_string();
+        };
+        state.pattern = Some(state.pattern());
+        if !state.is_over() {
+            state.word.clear();
+        }
+        super::to_state(&state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{new_game, PLAYERS};
+    use super::*;
+
+    fn with_word(word: &str) -> String {
+        let mut state: State = super::super::parse_state(&new_game(&Hangman)).unwrap();
+        state.word = word.to_string();
+        super::super::to_state(&state)
+    }
+
+    fn guess(state: &str, seat: Seat, move_data: &str) -> Result<String, String> {
+        Hangman.apply_move(state, &PLAYERS, seat, move_data)
+    }
+
+    fn scores(state: &str) -> BTreeMap<String, usize> {
+        super::super::parse_state::<State>(state).unwrap().scores
+    }
+
+    #[test]
+    fn a_letter_scores_every_place_it_appears() {
+        let state = with_word("balloon");
+        let state = guess(&state, Seat::First, r#"{"letter": "L"}"#).unwrap();
+        assert_eq!(scores(&state)["alice"], 2);
+        assert_eq!(Hangman.to_move(&state).unwrap(), Some(Seat::Second));
+
+        let state = guess(&state, Seat::Second, r#"{"letter": "z"}"#).unwrap();
+        assert_eq!(scores(&state)["bob"], 0);
+        let read_back: State = super::super::parse_state(&state).unwrap();
+        assert_eq!(read_back.misses, vec!["z"]);
+        assert_eq!(Hangman.outcome(&state, &PLAYERS).unwrap(), None);
+    }
+
+    #[test]
+    fn bad_guesses_are_refused() {
+        let state = guess(&with_word("rocket"), Seat::First, r#"{"letter": "o"}"#).unwrap();
+        assert_eq!(guess(&state, Seat::Second, r#"{"letter": "o"}"#).unwrap_err(), "'o' has already been guessed");
+        assert_eq!(guess(&state, Seat::Second, r#"{"letter": "ab"}"#).unwrap_err(), "Guess a single letter");
+        assert_eq!(guess(&state, Seat::Second, r#"{"letter": "1"}"#).unwrap_err(), "Guess a single letter");
+        assert_eq!(guess(&state, Seat::Second, r#"{"word": " "}"#).unwrap_err(), "Guess a word");
+    }
+
+    #[test]
+    fn the_right_word_scores_the_hidden_letters_and_ends_the_game() {
+        let state = guess(&with_word("balloon"), Seat::First, r#"{"letter": "o"}"#).unwrap();
+        let state = guess(&state, Seat::Second, r#"{"word": "Balloon"}"#).unwrap();
+        assert_eq!(scores(&state)["alice"], 2);
+        assert_eq!(scores(&state)["bob"], 5);
+        assert_eq!(Hangman.outcome(&state, &PLAYERS).unwrap(), Some(Outcome::Winner(Seat::Second)));
+        assert_eq!(guess(&state, Seat::First, r#"{"letter": "x"}"#).unwrap_err(), "The game is over");
+    }
+
+    #[test]
+    fn a_wrong_word_is_a_miss() {
+        let state = guess(&with_word("rocket"), Seat::First, r#"{"word": "pocket"}"#).unwrap();
+        let read_back: State = super::super::parse_state(&state).unwrap();
+        assert_eq!(read_back.misses, vec!["pocket"]);
+        assert_eq!(scores(&state)["alice"], 0);
+    }
+
+    #[test]
+    fn running_out_of_misses_ends_the_game_on_points() {
+        let mut state = with_word("rocket");
+        for (i, letter) in "abdfgh".chars().enumerate() {
+            let seat = if i % 2 == 0 { Seat::First } else { Seat::Second };
+            assert_eq!(Hangman.outcome(&state, &PLAYERS).unwrap(), None);
+            state = guess(&state, seat, &format!(r#"{{"letter": "{}"}}"#, letter)).unwrap();
+        }
+        assert_eq!(Hangman.outcome(&state, &PLAYERS).unwrap(), Some(Outcome::Draw));
+    }
+
+    #[test]
+    fn views_hide_the_word_until_the_game_is_over() {
+        let state = guess(&with_word("rocket"), Seat::First, r#"{"letter": "o"}"#).unwrap();
+        let state = guess(&state, Seat::Second, r#"{"letter": "e"}"#).unwrap();
+        let view: serde_json::Value = serde_json::from_str(&Hangman.view(&state, None)).unwrap();
+        assert_eq!(view["pattern"], "_ o _ _ e _");
+        assert!(view.get("word").is_none());
+
+        let state = guess(&state, Seat::First, r#"{"word": "rocket"}"#).unwrap();
+        let view: serde_json::Value = serde_json::from_str(&Hangman.view(&state, Some(Seat::Second))).unwrap();
+        assert_eq!(view["word"], "rocket");
+        assert_eq!(view["pattern"], "r o c k e t");
+    }
+}
@@ -0,0 +1,183 @@
+// src/games/mod.rs
+//! Two-player games played inside a conversation.
+//!
+//! Each game type is a [`GameEngine`]: it builds a new game's state, checks
+//! and applies moves, says whose move it is and when the game is over, and
+//! decides how much of the state each player gets to see. The stored
+//! `game_state` is the engine's own JSON; nothing outside the engine reads it.
+//!
+//! Engines are looked up by game type in a [`GameRegistry`]. The WebSocket
+//! game commands only ever go through the registry, so a new game is added
+//! by registering its engine at startup.
+pub mod checkers;
+pub mod chess;
+pub mod connect_four;
+pub mod hangman;
+pub mod tictactoe;
+pub mod trivia;
+
+use std::collections::HashMap;
+
+use futures_util::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use sqlx::SqlitePool;
+
+/// Which side of a game a player is on. The game's creator is `First` and
+/// moves first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seat {
+    First,
+    Second,
+}
+
+impl Seat {
+    pub fn other(self) -> Seat {
+        match self {
+            Seat::First => Seat::Second,
+            Seat::Second => Seat::First,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Seat::First => 0,
+            Seat::Second => 1,
+        }
+    }
+}
+
+/// The usernames in each seat.
+#[derive(Debug, Clone, Copy)]
+pub struct Players<'a> {
+    pub first: &'a str,
+    pub second: Option<&'a str>,
+}
+
+impl<'a> Players<'a> {
+    pub fn name(&self, seat: Seat) -> &'a str {
+        match seat {
+            Seat::First => self.first,
+            Seat::Second => self.second.unwrap_or_default(),
+        }
+    }
+
+    pub fn seat_of(&self, username: &str) -> Option<Seat> {
+        if username == self.first {
+            Some(Seat::First)
+        } else if self.second == Some(username) {
+            Some(Seat::Second)
+        } else {
+            None
+        }
+    }
+}
+
+/// How a finished game ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Winner(Seat),
+    Draw,
+}
+
+/// The rules of one game type. States and moves are JSON strings; errors
+/// are shown to the player as they are.
+pub trait GameEngine: Send + Sync {
+    /// The `game_type` games of this kind are created with.
+    fn game_type(&self) -> &'static str;
+
+    /// Name used in chat announcements, e.g. "♟️ Chess".
+    fn label(&self) -> &'static str;
+
+    /// The state a new game starts from.
+    fn initial_state<'a>(&'a self, pool: &'a SqlitePool) -> BoxFuture<'a, Result<String, String>>;
+
+    /// Whose move it is, or `None` when either player may move.
+    fn to_move(&self, state: &str) -> Result<Option<Seat>, String>;
+
+    /// Check a move by the player in `seat` and return the state after it.
+    fn apply_move(&self, state: &str, players: &Players, seat: Seat, move_data: &str) -> Result<String, String>;
+
+    /// How the game ended, or `None` while it goes on.
+    fn outcome(&self, state: &str, players: &Players) -> Result<Option<Outcome>, String>;
+
+    /// The state as shown to `viewer`, `None` being someone watching. Engines
+    /// with hidden information strip it here.
+    fn view(&self, state: &str, _viewer: Option<Seat>) -> String {
+        state.to_string()
+    }
+}
+
+/// The game types that can be played, by `game_type`.
+#[derive(Default)]
+pub struct GameRegistry {
+    engines: HashMap<&'static str, Box<dyn GameEngine>>,
+}
+
+impl GameRegistry {
+    /// A registry with every game that ships with the server.
+    pub fn with_builtin() -> GameRegistry {
+        let mut registry = GameRegistry::default();
+        registry.register(chess::Chess);
+        registry.register(tictactoe::TicTacToe);
+        registry.register(trivia::Trivia);
+        registry.register(connect_four::ConnectFour);
+        registry.register(checkers::Checkers);
+        registry.register(hangman::Hangman);
+        registry
+    }
+
+    /// Add an engine, replacing any registered for the same game type.
+    pub fn register(&mut self, engine: impl GameEngine + 'static) {
+        self.engines.insert(engine.game_type(), Box::new(engine));
+    }
+
+    pub fn get(&self, game_type: &str) -> Option<&dyn GameEngine> {
+        self.engines.get(game_type).map(|engine| engine.as_ref())
+    }
+
+    pub fn label(&self, game_type: &str) -> &'static str {
+        self.get(game_type).map_or("Game", |engine| engine.label())
+    }
+}
+
+fn parse_state<T: DeserializeOwned>(state: &str) -> Result<T, String> {
+    serde_json::from_str(state).map_err(|_| "Invalid game state".to_string())
+}
+
+fn parse_move<T: DeserializeOwned>(move_data: &str) -> Result<T, String> {
+    serde_json::from_str(move_data).map_err(|_| "Invalid move format".to_string())
+}
+
+fn to_state<T: serde::Serialize>(state: &T) -> String {
+    serde_json::to_string(state).unwrap_or_default()
+}
+
+/// The outcome of a game won on points.
+fn by_score<T: Ord>(first: T, second: T) -> Outcome {
+    match first.cmp(&second) {
+        std::cmp::Ordering::Greater => Outcome::Winner(Seat::First),
+        std::cmp::Ordering::Less => Outcome::Winner(Seat::Second),
+        std::cmp::Ordering::Equal => Outcome::Draw,
+    }
+}
+
+/// A ready future for engines whose initial state needs no lookups.
+fn ready(state: String) -> BoxFuture<'static, Result<String, String>> {
+    Box::pin(futures_util::future::ready(Ok(state)))
+}
+
+#[cfg(test)]
+mod testing {
+    use super::{GameEngine, Players};
+
+    pub const PLAYERS: Players<'static> = Players { first: "alice", second: Some("bob") };
+
+    /// A new game's state, for engines that need no database to start one.
+    pub fn new_game(engine: &dyn GameEngine) -> String {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let pool = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+            engine.initial_state(&pool).await.unwrap()
+        })
+    }
+}
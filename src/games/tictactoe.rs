@@ -0,0 +1,116 @@
+// src/games/tictactoe.rs
+//! Tic-tac-toe: the game's creator is X and moves first.
+//!
+//! The state is the 3x3 `board` of `"X"`, `"O"` or `""`, whose `turn` it is
+//! and the `moves` so far as `[row, col]`. States saved before moves were
+//! recorded never updated `turn`, so whose move it is comes from counting
+//! the marks instead.
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use super::{GameEngine, Outcome, Players, Seat};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    board: Vec<Vec<String>>,
+    #[serde(default)]
+    turn: String,
+    #[serde(default)]
+    moves: Vec<[usize; 2]>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TicTacToeMove {
+    row: usize,
+    col: usize,
+}
+
+impl State {
+    fn mark_count(&self, mark: &str) -> usize {
+        self.board.iter().flatten().filter(|cell| *cell == mark).count()
+    }
+
+    fn to_move(&self) -> Seat {
+        if self.mark_count("X") > self.mark_count("O") {
+            Seat::Second
+        } else {
+            Seat::First
+        }
+    }
+
+    fn has_line(&self, mark: &str) -> bool {
+        let at = |row: usize, col: usize| self.board[row][col] == mark;
+        (0..3).any(|i| (0..3).all(|j| at(i, j)) || (0..3).all(|j| at(j, i)))
+            || (0..3).all(|i| at(i, i))
+            || (0..3).all(|i| at(i, 2 - i))
+    }
+}
+
+fn mark(seat: Seat) -> &'static str {
+    match seat {
+        Seat::First => "X",
+        Seat::Second => "O",
+    }
+}
+
+fn read(state: &str) -> Result<State, String> {
+    let state: State = super::parse_state(state)?;
+    if state.board.len() != 3 || state.board.iter().any(|row| row.len() != 3) {
+        return Err("Invalid board".to_string());
+    }
+    Ok(state)
+}
+
+/// The `tictactoe` engine.
+pub struct TicTacToe;
+
+impl GameEngine for TicTacToe {
+    fn game_type(&self) -> &'static str {
+        "tictactoe"
+    }
+
+    fn label(&self) -> &'static str {
+        "⭕ Tic-Tac-Toe"
+    }
+
+    fn initial_state<'a>(&'a self, _pool: &'a SqlitePool) -> BoxFuture<'a, Result<String, String>> {
+        super::ready(super::to_state(&State {
+            board: vec![vec![String::new(); 3]; 3],
+            turn: "X".to_string(),
+            moves: Vec::new(),
+        }))
+    }
+
+    fn to_move(&self, state: &str) -> Result<Option<Seat>, String> {
+        Ok(Some(read(state)?.to_move()))
+    }
+
+    fn apply_move(&self, state: &str, _players: &Players, seat: Seat, move_data: &str) -> Result<String, String> {
+        let mut state = read(state)?;
+        let TicTacToeMove { row, col } = super::parse_move(move_data)?;
+        if row >= 3 || col >= 3 {
+            return Err("Position out of bounds".to_string());
+        }
+        if !state.board[row][col].is_empty() {
+            return Err("Position already taken".to_string());
+        }
+        state.board[row][col] = mark(seat).to_string();
+        state.moves.push([row, col]);
+        state.turn = mark(seat.other()).to_string();
+        Ok(super::to_state(&state))
+    }
+
+    fn outcome(&self, state: &str, _players: &Players) -> Result<Option<Outcome>, String> {
+        let state = read(state)?;
+        Ok(if state.has_line("X") {
+            Some(Outcome::Winner(Seat::First))
+        } else if state.has_line("O") {
+            Some(Outcome::Winner(Seat::Second))
+        } else if state.board.iter().flatten().all(|cell| !cell.is_empty()) {
+            Some(Outcome::Draw)
+        } else {
+            None
+        })
+    }
+}
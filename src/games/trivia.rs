@@ -0,0 +1,175 @@
+// src/games/trivia.rs
+//! Trivia: both players answer each question, in either order, and score a
+//! point per right answer.
+//!
+//! A game deals a deck of questions from `trivia_questions` when it starts,
+//! answers included, so that answering needs no lookups. Once both players
+//! have answered, the round's answer is shown in `last_round` and the next
+//! question comes off the deck; the game ends when the deck runs out. Views
+//! never include the deck or the current question's answer.
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+use super::{GameEngine, Outcome, Players, Seat};
+
+// Questions dealt per game
+const ROUNDS: i64 = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Question {
+    id: i64,
+    question: String,
+    options: Vec<String>,
+    category: String,
+    // Index into options
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    answer: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Round {
+    question: String,
+    answer: Option<String>,
+    // Who got it right
+    correct: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    current_question: Option<Question>,
+    #[serde(default)]
+    scores: BTreeMap<String, i64>,
+    // Who has answered the current question
+    #[serde(default)]
+    answered: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deck: Vec<Question>,
+    // Questions still to come after the current one
+    #[serde(default)]
+    questions_left: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_round: Option<Round>,
+    // Who answered the current question right so far
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    correct: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Answer {
+    answer: usize,
+}
+
+async fn deal(pool: &SqlitePool) -> Result<String, String> {
+    let rows = sqlx::query_as::<_, (i64, String, String, i64, String)>(
+        "SELECT id, question, options, correct_answer, category FROM trivia_questions ORDER BY RANDOM() LIMIT ?",
+    )
+    .bind(ROUNDS)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("Failed to load trivia questions: {}", e))?;
+
+    let mut deck: Vec<Question> = rows
+        .into_iter()
+        .map(|(id, question, options, answer, category)| Question {
+            id,
+            question,
+            options: serde_json::from_str(&options).unwrap_or_default(),
+            category,
+            answer: usize::try_from(answer).ok(),
+        })
+        .collect();
+    if deck.is_empty() {
+        return Err("There are no trivia questions".to_string());
+    }
+    let current_question = deck.remove(0);
+    Ok(super::to_state(&State {
+        current_question: Some(current_question),
+        scores: BTreeMap::new(),
+        answered: Vec::new(),
+        questions_left: deck.len(),
+        deck,
+        last_round: None,
+        correct: Vec::new(),
+    }))
+}
+
+/// The `trivia` engine.
+pub struct Trivia;
+
+impl GameEngine for Trivia {
+    fn game_type(&self) -> &'static str {
+        "trivia"
+    }
+
+    fn label(&self) -> &'static str {
+        "🧠 Trivia"
+    }
+
+    fn initial_state<'a>(&'a self, pool: &'a SqlitePool) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(deal(pool))
+    }
+
+    fn to_move(&self, _state: &str) -> Result<Option<Seat>, String> {
+        Ok(None)
+    }
+
+    fn apply_move(&self, state: &str, players: &Players, seat: Seat, move_data: &str) -> Result<String, String> {
+        let mut state: State = super::parse_state(state)?;
+        let Answer { answer } = super::parse_move(move_data)?;
+        let player = players.name(seat);
+        let question = state.current_question.take().ok_or("There is no question to answer")?;
+        if answer >= question.options.len() {
+            return Err("Invalid answer".to_string());
+        }
+        if state.answered.iter().any(|name| name == player) {
+            return Err("You have already answered this question".to_string());
+        }
+
+        state.answered.push(player.to_string());
+        let score = state.scores.entry(player.to_string()).or_insert(0);
+        if question.answer == Some(answer) {
+            *score += 1;
+            state.correct.push(player.to_string());
+        }
+
+        let everyone_answered = [Seat::First, Seat::Second]
+            .iter()
+            .all(|&seat| state.answered.iter().any(|name| name == players.name(seat)));
+        if everyone_answered {
+            state.last_round = Some(Round {
+                answer: question.answer.and_then(|i| question.options.get(i)).cloned(),
+                question: question.question,
+                correct: std::mem::take(&mut state.correct),
+            });
+            state.answered.clear();
+            state.current_question = (!state.deck.is_empty()).then(|| state.deck.remove(0));
+            state.questions_left = state.deck.len();
+        } else {
+            state.current_question = Some(question);
+        }
+        Ok(super::to_state(&state))
+    }
+
+    fn outcome(&self, state: &str, players: &Players) -> Result<Option<Outcome>, String> {
+        let state: State = super::parse_state(state)?;
+        if state.current_question.is_some() {
+            return Ok(None);
+        }
+        let score = |seat| state.scores.get(players.name(seat)).copied().unwrap_or(0);
+        Ok(Some(super::by_score(score(Seat::First), score(Seat::Second))))
+    }
+
+    fn view(&self, state: &str, _viewer: Option<Seat>) -> String {
+        let Ok(mut state) = super::parse_state::<State>(state) else {
+            return state.to_string();
+        };
+        state.deck.clear();
+        state.correct.clear();
+        if let Some(question) = state.current_question.as_mut() {
+            question.answer = None;
+        }
+        super::to_state(&state)
+    }
+}
@@ -0,0 +1,541 @@
+// src/handlers/conversations.rs
+//! The conversation list: every DM a user has messages in and every group
+//! they belong to, each with its last message, unread count, the user's
+//! pin/archive/mute settings and their chat theme.
+//!
+//! `GET /conversations` and the `get_conversations` frame return the whole
+//! list, pinned conversations first and the rest by last activity. After
+//! that, a message landing in a conversation, or being edited, recalled or
+//! revealed, pushes `conversation_activity` to everyone in it: the new last
+//! message, looked up once, and how each one's unread count moved. Reading a
+//! conversation, or changing its settings through `PUT
+//! /conversations/settings`, pushes the user's fresh summary as
+//! `conversation_updated`. Thread-only replies leave the list alone.
+use std::convert::Infallible;
+
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::handlers::groups::get_group_members;
+use crate::handlers::mentions::preview;
+use crate::protocol::{MessageKind, ServerEvent};
+use crate::{ChatMessage, Users};
+use super::{error_reply, username_from_auth};
+
+/// A conversation as seen by one user: a DM with a peer, or a group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Direct(String),
+    Group(i64),
+}
+
+/// The newest message in a conversation's main history.
+#[derive(Debug, Clone, Serialize)]
+pub struct LastMessage {
+    pub id: i64,
+    pub sender_username: String,
+    // None while recalled or not yet revealed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub recalled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reveal_at: Option<String>,
+    pub attachment_count: i64,
+    pub timestamp: String,
+    pub seq: i64,
+}
+
+/// One entry of the conversation list.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationSummary {
+    pub kind: MessageKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_name: Option<String>,
+    pub last_message: Option<LastMessage>,
+    // The last message's time, or when the user joined a group still without one
+    pub last_activity: Option<String>,
+    pub unread_count: i64,
+    pub muted: bool,
+    pub pinned: bool,
+    pub archived: bool,
+    pub theme: Option<String>,
+}
+
+/// What a new, edited, recalled or revealed message changed in one
+/// conversation, as seen by one participant: its last message now, and how
+/// their unread count moved.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversationActivity {
+    pub kind: MessageKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<i64>,
+    pub last_message: Option<LastMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<String>,
+    pub unread_delta: i64,
+}
+
+/// Columns of a conversation's last message, joined in as `lm`.
+fn last_message_columns(kind: MessageKind) -> String {
+    format!(
+        "lm.id AS last_id, lm.sender_username AS last_sender, lm.message AS last_text, lm.timestamp AS last_timestamp,
+         lm.deleted AS last_deleted, lm.reveal_at AS last_reveal_at, lm.seq AS last_seq,
+         (SELECT COUNT(*) FROM message_attachments a WHERE a.message_kind = '{}' AND a.message_id = lm.id) AS last_attachment_count",
+        kind.as_str()
+    )
+}
+
+fn last_message_from_row(row: &SqliteRow, ghost: bool) -> Option<LastMessage> {
+    let id: i64 = row.try_get::<Option<i64>, _>("last_id").ok().flatten()?;
+    let recalled = row.try_get::<Option<i64>, _>("last_deleted").ok().flatten().unwrap_or(0) != 0;
+    let reveal_at: Option<String> = row.get("last_reveal_at");
+    let unrevealed = crate::reveal::is_pending(reveal_at.as_deref());
+    Some(LastMessage {
+        id,
+        sender_username: if ghost { "Anonymous".to_string() } else { row.get("last_sender") },
+        preview: (!recalled && !unrevealed).then(|| preview(&row.get::<String, _>("last_text"))),
+        recalled,
+        reveal_at,
+        attachment_count: row.get("last_attachment_count"),
+        timestamp: row.get("last_timestamp"),
+        seq: row.try_get::<Option<i64>, _>("last_seq").ok().flatten().unwrap_or(0),
+    })
+}
+
+fn flag(row: &SqliteRow, column: &str) -> bool {
+    row.try_get::<Option<i64>, _>(column).ok().flatten().unwrap_or(0) != 0
+}
+
+/// `username`'s DM conversations, or with `peer` only that one, which is
+/// listed even before it has messages.
+async fn direct_summaries(pool: &SqlitePool, username: &str, peer: Option<&str>) -> Result<Vec<ConversationSummary>, sqlx::Error> {
+    // Unread messages are the peer's in the main history, not recalled, without a read receipt
+    let rows = sqlx::query(&format!(
+        "WITH mine AS (
+             SELECT id, sender_username, receiver_username, in_channel, deleted, seq, conversation_key,
+                    CASE WHEN sender_username = ?1 COLLATE NOCASE THEN receiver_username ELSE sender_username END AS peer
+             FROM messages
+             WHERE (sender_username = ?1 COLLATE NOCASE OR receiver_username = ?1 COLLATE NOCASE)
+               AND (?2 IS NULL OR conversation_key = json_array(min(lower(?1), lower(?2)), max(lower(?1), lower(?2))))
+         ),
+         conversations AS (
+             SELECT conversation_key, MIN(peer) AS peer,
+                    MAX(CASE WHEN in_channel = 1 THEN seq END) AS last_seq,
+                    SUM(in_channel = 1 AND COALESCE(deleted, 0) = 0
+                        AND receiver_username = ?1 COLLATE NOCASE AND sender_username != ?1 COLLATE NOCASE
+                        AND NOT EXISTS (SELECT 1 FROM message_receipts r
+                                        WHERE r.message_kind = 'direct' AND r.message_id = mine.id
+                                          AND r.username = ?1 AND r.read_at IS NOT NULL)) AS unread_count
+             FROM mine
+             GROUP BY conversation_key
+             UNION ALL
+             SELECT json_array(min(lower(?1), lower(?2)), max(lower(?1), lower(?2))), ?2, NULL, 0
+             WHERE ?2 IS NOT NULL AND NOT EXISTS (SELECT 1 FROM mine)
+         )
+         SELECT c.peer, c.unread_count, {}, cs.pinned, cs.archived, cs.muted, t.theme_key
+         FROM conversations c
+         LEFT JOIN messages lm ON lm.conversation_key = c.conversation_key AND lm.seq = c.last_seq
+         LEFT JOIN conversation_settings cs ON cs.username = ?1 AND cs.peer_username = c.peer COLLATE NOCASE
+         LEFT JOIN chat_themes t ON t.owner_username = ?1 AND t.peer_username = c.peer COLLATE NOCASE",
+        last_message_columns(MessageKind::Direct)
+    ))
+    .bind(username)
+    .bind(peer)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let last_message = last_message_from_row(row, false);
+            ConversationSummary {
+                kind: MessageKind::Direct,
+                peer_username: Some(row.get("peer")),
+                group_id: None,
+                group_name: None,
+                last_activity: last_message.as_ref().map(|m| m.timestamp.clone()),
+                last_message,
+                unread_count: row.try_get::<Option<i64>, _>("unread_count").ok().flatten().unwrap_or(0),
+                muted: flag(row, "muted"),
+                pinned: flag(row, "pinned"),
+                archived: flag(row, "archived"),
+                theme: row.get("theme_key"),
+            }
+        })
+        .collect())
+}
+
+/// `username`'s groups, or with `group_id` only that one if they are in it.
+async fn group_summaries(pool: &SqlitePool, username: &str, group_id: Option<i64>) -> Result<Vec<ConversationSummary>, sqlx::Error> {
+    // joined_at is SQLite's CURRENT_TIMESTAMP; put it in the RFC 3339 form messages use
+    let rows = sqlx::query(&format!(
+        "WITH mine AS (
+             SELECT group_id, notifications_muted, joined_at FROM group_members
+             WHERE username = ?1 COLLATE NOCASE AND (?2 IS NULL OR group_id = ?2)
+         ),
+         unread AS (
+             SELECT g.group_id, COUNT(*) AS n
+             FROM mine JOIN group_messages g ON g.group_id = mine.group_id
+             WHERE g.sender_username != ?1 COLLATE NOCASE AND g.in_channel = 1 AND COALESCE(g.deleted, 0) = 0
+               AND NOT EXISTS (SELECT 1 FROM message_receipts r
+                               WHERE r.message_kind = 'group' AND r.message_id = g.id
+                                 AND r.username = ?1 AND r.read_at IS NOT NULL)
+             GROUP BY g.group_id
+         )
+         SELECT mine.group_id, gr.name, gr.ghost_mode, mine.notifications_muted,
+                strftime('%Y-%m-%dT%H:%M:%S+00:00', mine.joined_at) AS joined_at,
+                COALESCE(u.n, 0) AS unread_count, {}, cs.pinned, cs.archived, t.theme_key
+         FROM mine
+         JOIN groups gr ON gr.id = mine.group_id
+         LEFT JOIN unread u ON u.group_id = mine.group_id
+         LEFT JOIN group_messages lm ON lm.id = (SELECT id FROM group_messages
+                                                 WHERE group_id = mine.group_id AND in_channel = 1
+                                                 ORDER BY seq DESC LIMIT 1)
+         LEFT JOIN conversation_settings cs ON cs.username = ?1 AND cs.group_id = mine.group_id
+         LEFT JOIN chat_themes t ON t.owner_username = ?1 AND t.group_id = mine.group_id",
+        last_message_columns(MessageKind::Group)
+    ))
+    .bind(username)
+    .bind(group_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let last_message = last_message_from_row(row, flag(row, "ghost_mode"));
+            ConversationSummary {
+                kind: MessageKind::Group,
+                peer_username: None,
+                group_id: Some(row.get("group_id")),
+                group_name: Some(row.get("name")),
+                last_activity: last_message.as_ref().map(|m| m.timestamp.clone()).or(row.get("joined_at")),
+                last_message,
+                unread_count: row.get("unread_count"),
+                muted: flag(row, "notifications_muted"),
+                pinned: flag(row, "pinned"),
+                archived: flag(row, "archived"),
+                theme: row.get("theme_key"),
+            }
+        })
+        .collect())
+}
+
+/// `username`'s summary of one conversation. `None` for a group they are
+/// not a member of.
+pub async fn summary(pool: &SqlitePool, username: &str, target: &Target) -> Result<Option<ConversationSummary>, sqlx::Error> {
+    let mut summaries = match target {
+        Target::Direct(peer) => direct_summaries(pool, username, Some(peer)).await?,
+        Target::Group(group_id) => group_summaries(pool, username, Some(*group_id)).await?,
+    };
+    Ok(summaries.pop())
+}
+
+/// Every conversation of `username`: pinned ones first, then the most
+/// recently active.
+pub async fn list(pool: &SqlitePool, username: &str) -> Result<Vec<ConversationSummary>, sqlx::Error> {
+    let mut conversations = direct_summaries(pool, username, None).await?;
+    conversations.extend(group_summaries(pool, username, None).await?);
+    conversations.sort_by(|a, b| b.pinned.cmp(&a.pinned).then_with(|| b.last_activity.cmp(&a.last_activity)));
+    Ok(conversations)
+}
+
+/// Send `username` a fresh summary of one conversation, if they have a
+/// socket open.
+pub async fn push(users: &Users, pool: &SqlitePool, username: &str, target: &Target) {
+    if users.idle_secs(username).is_none() {
+        return;
+    }
+    match summary(pool, username, target).await {
+        Ok(Some(conversation)) => {
+            users.send_to_user(username, &ServerEvent::ConversationUpdated(conversation));
+        }
+        Ok(None) => {}
+        Err(e) => println!("DEBUG: Failed to summarize conversation for {}: {}", username, e),
+    }
+}
+
+/// The newest message in the main history of a DM between `sender` and
+/// `receiver`, or of a group.
+async fn last_message(pool: &SqlitePool, group_id: Option<i64>, sender: &str, receiver: &str) -> Result<Option<LastMessage>, sqlx::Error> {
+    let row = match group_id {
+        Some(group_id) => {
+            sqlx::query(&format!(
+                "SELECT gr.ghost_mode, {} FROM groups gr
+                 LEFT JOIN group_messages lm ON lm.id = (SELECT id FROM group_messages
+                                                         WHERE group_id = gr.id AND in_channel = 1
+                                                         ORDER BY seq DESC LIMIT 1)
+                 WHERE gr.id = ?",
+                last_message_columns(MessageKind::Group)
+            ))
+            .bind(group_id)
+            .fetch_optional(pool)
+            .await?
+        }
+        None => {
+            sqlx::query(&format!(
+                "SELECT {} FROM messages lm
+                 WHERE lm.conversation_key = json_array(min(lower(?1), lower(?2)), max(lower(?1), lower(?2)))
+                   AND lm.in_channel = 1
+                 ORDER BY lm.seq DESC LIMIT 1",
+                last_message_columns(MessageKind::Direct)
+            ))
+            .bind(sender)
+            .bind(receiver)
+            .fetch_optional(pool)
+            .await?
+        }
+    };
+    Ok(row.and_then(|row| last_message_from_row(&row, flag(&row, "ghost_mode"))))
+}
+
+/// Tell everyone in a DM or group what changed in it. The last message is
+/// looked up once for all of them; `unread_delta` says how each one's unread
+/// count moved.
+async fn push_activity(
+    users: &Users,
+    pool: &SqlitePool,
+    group_id: Option<i64>,
+    sender: &str,
+    receiver: &str,
+    unread_delta: impl Fn(&str) -> i64,
+) {
+    // Each participant, with the peer they see a DM under
+    let participants: Vec<(String, Option<String>)> = match group_id {
+        Some(group_id) => get_group_members(pool, group_id).await.into_iter().map(|member| (member, None)).collect(),
+        None if sender.eq_ignore_ascii_case(receiver) => vec![(sender.to_string(), Some(receiver.to_string()))],
+        None => vec![
+            (sender.to_string(), Some(receiver.to_string())),
+            (receiver.to_string(), Some(sender.to_string())),
+        ],
+    };
+    if !participants.iter().any(|(username, _)| users.idle_secs(username).is_some()) {
+        return;
+    }
+    let last_message = match last_message(pool, group_id, sender, receiver).await {
+        Ok(last_message) => last_message,
+        Err(e) => {
+            println!("DEBUG: Failed to load the last message for a conversation update: {}", e);
+            return;
+        }
+    };
+    for (username, peer_username) in participants {
+        let activity = ConversationActivity {
+            kind: MessageKind::of(group_id),
+            peer_username,
+            group_id,
+            last_activity: last_message.as_ref().map(|m| m.timestamp.clone()),
+            last_message: last_message.clone(),
+            unread_delta: unread_delta(&username),
+        };
+        users.send_to_user(&username, &ServerEvent::ConversationActivity(activity));
+    }
+}
+
+/// A message was just stored and delivered: one more unread for everyone
+/// but its sender.
+pub async fn message_stored(users: &Users, pool: &SqlitePool, msg: &ChatMessage) {
+    if msg.thread_only {
+        return;
+    }
+    let sender = &msg.sender_username;
+    push_activity(users, pool, msg.group_id, sender, &msg.receiver_username, |username| {
+        i64::from(!username.eq_ignore_ascii_case(sender))
+    })
+    .await;
+}
+
+/// A message was edited, revealed or, with `recalled`, recalled, which may
+/// change a preview. A recall also takes it off the unread counts of those
+/// who had not read it.
+pub async fn message_changed(users: &Users, pool: &SqlitePool, kind: MessageKind, message_id: i64, recalled: bool) {
+    let row = match kind {
+        MessageKind::Direct => sqlx::query("SELECT NULL AS group_id, sender_username, receiver_username FROM messages WHERE id = ? AND in_channel = 1"),
+        MessageKind::Group => sqlx::query("SELECT group_id, sender_username, '' AS receiver_username FROM group_messages WHERE id = ? AND in_channel = 1"),
+    }
+    .bind(message_id)
+    .fetch_optional(pool)
+    .await;
+    let row = match row {
+        Ok(Some(row)) => row,
+        Ok(None) => return,
+        Err(e) => {
+            println!("DEBUG: Failed to load message {} for conversation update: {}", message_id, e);
+            return;
+        }
+    };
+    let group_id: Option<i64> = row.get("group_id");
+    let sender: String = row.get("sender_username");
+    let receiver: String = row.get("receiver_username");
+
+    let readers: Vec<String> = if recalled {
+        sqlx::query_scalar("SELECT username FROM message_receipts WHERE message_kind = ? AND message_id = ? AND read_at IS NOT NULL")
+            .bind(kind.as_str())
+            .bind(message_id)
+            .fetch_all(pool)
+            .await
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let still_unread = |username: &str| {
+        recalled && !username.eq_ignore_ascii_case(&sender) && !readers.iter().any(|r| r.eq_ignore_ascii_case(username))
+    };
+    push_activity(users, pool, group_id, &sender, &receiver, |username| -i64::from(still_unread(username))).await;
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SettingsRequest {
+    #[serde(default)]
+    pub receiver_username: Option<String>,
+    #[serde(default)]
+    pub group_id: Option<i64>,
+    // Fields left out keep their current value
+    #[serde(default)]
+    pub pinned: Option<bool>,
+    #[serde(default)]
+    pub archived: Option<bool>,
+    #[serde(default)]
+    pub muted: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConversationList {
+    conversations: Vec<ConversationSummary>,
+}
+
+pub fn routes(pool: SqlitePool, users: Users) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let pool1 = pool.clone();
+    let pool2 = pool;
+
+    let list = warp::path("conversations")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool1.clone()))
+        .and_then(list_handler);
+
+    let settings = warp::path!("conversations" / "settings")
+        .and(warp::put())
+        .and(warp::body::json::<SettingsRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool2.clone()))
+        .and(warp::any().map(move || users.clone()))
+        .and_then(settings_handler);
+
+    list.or(settings)
+}
+
+/// `GET /conversations`
+async fn list_handler(auth_header: String, pool: SqlitePool) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    match list(&pool, &username).await {
+        Ok(conversations) => Ok(warp::reply::json(&ConversationList { conversations }).into_response()),
+        Err(e) => Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load conversations: {}", e))),
+    }
+}
+
+/// `PUT /conversations/settings`
+async fn settings_handler(
+    request: SettingsRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    if request.pinned.is_none() && request.archived.is_none() && request.muted.is_none() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, "Nothing to change; set pinned, archived or muted"));
+    }
+
+    let now = crate::get_current_time();
+    let (target, result) = match (request.group_id, request.receiver_username.as_deref()) {
+        (Some(group_id), None) => {
+            match crate::roles::role_of(&pool, group_id, &username).await {
+                Ok(Some(_)) => {}
+                Ok(None) => return Ok(error_reply(StatusCode::FORBIDDEN, "Not a member of this group")),
+                Err(e) => return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to check membership: {}", e))),
+            }
+            let result = sqlx::query(
+                "INSERT INTO conversation_settings (username, group_id, pinned, archived, updated_at)
+                 VALUES (?1, ?2, COALESCE(?3, 0), COALESCE(?4, 0), ?5)
+                 ON CONFLICT (username, group_id)
+                 DO UPDATE SET pinned = COALESCE(?3, pinned), archived = COALESCE(?4, archived), updated_at = ?5"
+            )
+            .bind(&username)
+            .bind(group_id)
+            .bind(request.pinned)
+            .bind(request.archived)
+            .bind(&now)
+            .execute(&pool)
+            .await;
+            // A group's mute belongs to the membership, as set by PUT /groups/{id}/notifications
+            let result = match (result, request.muted) {
+                (Ok(_), Some(muted)) => sqlx::query("UPDATE group_members SET notifications_muted = ? WHERE group_id = ? AND username = ? COLLATE NOCASE")
+                    .bind(muted)
+                    .bind(group_id)
+                    .bind(&username)
+                    .execute(&pool)
+                    .await,
+                (result, _) => result,
+            };
+            (Target::Group(group_id), result)
+        }
+        (None, Some(peer)) => {
+            let peer: Option<String> = match sqlx::query_scalar("SELECT username FROM users WHERE username = ? COLLATE NOCASE")
+                .bind(peer)
+                .fetch_optional(&pool)
+                .await
+            {
+                Ok(peer) => peer,
+                Err(e) => return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to look up user: {}", e))),
+            };
+            let Some(peer) = peer else {
+                return Ok(error_reply(StatusCode::NOT_FOUND, "No such user"));
+            };
+            let result = sqlx::query(
+                "INSERT INTO conversation_settings (username, peer_username, pinned, archived, muted, updated_at)
+                 VALUES (?1, ?2, COALESCE(?3, 0), COALESCE(?4, 0), COALESCE(?5, 0), ?6)
+                 ON CONFLICT (username, peer_username)
+                 DO UPDATE SET pinned = COALESCE(?3, pinned), archived = COALESCE(?4, archived),
+                               muted = COALESCE(?5, muted), updated_at = ?6"
+            )
+            .bind(&username)
+            .bind(&peer)
+            .bind(request.pinned)
+            .bind(request.archived)
+            .bind(request.muted)
+            .bind(&now)
+            .execute(&pool)
+            .await;
+            (Target::Direct(peer), result)
+        }
+        _ => return Ok(error_reply(StatusCode::BAD_REQUEST, "Give exactly one of receiver_username or group_id")),
+    };
+    if let Err(e) = result {
+        return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to save conversation settings: {}", e)));
+    }
+
+    match summary(&pool, &username, &target).await {
+        Ok(Some(conversation)) => {
+            // The user's other devices re-sort their lists too
+            users.send_to_user(&username, &ServerEvent::ConversationUpdated(conversation.clone()));
+            Ok(warp::reply::json(&conversation).into_response())
+        }
+        Ok(None) => Ok(error_reply(StatusCode::FORBIDDEN, "Not a member of this group")),
+        Err(e) => Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load conversation: {}", e))),
+    }
+}
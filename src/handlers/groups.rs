@@ -4,12 +4,47 @@ use serde::{Deserialize, Serialize};
 use warp::Reply;
 use std::convert::Infallible;
 
+use crate::protocol::ServerEvent;
+use crate::roles::{self, Denied, Permission, Role};
+use crate::Users;
+
+/// How people who are not members get into a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JoinPolicy {
+    /// Anyone can join directly.
+    Open,
+    /// Only by redeeming an invite; the group is not listed to outsiders.
+    InviteOnly,
+    /// Joining files a request that a moderator or above approves.
+    Approval,
+}
+
+impl JoinPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JoinPolicy::Open => "open",
+            JoinPolicy::InviteOnly => "invite_only",
+            JoinPolicy::Approval => "approval",
+        }
+    }
+
+    pub fn parse(s: &str) -> JoinPolicy {
+        match s {
+            "invite_only" => JoinPolicy::InviteOnly,
+            "approval" => JoinPolicy::Approval,
+            _ => JoinPolicy::Open,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateGroupRequest {
     pub name: String,
     pub description: Option<String>,
     pub members: Vec<String>,
     pub ghost_mode: Option<bool>,
+    pub join_policy: Option<JoinPolicy>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,12 +53,68 @@ pub struct UpdateGroupRequest {
     pub name: Option<String>,
     pub description: Option<String>,
     pub ghost_mode: Option<bool>,
+    pub join_policy: Option<JoinPolicy>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct JoinLeaveGroupRequest {
     pub group_id: i64,
     pub username: String,
+    // Shown to reviewers when the group requires approval
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateInviteRequest {
+    pub expires_in_secs: Option<i64>,
+    pub max_uses: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DecideJoinRequest {
+    pub approve: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupInvite {
+    pub id: i64,
+    pub group_id: i64,
+    pub token: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub max_uses: Option<i64>,
+    pub uses: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JoinRequest {
+    pub id: i64,
+    pub group_id: i64,
+    pub group_name: String,
+    pub username: String,
+    pub message: Option<String>,
+    // pending, approved or rejected
+    pub status: String,
+    pub created_at: String,
+    pub decided_by: Option<String>,
+    pub decided_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddMembersRequest {
+    pub usernames: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetRoleRequest {
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationSettingsRequest {
+    pub muted: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -34,6 +125,105 @@ pub struct GroupResponse {
     pub members: Vec<String>,
     pub is_member: bool,
     pub ghost_mode: bool,
+    pub join_policy: JoinPolicy,
+    // The caller's own role, for groups they belong to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    // The caller has a request waiting for approval
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub join_requested: bool,
+    // The caller muted this group's notifications; mentions still come through
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub notifications_muted: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ModerationRequest {
+    pub reason: Option<String>,
+    pub duration_secs: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BanRequest {
+    pub username: String,
+    pub reason: Option<String>,
+    // Permanent when absent
+    pub duration_secs: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferOwnershipRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupMember {
+    pub username: String,
+    pub role: Role,
+    pub joined_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub muted_until: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberAction {
+    Kicked,
+    Banned,
+    Unbanned,
+    Muted,
+    Unmuted,
+    RoleChanged,
+    OwnershipTransferred,
+}
+
+impl MemberAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            MemberAction::Kicked => "kicked",
+            MemberAction::Banned => "banned",
+            MemberAction::Unbanned => "unbanned",
+            MemberAction::Muted => "muted",
+            MemberAction::Unmuted => "unmuted",
+            MemberAction::RoleChanged => "role_changed",
+            MemberAction::OwnershipTransferred => "ownership_transferred",
+        }
+    }
+
+    fn parse(s: &str) -> Option<MemberAction> {
+        [
+            MemberAction::Kicked,
+            MemberAction::Banned,
+            MemberAction::Unbanned,
+            MemberAction::Muted,
+            MemberAction::Unmuted,
+            MemberAction::RoleChanged,
+            MemberAction::OwnershipTransferred,
+        ]
+        .into_iter()
+        .find(|action| action.as_str() == s)
+    }
+}
+
+/// One entry of a group's membership audit trail, also pushed to the group
+/// as a `member_changed` event.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberChange {
+    pub id: i64,
+    pub group_id: i64,
+    // The member acted on
+    pub username: String,
+    pub action: MemberAction,
+    pub actor: String,
+    // The new role, for role changes and transfers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+    // When a ban or mute lapses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub created_at: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -43,13 +233,43 @@ pub struct GroupsListResponse {
 }
 
 // ---------------- Routes ----------------
-pub fn extended_routes(pool: SqlitePool) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+pub fn extended_routes(pool: SqlitePool, users: Users) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     let pool1 = pool.clone();
     let pool2 = pool.clone();
     let pool3 = pool.clone();
     let pool4 = pool.clone();
     let pool5 = pool.clone();
     let pool6 = pool.clone();
+    let pool7 = pool.clone();
+    let pool8 = pool.clone();
+    let pool9 = pool.clone();
+    let pool10 = pool.clone();
+    let pool11 = pool.clone();
+    let pool12 = pool.clone();
+    let pool13 = pool.clone();
+    let pool14 = pool.clone();
+    let pool15 = pool.clone();
+    let pool16 = pool.clone();
+    let pool17 = pool.clone();
+    let users1 = users.clone();
+    let users2 = users.clone();
+    let users3 = users.clone();
+    let users4 = users.clone();
+    let users5 = users.clone();
+    let users6 = users.clone();
+    let users7 = users.clone();
+    let users8 = users.clone();
+    let users9 = users.clone();
+    let users10 = users.clone();
+    let users11 = users.clone();
+    let pool18 = pool.clone();
+    let pool19 = pool.clone();
+    let pool20 = pool.clone();
+    let pool21 = pool.clone();
+    let pool22 = pool.clone();
+    let pool23 = pool.clone();
+    let pool24 = pool.clone();
+    let pool25 = pool.clone();
 
     // Test route to check if routing works at all
     let test_join = warp::path!("groups" / "test-join")
@@ -73,6 +293,7 @@ pub fn extended_routes(pool: SqlitePool) -> impl Filter<Extract = impl Reply, Er
         .and(warp::body::json::<JoinLeaveGroupRequest>())
         .and(warp::header::<String>("authorization"))
         .and(warp::any().map(move || pool4.clone()))
+        .and(warp::any().map(move || users1.clone()))
         .and_then(join_group_handler);
 
     let leave_group = warp::path!("groups" / "leave")
@@ -95,6 +316,148 @@ pub fn extended_routes(pool: SqlitePool) -> impl Filter<Extract = impl Reply, Er
         .and(warp::any().map(move || pool3.clone()))
         .and_then(delete_group_handler);
 
+    let list_members = warp::path!("groups" / i64 / "members")
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool7.clone()))
+        .and_then(list_members_handler);
+
+    let add_members = warp::path!("groups" / i64 / "members")
+        .and(warp::post())
+        .and(warp::body::json::<AddMembersRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool8.clone()))
+        .and_then(add_members_handler);
+
+    // Removing a member is a kick without a reason
+    let remove_member = warp::path!("groups" / i64 / "members" / String)
+        .and(warp::delete())
+        .and(warp::any().map(ModerationRequest::default))
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool9.clone()))
+        .and(warp::any().map(move || users4.clone()))
+        .and_then(kick_member_handler);
+
+    let kick_member = warp::path!("groups" / i64 / "members" / String / "kick")
+        .and(warp::post())
+        .and(warp::body::json::<ModerationRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool18.clone()))
+        .and(warp::any().map(move || users5.clone()))
+        .and_then(kick_member_handler);
+
+    let mute_member = warp::path!("groups" / i64 / "members" / String / "mute")
+        .and(warp::post())
+        .and(warp::body::json::<ModerationRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool19.clone()))
+        .and(warp::any().map(move || users6.clone()))
+        .and_then(mute_member_handler);
+
+    let unmute_member = warp::path!("groups" / i64 / "members" / String / "mute")
+        .and(warp::delete())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool20.clone()))
+        .and(warp::any().map(move || users7.clone()))
+        .and_then(unmute_member_handler);
+
+    let ban_user = warp::path!("groups" / i64 / "bans")
+        .and(warp::post())
+        .and(warp::body::json::<BanRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool21.clone()))
+        .and(warp::any().map(move || users8.clone()))
+        .and_then(ban_user_handler);
+
+    let list_bans = warp::path!("groups" / i64 / "bans")
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool22.clone()))
+        .and_then(list_bans_handler);
+
+    let unban_user = warp::path!("groups" / i64 / "bans" / String)
+        .and(warp::delete())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool23.clone()))
+        .and(warp::any().map(move || users9.clone()))
+        .and_then(unban_user_handler);
+
+    let transfer_ownership = warp::path!("groups" / i64 / "transfer")
+        .and(warp::post())
+        .and(warp::body::json::<TransferOwnershipRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool24.clone()))
+        .and(warp::any().map(move || users10.clone()))
+        .and_then(transfer_ownership_handler);
+
+    let audit_log = warp::path!("groups" / i64 / "audit")
+        .and(warp::get())
+        .and(warp::query::<std::collections::HashMap<String, String>>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool.clone()))
+        .and_then(audit_log_handler);
+
+    let set_notifications = warp::path!("groups" / i64 / "notifications")
+        .and(warp::put())
+        .and(warp::body::json::<NotificationSettingsRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool25.clone()))
+        .and_then(set_notifications_handler);
+
+    let set_role = warp::path!("groups" / i64 / "members" / String / "role")
+        .and(warp::put())
+        .and(warp::body::json::<SetRoleRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool10.clone()))
+        .and(warp::any().map(move || users11.clone()))
+        .and_then(set_role_handler);
+
+    let create_invite = warp::path!("groups" / i64 / "invites")
+        .and(warp::post())
+        .and(warp::body::json::<CreateInviteRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool11.clone()))
+        .and_then(create_invite_handler);
+
+    let list_invites = warp::path!("groups" / i64 / "invites")
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool12.clone()))
+        .and_then(list_invites_handler);
+
+    let revoke_invite = warp::path!("groups" / i64 / "invites" / i64)
+        .and(warp::delete())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool13.clone()))
+        .and_then(revoke_invite_handler);
+
+    let preview_invite = warp::path!("invites" / String)
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool14.clone()))
+        .and_then(preview_invite_handler);
+
+    let redeem_invite = warp::path!("invites" / String / "redeem")
+        .and(warp::post())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool15.clone()))
+        .and(warp::any().map(move || users2.clone()))
+        .and_then(redeem_invite_handler);
+
+    let list_join_requests = warp::path!("groups" / i64 / "join_requests")
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool16.clone()))
+        .and_then(list_join_requests_handler);
+
+    let decide_join_request = warp::path!("groups" / i64 / "join_requests" / i64)
+        .and(warp::post())
+        .and(warp::body::json::<DecideJoinRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool17.clone()))
+        .and(warp::any().map(move || users3.clone()))
+        .and_then(decide_join_request_handler);
+
     let create_group = warp::path("groups")
         .and(warp::post())
         .and(warp::body::json::<CreateGroupRequest>())
@@ -115,21 +478,48 @@ pub fn extended_routes(pool: SqlitePool) -> impl Filter<Extract = impl Reply, Er
         .or(leave_group)
         .or(update_group)
         .or(delete_group)
+        .or(list_members)
+        .or(add_members)
+        .or(remove_member)
+        .or(set_role)
+        .or(kick_member)
+        .or(mute_member)
+        .or(unmute_member)
+        .or(ban_user)
+        .or(list_bans)
+        .or(unban_user)
+        .or(transfer_ownership)
+        .or(audit_log)
+        .or(set_notifications)
+        .or(create_invite)
+        .or(list_invites)
+        .or(revoke_invite)
+        .or(preview_invite)
+        .or(redeem_invite)
+        .or(list_join_requests)
+        .or(decide_join_request)
         .or(create_group)
         .or(list_groups)
 }
 
 // Helper function to extract username from auth header
 fn extract_username_from_auth_header(auth_header: String) -> Result<String, String> {
-    let token = if auth_header.starts_with("Bearer ") {
-        &auth_header[7..]
-    } else {
-        return Err("Invalid authorization header".to_string());
+    let token = match auth_header.strip_prefix("Bearer ") {
+        Some(token) => token,
+        None => return Err("Invalid authorization header".to_string()),
     };
 
     crate::verify_jwt(token).map_err(|_| "Invalid or expired token".to_string())
 }
 
+fn denied_reply(denied: Denied) -> warp::reply::WithStatus<warp::reply::Json> {
+    let status = match denied {
+        Denied::Database(_) => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        _ => warp::http::StatusCode::FORBIDDEN,
+    };
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({"error": denied.to_string()})), status)
+}
+
 // ---------------- Handlers ----------------
 
 async fn create_group_handler(
@@ -149,17 +539,28 @@ async fn create_group_handler(
         }
     };
     
-    let group_id = sqlx::query("INSERT INTO groups (name, description, ghost_mode) VALUES (?, ?, ?)")
+    let join_policy = req.join_policy.unwrap_or(JoinPolicy::Open);
+    let group_id = match sqlx::query("INSERT INTO groups (name, owner_username, description, ghost_mode, join_policy) VALUES (?, ?, ?, ?, ?)")
         .bind(&req.name)
+        .bind(&creator_username)
         .bind(&req.description)
         .bind(req.ghost_mode.unwrap_or(false) as i32)
+        .bind(join_policy.as_str())
         .execute(&pool)
         .await
-        .expect("Failed to insert group")
-        .last_insert_rowid();
+    {
+        Ok(result) => result.last_insert_rowid(),
+        Err(e) => {
+            println!("Failed to insert group: {:?}", e);
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": "Failed to create group"})),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    };
 
-    // Add creator as member
-    let _ = sqlx::query("INSERT INTO group_members (group_id, username) VALUES (?, ?)")
+    // Add creator as owner
+    let _ = sqlx::query("INSERT INTO group_members (group_id, username, role) VALUES (?, ?, 'owner')")
         .bind(group_id)
         .bind(&creator_username)
         .execute(&pool)
@@ -188,6 +589,10 @@ async fn create_group_handler(
         members: all_members,
         is_member: true,
         ghost_mode: req.ghost_mode.unwrap_or(false),
+        join_policy,
+        role: Some(Role::Owner),
+        join_requested: false,
+        notifications_muted: false,
     };
 
     Ok(warp::reply::with_status(
@@ -200,6 +605,7 @@ async fn join_group_handler(
     req: JoinLeaveGroupRequest,
     auth_header: String,
     pool: SqlitePool,
+    users: Users,
 ) -> Result<impl Reply, Infallible> {
     println!("Join group request: {:?}", req);
     
@@ -220,6 +626,17 @@ async fn join_group_handler(
         ));
     }
     
+    let Some((group_name, join_policy)) = group_info(&pool, req.group_id).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "Group not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+
+    if let Ok(Some(until)) = roles::active_ban(&pool, req.group_id, &req.username).await {
+        return Ok(denied_reply(Denied::Banned { until }));
+    }
+
     let existing = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
         .bind(req.group_id)
         .bind(&req.username)
@@ -234,6 +651,29 @@ async fn join_group_handler(
                 warp::http::StatusCode::OK,
             ))
         }
+        Ok(None) if join_policy == JoinPolicy::InviteOnly => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "This group can only be joined with an invite"})),
+            warp::http::StatusCode::FORBIDDEN,
+        )),
+        Ok(None) if join_policy == JoinPolicy::Approval => {
+            match file_join_request(&pool, &users, req.group_id, &group_name, &req.username, req.message.as_deref()).await {
+                Ok((request_id, true)) => Ok(warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({"status": "requested", "request_id": request_id})),
+                    warp::http::StatusCode::ACCEPTED,
+                )),
+                Ok((request_id, false)) => Ok(warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({"status": "already_requested", "request_id": request_id})),
+                    warp::http::StatusCode::OK,
+                )),
+                Err(e) => {
+                    println!("Failed to file join request: {:?}", e);
+                    Ok(warp::reply::with_status(
+                        warp::reply::json(&serde_json::json!({"status": "error", "message": "Database error"})),
+                        warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    ))
+                }
+            }
+        }
         Ok(None) => {
             let result = sqlx::query("INSERT INTO group_members (group_id, username) VALUES (?, ?)")
                 .bind(req.group_id)
@@ -285,17 +725,21 @@ async fn update_group_handler(
         }
     };
 
-    let member_check = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
-        .bind(req.group_id)
-        .bind(&username)
-        .fetch_optional(&pool)
-        .await;
-
-    if member_check.unwrap_or(None).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&serde_json::json!({"error": "Not a member of this group"})),
-            warp::http::StatusCode::FORBIDDEN,
-        ));
+    // Check every requested change before applying any of them
+    let mut needed = vec![];
+    if req.name.is_some() || req.description.is_some() || req.join_policy.is_some() {
+        needed.push(Permission::EditInfo);
+    }
+    if req.ghost_mode.is_some() {
+        needed.push(Permission::ToggleGhostMode);
+    }
+    if needed.is_empty() {
+        needed.push(Permission::Post);
+    }
+    for permission in needed {
+        if let Err(denied) = roles::require(&pool, req.group_id, &username, permission).await {
+            return Ok(denied_reply(denied));
+        }
     }
     
     if let Some(name) = req.name {
@@ -312,6 +756,13 @@ async fn update_group_handler(
             .execute(&pool)
             .await;
     }
+    if let Some(join_policy) = req.join_policy {
+        let _ = sqlx::query("UPDATE groups SET join_policy = ? WHERE id = ?")
+            .bind(join_policy.as_str())
+            .bind(req.group_id)
+            .execute(&pool)
+            .await;
+    }
     if let Some(ghost) = req.ghost_mode {
         let _ = sqlx::query("UPDATE groups SET ghost_mode = ? WHERE id = ?")
             .bind(ghost as i32)
@@ -343,17 +794,8 @@ async fn delete_group_handler(
         }
     };
 
-    let member_check = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
-        .bind(group_id)
-        .bind(&username)
-        .fetch_optional(&pool)
-        .await;
-
-    if member_check.unwrap_or(None).is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&serde_json::json!({"error": "Not authorized to delete this group"})),
-            warp::http::StatusCode::FORBIDDEN,
-        ));
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::DeleteGroup).await {
+        return Ok(denied_reply(denied));
     }
     
     let _ = sqlx::query("DELETE FROM group_members WHERE group_id = ?")
@@ -395,6 +837,8 @@ async fn leave_group_handler(
         ));
     }
     
+    let was_owner = matches!(roles::role_of(&pool, req.group_id, &req.username).await, Ok(Some(Role::Owner)));
+
     let result = sqlx::query("DELETE FROM group_members WHERE group_id = ? AND username = ?")
         .bind(req.group_id)
         .bind(&req.username)
@@ -405,6 +849,9 @@ async fn leave_group_handler(
         Ok(rows) => {
             if rows.rows_affected() > 0 {
                 println!("Successfully removed {} from group {}", req.username, req.group_id);
+                if was_owner {
+                    hand_over_ownership(&pool, req.group_id).await;
+                }
                 Ok(warp::reply::with_status(
                     warp::reply::json(&serde_json::json!({"status": "left"})),
                     warp::http::StatusCode::OK,
@@ -445,7 +892,7 @@ async fn list_groups_handler(
     
     // Get groups where user is a member
     let member_groups_rows = sqlx::query(
-        "SELECT g.id, g.name, g.description 
+        "SELECT g.id, g.name, g.description, g.join_policy, gm.role, gm.notifications_muted
          FROM groups g 
          INNER JOIN group_members gm ON g.id = gm.group_id 
          WHERE gm.username = ?"
@@ -471,20 +918,29 @@ async fn list_groups_handler(
             members,
             is_member: true,
             ghost_mode: ghost_mode != 0,
+            join_policy: JoinPolicy::parse(row.get("join_policy")),
+            role: Some(Role::parse(row.get("role")).unwrap_or(Role::Member)),
+            join_requested: false,
+            notifications_muted: row.get::<i64, _>("notifications_muted") != 0,
         });
     }
 
-    // Get groups where user is NOT a member (available to join)
+    // Get groups where user is NOT a member (available to join); invite-only
+    // groups are not listed to outsiders
     let available_groups_rows = sqlx::query(
-        "SELECT g.id, g.name, g.description 
+        "SELECT g.id, g.name, g.description, g.join_policy,
+                EXISTS (SELECT 1 FROM group_join_requests r
+                        WHERE r.group_id = g.id AND r.username = ? AND r.status = 'pending') AS join_requested
          FROM groups g 
-         WHERE g.id NOT IN (
+         WHERE g.join_policy != 'invite_only'
+           AND g.id NOT IN (
              SELECT gm.group_id 
              FROM group_members gm 
              WHERE gm.username = ?
          )"
     )
     .bind(&username)
+    .bind(&username)
     .fetch_all(&pool)
     .await
     .expect("Failed to fetch available groups");
@@ -505,6 +961,10 @@ async fn list_groups_handler(
             members,
             is_member: false,
             ghost_mode: ghost_mode != 0,
+            join_policy: JoinPolicy::parse(row.get("join_policy")),
+            role: None,
+            join_requested: row.get::<i64, _>("join_requested") != 0,
+            notifications_muted: false,
         });
     }
 
@@ -517,14 +977,1269 @@ async fn list_groups_handler(
     ))
 }
 
-// ---------------- Helper ----------------
+async fn list_members_handler(
+    group_id: i64,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::Post).await {
+        return Ok(denied_reply(denied));
+    }
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let rows = sqlx::query("SELECT username, role, joined_at, muted_until FROM group_members WHERE group_id = ?")
+        .bind(group_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap_or_default();
+    let mut members: Vec<GroupMember> = rows
+        .iter()
+        .map(|row| GroupMember {
+            username: row.get("username"),
+            role: Role::parse(row.get("role")).unwrap_or(Role::Member),
+            joined_at: row.get("joined_at"),
+            // Only mutes still in force
+            muted_until: row.get::<Option<String>, _>("muted_until").filter(|until| *until > now),
+        })
+        .collect();
+    // Highest role first, then alphabetically
+    members.sort_by(|a, b| b.role.cmp(&a.role).then_with(|| a.username.to_lowercase().cmp(&b.username.to_lowercase())));
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"group_id": group_id, "members": members})),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn add_members_handler(
+    group_id: i64,
+    req: AddMembersRequest,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::AddMembers).await {
+        return Ok(denied_reply(denied));
+    }
+
+    let mut added = Vec::new();
+    let mut already_members = Vec::new();
+    let mut unknown = Vec::new();
+    let mut banned = Vec::new();
+    for requested in req.usernames.iter().map(|u| u.trim()).filter(|u| !u.is_empty()) {
+        // Store the name as registered
+        let Ok(Some(member)) = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE username = ? COLLATE NOCASE")
+            .bind(requested)
+            .fetch_optional(&pool)
+            .await
+        else {
+            unknown.push(requested.to_string());
+            continue;
+        };
+        // Lifting a ban is an explicit step, not a side effect of adding someone
+        if let Ok(Some(_)) = roles::active_ban(&pool, group_id, &member).await {
+            banned.push(member);
+            continue;
+        }
+        let inserted = sqlx::query("INSERT OR IGNORE INTO group_members (group_id, username) VALUES (?, ?)")
+            .bind(group_id)
+            .bind(&member)
+            .execute(&pool)
+            .await
+            .map(|r| r.rows_affected())
+            .unwrap_or(0);
+        if inserted > 0 {
+            added.push(member);
+        } else {
+            already_members.push(member);
+        }
+    }
+    println!("{} added {:?} to group {}", username, added, group_id);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "added": added,
+            "already_members": already_members,
+            "unknown": unknown,
+            "banned": banned,
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn kick_member_handler(
+    group_id: i64,
+    target: String,
+    req: ModerationRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(reply) = check_outranks(&pool, group_id, &username, &target, Permission::RemoveMembers).await {
+        return Ok(reply);
+    }
+
+    let _ = sqlx::query("DELETE FROM group_members WHERE group_id = ? AND username = ?")
+        .bind(group_id)
+        .bind(&target)
+        .execute(&pool)
+        .await;
+    println!("{} removed {} from group {}", username, target, group_id);
+
+    let change = record_member_change(&pool, &users, group_id, &username, &target, MemberAction::Kicked, None, None, req.reason).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&change),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn mute_member_handler(
+    group_id: i64,
+    target: String,
+    req: ModerationRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    // Mutes are always temporary
+    let Some(duration_secs) = req.duration_secs.filter(|secs| *secs > 0) else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "A mute needs a positive duration_secs"})),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    };
+    if let Err(reply) = check_outranks(&pool, group_id, &username, &target, Permission::Mute).await {
+        return Ok(reply);
+    }
+
+    let until = (chrono::Utc::now() + chrono::Duration::seconds(duration_secs)).to_rfc3339();
+    let _ = sqlx::query("UPDATE group_members SET muted_until = ? WHERE group_id = ? AND username = ?")
+        .bind(&until)
+        .bind(group_id)
+        .bind(&target)
+        .execute(&pool)
+        .await;
+    println!("{} muted {} in group {} until {}", username, target, group_id, until);
+
+    let change = record_member_change(&pool, &users, group_id, &username, &target, MemberAction::Muted, None, Some(until), req.reason).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&change),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn unmute_member_handler(
+    group_id: i64,
+    target: String,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(reply) = check_outranks(&pool, group_id, &username, &target, Permission::Mute).await {
+        return Ok(reply);
+    }
+
+    let _ = sqlx::query("UPDATE group_members SET muted_until = NULL WHERE group_id = ? AND username = ?")
+        .bind(group_id)
+        .bind(&target)
+        .execute(&pool)
+        .await;
+
+    let change = record_member_change(&pool, &users, group_id, &username, &target, MemberAction::Unmuted, None, None, None).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&change),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn ban_user_handler(
+    group_id: i64,
+    req: BanRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if req.duration_secs.is_some_and(|secs| secs <= 0) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "duration_secs must be positive"})),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    // Anyone can be banned, not only members, but members must rank below the caller
+    let actor_role = match roles::require(&pool, group_id, &username, Permission::Ban).await {
+        Ok(role) => role,
+        Err(denied) => return Ok(denied_reply(denied)),
+    };
+    let target = match sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE username = ? COLLATE NOCASE")
+        .bind(req.username.trim())
+        .fetch_optional(&pool)
+        .await
+    {
+        Ok(Some(target)) => target,
+        _ => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": "No such user"})),
+                warp::http::StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+    if let Ok(Some(target_role)) = roles::role_of(&pool, group_id, &target).await {
+        if target_role >= actor_role {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": format!("A {} cannot ban a {}", actor_role, target_role)})),
+                warp::http::StatusCode::FORBIDDEN,
+            ));
+        }
+    }
+
+    let now = chrono::Utc::now();
+    let until = req.duration_secs.map(|secs| (now + chrono::Duration::seconds(secs)).to_rfc3339());
+    let reason = req.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    let _ = sqlx::query(
+        "INSERT INTO group_bans (group_id, username, banned_by, reason, created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?)
+         ON CONFLICT(group_id, username) DO UPDATE SET
+             banned_by = excluded.banned_by, reason = excluded.reason,
+             created_at = excluded.created_at, expires_at = excluded.expires_at"
+    )
+    .bind(group_id)
+    .bind(&target)
+    .bind(&username)
+    .bind(reason)
+    .bind(now.to_rfc3339())
+    .bind(&until)
+    .execute(&pool)
+    .await;
+    let _ = sqlx::query("DELETE FROM group_members WHERE group_id = ? AND username = ?")
+        .bind(group_id)
+        .bind(&target)
+        .execute(&pool)
+        .await;
+    let _ = sqlx::query(
+        "UPDATE group_join_requests SET status = 'rejected', decided_by = ?, decided_at = ?
+         WHERE group_id = ? AND username = ? AND status = 'pending'"
+    )
+    .bind(&username)
+    .bind(now.to_rfc3339())
+    .bind(group_id)
+    .bind(&target)
+    .execute(&pool)
+    .await;
+    println!("{} banned {} from group {} until {:?}", username, target, group_id, until);
+
+    let change = record_member_change(&pool, &users, group_id, &username, &target, MemberAction::Banned, None, until, reason.map(str::to_string)).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&change),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Mute or unmute a group's notifications for the caller.
+async fn set_notifications_handler(
+    group_id: i64,
+    req: NotificationSettingsRequest,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::View).await {
+        return Ok(denied_reply(denied));
+    }
+
+    match sqlx::query("UPDATE group_members SET notifications_muted = ? WHERE group_id = ? AND username = ?")
+        .bind(req.muted)
+        .bind(group_id)
+        .bind(&username)
+        .execute(&pool)
+        .await
+    {
+        Ok(_) => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"group_id": group_id, "notifications_muted": req.muted})),
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": format!("Failed to update notifications: {}", e)})),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn list_bans_handler(
+    group_id: i64,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::Ban).await {
+        return Ok(denied_reply(denied));
+    }
+
+    let rows = sqlx::query(
+        "SELECT username, banned_by, reason, created_at, expires_at FROM group_bans
+         WHERE group_id = ? AND (expires_at IS NULL OR expires_at > ?)
+         ORDER BY created_at DESC"
+    )
+    .bind(group_id)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .fetch_all(&pool)
+    .await
+    .unwrap_or_default();
+    let bans: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            serde_json::json!({
+                "username": row.get::<String, _>("username"),
+                "banned_by": row.get::<String, _>("banned_by"),
+                "reason": row.get::<Option<String>, _>("reason"),
+                "created_at": row.get::<String, _>("created_at"),
+                "expires_at": row.get::<Option<String>, _>("expires_at"),
+            })
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"bans": bans})),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn unban_user_handler(
+    group_id: i64,
+    target: String,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::Ban).await {
+        return Ok(denied_reply(denied));
+    }
+
+    let target: Option<String> = sqlx::query_scalar("DELETE FROM group_bans WHERE group_id = ? AND username = ? RETURNING username")
+        .bind(group_id)
+        .bind(&target)
+        .fetch_optional(&pool)
+        .await
+        .unwrap_or(None);
+    let Some(target) = target else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "That user is not banned"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+
+    let change = record_member_change(&pool, &users, group_id, &username, &target, MemberAction::Unbanned, None, None, None).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&change),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn transfer_ownership_handler(
+    group_id: i64,
+    req: TransferOwnershipRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    // Ownership transfer is the one role change that raises someone to the caller's rank
+    if let Err(reply) = check_outranks(&pool, group_id, &username, &req.username, Permission::TransferOwnership).await {
+        return Ok(reply);
+    }
+
+    // The old owner stays on as an admin
+    let result = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("UPDATE group_members SET role = 'admin' WHERE group_id = ? AND username = ?")
+            .bind(group_id)
+            .bind(&username)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE group_members SET role = 'owner', muted_until = NULL WHERE group_id = ? AND username = ?")
+            .bind(group_id)
+            .bind(&req.username)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE groups SET owner_username = ? WHERE id = ?")
+            .bind(&req.username)
+            .bind(group_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = result {
+        println!("Failed to transfer ownership: {:?}", e);
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "Failed to transfer ownership"})),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        ));
+    }
+    println!("{} handed group {} to {}", username, group_id, req.username);
+
+    let change = record_member_change(&pool, &users, group_id, &username, &req.username, MemberAction::OwnershipTransferred, Some(Role::Owner), None, None).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&change),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn audit_log_handler(
+    group_id: i64,
+    params: std::collections::HashMap<String, String>,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::ViewAudit).await {
+        return Ok(denied_reply(denied));
+    }
+
+    // Newest first; `before_id` pages back
+    let limit = params.get("limit").and_then(|l| l.parse::<i64>().ok()).unwrap_or(50).clamp(1, 200);
+    let before_id = params.get("before_id").and_then(|b| b.parse::<i64>().ok()).unwrap_or(i64::MAX);
+    let rows = sqlx::query(
+        "SELECT id, group_id, actor, target, action, role, until, reason, created_at FROM group_member_audit
+         WHERE group_id = ? AND id < ? ORDER BY id DESC LIMIT ?"
+    )
+    .bind(group_id)
+    .bind(before_id)
+    .bind(limit)
+    .fetch_all(&pool)
+    .await
+    .unwrap_or_default();
+    let entries: Vec<MemberChange> = rows
+        .iter()
+        .filter_map(|row| {
+            Some(MemberChange {
+                id: row.get("id"),
+                group_id: row.get("group_id"),
+                username: row.get("target"),
+                action: MemberAction::parse(row.get("action"))?,
+                actor: row.get("actor"),
+                role: row.get::<Option<String>, _>("role").and_then(|r| Role::parse(&r)),
+                until: row.get("until"),
+                reason: row.get("reason"),
+                created_at: row.get("created_at"),
+            })
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"entries": entries})),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn set_role_handler(
+    group_id: i64,
+    target: String,
+    req: SetRoleRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    let actor_role = match roles::require(&pool, group_id, &username, Permission::ManageRoles).await {
+        Ok(role) => role,
+        Err(denied) => return Ok(denied_reply(denied)),
+    };
+    if req.role == Role::Owner {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "A group has exactly one owner"})),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    let target_role = match roles::role_of(&pool, group_id, &target).await {
+        Ok(Some(role)) => role,
+        _ => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": "Not a member of this group"})),
+                warp::http::StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+    // Both the member's current role and the new one must be below the caller's
+    if target_role >= actor_role || req.role >= actor_role {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": format!("A {} cannot make a {} into a {}", actor_role, target_role, req.role)})),
+            warp::http::StatusCode::FORBIDDEN,
+        ));
+    }
+
+    let _ = sqlx::query("UPDATE group_members SET role = ? WHERE group_id = ? AND username = ?")
+        .bind(req.role.as_str())
+        .bind(group_id)
+        .bind(&target)
+        .execute(&pool)
+        .await;
+    println!("{} made {} a {} in group {}", username, target, req.role, group_id);
+
+    let change = record_member_change(&pool, &users, group_id, &username, &target, MemberAction::RoleChanged, Some(req.role), None, None).await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&change),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn create_invite_handler(
+    group_id: i64,
+    req: CreateInviteRequest,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::AddMembers).await {
+        return Ok(denied_reply(denied));
+    }
+    if req.expires_in_secs.is_some_and(|secs| secs <= 0) || req.max_uses.is_some_and(|uses| uses <= 0) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "expires_in_secs and max_uses must be positive"})),
+            warp::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let now = chrono::Utc::now();
+    let invite = GroupInvite {
+        id: 0,
+        group_id,
+        token: crate::auth::random_hex(16),
+        created_by: username,
+        created_at: now.to_rfc3339(),
+        expires_at: req.expires_in_secs.map(|secs| (now + chrono::Duration::seconds(secs)).to_rfc3339()),
+        max_uses: req.max_uses,
+        uses: 0,
+    };
+    let result = sqlx::query(
+        "INSERT INTO group_invites (group_id, token, created_by, created_at, expires_at, max_uses) VALUES (?, ?, ?, ?, ?, ?)"
+    )
+    .bind(group_id)
+    .bind(&invite.token)
+    .bind(&invite.created_by)
+    .bind(&invite.created_at)
+    .bind(&invite.expires_at)
+    .bind(invite.max_uses)
+    .execute(&pool)
+    .await;
+
+    match result {
+        Ok(result) => Ok(warp::reply::with_status(
+            warp::reply::json(&GroupInvite { id: result.last_insert_rowid(), ..invite }),
+            warp::http::StatusCode::CREATED,
+        )),
+        Err(e) => {
+            println!("Failed to create invite: {:?}", e);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": "Failed to create invite"})),
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+async fn list_invites_handler(
+    group_id: i64,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::AddMembers).await {
+        return Ok(denied_reply(denied));
+    }
+
+    // Only invites that can still be redeemed
+    let rows = sqlx::query(
+        "SELECT id, group_id, token, created_by, created_at, expires_at, max_uses, uses FROM group_invites
+         WHERE group_id = ? AND revoked_at IS NULL
+           AND (expires_at IS NULL OR expires_at > ?)
+           AND (max_uses IS NULL OR uses < max_uses)
+         ORDER BY id DESC"
+    )
+    .bind(group_id)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .fetch_all(&pool)
+    .await
+    .unwrap_or_default();
+    let invites: Vec<GroupInvite> = rows.iter().map(invite_from_row).collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"invites": invites})),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn revoke_invite_handler(
+    group_id: i64,
+    invite_id: i64,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::AddMembers).await {
+        return Ok(denied_reply(denied));
+    }
+
+    let revoked = sqlx::query("UPDATE group_invites SET revoked_at = ? WHERE id = ? AND group_id = ? AND revoked_at IS NULL")
+        .bind(chrono::Utc::now().to_rfc3339())
+        .bind(invite_id)
+        .bind(group_id)
+        .execute(&pool)
+        .await
+        .map(|r| r.rows_affected())
+        .unwrap_or(0);
+    if revoked == 0 {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "Invite not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    }
+    println!("{} revoked invite {} of group {}", username, invite_id, group_id);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"status": "revoked"})),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Look up an invite that can still be redeemed.
+async fn usable_invite(pool: &SqlitePool, token: &str) -> Result<GroupInvite, &'static str> {
+    let row = sqlx::query(
+        "SELECT id, group_id, token, created_by, created_at, expires_at, max_uses, uses, revoked_at FROM group_invites WHERE token = ?"
+    )
+    .bind(token)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None)
+    .ok_or("Invite not found")?;
+
+    let invite = invite_from_row(&row);
+    if row.get::<Option<String>, _>("revoked_at").is_some() {
+        return Err("This invite has been revoked");
+    }
+    if invite.expires_at.as_deref().is_some_and(|at| at <= chrono::Utc::now().to_rfc3339().as_str()) {
+        return Err("This invite has expired");
+    }
+    if invite.max_uses.is_some_and(|max| invite.uses >= max) {
+        return Err("This invite has been used up");
+    }
+    Ok(invite)
+}
+
+async fn preview_invite_handler(
+    token: String,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    if let Err(error) = extract_username_from_auth_header(auth_header) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": error})),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    }
+
+    let invite = match usable_invite(&pool, &token).await {
+        Ok(invite) => invite,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+    let row = sqlx::query("SELECT name, description, (SELECT COUNT(*) FROM group_members WHERE group_id = groups.id) AS member_count FROM groups WHERE id = ?")
+        .bind(invite.group_id)
+        .fetch_optional(&pool)
+        .await
+        .unwrap_or(None);
+    let Some(row) = row else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "Invite not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "group_id": invite.group_id,
+            "name": row.get::<String, _>("name"),
+            "description": row.get::<Option<String>, _>("description"),
+            "member_count": row.get::<i64, _>("member_count"),
+            "invited_by": invite.created_by,
+        })),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn redeem_invite_handler(
+    token: String,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    let invite = match usable_invite(&pool, &token).await {
+        Ok(invite) => invite,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::GONE,
+            ));
+        }
+    };
+    if let Ok(Some(_)) = roles::role_of(&pool, invite.group_id, &username).await {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"status": "already_member", "group_id": invite.group_id})),
+            warp::http::StatusCode::OK,
+        ));
+    }
+    // A ban outlasts any invite
+    if let Ok(Some(until)) = roles::active_ban(&pool, invite.group_id, &username).await {
+        return Ok(denied_reply(Denied::Banned { until }));
+    }
+
+    // Count the use first, so concurrent redemptions cannot exceed max_uses
+    let claimed = sqlx::query(
+        "UPDATE group_invites SET uses = uses + 1
+         WHERE id = ? AND revoked_at IS NULL AND (max_uses IS NULL OR uses < max_uses)"
+    )
+    .bind(invite.id)
+    .execute(&pool)
+    .await
+    .map(|r| r.rows_affected())
+    .unwrap_or(0);
+    if claimed == 0 {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "This invite has been used up"})),
+            warp::http::StatusCode::GONE,
+        ));
+    }
+
+    let _ = sqlx::query("INSERT OR IGNORE INTO group_members (group_id, username) VALUES (?, ?)")
+        .bind(invite.group_id)
+        .bind(&username)
+        .execute(&pool)
+        .await;
+    // An invite settles any request the user had waiting
+    let settled: Vec<i64> = sqlx::query_scalar(
+        "UPDATE group_join_requests SET status = 'approved', decided_by = ?, decided_at = ?
+         WHERE group_id = ? AND username = ? AND status = 'pending' RETURNING id"
+    )
+    .bind(&invite.created_by)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .bind(invite.group_id)
+    .bind(&username)
+    .fetch_all(&pool)
+    .await
+    .unwrap_or_default();
+    for request_id in settled {
+        if let Some(request) = fetch_join_request(&pool, request_id).await {
+            notify_reviewers(&pool, &users, invite.group_id, &ServerEvent::JoinRequestDecided { request }).await;
+        }
+    }
+    println!("{} joined group {} with invite {}", username, invite.group_id, invite.id);
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"status": "joined", "group_id": invite.group_id})),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn list_join_requests_handler(
+    group_id: i64,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::AddMembers).await {
+        return Ok(denied_reply(denied));
+    }
+
+    let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM group_join_requests WHERE group_id = ? AND status = 'pending' ORDER BY id")
+        .bind(group_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap_or_default();
+    let mut requests = Vec::new();
+    for id in ids {
+        if let Some(request) = fetch_join_request(&pool, id).await {
+            requests.push(request);
+        }
+    }
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({"requests": requests})),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+async fn decide_join_request_handler(
+    group_id: i64,
+    request_id: i64,
+    req: DecideJoinRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<impl Reply, Infallible> {
+    let username = match extract_username_from_auth_header(auth_header) {
+        Ok(username) => username,
+        Err(error) => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": error})),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
+
+    if let Err(denied) = roles::require(&pool, group_id, &username, Permission::AddMembers).await {
+        return Ok(denied_reply(denied));
+    }
+
+    // A ban outlasts a pending request, as it does an invite. Banning
+    // rejects pending requests, but one can be filed while the ban goes in
+    if req.approve {
+        let pending = fetch_join_request(&pool, request_id).await.filter(|r| r.group_id == group_id && r.status == "pending");
+        if let Some(pending) = pending {
+            if let Ok(Some(_)) = roles::active_ban(&pool, group_id, &pending.username).await {
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&serde_json::json!({"error": format!("{} is banned from this group", pending.username)})),
+                    warp::http::StatusCode::FORBIDDEN,
+                ));
+            }
+        }
+    }
+
+    // Only a pending request can be decided, and only once
+    let requester: Option<String> = sqlx::query_scalar(
+        "UPDATE group_join_requests SET status = ?, decided_by = ?, decided_at = ?
+         WHERE id = ? AND group_id = ? AND status = 'pending' RETURNING username"
+    )
+    .bind(if req.approve { "approved" } else { "rejected" })
+    .bind(&username)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .bind(request_id)
+    .bind(group_id)
+    .fetch_optional(&pool)
+    .await
+    .unwrap_or(None);
+    let Some(requester) = requester else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "No pending join request with that id"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+
+    if req.approve {
+        let _ = sqlx::query("INSERT OR IGNORE INTO group_members (group_id, username) VALUES (?, ?)")
+            .bind(group_id)
+            .bind(&requester)
+            .execute(&pool)
+            .await;
+    }
+    println!("{} {} {}'s request to join group {}", username, if req.approve { "approved" } else { "rejected" }, requester, group_id);
+
+    let Some(request) = fetch_join_request(&pool, request_id).await else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": "Join request not found"})),
+            warp::http::StatusCode::NOT_FOUND,
+        ));
+    };
+    // The requester learns the outcome; other reviewers drop it from their queue
+    let event = ServerEvent::JoinRequestDecided { request: request.clone() };
+    users.send_to_user(&requester, &event);
+    notify_reviewers(&pool, &users, group_id, &event).await;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&request),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+// ---------------- Helper ----------------
+
+/// Check that `actor` holds `permission` and that `target` is a member
+/// ranked below them, returning the reply to send when not.
+async fn check_outranks(
+    pool: &SqlitePool,
+    group_id: i64,
+    actor: &str,
+    target: &str,
+    permission: Permission,
+) -> Result<(), warp::reply::WithStatus<warp::reply::Json>> {
+    let actor_role = roles::require(pool, group_id, actor, permission).await.map_err(denied_reply)?;
+    let target_role = match roles::role_of(pool, group_id, target).await {
+        Ok(Some(role)) => role,
+        _ => {
+            return Err(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({"error": "Not a member of this group"})),
+                warp::http::StatusCode::NOT_FOUND,
+            ));
+        }
+    };
+    if target_role >= actor_role {
+        return Err(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({"error": format!("A {} cannot act on a {}", actor_role, target_role)})),
+            warp::http::StatusCode::FORBIDDEN,
+        ));
+    }
+    Ok(())
+}
+
+/// Write a membership change to the audit trail and tell the group, and the
+/// member acted on, who may no longer be in it.
+#[allow(clippy::too_many_arguments)]
+async fn record_member_change(
+    pool: &SqlitePool,
+    users: &Users,
+    group_id: i64,
+    actor: &str,
+    target: &str,
+    action: MemberAction,
+    role: Option<Role>,
+    until: Option<String>,
+    reason: Option<String>,
+) -> MemberChange {
+    let created_at = chrono::Utc::now().to_rfc3339();
+    let reason = reason.map(|r| r.trim().to_string()).filter(|r| !r.is_empty());
+    let id = sqlx::query(
+        "INSERT INTO group_member_audit (group_id, actor, target, action, role, until, reason, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(group_id)
+    .bind(actor)
+    .bind(target)
+    .bind(action.as_str())
+    .bind(role.map(Role::as_str))
+    .bind(&until)
+    .bind(&reason)
+    .bind(&created_at)
+    .execute(pool)
+    .await
+    .map(|r| r.last_insert_rowid())
+    .unwrap_or(0);
+
+    let change = MemberChange {
+        id,
+        group_id,
+        username: target.to_string(),
+        action,
+        actor: actor.to_string(),
+        role,
+        until,
+        reason,
+        created_at,
+    };
+    let mut audience = get_group_members(pool, group_id).await;
+    audience.push(target.to_string());
+    users.send_to_users(audience.iter().map(String::as_str), &ServerEvent::MemberChanged(change.clone()));
+    change
+}
+
+/// A group's name and join policy, or `None` when there is no such group.
+async fn group_info(pool: &SqlitePool, group_id: i64) -> Option<(String, JoinPolicy)> {
+    let row = sqlx::query("SELECT name, join_policy FROM groups WHERE id = ?")
+        .bind(group_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)?;
+    Some((row.get("name"), JoinPolicy::parse(row.get("join_policy"))))
+}
+
+fn invite_from_row(row: &sqlx::sqlite::SqliteRow) -> GroupInvite {
+    GroupInvite {
+        id: row.get("id"),
+        group_id: row.get("group_id"),
+        token: row.get("token"),
+        created_by: row.get("created_by"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+        max_uses: row.get("max_uses"),
+        uses: row.get("uses"),
+    }
+}
+
+async fn fetch_join_request(pool: &SqlitePool, request_id: i64) -> Option<JoinRequest> {
+    let row = sqlx::query(
+        "SELECT r.id, r.group_id, g.name AS group_name, r.username, r.message, r.status, r.created_at, r.decided_by, r.decided_at
+         FROM group_join_requests r JOIN groups g ON g.id = r.group_id
+         WHERE r.id = ?"
+    )
+    .bind(request_id)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None)?;
+    Some(JoinRequest {
+        id: row.get("id"),
+        group_id: row.get("group_id"),
+        group_name: row.get("group_name"),
+        username: row.get("username"),
+        message: row.get("message"),
+        status: row.get("status"),
+        created_at: row.get("created_at"),
+        decided_by: row.get("decided_by"),
+        decided_at: row.get("decided_at"),
+    })
+}
+
+/// File a request to join an approval-only group and tell its reviewers.
+/// Returns the request id and whether it is new, rather than one the user
+/// already had pending.
+async fn file_join_request(
+    pool: &SqlitePool,
+    users: &Users,
+    group_id: i64,
+    group_name: &str,
+    username: &str,
+    message: Option<&str>,
+) -> Result<(i64, bool), sqlx::Error> {
+    let pending: Option<i64> = sqlx::query_scalar("SELECT id FROM group_join_requests WHERE group_id = ? AND username = ? AND status = 'pending'")
+        .bind(group_id)
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+    if let Some(id) = pending {
+        return Ok((id, false));
+    }
+
+    let created_at = chrono::Utc::now().to_rfc3339();
+    let message = message.map(str::trim).filter(|m| !m.is_empty());
+    let id = sqlx::query("INSERT INTO group_join_requests (group_id, username, message, created_at) VALUES (?, ?, ?, ?)")
+        .bind(group_id)
+        .bind(username)
+        .bind(message)
+        .bind(&created_at)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+    println!("{} asked to join group {}", username, group_id);
+
+    let request = JoinRequest {
+        id,
+        group_id,
+        group_name: group_name.to_string(),
+        username: username.to_string(),
+        message: message.map(str::to_string),
+        status: "pending".to_string(),
+        created_at,
+        decided_by: None,
+        decided_at: None,
+    };
+    notify_reviewers(pool, users, group_id, &ServerEvent::JoinRequested { request }).await;
+    Ok((id, true))
+}
+
+/// Push an event to every member who may review join requests.
+async fn notify_reviewers(pool: &SqlitePool, users: &Users, group_id: i64, event: &ServerEvent) {
+    let reviewers: Vec<String> = sqlx::query_scalar(
+        "SELECT username FROM group_members WHERE group_id = ? AND role IN ('moderator', 'admin', 'owner')"
+    )
+    .bind(group_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+    users.send_to_users(reviewers.iter().map(String::as_str), event);
+}
+
+/// After the owner leaves, the highest-ranking, longest-standing member
+/// becomes owner. A group nobody is left in is deleted.
+async fn hand_over_ownership(pool: &SqlitePool, group_id: i64) {
+    let successor: Option<String> = sqlx::query_scalar(
+        "SELECT username FROM group_members WHERE group_id = ?
+         ORDER BY CASE role WHEN 'admin' THEN 0 WHEN 'moderator' THEN 1 ELSE 2 END, id
+         LIMIT 1"
+    )
+    .bind(group_id)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None);
+
+    match successor {
+        Some(successor) => {
+            let _ = sqlx::query("UPDATE group_members SET role = 'owner' WHERE group_id = ? AND username = ?")
+                .bind(group_id)
+                .bind(&successor)
+                .execute(pool)
+                .await;
+            let _ = sqlx::query("UPDATE groups SET owner_username = ? WHERE id = ?")
+                .bind(&successor)
+                .bind(group_id)
+                .execute(pool)
+                .await;
+            println!("{} is now the owner of group {}", successor, group_id);
+        }
+        None => {
+            let _ = sqlx::query("DELETE FROM groups WHERE id = ?")
+                .bind(group_id)
+                .execute(pool)
+                .await;
+            println!("Deleted group {} after its last member left", group_id);
+        }
+    }
+}
 
 pub async fn get_group_members(pool: &SqlitePool, group_id: i64) -> Vec<String> {
     let rows = sqlx::query("SELECT username FROM group_members WHERE group_id = ?")
         .bind(group_id)
         .fetch_all(pool)
         .await
-        .expect("Failed to fetch group members");
+        .unwrap_or_default();
 
     rows.into_iter()
         .map(|r| r.get("username"))
@@ -0,0 +1,340 @@
+// src/handlers/mentions.rs
+//! `@username` and `@all` mentions in group messages.
+//!
+//! When a group message is stored, its `@` mentions are matched against the
+//! group's members and each mentioned member gets a row in `mentions` and a
+//! `mentioned` event on their own sockets. `@all` mentions every member but
+//! the sender. Mentions are delivered even to members who have muted the
+//! group's notifications; that mute only quiets ordinary messages.
+//!
+//! `GET /mentions` pages through the caller's mentions, newest first, with
+//! their unread count. They are marked read by `POST /mentions/read`, or by
+//! reading the group up to the mentioning message.
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::handlers::groups::get_group_members;
+use crate::protocol::ServerEvent;
+use crate::{ChatMessage, Users};
+use super::{error_reply, username_from_auth};
+
+const DEFAULT_PAGE: i64 = 50;
+const MAX_PAGE: i64 = 200;
+/// Longest message preview carried by a mention, in characters.
+const PREVIEW_CHARS: usize = 200;
+
+/// One mention of the caller, as shown in the inbox and the `mentioned` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mention {
+    pub id: i64,
+    pub message_id: i64,
+    pub group_id: i64,
+    pub group_name: String,
+    pub mentioned_by: String,
+    // Mentioned through @all rather than by name
+    pub everyone: bool,
+    // None while the message is not yet revealed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    // Set when the mention is in a thread reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_root: Option<i64>,
+    pub created_at: String,
+    pub read_at: Option<String>,
+}
+
+/// The names a message mentions, and whether it mentions `@all`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Mentioned {
+    pub names: Vec<String>,
+    pub all: bool,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Find the `@name` tokens in a message. An `@` inside a word, as in an email
+/// address, does not start a mention.
+pub fn parse(text: &str) -> Mentioned {
+    let mut mentioned = Mentioned::default();
+    let mut prev: Option<char> = None;
+    for (i, c) in text.char_indices() {
+        let starts_word = prev.is_none_or(|p| !is_name_char(p) && p != '@');
+        prev = Some(c);
+        if c != '@' || !starts_word {
+            continue;
+        }
+        let rest = &text[i + 1..];
+        let end = rest.find(|ch: char| !is_name_char(ch)).unwrap_or(rest.len());
+        // Trailing punctuation ends the sentence, not the name
+        let name = rest[..end].trim_end_matches(['.', '-']);
+        if name.is_empty() {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("all") {
+            mentioned.all = true;
+        } else if !mentioned.names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            mentioned.names.push(name.to_string());
+        }
+    }
+    mentioned
+}
+
+/// The start of a message, cut at a character boundary, for inboxes and lists.
+pub fn preview(text: &str) -> String {
+    match text.char_indices().nth(PREVIEW_CHARS) {
+        Some((cut, _)) => format!("{}…", &text[..cut]),
+        None => text.to_string(),
+    }
+}
+
+/// Record the mentions in a freshly stored group message and tell each
+/// mentioned member. Names that are not members of the group are ignored.
+pub async fn notify(users: &Users, pool: &SqlitePool, msg: &ChatMessage) {
+    let Some(group_id) = msg.group_id else { return };
+    let mentioned = parse(&msg.message);
+    if mentioned.names.is_empty() && !mentioned.all {
+        return;
+    }
+
+    let members = get_group_members(pool, group_id).await;
+    let targets: Vec<(&String, bool)> = members
+        .iter()
+        .filter(|member| !member.eq_ignore_ascii_case(&msg.sender_username))
+        .filter_map(|member| {
+            let by_name = mentioned.names.iter().any(|n| n.eq_ignore_ascii_case(member));
+            (by_name || mentioned.all).then_some((member, !by_name))
+        })
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    let Ok(Some(group)) = sqlx::query("SELECT name, ghost_mode FROM groups WHERE id = ?")
+        .bind(group_id)
+        .fetch_optional(pool)
+        .await
+    else {
+        return;
+    };
+    let ghost = group.try_get::<Option<i32>, _>("ghost_mode").ok().flatten().unwrap_or(0) != 0;
+    let unrevealed = crate::reveal::is_pending(msg.reveal_at.as_deref());
+    let created_at = crate::get_current_time();
+
+    for (username, everyone) in targets {
+        let inserted: Option<i64> = sqlx::query_scalar(
+            "INSERT INTO mentions (message_id, group_id, username, mentioned_by, everyone, created_at)
+             VALUES (?, ?, ?, ?, ?, ?)
+             ON CONFLICT (message_id, username) DO NOTHING
+             RETURNING id"
+        )
+        .bind(msg.id)
+        .bind(group_id)
+        .bind(username)
+        .bind(&msg.sender_username)
+        .bind(everyone)
+        .bind(&created_at)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or_else(|e| {
+            println!("DEBUG: Failed to record mention of {} in message {}: {}", username, msg.id, e);
+            None
+        });
+        let Some(id) = inserted else { continue };
+
+        let mention = Mention {
+            id,
+            message_id: msg.id,
+            group_id,
+            group_name: group.get("name"),
+            mentioned_by: if ghost { "Anonymous".to_string() } else { msg.sender_username.clone() },
+            everyone,
+            message: (!unrevealed).then(|| preview(&msg.message)),
+            thread_root: msg.thread_root,
+            created_at: created_at.clone(),
+            read_at: None,
+        };
+        users.send_to_user(username, &ServerEvent::Mentioned(mention));
+    }
+}
+
+/// Mark the user's mentions in a group read up to a point in its main
+/// history. Mentions in thread-only replies stay unread.
+pub async fn mark_read_up_to(pool: &SqlitePool, username: &str, group_id: i64, up_to_seq: i64) {
+    let result = sqlx::query(
+        "UPDATE mentions SET read_at = ?
+         WHERE username = ? COLLATE NOCASE AND group_id = ? AND read_at IS NULL
+           AND message_id IN (SELECT id FROM group_messages WHERE group_id = ? AND seq <= ? AND in_channel = 1)"
+    )
+    .bind(crate::get_current_time())
+    .bind(username)
+    .bind(group_id)
+    .bind(group_id)
+    .bind(up_to_seq)
+    .execute(pool)
+    .await;
+    if let Err(e) = result {
+        println!("DEBUG: Failed to mark mentions read for {}: {}", username, e);
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MarkReadRequest {
+    // Specific mentions; with neither field set, every mention is marked
+    #[serde(default)]
+    pub ids: Vec<i64>,
+    #[serde(default)]
+    pub group_id: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct MentionsPage {
+    mentions: Vec<Mention>,
+    has_more: bool,
+    unread_count: i64,
+}
+
+pub fn routes(pool: SqlitePool) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let pool1 = pool.clone();
+    let pool2 = pool;
+
+    let list = warp::path("mentions")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool1.clone()))
+        .and_then(list_handler);
+
+    let mark_read = warp::path!("mentions" / "read")
+        .and(warp::post())
+        .and(warp::body::json::<MarkReadRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool2.clone()))
+        .and_then(mark_read_handler);
+
+    list.or(mark_read)
+}
+
+// Mentions of the user in groups they still belong to, leaving out recalled messages
+const VISIBLE_MENTIONS: &str = "FROM mentions mn
+     JOIN groups g ON g.id = mn.group_id
+     JOIN group_messages gm ON gm.id = mn.message_id
+     JOIN group_members mem ON mem.group_id = mn.group_id AND mem.username = mn.username
+     WHERE mn.username = ? COLLATE NOCASE AND COALESCE(gm.deleted, 0) = 0";
+
+async fn unread_count(pool: &SqlitePool, username: &str) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(&format!("SELECT COUNT(*) {} AND mn.read_at IS NULL", VISIBLE_MENTIONS))
+        .bind(username)
+        .fetch_one(pool)
+        .await
+}
+
+/// `GET /mentions?limit=&before_id=&unread=true`
+async fn list_handler(
+    query: HashMap<String, String>,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    let limit = query
+        .get("limit")
+        .and_then(|l| l.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_PAGE)
+        .clamp(1, MAX_PAGE);
+    let before_id = query.get("before_id").and_then(|b| b.parse::<i64>().ok());
+    let unread_only = query.get("unread").is_some_and(|u| u == "true" || u == "1");
+
+    let sql = format!(
+        "SELECT mn.id, mn.message_id, mn.group_id, g.name AS group_name, g.ghost_mode, mn.mentioned_by,
+                mn.everyone, mn.created_at, mn.read_at, gm.message, gm.reveal_at, gm.thread_root
+         {}
+           AND (? IS NULL OR mn.id < ?)
+           AND (? = 0 OR mn.read_at IS NULL)
+         ORDER BY mn.id DESC
+         LIMIT ?",
+        VISIBLE_MENTIONS
+    );
+    let rows = match sqlx::query(&sql)
+        .bind(&username)
+        .bind(before_id)
+        .bind(before_id)
+        .bind(unread_only)
+        .bind(limit + 1)
+        .fetch_all(&pool)
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) => return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load mentions: {}", e))),
+    };
+
+    let has_more = rows.len() as i64 > limit;
+    let mentions = rows
+        .iter()
+        .take(limit as usize)
+        .map(|row| {
+            let ghost = row.try_get::<Option<i32>, _>("ghost_mode").ok().flatten().unwrap_or(0) != 0;
+            let unrevealed = crate::reveal::is_pending(row.get::<Option<String>, _>("reveal_at").as_deref());
+            Mention {
+                id: row.get("id"),
+                message_id: row.get("message_id"),
+                group_id: row.get("group_id"),
+                group_name: row.get("group_name"),
+                mentioned_by: if ghost { "Anonymous".to_string() } else { row.get("mentioned_by") },
+                everyone: row.get::<i64, _>("everyone") != 0,
+                message: (!unrevealed).then(|| preview(&row.get::<String, _>("message"))),
+                thread_root: row.get("thread_root"),
+                created_at: row.get("created_at"),
+                read_at: row.get("read_at"),
+            }
+        })
+        .collect();
+
+    match unread_count(&pool, &username).await {
+        Ok(unread_count) => Ok(warp::reply::json(&MentionsPage { mentions, has_more, unread_count }).into_response()),
+        Err(e) => Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to count mentions: {}", e))),
+    }
+}
+
+/// `POST /mentions/read`
+async fn mark_read_handler(
+    request: MarkReadRequest,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+
+    let mut sql = String::from(
+        "UPDATE mentions SET read_at = ? WHERE username = ? COLLATE NOCASE AND read_at IS NULL AND (? IS NULL OR group_id = ?)",
+    );
+    if !request.ids.is_empty() {
+        sql.push_str(&format!(" AND id IN ({})", vec!["?"; request.ids.len()].join(", ")));
+    }
+    let mut update = sqlx::query(&sql)
+        .bind(crate::get_current_time())
+        .bind(&username)
+        .bind(request.group_id)
+        .bind(request.group_id);
+    for id in &request.ids {
+        update = update.bind(id);
+    }
+    let updated = match update.execute(&pool).await {
+        Ok(result) => result.rows_affected(),
+        Err(e) => return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to mark mentions read: {}", e))),
+    };
+
+    match unread_count(&pool, &username).await {
+        Ok(unread_count) => Ok(warp::reply::json(&serde_json::json!({"updated": updated, "unread_count": unread_count})).into_response()),
+        Err(e) => Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to count mentions: {}", e))),
+    }
+}
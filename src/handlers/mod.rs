@@ -1,2 +1,26 @@
 // src/handlers/mod.rs
+pub mod conversations;
 pub mod groups;
+pub mod mentions;
+pub mod polls;
+pub mod scheduled;
+pub mod uploads;
+
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::Reply;
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// The `{"error": ...}` body the REST handlers fail with.
+fn error_reply(status: StatusCode, error: impl Into<String>) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&ErrorResponse { error: error.into() }), status).into_response()
+}
+
+/// The user a `Bearer` token in an `Authorization` header was issued to.
+fn username_from_auth(auth_header: &str) -> Option<String> {
+    crate::verify_jwt(auth_header.strip_prefix("Bearer ")?).ok()
+}
@@ -0,0 +1,1147 @@
+// src/handlers/polls.rs
+//! Polls: creating, voting, closing, reading and exporting them, over the
+//! `*_poll` frames or `/polls`.
+//!
+//! A poll belongs either to a group, where roles decide who may do what, or
+//! to a DM, given by `receiver_username`, where only its two people can see
+//! and vote on it and only its creator can close it. Anyone else asking
+//! about a DM poll is told it does not exist.
+//!
+//! A `standard` poll takes one choice, or several with
+//! `allow_multiple_choices`. A `ranked` poll takes each voter's options in
+//! order of preference and is tallied by instant runoff. A `quiz` poll has
+//! one correct option, shown to a voter once they have answered; answers are
+//! final, and closed quizzes add up to per-user scores in the group. Any
+//! type can be `anonymous`: who voted for what is then never sent to anyone,
+//! the creator included, and votes are not announced in the group.
+//!
+//! A poll takes votes while it is active. It closes when its creator (or a
+//! group admin) closes it, or when `expires_at` passes, which the background
+//! sweep in `main` checks every few seconds. Closing posts the final tallies
+//! into the group and sends `poll_closed` to its members. Votes on a closed
+//! or expired poll are refused the same way from both the REST route and the
+//! WebSocket frame, since both go through [`vote`]; likewise [`create`] and
+//! [`vote`] make the same announcements in the conversation for both.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::protocol::{CommandError, MessageKind, ServerEvent};
+use crate::roles::{self, Denied, Permission};
+use crate::Users;
+use super::{error_reply, username_from_auth};
+
+const MIN_OPTIONS: usize = 2;
+const MAX_OPTIONS: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PollType {
+    #[default]
+    Standard,
+    Ranked,
+    Quiz,
+}
+
+impl PollType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PollType::Standard => "standard",
+            PollType::Ranked => "ranked",
+            PollType::Quiz => "quiz",
+        }
+    }
+
+    pub fn parse(s: &str) -> PollType {
+        match s {
+            "ranked" => PollType::Ranked,
+            "quiz" => PollType::Quiz,
+            _ => PollType::Standard,
+        }
+    }
+}
+
+/// Everything a new poll is made of, from either the REST route or the
+/// `create_poll` frame. Exactly one of `group_id` and `receiver_username`
+/// is set.
+#[derive(Debug, Clone, Default)]
+pub struct NewPoll {
+    pub group_id: Option<i64>,
+    pub receiver_username: Option<String>,
+    pub question: String,
+    pub options: Vec<String>,
+    pub allow_multiple_choices: bool,
+    pub expires_at: Option<String>,
+    pub poll_type: PollType,
+    pub anonymous: bool,
+    // Index into `options` of a quiz's correct answer
+    pub correct_option: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatePollRequest {
+    #[serde(default)]
+    pub group_id: Option<i64>,
+    // For a poll in the DM with this user
+    #[serde(default)]
+    pub receiver_username: Option<String>,
+    pub question: String,
+    pub options: Vec<String>,
+    pub allow_multiple_choices: Option<bool>,
+    pub expires_at: Option<String>,
+    #[serde(default)]
+    pub poll_type: Option<PollType>,
+    #[serde(default)]
+    pub anonymous: Option<bool>,
+    #[serde(default)]
+    pub correct_option: Option<usize>,
+}
+
+impl From<CreatePollRequest> for NewPoll {
+    fn from(request: CreatePollRequest) -> Self {
+        NewPoll {
+            group_id: request.group_id,
+            receiver_username: request.receiver_username,
+            question: request.question,
+            options: request.options,
+            allow_multiple_choices: request.allow_multiple_choices.unwrap_or(false),
+            expires_at: request.expires_at,
+            poll_type: request.poll_type.unwrap_or_default(),
+            anonymous: request.anonymous.unwrap_or(false),
+            correct_option: request.correct_option,
+        }
+    }
+}
+
+/// A vote: the chosen options, or for a ranked poll every option the voter
+/// ranks, most preferred first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VotePollRequest {
+    pub poll_id: i64,
+    pub option_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollOption {
+    pub id: i64,
+    pub option_text: String,
+    // First preferences on a ranked poll
+    pub vote_count: i64,
+    // Always false on anonymous polls
+    pub voted_by_current_user: bool,
+    // Where the viewer ranked this option on a ranked poll, 1 being first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_user_rank: Option<i64>,
+}
+
+/// One instant-runoff round: each ballot counts for its highest-ranked
+/// option still in the running, then the last-placed options drop out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunoffRound {
+    pub tallies: Vec<OptionTally>,
+    pub eliminated: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OptionTally {
+    pub option_id: i64,
+    pub votes: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RankedResult {
+    pub rounds: Vec<RunoffRound>,
+    // None on a tie or when nobody voted
+    pub winner: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tied: Vec<i64>,
+}
+
+/// A member's results across a group's closed, named quizzes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuizScore {
+    pub username: String,
+    pub answered: i64,
+    pub correct: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Poll {
+    pub id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<i64>,
+    // The other person of a DM poll
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receiver_username: Option<String>,
+    pub creator_username: String,
+    pub question: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub is_active: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed_at: Option<String>,
+    // None when the poll expired rather than being closed by someone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed_by: Option<String>,
+    pub allow_multiple_choices: bool,
+    #[serde(default)]
+    pub poll_type: PollType,
+    #[serde(default)]
+    pub anonymous: bool,
+    pub options: Vec<PollOption>,
+    // Choices on a standard poll; ballots on ranked and quiz polls
+    pub total_votes: i64,
+    // Whether the viewer has voted, which anonymous polls still tell them
+    #[serde(default)]
+    pub has_voted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ranked: Option<RankedResult>,
+    // Quiz answer: shown to the creator, to those who answered, and to everyone once closed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correct_option_id: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answered_correctly: Option<bool>,
+}
+
+impl Poll {
+    /// Whether `username` is one of a DM poll's two people.
+    fn in_dm(&self, username: &str) -> bool {
+        self.receiver_username.as_deref().is_some_and(|receiver| {
+            receiver.eq_ignore_ascii_case(username) || self.creator_username.eq_ignore_ascii_case(username)
+        })
+    }
+
+    /// Whoever is in a DM poll's conversation other than `username`.
+    fn dm_peer(&self, username: &str) -> Option<&str> {
+        let receiver = self.receiver_username.as_deref()?;
+        Some(if receiver.eq_ignore_ascii_case(username) { &self.creator_username } else { receiver })
+    }
+}
+
+/// A voter's choice, one per option they picked or ranked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoterChoice {
+    pub username: String,
+    pub option_id: i64,
+    pub option_text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rank: Option<i64>,
+    pub voted_at: String,
+}
+
+/// A poll's results for download. `votes` is left out of anonymous polls,
+/// and of quizzes whose answer the exporter may not see yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PollExport {
+    pub poll: Poll,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub votes: Option<Vec<VoterChoice>>,
+}
+
+#[derive(Debug)]
+pub enum PollError {
+    NotFound,
+    Denied(Denied),
+    // Closing someone else's poll in a DM
+    NotCreator,
+    // Closed, or past expires_at
+    Closed,
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for PollError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PollError::NotFound => f.write_str("Poll not found"),
+            PollError::Denied(denied) => denied.fmt(f),
+            PollError::NotCreator => f.write_str("Only the poll's creator can close it"),
+            PollError::Closed => f.write_str("Poll is closed"),
+            PollError::Invalid(message) => f.write_str(message),
+            PollError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for PollError {
+    fn from(e: sqlx::Error) -> Self {
+        PollError::Database(e)
+    }
+}
+
+impl From<Denied> for PollError {
+    fn from(denied: Denied) -> Self {
+        match denied {
+            Denied::Database(e) => PollError::Database(e),
+            denied => PollError::Denied(denied),
+        }
+    }
+}
+
+impl From<PollError> for CommandError {
+    fn from(e: PollError) -> Self {
+        match e {
+            PollError::NotFound => CommandError::not_found(e.to_string()),
+            PollError::Denied(_) | PollError::NotCreator => CommandError::forbidden(e.to_string()),
+            PollError::Closed | PollError::Invalid(_) => CommandError::invalid(e.to_string()),
+            PollError::Database(_) => CommandError::internal(e.to_string()),
+        }
+    }
+}
+
+/// Read an expiry as RFC 3339, or as a bare `YYYY-MM-DDTHH:MM[:SS]` in UTC
+/// the way older clients sent it.
+fn parse_expiry(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(s.trim()) {
+        return Some(at.with_timezone(&Utc));
+    }
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s.trim(), format).ok())
+        .map(|at| at.and_utc())
+}
+
+fn is_expired(expires_at: Option<&str>) -> bool {
+    expires_at.and_then(parse_expiry).is_some_and(|at| at <= Utc::now())
+}
+
+/// Check that `username` may do what `permission` stands for on `poll`. In
+/// a group that is up to their role; in a DM either of its people may.
+async fn require(pool: &SqlitePool, poll: &Poll, username: &str, permission: Permission) -> Result<(), PollError> {
+    match poll.group_id {
+        Some(group_id) => {
+            roles::require(pool, group_id, username, permission).await?;
+            Ok(())
+        }
+        None if poll.in_dm(username) => Ok(()),
+        None => Err(PollError::NotFound),
+    }
+}
+
+/// Check and store a new poll and announce it in its conversation. Returns
+/// the poll as its creator sees it.
+pub async fn create(users: &Users, pool: &SqlitePool, username: &str, poll: &NewPoll) -> Result<Poll, PollError> {
+    let receiver = match (poll.group_id, poll.receiver_username.as_deref().map(str::trim).filter(|r| !r.is_empty())) {
+        (Some(group_id), None) => {
+            roles::require(pool, group_id, username, Permission::CreatePoll).await?;
+            None
+        }
+        (None, Some(receiver)) => {
+            if receiver.eq_ignore_ascii_case(username) {
+                return Err(PollError::Invalid("A DM poll needs someone else to ask".to_string()));
+            }
+            let receiver: Option<String> = sqlx::query_scalar("SELECT username FROM users WHERE username = ? COLLATE NOCASE")
+                .bind(receiver)
+                .fetch_optional(pool)
+                .await?;
+            Some(receiver.ok_or_else(|| PollError::Invalid("No such user".to_string()))?)
+        }
+        _ => return Err(PollError::Invalid("A poll needs either a group_id or a receiver_username".to_string())),
+    };
+    // Blank options are dropped, so keep where each one was sent to place a quiz's answer
+    let options: Vec<(usize, &str)> =
+        poll.options.iter().map(|o| o.trim()).enumerate().filter(|(_, o)| !o.is_empty()).collect();
+    if poll.question.trim().is_empty() || options.len() < MIN_OPTIONS || options.len() > MAX_OPTIONS {
+        return Err(PollError::Invalid(format!(
+            "A poll needs a question and between {} and {} options",
+            MIN_OPTIONS, MAX_OPTIONS
+        )));
+    }
+    if poll.allow_multiple_choices && poll.poll_type != PollType::Standard {
+        return Err(PollError::Invalid(format!("A {} poll cannot allow multiple choices", poll.poll_type.as_str())));
+    }
+    let correct_index = match (poll.poll_type, poll.correct_option) {
+        (PollType::Quiz, Some(index)) => Some(
+            options
+                .iter()
+                .position(|(sent, _)| *sent == index)
+                .ok_or_else(|| PollError::Invalid("correct_option must be one of the options".to_string()))?,
+        ),
+        (PollType::Quiz, None) => return Err(PollError::Invalid("A quiz needs a correct_option".to_string())),
+        (_, Some(_)) => return Err(PollError::Invalid("Only a quiz has a correct_option".to_string())),
+        (_, None) => None,
+    };
+    let expires_at = match poll.expires_at.as_deref().filter(|at| !at.trim().is_empty()) {
+        Some(at) => {
+            let at = parse_expiry(at).ok_or_else(|| PollError::Invalid(format!("Invalid expires_at `{}`", at)))?;
+            if at <= Utc::now() {
+                return Err(PollError::Invalid("expires_at must be in the future".to_string()));
+            }
+            Some(at.to_rfc3339())
+        }
+        None => None,
+    };
+
+    let mut tx = pool.begin().await?;
+    let poll_id: i64 = sqlx::query_scalar(
+        "INSERT INTO polls (group_id, receiver_username, creator_username, question, created_at, expires_at,
+                            allow_multiple_choices, poll_type, anonymous)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+         RETURNING id",
+    )
+    .bind(poll.group_id)
+    .bind(&receiver)
+    .bind(username)
+    .bind(poll.question.trim())
+    .bind(crate::get_current_time())
+    .bind(&expires_at)
+    .bind(poll.allow_multiple_choices)
+    .bind(poll.poll_type.as_str())
+    .bind(poll.anonymous)
+    .fetch_one(&mut *tx)
+    .await?;
+    for (index, (_, option_text)) in options.iter().enumerate() {
+        let option_id: i64 =
+            sqlx::query_scalar("INSERT INTO poll_options (poll_id, option_text, option_order) VALUES (?, ?, ?) RETURNING id")
+                .bind(poll_id)
+                .bind(option_text)
+                .bind(index as i64)
+                .fetch_one(&mut *tx)
+                .await?;
+        if correct_index == Some(index) {
+            sqlx::query("UPDATE polls SET correct_option_id = ? WHERE id = ?")
+                .bind(option_id)
+                .bind(poll_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
+
+    let poll = get(pool, username, poll_id).await?;
+    announce(users, pool, &poll, username, &format!("📊 Poll created: {}", poll.question)).await?;
+    Ok(poll)
+}
+
+/// Tally ranked ballots by instant runoff. Each round counts every ballot
+/// for its highest-ranked option still in the running; an option with more
+/// than half of those ballots wins. Otherwise the options with the fewest
+/// votes are eliminated together only when their combined votes still fall
+/// short of the next-lowest option; a tie that could matter is broken by
+/// [`break_tie`]. If nothing separates every remaining option they are tied.
+pub fn instant_runoff(option_ids: &[i64], ballots: &[Vec<i64>]) -> RankedResult {
+    let mut remaining = option_ids.to_vec();
+    let mut rounds = Vec::new();
+    if ballots.is_empty() {
+        return RankedResult { rounds, winner: None, tied: Vec::new() };
+    }
+    loop {
+        let mut tallies: Vec<OptionTally> = remaining.iter().map(|&option_id| OptionTally { option_id, votes: 0 }).collect();
+        let mut active = 0;
+        for ballot in ballots {
+            let Some(choice) = ballot.iter().find(|id| remaining.contains(id)) else {
+                // Every option this ballot ranked is out
+                continue;
+            };
+            active += 1;
+            if let Some(tally) = tallies.iter_mut().find(|t| t.option_id == *choice) {
+                tally.votes += 1;
+            }
+        }
+
+        let top = tallies.iter().map(|t| t.votes).max().unwrap_or(0);
+        if active > 0 && top * 2 > active {
+            let winner = tallies.iter().find(|t| t.votes == top).map(|t| t.option_id);
+            rounds.push(RunoffRound { tallies, eliminated: Vec::new() });
+            return RankedResult { rounds, winner, tied: Vec::new() };
+        }
+        let lowest = tallies.iter().map(|t| t.votes).min().unwrap_or(0);
+        let mut eliminated: Vec<i64> = tallies.iter().filter(|t| t.votes == lowest).map(|t| t.option_id).collect();
+        let next_lowest = tallies.iter().map(|t| t.votes).filter(|&votes| votes > lowest).min();
+        let combined = lowest * eliminated.len() as i64;
+        if eliminated.len() > 1 && next_lowest.is_none_or(|next| combined >= next) {
+            eliminated = break_tie(&eliminated, &remaining, &rounds, ballots);
+        }
+        if eliminated.len() == remaining.len() {
+            rounds.push(RunoffRound { tallies, eliminated: Vec::new() });
+            return RankedResult { rounds, winner: None, tied: remaining };
+        }
+        remaining.retain(|id| !eliminated.contains(id));
+        rounds.push(RunoffRound { tallies, eliminated });
+    }
+}
+
+/// Narrow a tie for last place to the options to eliminate: whichever had
+/// the fewest votes in the latest earlier round that separates them, then
+/// whichever the fewest ballots rank at all. Options nothing separates go
+/// out together.
+fn break_tie(tied: &[i64], remaining: &[i64], rounds: &[RunoffRound], ballots: &[Vec<i64>]) -> Vec<i64> {
+    let mut candidates = tied.to_vec();
+    let mut keep_fewest = |score: &dyn Fn(i64) -> i64| {
+        let fewest = candidates.iter().map(|&id| score(id)).min().unwrap_or(0);
+        candidates.retain(|&id| score(id) == fewest);
+    };
+    for round in rounds.iter().rev() {
+        keep_fewest(&|id| round.tallies.iter().find(|t| t.option_id == id).map_or(0, |t| t.votes));
+    }
+    keep_fewest(&|id| {
+        ballots
+            .iter()
+            .filter(|ballot| ballot.contains(&id) && ballot.iter().any(|other| remaining.contains(other)))
+            .count() as i64
+    });
+    candidates
+}
+
+/// A poll with its tallies; `voted_by_current_user`, `has_voted` and the
+/// quiz fields are from `viewer`'s side.
+pub async fn load(pool: &SqlitePool, poll_id: i64, viewer: &str) -> Result<Option<Poll>, sqlx::Error> {
+    let Some(poll_row) = sqlx::query(
+        "SELECT id, group_id, receiver_username, creator_username, question, created_at, expires_at, is_active, closed_at, closed_by,
+                allow_multiple_choices, poll_type, anonymous, correct_option_id
+         FROM polls WHERE id = ?",
+    )
+    .bind(poll_id)
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let poll_type = PollType::parse(&poll_row.get::<String, _>("poll_type"));
+    let anonymous = poll_row.get::<i64, _>("anonymous") != 0;
+    let is_active = poll_row.get::<Option<bool>, _>("is_active").unwrap_or(true);
+    let creator_username: String = poll_row.get("creator_username");
+
+    // A ranked ballot has a row per option; only first preferences count here
+    let options_rows = sqlx::query(
+        "SELECT po.id, po.option_text,
+                COUNT(CASE WHEN pv.rank IS NULL OR pv.rank = 1 THEN pv.id END) as vote_count,
+                CASE WHEN user_votes.option_id IS NOT NULL THEN 1 ELSE 0 END as voted_by_current_user,
+                user_votes.rank as current_user_rank
+         FROM poll_options po
+         LEFT JOIN poll_votes pv ON po.id = pv.option_id
+         LEFT JOIN poll_votes user_votes ON po.id = user_votes.option_id AND user_votes.username = ?
+         WHERE po.poll_id = ?
+         GROUP BY po.id, po.option_text, po.option_order
+         ORDER BY po.option_order",
+    )
+    .bind(viewer)
+    .bind(poll_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut has_voted = false;
+    let mut viewer_choice = None;
+    let options: Vec<PollOption> = options_rows
+        .iter()
+        .map(|row| {
+            let voted = row.get::<i64, _>("voted_by_current_user") == 1;
+            if voted {
+                has_voted = true;
+                viewer_choice = Some(row.get::<i64, _>("id"));
+            }
+            PollOption {
+                id: row.get("id"),
+                option_text: row.get("option_text"),
+                vote_count: row.get("vote_count"),
+                voted_by_current_user: voted && !anonymous,
+                current_user_rank: if anonymous { None } else { row.get("current_user_rank") },
+            }
+        })
+        .collect();
+    let total_votes = match poll_type {
+        PollType::Standard => options.iter().map(|o| o.vote_count).sum(),
+        PollType::Ranked | PollType::Quiz => {
+            sqlx::query_scalar("SELECT COUNT(DISTINCT username) FROM poll_votes WHERE poll_id = ?")
+                .bind(poll_id)
+                .fetch_one(pool)
+                .await?
+        }
+    };
+
+    let ranked = match poll_type {
+        PollType::Ranked => {
+            let rows = sqlx::query("SELECT username, option_id FROM poll_votes WHERE poll_id = ? ORDER BY username, rank")
+                .bind(poll_id)
+                .fetch_all(pool)
+                .await?;
+            let mut ballots: Vec<(String, Vec<i64>)> = Vec::new();
+            for row in rows {
+                let username: String = row.get("username");
+                match ballots.last_mut() {
+                    Some((voter, ballot)) if *voter == username => ballot.push(row.get("option_id")),
+                    _ => ballots.push((username, vec![row.get("option_id")])),
+                }
+            }
+            let ballots: Vec<Vec<i64>> = ballots.into_iter().map(|(_, ballot)| ballot).collect();
+            let option_ids: Vec<i64> = options.iter().map(|o| o.id).collect();
+            Some(instant_runoff(&option_ids, &ballots))
+        }
+        _ => None,
+    };
+
+    let (correct_option_id, answered_correctly) = match poll_type {
+        PollType::Quiz => {
+            let correct: Option<i64> = poll_row.get("correct_option_id");
+            let shown = has_voted || !is_active || (!viewer.is_empty() && creator_username.eq_ignore_ascii_case(viewer));
+            let answered_correctly = match viewer_choice {
+                Some(choice) if !anonymous => Some(Some(choice) == correct),
+                _ => None,
+            };
+            (correct.filter(|_| shown), answered_correctly)
+        }
+        _ => (None, None),
+    };
+
+    Ok(Some(Poll {
+        id: poll_row.get("id"),
+        group_id: poll_row.get("group_id"),
+        receiver_username: poll_row.get("receiver_username"),
+        creator_username,
+        question: poll_row.get("question"),
+        created_at: poll_row.get("created_at"),
+        expires_at: poll_row.get("expires_at"),
+        is_active,
+        closed_at: poll_row.get("closed_at"),
+        closed_by: poll_row.get("closed_by"),
+        allow_multiple_choices: poll_row.get::<Option<bool>, _>("allow_multiple_choices").unwrap_or(false),
+        poll_type,
+        anonymous,
+        options,
+        total_votes,
+        has_voted,
+        ranked,
+        correct_option_id,
+        answered_correctly,
+    }))
+}
+
+/// A poll as `username` may see it: they must be able to view its group, or
+/// be in its DM.
+pub async fn get(pool: &SqlitePool, username: &str, poll_id: i64) -> Result<Poll, PollError> {
+    let poll = load(pool, poll_id, username).await?.ok_or(PollError::NotFound)?;
+    require(pool, &poll, username, Permission::View).await?;
+    Ok(poll)
+}
+
+/// Record `username`'s vote, replacing any earlier one on a single-choice
+/// or ranked poll; a quiz answer is final. The vote is announced in the
+/// conversation, except on an anonymous poll, where the announcement would
+/// give the voter away and only their own sockets hear of it. Returns the
+/// poll as the voter now sees it.
+pub async fn vote(users: &Users, pool: &SqlitePool, username: &str, poll_id: i64, option_ids: &[i64]) -> Result<Poll, PollError> {
+    let poll = load(pool, poll_id, username).await?.ok_or(PollError::NotFound)?;
+    // A vote is announced in the conversation, so it needs the right to post there
+    require(pool, &poll, username, Permission::Post).await?;
+    if !poll.is_active || is_expired(poll.expires_at.as_deref()) {
+        return Err(PollError::Closed);
+    }
+    let single = match poll.poll_type {
+        PollType::Standard => !poll.allow_multiple_choices,
+        PollType::Ranked => false,
+        PollType::Quiz => true,
+    };
+    if option_ids.is_empty() || (single && option_ids.len() > 1) {
+        return Err(PollError::Invalid("Invalid vote options".to_string()));
+    }
+    if let Some(unknown) = option_ids.iter().find(|id| !poll.options.iter().any(|o| o.id == **id)) {
+        return Err(PollError::Invalid(format!("Option {} is not part of this poll", unknown)));
+    }
+    if poll.poll_type == PollType::Ranked && option_ids.iter().enumerate().any(|(i, id)| option_ids[..i].contains(id)) {
+        return Err(PollError::Invalid("Each option can be ranked only once".to_string()));
+    }
+
+    let voted_at = crate::get_current_time();
+    if poll.poll_type == PollType::Quiz {
+        // One statement, so two racing answers cannot both find no earlier one
+        let inserted = sqlx::query(
+            "INSERT INTO poll_votes (poll_id, option_id, username, voted_at)
+             SELECT ?, ?, ?, ?
+             WHERE NOT EXISTS (SELECT 1 FROM poll_votes WHERE poll_id = ? AND username = ?)",
+        )
+        .bind(poll_id)
+        .bind(option_ids[0])
+        .bind(username)
+        .bind(&voted_at)
+        .bind(poll_id)
+        .bind(username)
+        .execute(pool)
+        .await?;
+        if inserted.rows_affected() == 0 {
+            return Err(PollError::Invalid("You have already answered this quiz".to_string()));
+        }
+        return announce_vote(users, pool, username, poll_id).await;
+    }
+
+    let mut tx = pool.begin().await?;
+    if single || poll.poll_type == PollType::Ranked {
+        sqlx::query("DELETE FROM poll_votes WHERE poll_id = ? AND username = ?")
+            .bind(poll_id)
+            .bind(username)
+            .execute(&mut *tx)
+            .await?;
+    }
+    for (index, option_id) in option_ids.iter().enumerate() {
+        let rank = (poll.poll_type == PollType::Ranked).then_some(index as i64 + 1);
+        sqlx::query("INSERT OR IGNORE INTO poll_votes (poll_id, option_id, username, voted_at, rank) VALUES (?, ?, ?, ?, ?)")
+            .bind(poll_id)
+            .bind(option_id)
+            .bind(username)
+            .bind(&voted_at)
+            .bind(rank)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    announce_vote(users, pool, username, poll_id).await
+}
+
+/// The announcement half of [`vote`], once the vote is stored.
+async fn announce_vote(users: &Users, pool: &SqlitePool, username: &str, poll_id: i64) -> Result<Poll, PollError> {
+    let poll = load(pool, poll_id, username).await?.ok_or(PollError::NotFound)?;
+    if poll.anonymous {
+        users.send_to_user(username, &ServerEvent::PollDetails { poll: poll.clone() });
+    } else {
+        let text = format!("📊 Poll updated: {} voted on \"{}\"", username, poll.question);
+        if let Err(e) = announce(users, pool, &poll, username, &text).await {
+            println!("DEBUG: Failed to store poll vote message: {}", e);
+        }
+    }
+    Ok(poll)
+}
+
+/// Scores across `group_id`'s closed quizzes, best first. Anonymous quizzes
+/// are left out, since their answers are not tied to anyone.
+pub async fn quiz_scores(pool: &SqlitePool, username: &str, group_id: i64) -> Result<Vec<QuizScore>, PollError> {
+    roles::require(pool, group_id, username, Permission::View).await?;
+    let rows = sqlx::query(
+        "SELECT pv.username,
+                COUNT(*) AS answered,
+                SUM(CASE WHEN pv.option_id = p.correct_option_id THEN 1 ELSE 0 END) AS correct
+         FROM poll_votes pv
+         JOIN polls p ON p.id = pv.poll_id
+         WHERE p.group_id = ? AND p.poll_type = 'quiz' AND p.anonymous = 0 AND p.is_active = 0
+         GROUP BY pv.username
+         ORDER BY correct DESC, answered ASC, pv.username COLLATE NOCASE",
+    )
+    .bind(group_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .iter()
+        .map(|row| QuizScore { username: row.get("username"), answered: row.get("answered"), correct: row.get("correct") })
+        .collect())
+}
+
+/// Close a poll now. Its creator may close it, and so may group admins.
+pub async fn close(users: &Users, pool: &SqlitePool, username: &str, poll_id: i64) -> Result<Poll, PollError> {
+    let poll = load(pool, poll_id, username).await?.ok_or(PollError::NotFound)?;
+    let creator = poll.creator_username.eq_ignore_ascii_case(username);
+    require(pool, &poll, username, if creator { Permission::View } else { Permission::ClosePolls }).await?;
+    if poll.group_id.is_none() && !creator {
+        return Err(PollError::NotCreator);
+    }
+    if !poll.is_active {
+        return Err(PollError::Closed);
+    }
+    finish(users, pool, poll_id, Some(username)).await?.ok_or(PollError::Closed)
+}
+
+/// Close every active poll whose `expires_at` has passed. Run by the
+/// background sweep in `main`.
+pub async fn close_expired(users: &Users, pool: &SqlitePool) {
+    // Expiries sent by older clients may not be RFC 3339, so they are compared here rather than in SQL
+    let rows = sqlx::query("SELECT id, expires_at FROM polls WHERE is_active = 1 AND expires_at IS NOT NULL")
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    for row in rows {
+        if !is_expired(row.get::<Option<String>, _>("expires_at").as_deref()) {
+            continue;
+        }
+        let poll_id: i64 = row.get("id");
+        if let Err(e) = finish(users, pool, poll_id, None).await {
+            println!("DEBUG: Failed to close expired poll {}: {}", poll_id, e);
+        }
+    }
+}
+
+/// A poll's results as `username` sees them, with who chose what unless the
+/// poll is anonymous or a quiz whose answer they may not see yet.
+pub async fn export(pool: &SqlitePool, username: &str, poll_id: i64) -> Result<PollExport, PollError> {
+    let poll = get(pool, username, poll_id).await?;
+    if poll.anonymous || (poll.poll_type == PollType::Quiz && poll.correct_option_id.is_none()) {
+        return Ok(PollExport { poll, votes: None });
+    }
+    let rows = sqlx::query(
+        "SELECT pv.username, pv.option_id, po.option_text, pv.rank, pv.voted_at
+         FROM poll_votes pv
+         JOIN poll_options po ON po.id = pv.option_id
+         WHERE pv.poll_id = ?
+         ORDER BY pv.username COLLATE NOCASE, pv.rank, po.option_order",
+    )
+    .bind(poll_id)
+    .fetch_all(pool)
+    .await?;
+    let votes = rows
+        .iter()
+        .map(|row| VoterChoice {
+            username: row.get("username"),
+            option_id: row.get("option_id"),
+            option_text: row.get("option_text"),
+            rank: row.get("rank"),
+            voted_at: row.get("voted_at"),
+        })
+        .collect();
+    Ok(PollExport { poll, votes: Some(votes) })
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The export as CSV: a table of options and their votes, then after a blank
+/// line one of each voter's choices when those are included.
+fn export_csv(export: &PollExport) -> String {
+    let poll = &export.poll;
+    let mut csv = String::from("option_id,option,votes\n");
+    for option in &poll.options {
+        csv.push_str(&format!("{},{},{}\n", option.id, csv_field(&option.option_text), option.vote_count));
+    }
+    if let Some(votes) = &export.votes {
+        csv.push_str("\nusername,option_id,option,rank,voted_at\n");
+        for vote in votes {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                csv_field(&vote.username),
+                vote.option_id,
+                csv_field(&vote.option_text),
+                vote.rank.map(|rank| rank.to_string()).unwrap_or_default(),
+                csv_field(&vote.voted_at)
+            ));
+        }
+    }
+    csv
+}
+
+/// The tallies in a sentence, for the closing announcement.
+fn results_summary(poll: &Poll) -> String {
+    let option_text = |id: i64| poll.options.iter().find(|o| o.id == id).map_or("?", |o| o.option_text.as_str());
+    match poll.poll_type {
+        PollType::Ranked => {
+            let Some(result) = &poll.ranked else {
+                return "No votes were cast".to_string();
+            };
+            let rounds = result.rounds.len();
+            let rounds = format!("{} {}", rounds, if rounds == 1 { "round" } else { "rounds" });
+            return match result.winner {
+                Some(winner) => format!("Winner: {} after {} of instant runoff", option_text(winner), rounds),
+                None if result.tied.is_empty() => "No votes were cast".to_string(),
+                None => {
+                    let tied: Vec<&str> = result.tied.iter().map(|&id| option_text(id)).collect();
+                    format!("Tie between {} after {}", tied.join(", "), rounds)
+                }
+            };
+        }
+        PollType::Quiz => {
+            let answer = poll.correct_option_id.map_or("?", option_text);
+            let correct = poll.options.iter().find(|o| Some(o.id) == poll.correct_option_id).map_or(0, |o| o.vote_count);
+            return format!("Answer: {} — {} of {} answered correctly", answer, correct, poll.total_votes);
+        }
+        PollType::Standard => {}
+    }
+    let top = poll.options.iter().map(|o| o.vote_count).max().unwrap_or(0);
+    if top == 0 {
+        return "No votes were cast".to_string();
+    }
+    let leaders: Vec<&str> = poll.options.iter().filter(|o| o.vote_count == top).map(|o| o.option_text.as_str()).collect();
+    let votes = if top == 1 { "vote" } else { "votes" };
+    if leaders.len() == 1 {
+        format!("Winner: {} ({} {} of {})", leaders[0], top, votes, poll.total_votes)
+    } else {
+        format!("Tie between {} ({} {} each)", leaders.join(", "), top, votes)
+    }
+}
+
+/// Mark a poll closed, post its results in the group and send `poll_closed`.
+/// `None` when it was already closed.
+async fn finish(users: &Users, pool: &SqlitePool, poll_id: i64, closed_by: Option<&str>) -> Result<Option<Poll>, sqlx::Error> {
+    let closed_at = crate::get_current_time();
+    let closed = sqlx::query("UPDATE polls SET is_active = 0, closed_at = ?, closed_by = ? WHERE id = ? AND is_active = 1")
+        .bind(&closed_at)
+        .bind(closed_by)
+        .bind(poll_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if closed == 0 {
+        return Ok(None);
+    }
+    let Some(poll) = load(pool, poll_id, "").await? else {
+        return Ok(None);
+    };
+
+    let text = format!("📊 Poll closed: {} — {}", poll.question, results_summary(&poll));
+    let sender = closed_by.unwrap_or(&poll.creator_username).to_string();
+    announce(users, pool, &poll, &sender, &text).await?;
+
+    users.send_to_users(audience(pool, &poll).await.iter().map(String::as_str), &ServerEvent::PollClosed { poll: poll.clone() });
+    Ok(Some(poll))
+}
+
+/// Everyone who can see a poll: its group's members or its DM's two people.
+async fn audience(pool: &SqlitePool, poll: &Poll) -> Vec<String> {
+    match (poll.group_id, &poll.receiver_username) {
+        (Some(group_id), _) => crate::handlers::groups::get_group_members(pool, group_id).await,
+        (None, Some(receiver)) => vec![poll.creator_username.clone(), receiver.clone()],
+        (None, None) => Vec::new(),
+    }
+}
+
+/// Post a `📊 Poll ...` line from `sender` into the poll's conversation.
+async fn announce(users: &Users, pool: &SqlitePool, poll: &Poll, sender: &str, text: &str) -> Result<(), sqlx::Error> {
+    let timestamp = crate::get_current_time();
+    let mut msg = match poll.group_id {
+        Some(group_id) => crate::store_group_message(pool, group_id, sender, text, &timestamp, None, None).await?,
+        None => {
+            let peer = poll.dm_peer(sender).unwrap_or_default();
+            crate::store_message(pool, sender, peer, text, &timestamp, None, None).await?
+        }
+    };
+    let table = MessageKind::of(poll.group_id).table();
+    sqlx::query(&format!("UPDATE {} SET poll_id = ? WHERE id = ?", table))
+        .bind(poll.id)
+        .bind(msg.id)
+        .execute(pool)
+        .await?;
+    msg.poll_id = Some(poll.id);
+    crate::push_chat_message(users, pool, msg).await;
+    Ok(())
+}
+
+fn poll_error_reply(e: PollError) -> warp::reply::Response {
+    let status = match e {
+        PollError::NotFound => StatusCode::NOT_FOUND,
+        PollError::Denied(_) | PollError::NotCreator => StatusCode::FORBIDDEN,
+        PollError::Closed => StatusCode::CONFLICT,
+        PollError::Invalid(_) => StatusCode::BAD_REQUEST,
+        PollError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_reply(status, e.to_string())
+}
+
+pub fn routes(pool: SqlitePool, users: Users) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let pool1 = pool.clone();
+    let pool2 = pool.clone();
+    let pool3 = pool.clone();
+    let pool4 = pool.clone();
+    let pool5 = pool.clone();
+    let pool6 = pool;
+    let users1 = users.clone();
+    let users2 = users.clone();
+
+    let create = warp::path!("polls" / "create")
+        .and(warp::post())
+        .and(warp::body::json::<CreatePollRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool1.clone()))
+        .and(warp::any().map(move || users1.clone()))
+        .and_then(create_handler);
+
+    let vote = warp::path!("polls" / "vote")
+        .and(warp::post())
+        .and(warp::body::json::<VotePollRequest>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool2.clone()))
+        .and(warp::any().map(move || users2.clone()))
+        .and_then(vote_handler);
+
+    let get = warp::path!("polls" / i64)
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool3.clone()))
+        .and_then(get_handler);
+
+    let close = warp::path!("polls" / i64 / "close")
+        .and(warp::post())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool4.clone()))
+        .and(warp::any().map(move || users.clone()))
+        .and_then(close_handler);
+
+    let quiz_scores = warp::path!("groups" / i64 / "quiz_scores")
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool5.clone()))
+        .and_then(quiz_scores_handler);
+
+    let export = warp::path!("polls" / i64 / "export")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool6.clone()))
+        .and_then(export_handler);
+
+    create.or(vote).or(get).or(close).or(quiz_scores).or(export)
+}
+
+/// `POST /polls/create`
+async fn create_handler(
+    request: CreatePollRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    match create(&users, &pool, &username, &NewPoll::from(request)).await {
+        Ok(poll) => Ok(warp::reply::with_status(warp::reply::json(&poll), StatusCode::CREATED).into_response()),
+        Err(e) => Ok(poll_error_reply(e)),
+    }
+}
+
+/// `POST /polls/vote`
+async fn vote_handler(
+    request: VotePollRequest,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    match vote(&users, &pool, &username, request.poll_id, &request.option_ids).await {
+        Ok(poll) => Ok(warp::reply::json(&poll).into_response()),
+        Err(e) => Ok(poll_error_reply(e)),
+    }
+}
+
+/// `GET /polls/{id}`
+async fn get_handler(poll_id: i64, auth_header: String, pool: SqlitePool) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    match get(&pool, &username, poll_id).await {
+        Ok(poll) => Ok(warp::reply::json(&poll).into_response()),
+        Err(e) => Ok(poll_error_reply(e)),
+    }
+}
+
+/// `POST /polls/{id}/close`
+async fn close_handler(poll_id: i64, auth_header: String, pool: SqlitePool, users: Users) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    match close(&users, &pool, &username, poll_id).await {
+        Ok(poll) => Ok(warp::reply::json(&poll).into_response()),
+        Err(e) => Ok(poll_error_reply(e)),
+    }
+}
+
+#[derive(Serialize)]
+struct QuizScoresResponse {
+    group_id: i64,
+    scores: Vec<QuizScore>,
+}
+
+/// `GET /groups/{id}/quiz_scores`
+async fn quiz_scores_handler(group_id: i64, auth_header: String, pool: SqlitePool) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    match quiz_scores(&pool, &username, group_id).await {
+        Ok(scores) => Ok(warp::reply::json(&QuizScoresResponse { group_id, scores }).into_response()),
+        Err(e) => Ok(poll_error_reply(e)),
+    }
+}
+
+/// `GET /polls/{id}/export?format=json|csv`
+async fn export_handler(
+    poll_id: i64,
+    query: HashMap<String, String>,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    let csv = match query.get("format").map(String::as_str) {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(other) => return Ok(error_reply(StatusCode::BAD_REQUEST, format!("Unknown format `{}`; use json or csv", other))),
+    };
+    let export = match export(&pool, &username, poll_id).await {
+        Ok(export) => export,
+        Err(e) => return Ok(poll_error_reply(e)),
+    };
+    let (content_type, extension, body) = if csv {
+        ("text/csv; charset=utf-8", "csv", export_csv(&export))
+    } else {
+        match serde_json::to_string_pretty(&export) {
+            Ok(json) => ("application/json", "json", json),
+            Err(e) => return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        }
+    };
+    let response = warp::http::Response::builder()
+        .header("Content-Type", content_type)
+        .header("Content-Disposition", format!("attachment; filename=\"poll-{}.{}\"", poll_id, extension))
+        .body(warp::hyper::Body::from(body));
+    Ok(match response {
+        Ok(response) => response,
+        Err(e) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: i64 = 1;
+    const B: i64 = 2;
+    const C: i64 = 3;
+    const D: i64 = 4;
+
+    fn ballots(groups: &[(usize, &[i64])]) -> Vec<Vec<i64>> {
+        groups.iter().flat_map(|&(count, ranking)| std::iter::repeat_n(ranking.to_vec(), count)).collect()
+    }
+
+    #[test]
+    fn a_majority_of_first_preferences_wins_outright() {
+        let result = instant_runoff(&[A, B, C], &ballots(&[(3, &[A]), (1, &[B]), (1, &[C])]));
+        assert_eq!(result.winner, Some(A));
+        assert_eq!(result.rounds.len(), 1);
+    }
+
+    #[test]
+    fn a_tie_for_last_that_could_matter_is_broken() {
+        // B and C together outpoll A, so dropping both would hand A the win
+        let result = instant_runoff(&[A, B, C], &ballots(&[(4, &[A]), (3, &[B]), (3, &[C, B])]));
+        assert_eq!(result.winner, Some(B));
+        assert_eq!(result.rounds[0].eliminated, vec![C]);
+    }
+
+    #[test]
+    fn earlier_rounds_break_a_tie_first() {
+        // After D goes, B and C tie on 3; C had fewer in the first round
+        let result = instant_runoff(&[A, B, C, D], &ballots(&[(5, &[A]), (3, &[B]), (2, &[C]), (1, &[D, C])]));
+        assert_eq!(result.rounds[0].eliminated, vec![D]);
+        assert_eq!(result.rounds[1].eliminated, vec![C]);
+        assert_eq!(result.winner, Some(A));
+    }
+
+    #[test]
+    fn a_tie_that_cannot_matter_is_eliminated_together() {
+        let result = instant_runoff(&[A, B, C, D], &ballots(&[(4, &[A]), (3, &[B]), (1, &[C, B]), (1, &[D, B])]));
+        assert_eq!(result.rounds[0].eliminated, vec![C, D]);
+        assert_eq!(result.winner, Some(B));
+    }
+
+    #[test]
+    fn options_nothing_separates_are_tied() {
+        let result = instant_runoff(&[A, B], &ballots(&[(2, &[A, B]), (2, &[B, A])]));
+        assert_eq!(result.winner, None);
+        assert_eq!(result.tied, vec![A, B]);
+        assert!(instant_runoff(&[A, B], &[]).winner.is_none());
+    }
+}
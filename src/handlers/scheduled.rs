@@ -0,0 +1,656 @@
+// src/handlers/scheduled.rs
+//! Scheduled messages: creating, listing, editing and cancelling them, and
+//! the dispatch step the background scheduler runs every second.
+//!
+//! An entry is `pending` until it goes out, then `sent`; a `recurrence` rule
+//! (see [`crate::recurrence`]) keeps it pending and moves `scheduled_at` to
+//! the next occurrence instead. Times may be given as a wall-clock
+//! `local_time` in an IANA `time_zone`, which is what recurring entries
+//! repeat in. When the target user or group is gone, or the sender may no
+//! longer post there, the send is retried a couple of times and the entry
+//! then turns `failed` with the reason in `last_error`; editing a failed
+//! entry arms it again. Only the sender can see or change their entries,
+//! over the `*_scheduled_message(s)` frames or `/scheduled_messages`, and
+//! every change is pushed to their sockets as `scheduled_message_updated`.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, SqlitePool};
+use warp::http::StatusCode;
+use warp::{Filter, Reply};
+
+use crate::handlers::mentions;
+use crate::protocol::{CommandError, ServerEvent};
+use crate::recurrence::{resolve_local, Rule};
+use crate::roles::{self, Permission};
+use crate::Users;
+use super::{error_reply, username_from_auth};
+
+/// How many times one send is attempted before the entry turns `failed`.
+const MAX_ATTEMPTS: i64 = 3;
+/// Wait before the second and third attempt, in seconds.
+const RETRY_DELAYS: [i64; 2] = [60, 300];
+const LOCAL_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+const LIST_LIMIT: i64 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Pending,
+    Sent,
+    Cancelled,
+    Failed,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Pending => "pending",
+            Status::Sent => "sent",
+            Status::Cancelled => "cancelled",
+            Status::Failed => "failed",
+        }
+    }
+
+    pub fn parse(s: &str) -> Status {
+        match s {
+            "sent" => Status::Sent,
+            "cancelled" => Status::Cancelled,
+            "failed" => Status::Failed,
+            _ => Status::Pending,
+        }
+    }
+
+    /// Whether the entry can still be edited or cancelled.
+    fn is_open(self) -> bool {
+        matches!(self, Status::Pending | Status::Failed)
+    }
+}
+
+/// A scheduled message as its sender sees it.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receiver_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<i64>,
+    pub message: String,
+    pub status: Status,
+    // The next send, or the last one once the entry is finished
+    pub scheduled_at: String,
+    pub scheduled_at_epoch: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    // scheduled_at as a wall-clock time in time_zone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recurrence: Option<String>,
+    pub attempts: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    pub sent_count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_sent_at: Option<String>,
+    pub created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<String>,
+}
+
+/// When an entry goes out. With none of the time fields set a new entry is
+/// sent in a minute. `recurrence` is an RRULE such as `FREQ=DAILY`; an empty
+/// one stops an entry repeating.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Timing {
+    #[serde(default)]
+    pub scheduled_at: Option<String>,
+    #[serde(default)]
+    pub scheduled_at_epoch: Option<i64>,
+    // `YYYY-MM-DDTHH:MM[:SS]` in time_zone, UTC when there is none
+    #[serde(default)]
+    pub local_time: Option<String>,
+    #[serde(default)]
+    pub time_zone: Option<String>,
+    #[serde(default)]
+    pub recurrence: Option<String>,
+}
+
+impl Timing {
+    fn is_empty(&self) -> bool {
+        self.scheduled_at.is_none()
+            && self.scheduled_at_epoch.is_none()
+            && self.local_time.is_none()
+            && self.time_zone.is_none()
+            && self.recurrence.is_none()
+    }
+}
+
+/// An edit to a pending or failed entry; fields left out keep their value.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ScheduleChanges {
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(flatten)]
+    pub timing: Timing,
+}
+
+#[derive(Debug)]
+pub enum ScheduleError {
+    NotFound,
+    // Already sent or cancelled
+    Finished(Status),
+    Invalid(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::NotFound => f.write_str("No scheduled message with that id"),
+            ScheduleError::Finished(status) => write!(f, "This scheduled message is already {}", status.as_str()),
+            ScheduleError::Invalid(message) => f.write_str(message),
+            ScheduleError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ScheduleError {
+    fn from(e: sqlx::Error) -> Self {
+        ScheduleError::Database(e)
+    }
+}
+
+impl From<ScheduleError> for CommandError {
+    fn from(e: ScheduleError) -> Self {
+        match e {
+            ScheduleError::NotFound => CommandError::not_found(e.to_string()),
+            ScheduleError::Finished(_) | ScheduleError::Invalid(_) => CommandError::invalid(e.to_string()),
+            ScheduleError::Database(_) => CommandError::internal(e.to_string()),
+        }
+    }
+}
+
+fn parse_local(s: &str) -> Option<NaiveDateTime> {
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s.trim(), format).ok())
+}
+
+fn parse_zone(name: &str) -> Result<Tz, ScheduleError> {
+    name.parse::<Tz>().map_err(|_| ScheduleError::Invalid(format!("Unknown time zone `{}`", name)))
+}
+
+/// The schedule an entry is stored with.
+struct Plan {
+    next: DateTime<Utc>,
+    time_zone: Option<Tz>,
+    local_start: NaiveDateTime,
+    recurrence: Option<Rule>,
+}
+
+/// What an entry was stored with, for edits that change only part of it.
+struct Stored {
+    time_zone: Option<String>,
+    local_start: Option<String>,
+    recurrence: Option<String>,
+    scheduled_at_epoch: i64,
+}
+
+fn plan(timing: &Timing, stored: Option<&Stored>) -> Result<Plan, ScheduleError> {
+    let zone_name = timing.time_zone.as_deref().or(stored.and_then(|s| s.time_zone.as_deref()));
+    let time_zone = zone_name.map(parse_zone).transpose()?;
+    let tz = time_zone.unwrap_or(chrono_tz::UTC);
+
+    let recurrence = match timing.recurrence.as_deref() {
+        Some(rule) if rule.trim().is_empty() => None,
+        Some(rule) => Some(rule.parse::<Rule>().map_err(|e| ScheduleError::Invalid(format!("Invalid recurrence: {}", e)))?),
+        None => stored.and_then(|s| s.recurrence.as_deref()).and_then(|rule| rule.parse().ok()),
+    };
+
+    let now = Utc::now();
+    let in_zone = |at: DateTime<Utc>| at.with_timezone(&tz).naive_local();
+    let local_start = if let Some(local) = timing.local_time.as_deref() {
+        parse_local(local).ok_or_else(|| ScheduleError::Invalid(format!("Invalid local_time `{}`; use YYYY-MM-DDTHH:MM", local)))?
+    } else if let Some(epoch) = timing.scheduled_at_epoch {
+        in_zone(DateTime::from_timestamp(epoch, 0).ok_or_else(|| ScheduleError::Invalid("Invalid scheduled_at_epoch".to_string()))?)
+    } else if let Some(at) = timing.scheduled_at.as_deref() {
+        in_zone(
+            DateTime::parse_from_rfc3339(at)
+                .map_err(|_| ScheduleError::Invalid(format!("Invalid scheduled_at `{}`", at)))?
+                .with_timezone(&Utc),
+        )
+    } else if let Some(stored) = stored {
+        // A new time zone alone keeps the wall-clock time
+        match stored.local_start.as_deref().and_then(parse_local) {
+            Some(local) => local,
+            None => in_zone(DateTime::from_timestamp(stored.scheduled_at_epoch, 0).unwrap_or(now)),
+        }
+    } else {
+        in_zone(now + Duration::seconds(60))
+    };
+
+    let start = resolve_local(tz, local_start)
+        .ok_or_else(|| ScheduleError::Invalid(format!("{} does not exist in {}", local_start.format(LOCAL_FORMAT), tz.name())))?;
+    let next = match &recurrence {
+        // Repeating entries start at their first occurrence still ahead
+        Some(rule) => rule
+            .next_after(local_start, tz, (start - Duration::seconds(1)).max(now))
+            .ok_or_else(|| ScheduleError::Invalid("The recurrence has no occurrences left".to_string()))?,
+        None => start,
+    };
+    Ok(Plan { next, time_zone, local_start, recurrence })
+}
+
+fn from_row(row: &sqlx::sqlite::SqliteRow) -> ScheduledMessage {
+    let time_zone: Option<String> = row.get("time_zone");
+    let scheduled_at: String = row.get("scheduled_at");
+    let scheduled_at_epoch = row
+        .get::<Option<i64>, _>("scheduled_at_epoch")
+        .or_else(|| DateTime::parse_from_rfc3339(&scheduled_at).ok().map(|at| at.timestamp()))
+        .unwrap_or(0);
+    let local_time = time_zone
+        .as_deref()
+        .and_then(|name| name.parse::<Tz>().ok())
+        .zip(DateTime::from_timestamp(scheduled_at_epoch, 0))
+        .map(|(tz, at)| at.with_timezone(&tz).format("%Y-%m-%dT%H:%M").to_string());
+    ScheduledMessage {
+        id: row.get("id"),
+        receiver_username: row.get("receiver_username"),
+        group_id: row.get::<Option<i64>, _>("group_id").filter(|&g| g > 0),
+        message: row.get("message"),
+        status: Status::parse(row.get("status")),
+        scheduled_at,
+        scheduled_at_epoch,
+        time_zone,
+        local_time,
+        recurrence: row.get("recurrence"),
+        attempts: row.get("attempts"),
+        last_error: row.get("last_error"),
+        sent_count: row.get("sent_count"),
+        last_sent_at: row.get("sent_at"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+    }
+}
+
+const COLUMNS: &str = "id, receiver_username, group_id, message, status, scheduled_at, scheduled_at_epoch, time_zone,
+                       local_start, recurrence, attempts, last_error, sent_count, sent_at, created_at, updated_at";
+
+async fn load(pool: &SqlitePool, id: i64) -> Result<Option<sqlx::sqlite::SqliteRow>, sqlx::Error> {
+    sqlx::query(&format!("SELECT sender_username, {} FROM scheduled_messages WHERE id = ?", COLUMNS))
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+// The sender's own entry, or NotFound; others' entries are not revealed
+async fn load_own(pool: &SqlitePool, username: &str, id: i64) -> Result<sqlx::sqlite::SqliteRow, ScheduleError> {
+    match load(pool, id).await? {
+        Some(row) if row.get::<String, _>("sender_username").eq_ignore_ascii_case(username) => Ok(row),
+        _ => Err(ScheduleError::NotFound),
+    }
+}
+
+/// Store a new entry. The caller has already checked the sender may post
+/// to the target.
+pub async fn create(
+    pool: &SqlitePool,
+    username: &str,
+    receiver_username: Option<&str>,
+    group_id: Option<i64>,
+    message: &str,
+    timing: &Timing,
+) -> Result<ScheduledMessage, ScheduleError> {
+    if message.trim().is_empty() {
+        return Err(ScheduleError::Invalid("Message cannot be empty".to_string()));
+    }
+    let plan = plan(timing, None)?;
+    let now = crate::get_current_time();
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO scheduled_messages (sender_username, receiver_username, group_id, message, scheduled_at, scheduled_at_epoch,
+                                         time_zone, local_start, recurrence, status, sent, created_at, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 'pending', 0, ?, ?)
+         RETURNING id"
+    )
+    .bind(username)
+    .bind(receiver_username)
+    .bind(group_id)
+    .bind(message)
+    .bind(plan.next.to_rfc3339())
+    .bind(plan.next.timestamp())
+    .bind(plan.time_zone.map(|tz| tz.name().to_string()))
+    .bind(plan.local_start.format(LOCAL_FORMAT).to_string())
+    .bind(plan.recurrence.map(|rule| rule.to_string()))
+    .bind(&now)
+    .bind(&now)
+    .fetch_one(pool)
+    .await?;
+    let row = load(pool, id).await?.ok_or(ScheduleError::NotFound)?;
+    Ok(from_row(&row))
+}
+
+/// The user's entries, next to go out first. Sent and cancelled ones are
+/// left out unless `include_finished`.
+pub async fn list(pool: &SqlitePool, username: &str, include_finished: bool) -> Result<Vec<ScheduledMessage>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM scheduled_messages
+         WHERE sender_username = ? COLLATE NOCASE AND (? OR status IN ('pending', 'failed'))
+         ORDER BY scheduled_at_epoch, id
+         LIMIT ?",
+        COLUMNS
+    ))
+    .bind(username)
+    .bind(include_finished)
+    .bind(LIST_LIMIT)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.iter().map(from_row).collect())
+}
+
+/// Edit a pending or failed entry. Any edit re-arms a failed one; with no
+/// new time it goes out right away.
+pub async fn update(pool: &SqlitePool, username: &str, id: i64, changes: &ScheduleChanges) -> Result<ScheduledMessage, ScheduleError> {
+    let row = load_own(pool, username, id).await?;
+    let status = Status::parse(row.get("status"));
+    if !status.is_open() {
+        return Err(ScheduleError::Finished(status));
+    }
+    let message = changes.message.clone().unwrap_or_else(|| row.get("message"));
+    if message.trim().is_empty() {
+        return Err(ScheduleError::Invalid("Message cannot be empty".to_string()));
+    }
+
+    let now = crate::get_current_time();
+    if changes.timing.is_empty() {
+        sqlx::query(
+            "UPDATE scheduled_messages SET message = ?, status = 'pending', attempts = 0, last_error = NULL, updated_at = ?
+             WHERE id = ?"
+        )
+        .bind(&message)
+        .bind(&now)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    } else {
+        let stored = Stored {
+            time_zone: row.get("time_zone"),
+            local_start: row.get("local_start"),
+            recurrence: row.get("recurrence"),
+            scheduled_at_epoch: from_row(&row).scheduled_at_epoch,
+        };
+        let plan = plan(&changes.timing, Some(&stored))?;
+        sqlx::query(
+            "UPDATE scheduled_messages
+             SET message = ?, scheduled_at = ?, scheduled_at_epoch = ?, time_zone = ?, local_start = ?, recurrence = ?,
+                 status = 'pending', attempts = 0, last_error = NULL, updated_at = ?
+             WHERE id = ?"
+        )
+        .bind(&message)
+        .bind(plan.next.to_rfc3339())
+        .bind(plan.next.timestamp())
+        .bind(plan.time_zone.map(|tz| tz.name().to_string()))
+        .bind(plan.local_start.format(LOCAL_FORMAT).to_string())
+        .bind(plan.recurrence.map(|rule| rule.to_string()))
+        .bind(&now)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    }
+    let row = load(pool, id).await?.ok_or(ScheduleError::NotFound)?;
+    Ok(from_row(&row))
+}
+
+/// Cancel a pending or failed entry, stopping any further occurrences.
+pub async fn cancel(pool: &SqlitePool, username: &str, id: i64) -> Result<ScheduledMessage, ScheduleError> {
+    let row = load_own(pool, username, id).await?;
+    let status = Status::parse(row.get("status"));
+    if !status.is_open() {
+        return Err(ScheduleError::Finished(status));
+    }
+    sqlx::query("UPDATE scheduled_messages SET status = 'cancelled', updated_at = ? WHERE id = ?")
+        .bind(crate::get_current_time())
+        .bind(id)
+        .execute(pool)
+        .await?;
+    let row = load(pool, id).await?.ok_or(ScheduleError::NotFound)?;
+    Ok(from_row(&row))
+}
+
+/// Store and deliver one due entry, or say why it can't go out.
+async fn send(users: &Users, pool: &SqlitePool, sender: &str, entry: &ScheduledMessage) -> Result<(), String> {
+    let timestamp = crate::get_current_time();
+    if let Some(group_id) = entry.group_id {
+        let exists: Option<i64> = sqlx::query_scalar("SELECT id FROM groups WHERE id = ?")
+            .bind(group_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("Failed to look up the group: {}", e))?;
+        if exists.is_none() {
+            return Err("The group no longer exists".to_string());
+        }
+        // The sender may have left or lost posting rights since scheduling
+        roles::require(pool, group_id, sender, Permission::Post).await.map_err(|denied| denied.to_string())?;
+        let msg = crate::store_group_message(pool, group_id, sender, &entry.message, &timestamp, None, None)
+            .await
+            .map_err(|e| format!("Failed to store the message: {}", e))?;
+        mentions::notify(users, pool, &msg).await;
+        crate::deliver_chat_message(users, pool, msg).await;
+    } else if let Some(receiver) = entry.receiver_username.as_deref() {
+        let receiver: Option<String> = sqlx::query_scalar("SELECT username FROM users WHERE username = ? COLLATE NOCASE")
+            .bind(receiver)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("Failed to look up the recipient: {}", e))?;
+        let Some(receiver) = receiver else {
+            return Err("The recipient no longer exists".to_string());
+        };
+        let msg = crate::store_message(pool, sender, &receiver, &entry.message, &timestamp, None, None)
+            .await
+            .map_err(|e| format!("Failed to store the message: {}", e))?;
+        crate::deliver_chat_message(users, pool, msg).await;
+    } else {
+        return Err("The message has no recipient".to_string());
+    }
+    Ok(())
+}
+
+/// Send everything that is due, re-arm recurring entries and schedule
+/// retries for failed sends. Run by the scheduler loop in `main`.
+pub async fn dispatch_due(users: &Users, pool: &SqlitePool) {
+    let now = Utc::now();
+    let rows = sqlx::query(&format!(
+        "SELECT sender_username, {} FROM scheduled_messages
+         WHERE status = 'pending' AND (
+             (scheduled_at_epoch IS NOT NULL AND scheduled_at_epoch <= ?) OR
+             (scheduled_at_epoch IS NULL AND scheduled_at <= ?)
+         )
+         ORDER BY scheduled_at_epoch
+         LIMIT 20",
+        COLUMNS
+    ))
+    .bind(now.timestamp())
+    .bind(now.to_rfc3339())
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default();
+
+    for row in rows {
+        let sender: String = row.get("sender_username");
+        let entry = from_row(&row);
+        let finished_at = crate::get_current_time();
+
+        let result = match send(users, pool, &sender, &entry).await {
+            Ok(()) => {
+                let tz = entry.time_zone.as_deref().and_then(|name| name.parse::<Tz>().ok()).unwrap_or(chrono_tz::UTC);
+                let local_start = row.get::<Option<String>, _>("local_start").as_deref().and_then(parse_local);
+                let rule = entry.recurrence.as_deref().and_then(|rule| rule.parse::<Rule>().ok());
+                // Occurrences missed while the server was down are skipped, not sent in a burst
+                let next = rule.zip(local_start).and_then(|(rule, start)| rule.next_after(start, tz, Utc::now()));
+                match next {
+                    Some(next) => sqlx::query(
+                        "UPDATE scheduled_messages
+                         SET scheduled_at = ?, scheduled_at_epoch = ?, sent_count = sent_count + 1, sent_at = ?,
+                             attempts = 0, last_error = NULL, updated_at = ?
+                         WHERE id = ?"
+                    )
+                    .bind(next.to_rfc3339())
+                    .bind(next.timestamp())
+                    .bind(&finished_at)
+                    .bind(&finished_at)
+                    .bind(entry.id),
+                    None => sqlx::query(
+                        "UPDATE scheduled_messages
+                         SET status = 'sent', sent = 1, sent_count = sent_count + 1, sent_at = ?,
+                             attempts = 0, last_error = NULL, updated_at = ?
+                         WHERE id = ?"
+                    )
+                    .bind(&finished_at)
+                    .bind(&finished_at)
+                    .bind(entry.id),
+                }
+                .execute(pool)
+                .await
+            }
+            Err(reason) => {
+                println!("DEBUG: Scheduled message {} could not be sent: {}", entry.id, reason);
+                let attempts = entry.attempts + 1;
+                match RETRY_DELAYS.get(attempts as usize - 1).filter(|_| attempts < MAX_ATTEMPTS) {
+                    Some(delay) => {
+                        let retry_at = Utc::now() + Duration::seconds(*delay);
+                        sqlx::query(
+                            "UPDATE scheduled_messages
+                             SET attempts = ?, last_error = ?, scheduled_at = ?, scheduled_at_epoch = ?, updated_at = ?
+                             WHERE id = ?"
+                        )
+                        .bind(attempts)
+                        .bind(&reason)
+                        .bind(retry_at.to_rfc3339())
+                        .bind(retry_at.timestamp())
+                        .bind(&finished_at)
+                        .bind(entry.id)
+                    }
+                    None => sqlx::query(
+                        "UPDATE scheduled_messages SET status = 'failed', attempts = ?, last_error = ?, updated_at = ? WHERE id = ?"
+                    )
+                    .bind(attempts)
+                    .bind(&reason)
+                    .bind(&finished_at)
+                    .bind(entry.id),
+                }
+                .execute(pool)
+                .await
+            }
+        };
+        if let Err(e) = result {
+            println!("DEBUG: Failed to update scheduled message {}: {}", entry.id, e);
+            continue;
+        }
+
+        if let Ok(Some(row)) = load(pool, entry.id).await {
+            users.send_to_user(&sender, &ServerEvent::ScheduledMessageUpdated(from_row(&row)));
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ScheduledList {
+    scheduled: Vec<ScheduledMessage>,
+}
+
+fn schedule_error_reply(e: ScheduleError) -> warp::reply::Response {
+    let status = match e {
+        ScheduleError::NotFound => StatusCode::NOT_FOUND,
+        ScheduleError::Finished(_) => StatusCode::CONFLICT,
+        ScheduleError::Invalid(_) => StatusCode::BAD_REQUEST,
+        ScheduleError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_reply(status, e.to_string())
+}
+
+pub fn routes(pool: SqlitePool, users: Users) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let pool1 = pool.clone();
+    let pool2 = pool.clone();
+    let pool3 = pool;
+    let users1 = users.clone();
+    let users2 = users;
+
+    let list = warp::path("scheduled_messages")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool1.clone()))
+        .and_then(list_handler);
+
+    let update = warp::path!("scheduled_messages" / i64)
+        .and(warp::put())
+        .and(warp::body::json::<ScheduleChanges>())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool2.clone()))
+        .and(warp::any().map(move || users1.clone()))
+        .and_then(update_handler);
+
+    let cancel = warp::path!("scheduled_messages" / i64)
+        .and(warp::delete())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::any().map(move || pool3.clone()))
+        .and(warp::any().map(move || users2.clone()))
+        .and_then(cancel_handler);
+
+    list.or(update).or(cancel)
+}
+
+/// `GET /scheduled_messages?include_finished=true`
+async fn list_handler(
+    query: HashMap<String, String>,
+    auth_header: String,
+    pool: SqlitePool,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    let include_finished = query.get("include_finished").is_some_and(|v| v == "true" || v == "1");
+    match list(&pool, &username, include_finished).await {
+        Ok(scheduled) => Ok(warp::reply::json(&ScheduledList { scheduled }).into_response()),
+        Err(e) => Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to load scheduled messages: {}", e))),
+    }
+}
+
+/// `PUT /scheduled_messages/{id}`
+async fn update_handler(
+    id: i64,
+    changes: ScheduleChanges,
+    auth_header: String,
+    pool: SqlitePool,
+    users: Users,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    match update(&pool, &username, id, &changes).await {
+        Ok(entry) => {
+            users.send_to_user(&username, &ServerEvent::ScheduledMessageUpdated(entry.clone()));
+            Ok(warp::reply::json(&entry).into_response())
+        }
+        Err(e) => Ok(schedule_error_reply(e)),
+    }
+}
+
+/// `DELETE /scheduled_messages/{id}`
+async fn cancel_handler(id: i64, auth_header: String, pool: SqlitePool, users: Users) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+    match cancel(&pool, &username, id).await {
+        Ok(entry) => {
+            users.send_to_user(&username, &ServerEvent::ScheduledMessageUpdated(entry.clone()));
+            Ok(warp::reply::json(&entry).into_response())
+        }
+        Err(e) => Ok(schedule_error_reply(e)),
+    }
+}
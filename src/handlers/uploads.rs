@@ -0,0 +1,600 @@
+// src/handlers/uploads.rs
+//! File and image attachments behind `/uploads`.
+//!
+//! A file is uploaded for one conversation (a DM peer or a group) with a
+//! multipart `POST /uploads`, stored once under the SHA-256 of its content,
+//! and recorded in `attachments`. Messages then reference it by id. Downloads
+//! go through `GET /uploads/{id}` (and `/uploads/{id}/thumbnail` for images),
+//! which only serve the uploader and members of that conversation, and the
+//! uploader alone while the message carrying the file awaits its reveal.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Row, SqlitePool};
+use warp::http::StatusCode;
+use warp::{Buf, Filter, Reply};
+
+use crate::protocol::MessageKind;
+use crate::reveal;
+use crate::roles::{self, Denied, Permission};
+use super::{error_reply, username_from_auth};
+
+pub const UPLOAD_DIR: &str = "./db/uploads";
+pub const THUMBNAIL_DIR: &str = "./db/uploads/thumbs";
+
+/// Largest file accepted, in bytes.
+pub const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+/// How many attachments one message may carry.
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+/// Longest edge of a generated thumbnail, in pixels.
+const THUMBNAIL_EDGE: u32 = 320;
+/// Longest edge of an image thumbnails are made from, in pixels.
+const MAX_IMAGE_EDGE: u32 = 10_000;
+/// Memory the decoder may use for one image.
+const MAX_DECODE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Accepted MIME types and the extension files of that type are stored with.
+const ALLOWED_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("application/pdf", "pdf"),
+    ("text/plain", "txt"),
+    ("audio/mpeg", "mp3"),
+    ("video/mp4", "mp4"),
+];
+
+/// An attachment as shown to clients, inside messages and upload replies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: i64,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size_bytes: i64,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+}
+
+fn denied_reply(denied: Denied) -> warp::reply::Response {
+    let status = match denied {
+        Denied::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::FORBIDDEN,
+    };
+    error_reply(status, denied.to_string())
+}
+
+pub fn routes(pool: SqlitePool) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let pool1 = pool.clone();
+    let pool2 = pool.clone();
+    let pool3 = pool;
+
+    // The form limit leaves room for the non-file fields; the file itself is
+    // checked against MAX_UPLOAD_BYTES while it streams in
+    let upload = warp::path("uploads")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::header::<String>("authorization"))
+        .and(warp::multipart::form().max_length((MAX_UPLOAD_BYTES + 64 * 1024) as u64))
+        .and(warp::any().map(move || pool1.clone()))
+        .and_then(upload_handler);
+
+    // Browsers can't set headers on <img src>, so a ?token= is accepted too
+    let download = warp::path!("uploads" / i64)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || pool2.clone()))
+        .and_then(|id, auth, query, pool| serve_handler(id, auth, query, pool, false));
+
+    let thumbnail = warp::path!("uploads" / i64 / "thumbnail")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || pool3.clone()))
+        .and_then(|id, auth, query, pool| serve_handler(id, auth, query, pool, true));
+
+    upload.or(download).or(thumbnail)
+}
+
+// Files are fetched by <img> and <a> too, which can only carry the token in the query
+fn username_from_auth_or_query(auth_header: Option<&str>, query: &HashMap<String, String>) -> Option<String> {
+    match auth_header {
+        Some(header) => username_from_auth(header),
+        None => crate::verify_jwt(query.get("token")?).ok(),
+    }
+}
+
+/// Extension to store a file under, when its type is allowed.
+fn extension_for(mime_type: &str) -> Option<&'static str> {
+    ALLOWED_TYPES.iter().find(|(m, _)| *m == mime_type).map(|(_, ext)| *ext)
+}
+
+/// The type a file's leading bytes identify it as, for the types that have
+/// a signature. Used to refuse uploads whose declared type is a lie.
+fn sniff_mime(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if data.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else {
+        None
+    }
+}
+
+fn has_signature(mime_type: &str) -> bool {
+    mime_type.starts_with("image/") || mime_type == "application/pdf"
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base.chars().filter(|c| !c.is_control()).take(200).collect();
+    if cleaned.trim().is_empty() { "file".to_string() } else { cleaned }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Write `data` to `path` unless a file is already there. Content-addressed
+/// names mean an existing file already holds these bytes.
+async fn store_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if tokio::fs::try_exists(path).await.unwrap_or(false) {
+        return Ok(());
+    }
+    let tmp = path.with_extension(format!("part-{}", chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0)));
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// Decode an image within the limits above and shrink it to a JPEG no
+/// larger than `THUMBNAIL_EDGE` a side. Animated images give their first
+/// frame; transparency is laid over white.
+fn render_thumbnail(source: &Path) -> image::ImageResult<Vec<u8>> {
+    use image::{DynamicImage, ImageDecoder, ImageReader};
+
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_EDGE);
+    limits.max_image_height = Some(MAX_IMAGE_EDGE);
+    limits.max_alloc = Some(MAX_DECODE_BYTES);
+    let mut reader = ImageReader::open(source)?.with_guessed_format()?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    if image.width() > THUMBNAIL_EDGE || image.height() > THUMBNAIL_EDGE {
+        image = image.thumbnail(THUMBNAIL_EDGE, THUMBNAIL_EDGE);
+    }
+
+    let mut rgb = image::RgbImage::new(image.width(), image.height());
+    for (x, y, pixel) in image.to_rgba8().enumerate_pixels() {
+        let [r, g, b, a] = pixel.0;
+        let over_white = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        rgb.put_pixel(x, y, image::Rgb([over_white(r), over_white(g), over_white(b)]));
+    }
+    let mut jpeg = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 80).encode_image(&rgb)?;
+    Ok(jpeg)
+}
+
+/// Make and store the thumbnail of an uploaded image. Images that cannot be
+/// decoded, or are too large to, are served without one.
+async fn make_thumbnail(source: &Path, sha256: &str) -> Option<String> {
+    let name = format!("{}.jpg", sha256);
+    let target = PathBuf::from(THUMBNAIL_DIR).join(&name);
+    if tokio::fs::try_exists(&target).await.unwrap_or(false) {
+        return Some(name);
+    }
+    let source = source.to_path_buf();
+    let jpeg = match tokio::task::spawn_blocking(move || render_thumbnail(&source)).await {
+        Ok(Ok(jpeg)) => jpeg,
+        Ok(Err(e)) => {
+            println!("DEBUG: no thumbnail for {}: {}", sha256, e);
+            return None;
+        }
+        Err(e) => {
+            println!("DEBUG: thumbnail generation for {} failed: {}", sha256, e);
+            return None;
+        }
+    };
+    match tokio::fs::write(&target, jpeg).await {
+        Ok(()) => Some(name),
+        Err(e) => {
+            println!("DEBUG: failed to store thumbnail {}: {}", name, e);
+            None
+        }
+    }
+}
+
+struct UploadForm {
+    file_name: String,
+    mime_type: String,
+    data: Vec<u8>,
+    receiver_username: Option<String>,
+    group_id: Option<i64>,
+}
+
+enum FormError {
+    TooLarge,
+    Invalid(String),
+}
+
+async fn read_form(form: warp::multipart::FormData) -> Result<UploadForm, FormError> {
+    let mut parts = form;
+    let mut file: Option<(String, String, Vec<u8>)> = None;
+    let mut receiver_username = None;
+    let mut group_id = None;
+
+    while let Some(part) = parts.try_next().await.map_err(|e| FormError::Invalid(e.to_string()))? {
+        let name = part.name().to_string();
+        let file_name = part.filename().map(sanitize_file_name);
+        let content_type = part.content_type().map(|c| c.to_ascii_lowercase());
+
+        let mut data = Vec::new();
+        let mut stream = part.stream();
+        while let Some(mut chunk) = stream.try_next().await.map_err(|e| FormError::Invalid(e.to_string()))? {
+            while chunk.has_remaining() {
+                let bytes = chunk.chunk();
+                if data.len() + bytes.len() > MAX_UPLOAD_BYTES {
+                    return Err(FormError::TooLarge);
+                }
+                data.extend_from_slice(bytes);
+                let n = bytes.len();
+                chunk.advance(n);
+            }
+        }
+
+        match name.as_str() {
+            "file" => {
+                let mime_type = content_type.unwrap_or_else(|| "application/octet-stream".to_string());
+                file = Some((file_name.unwrap_or_else(|| "file".to_string()), mime_type, data));
+            }
+            "receiver_username" => {
+                let value = String::from_utf8_lossy(&data).trim().to_string();
+                receiver_username = Some(value).filter(|v| !v.is_empty());
+            }
+            "group_id" => {
+                let value = String::from_utf8_lossy(&data).trim().to_string();
+                group_id = Some(value.parse::<i64>().map_err(|_| FormError::Invalid("group_id must be a number".to_string()))?);
+            }
+            _ => {}
+        }
+    }
+
+    let (file_name, mime_type, data) = file.ok_or_else(|| FormError::Invalid("Missing `file` part".to_string()))?;
+    Ok(UploadForm { file_name, mime_type, data, receiver_username, group_id })
+}
+
+async fn upload_handler(
+    auth_header: String,
+    form: warp::multipart::FormData,
+    pool: SqlitePool,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth(&auth_header) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+
+    let form = match read_form(form).await {
+        Ok(form) => form,
+        Err(FormError::TooLarge) => {
+            return Ok(error_reply(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Files may be at most {} MB", MAX_UPLOAD_BYTES / (1024 * 1024)),
+            ));
+        }
+        Err(FormError::Invalid(e)) => return Ok(error_reply(StatusCode::BAD_REQUEST, e)),
+    };
+
+    if form.data.is_empty() {
+        return Ok(error_reply(StatusCode::BAD_REQUEST, "File is empty"));
+    }
+    let Some(extension) = extension_for(&form.mime_type) else {
+        return Ok(error_reply(StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("Files of type {} are not allowed", form.mime_type)));
+    };
+    if has_signature(&form.mime_type) && sniff_mime(&form.data) != Some(form.mime_type.as_str()) {
+        return Ok(error_reply(StatusCode::UNSUPPORTED_MEDIA_TYPE, "File content does not match its type"));
+    }
+
+    // The conversation the file is shared in decides who may download it
+    match (&form.receiver_username, form.group_id) {
+        (Some(_), Some(_)) | (None, None) => {
+            return Ok(error_reply(StatusCode::BAD_REQUEST, "Give exactly one of receiver_username or group_id"));
+        }
+        (Some(receiver), None) => {
+            let exists = sqlx::query("SELECT 1 FROM users WHERE username = ? COLLATE NOCASE")
+                .bind(receiver)
+                .fetch_optional(&pool)
+                .await
+                .unwrap_or(None)
+                .is_some();
+            if !exists {
+                return Ok(error_reply(StatusCode::NOT_FOUND, "User not found"));
+            }
+        }
+        (None, Some(group_id)) => {
+            // Uploading is the first half of posting, so a mute blocks it too
+            if let Err(denied) = roles::require(&pool, group_id, &username, Permission::Post).await {
+                return Ok(denied_reply(denied));
+            }
+        }
+    }
+
+    let sha256 = to_hex(&Sha256::digest(&form.data));
+    let stored_name = format!("{}.{}", sha256, extension);
+    let path = PathBuf::from(UPLOAD_DIR).join(&stored_name);
+    if let Err(e) = store_file(&path, &form.data).await {
+        eprintln!("Failed to store upload {}: {:?}", stored_name, e);
+        return Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, "Failed to store file"));
+    }
+
+    let thumbnail_name = if form.mime_type.starts_with("image/") {
+        make_thumbnail(&path, &sha256).await
+    } else {
+        None
+    };
+
+    let result = sqlx::query(
+        "INSERT INTO attachments
+            (owner_username, file_name, stored_name, mime_type, size_bytes, sha256, receiver_username, group_id, thumbnail_name, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&username)
+    .bind(&form.file_name)
+    .bind(&stored_name)
+    .bind(&form.mime_type)
+    .bind(form.data.len() as i64)
+    .bind(&sha256)
+    .bind(&form.receiver_username)
+    .bind(form.group_id)
+    .bind(&thumbnail_name)
+    .bind(crate::get_current_time())
+    .execute(&pool)
+    .await;
+
+    match result {
+        Ok(done) => {
+            println!("DEBUG: {} uploaded {} ({} bytes) as {}", username, form.file_name, form.data.len(), stored_name);
+            let attachment = Attachment {
+                id: done.last_insert_rowid(),
+                file_name: form.file_name,
+                mime_type: form.mime_type,
+                size_bytes: form.data.len() as i64,
+                url: String::new(),
+                thumbnail_url: None,
+            };
+            Ok(warp::reply::with_status(
+                warp::reply::json(&with_urls(attachment, thumbnail_name.is_some())),
+                StatusCode::CREATED,
+            ).into_response())
+        }
+        Err(e) => Ok(error_reply(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to record upload: {}", e))),
+    }
+}
+
+async fn serve_handler(
+    id: i64,
+    auth_header: Option<String>,
+    query: HashMap<String, String>,
+    pool: SqlitePool,
+    thumbnail: bool,
+) -> Result<warp::reply::Response, Infallible> {
+    let Some(username) = username_from_auth_or_query(auth_header.as_deref(), &query) else {
+        return Ok(error_reply(StatusCode::UNAUTHORIZED, "Invalid or expired token"));
+    };
+
+    let row = sqlx::query(
+        "SELECT owner_username, file_name, stored_name, mime_type, receiver_username, group_id, thumbnail_name
+         FROM attachments WHERE id = ?"
+    )
+    .bind(id)
+    .fetch_optional(&pool)
+    .await
+    .unwrap_or(None);
+    let Some(row) = row else {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "Attachment not found"));
+    };
+
+    let owner: String = row.get("owner_username");
+    let receiver: Option<String> = row.get("receiver_username");
+    let group_id: Option<i64> = row.get("group_id");
+    let allowed = owner.eq_ignore_ascii_case(&username)
+        || receiver.is_some_and(|r| r.eq_ignore_ascii_case(&username))
+        || match group_id {
+            Some(group_id) => roles::require(&pool, group_id, &username, Permission::View).await.is_ok(),
+            None => false,
+        };
+    // Not found rather than forbidden, so ids can't be probed
+    if !allowed || (!owner.eq_ignore_ascii_case(&username) && awaiting_reveal(&pool, id).await) {
+        return Ok(error_reply(StatusCode::NOT_FOUND, "Attachment not found"));
+    }
+
+    let (path, mime_type) = if thumbnail {
+        let Some(name) = row.get::<Option<String>, _>("thumbnail_name") else {
+            return Ok(error_reply(StatusCode::NOT_FOUND, "No thumbnail for this attachment"));
+        };
+        (PathBuf::from(THUMBNAIL_DIR).join(name), "image/jpeg".to_string())
+    } else {
+        (PathBuf::from(UPLOAD_DIR).join(row.get::<String, _>("stored_name")), row.get("mime_type"))
+    };
+
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read attachment {} at {:?}: {:?}", id, path, e);
+            return Ok(error_reply(StatusCode::NOT_FOUND, "Attachment file is missing"));
+        }
+    };
+
+    let file_name: String = row.get("file_name");
+    let disposition = if mime_type.starts_with("image/") { "inline" } else { "attachment" };
+    let response = warp::http::Response::builder()
+        .header("Content-Type", mime_type)
+        .header("Content-Disposition", format!("{}; filename=\"{}\"", disposition, file_name.replace(['"', '\\'], "_")))
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Cache-Control", "private, max-age=86400")
+        .body(warp::hyper::Body::from(data));
+    Ok(match response {
+        Ok(response) => response,
+        Err(e) => error_reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })
+}
+
+/// Whether every message carrying this attachment is still waiting for its
+/// `reveal_at`. Until one is revealed, only the uploader may fetch the file.
+async fn awaiting_reveal(pool: &SqlitePool, id: i64) -> bool {
+    let sql = format!(
+        "SELECT EXISTS(SELECT 1 FROM message_attachments WHERE attachment_id = ?)
+                AND NOT EXISTS(SELECT 1 FROM message_attachments ma JOIN messages m ON m.id = ma.message_id
+                               WHERE ma.attachment_id = ? AND ma.message_kind = 'direct' AND {revealed})
+                AND NOT EXISTS(SELECT 1 FROM message_attachments ma JOIN group_messages g ON g.id = ma.message_id
+                               WHERE ma.attachment_id = ? AND ma.message_kind = 'group' AND {revealed})",
+        revealed = reveal::REVEALED,
+    );
+    sqlx::query_scalar(&sql)
+        .bind(id)
+        .bind(id)
+        .bind(id)
+        .fetch_one(pool)
+        .await
+        .unwrap_or(true)
+}
+
+fn with_urls(mut attachment: Attachment, has_thumbnail: bool) -> Attachment {
+    attachment.url = format!("/uploads/{}", attachment.id);
+    attachment.thumbnail_url = has_thumbnail.then(|| format!("/uploads/{}/thumbnail", attachment.id));
+    attachment
+}
+
+/// Check that `sender` may attach these uploads to a message in the given
+/// conversation: each must exist, be theirs, and have been uploaded for that
+/// same DM peer or group.
+pub async fn validate_for_message(
+    pool: &SqlitePool,
+    sender: &str,
+    receiver_username: Option<&str>,
+    group_id: Option<i64>,
+    attachment_ids: &[i64],
+) -> Result<(), String> {
+    if attachment_ids.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return Err(format!("A message may carry at most {} attachments", MAX_ATTACHMENTS_PER_MESSAGE));
+    }
+    for id in attachment_ids {
+        let row = sqlx::query("SELECT owner_username, receiver_username, group_id FROM attachments WHERE id = ?")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Attachment {} not found", id))?;
+        let owner: String = row.get("owner_username");
+        let receiver: Option<String> = row.get("receiver_username");
+        let attachment_group: Option<i64> = row.get("group_id");
+        let same_conversation = match (receiver_username, group_id) {
+            (_, Some(group_id)) => attachment_group == Some(group_id),
+            (Some(peer), None) => receiver.is_some_and(|r| r.eq_ignore_ascii_case(peer)),
+            (None, None) => false,
+        };
+        if !owner.eq_ignore_ascii_case(sender) || !same_conversation {
+            return Err(format!("Attachment {} was not uploaded for this conversation", id));
+        }
+    }
+    Ok(())
+}
+
+/// Record that a stored message references these uploads.
+pub async fn link_to_message(pool: &SqlitePool, kind: MessageKind, message_id: i64, attachment_ids: &[i64]) {
+    for id in attachment_ids {
+        let _ = sqlx::query(
+            "INSERT OR IGNORE INTO message_attachments (message_kind, message_id, attachment_id) VALUES (?, ?, ?)"
+        )
+        .bind(kind.as_str())
+        .bind(message_id)
+        .bind(id)
+        .execute(pool)
+        .await;
+    }
+}
+
+/// The attachments of one message, in upload order.
+pub async fn for_message(pool: &SqlitePool, kind: MessageKind, message_id: i64) -> Vec<Attachment> {
+    sqlx::query(
+        "SELECT a.id, a.file_name, a.mime_type, a.size_bytes, a.thumbnail_name
+         FROM message_attachments ma
+         JOIN attachments a ON a.id = ma.attachment_id
+         WHERE ma.message_kind = ? AND ma.message_id = ?
+         ORDER BY a.id"
+    )
+    .bind(kind.as_str())
+    .bind(message_id)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default()
+    .into_iter()
+    .map(|row| {
+        let thumbnail: Option<String> = row.get("thumbnail_name");
+        let attachment = Attachment {
+            id: row.get("id"),
+            file_name: row.get("file_name"),
+            mime_type: row.get("mime_type"),
+            size_bytes: row.get("size_bytes"),
+            url: String::new(),
+            thumbnail_url: None,
+        };
+        with_urls(attachment, thumbnail.is_some())
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_png(name: &str, width: u32, height: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.png", name, std::process::id()));
+        image::RgbaImage::from_pixel(width, height, image::Rgba([200, 40, 40, 0])).save(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn thumbnails_fit_the_edge_and_keep_the_aspect() {
+        let path = write_png("wide", 1000, 500);
+        let jpeg = render_thumbnail(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let thumbnail = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_EDGE, THUMBNAIL_EDGE / 2));
+        // Fully transparent pixels come out white
+        assert!(thumbnail.to_rgb8().get_pixel(10, 10).0.iter().all(|&c| c > 240));
+    }
+
+    #[test]
+    fn small_images_are_not_enlarged() {
+        let path = write_png("small", 40, 30);
+        let jpeg = render_thumbnail(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let thumbnail = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (40, 30));
+    }
+
+    #[test]
+    fn oversized_and_broken_images_get_no_thumbnail() {
+        let path = write_png("tall", 1, MAX_IMAGE_EDGE + 1);
+        assert!(render_thumbnail(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let path = std::env::temp_dir().join(format!("broken-{}.png", std::process::id()));
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\nnot really").unwrap();
+        assert!(render_thumbnail(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
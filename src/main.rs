@@ -1,7 +1,6 @@
 #![recursion_limit = "256"]
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::{broadcast, Mutex};
 use warp::ws::{Message, WebSocket};
 use warp::{Filter, Reply};
 use futures_util::{SinkExt, StreamExt};
@@ -9,21 +8,29 @@ use serde::{Deserialize, Serialize};
 use sqlx::{SqlitePool, Row};
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use argon2::password_hash::{rand_core::OsRng, SaltString};
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
-use chrono::{Utc, Duration, Datelike};
 
 use dotenv::dotenv;
 use std::env;
 
+mod auth;
+mod games;
 mod handlers;
-use handlers::groups;
-
-use lazy_static::lazy_static;
-
-lazy_static! {
-    static ref TEMP_META: Arc<Mutex<HashMap<String, (String, String)>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-}
+mod migrations;
+mod presence;
+mod protocol;
+mod recurrence;
+mod registry;
+mod reveal;
+mod roles;
+mod search;
+mod threads;
+use auth::verify_jwt;
+use games::{GameEngine, GameRegistry, Outcome, Players};
+use handlers::{conversations, groups, mentions, polls, scheduled, uploads};
+use protocol::{CallSignal, ClientCommand, CommandError, MessageKind, ServerEvent};
+use presence::{PresenceTracker, Status, TypingScope};
+use registry::{ConnectionId, ConnectionRegistry};
+use roles::{Denied, Permission, Role};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ChatMessage {
@@ -36,12 +43,37 @@ struct ChatMessage {
     reactions: Option<HashMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     reveal_at: Option<String>,
-}
-
-#[derive(Debug, Clone)]
-struct User {
-    username: String,
-    sender: broadcast::Sender<ChatMessage>,
+    // Content replaced by a placeholder until reveal_at
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    withheld: bool,
+    // Position within its conversation (DM pair or group), starting at 1
+    #[serde(default)]
+    seq: i64,
+    // Receipt state; DMs carry the recipient's, groups the members who read it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delivered_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_by: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<uploads::Attachment>,
+    // Replies: the message quoted and the root of the thread joined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread_root: Option<i64>,
+    // A reply kept out of the main history
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    thread_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quoted: Option<threads::QuotedMessage>,
+    // On thread roots that have replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thread: Option<threads::ThreadSummary>,
+    // On a poll's announcements, the poll to open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    poll_id: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -60,6 +92,16 @@ struct LoginRequest {
 struct AuthResponse {
     message: String,
     token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    // Seconds until `token` expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_in: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -75,8 +117,6 @@ struct DMLockVerifyRequest { peer_username: String, pin: String }
 #[derive(Debug, Serialize, Deserialize)]
 struct DMLockChangeRequest { peer_username: String, old_pin: String, new_pin: String }
 #[derive(Debug, Serialize, Deserialize)]
-struct DMLockStatusResponse { locked: bool }
-#[derive(Debug, Serialize, Deserialize)]
 struct DMLockVerifyResponse { ok: bool }
 
     // Global lock types
@@ -87,23 +127,17 @@ struct DMLockVerifyResponse { ok: bool }
     #[derive(Debug, Serialize, Deserialize)]
     struct GlobalLockVerifyRequest { pin: String }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Claims {
-    sub: String,
-    exp: usize,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct HistoryMessage {
-    #[serde(rename = "type")]
-    message_type: String,
-    conversation_with: String,
-    messages: Vec<ChatMessage>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 struct UserListResponse {
     users: Vec<String>,
+    // Only for users sharing a DM or group with the caller
+    presence: HashMap<String, UserPresence>,
+}
+
+#[derive(Debug, Serialize)]
+struct UserPresence {
+    status: Status,
+    last_seen_at: Option<String>,
 }
 #[derive(Debug, Serialize, Deserialize)]
 struct NoteCreateRequest {
@@ -119,115 +153,6 @@ struct NoteUpdateRequest {
 }
 
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct IncomingMessage {
-    #[serde(rename = "type")]
-    message_type: String,
-    #[serde(default)]
-    receiver_username: Option<String>,
-    #[serde(default)]
-    message: Option<String>,
-    #[serde(default)]
-    timestamp: Option<String>,
-    #[serde(default)]
-    message_id: Option<i64>,
-    #[serde(default)]
-    emoji: Option<String>,
-    #[serde(default)]
-    group_id: Option<i64>,
-    // Poll-related fields
-    #[serde(default)]
-    poll_question: Option<String>,
-    #[serde(default)]
-    poll_options: Option<Vec<String>>,
-    #[serde(default)]
-    poll_allow_multiple: Option<bool>,
-    #[serde(default)]
-    poll_expires_at: Option<String>,
-    #[serde(default)]
-    poll_id: Option<i64>,
-    #[serde(default)]
-    poll_option_ids: Option<Vec<i64>>,
-    // Game-related fields (NEW)
-    #[serde(default)]
-    game_type: Option<String>,
-    #[serde(default)]
-    game_id: Option<i64>,
-    #[serde(default)]
-    game_move: Option<String>,
-    #[serde(default)]
-    target_username: Option<String>,
-    // WebRTC signaling
-    #[serde(default)]
-    sdp: Option<String>,
-    #[serde(default)]
-    candidate: Option<String>,
-    // Scheduling
-    #[serde(default)]
-    scheduled_at: Option<String>,
-    #[serde(default)]
-    scheduled_at_epoch: Option<i64>,
-    // Delayed reveal
-    #[serde(default)]
-    reveal_after_secs: Option<i64>,
-    #[serde(default)]
-    reveal_at: Option<String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ConversationHistoryResponse {
-    #[serde(rename = "type")]
-    message_type: String,
-    conversation_with: String,
-    messages: Vec<ChatMessage>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct GroupHistoryResponse {
-    #[serde(rename = "type")]
-    message_type: String,
-    group_id: i64,
-    messages: Vec<ChatMessage>,
-}
-
-// Poll-related structures
-#[derive(Debug, Serialize, Deserialize)]
-struct CreatePollRequest {
-    group_id: i64,
-    question: String,
-    options: Vec<String>,
-    allow_multiple_choices: Option<bool>,
-    expires_at: Option<String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct VotePollRequest {
-    poll_id: i64,
-    option_ids: Vec<i64>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct PollOption {
-    id: i64,
-    option_text: String,
-    vote_count: i64,
-    voted_by_current_user: bool,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Poll {
-    id: i64,
-    group_id: i64,
-    creator_username: String,
-    question: String,
-    created_at: String,
-    expires_at: Option<String>,
-    is_active: bool,
-    allow_multiple_choices: bool,
-    options: Vec<PollOption>,
-    total_votes: i64,
-}
-
 // Add these new structs after existing ones
 //#[derive(Debug, Serialize, Deserialize)]
 //struct HighlightRequest {
@@ -325,10 +250,10 @@ struct AIAssistantResponse {
     success: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Game {
     id: i64,
-    game_type: String, // "chess", "tictactoe", "trivia"
+    game_type: String, // a game type registered in the GameRegistry
     player1_username: String,
     player2_username: Option<String>,
     game_state: String, // JSON serialized game state
@@ -340,124 +265,24 @@ struct Game {
     conversation_id: Option<i64>, // group_id for groups, null for private
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GameMove {
-    game_id: i64,
-    player_username: String,
-    move_data: String, // JSON serialized move
-    timestamp: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct CreateGameRequest {
-    game_type: String,
-    target_username: Option<String>, // for private games
-    group_id: Option<i64>, // for group games
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct GameMoveRequest {
-    game_id: i64,
-    move_data: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct TriviaQuestion {
-    id: i64,
-    question: String,
-    options: Vec<String>,
-    correct_answer: i32,
-    category: String,
-}
-
-type Users = Arc<Mutex<HashMap<String, User>>>;
-
-const JWT_SECRET: &[u8] = b"your-secret-key-change-this-in-production";
-
-async fn create_game_tables(pool: &SqlitePool) {
-    // Games table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS games (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            game_type TEXT NOT NULL,
-            player1_username TEXT NOT NULL,
-            player2_username TEXT,
-            game_state TEXT NOT NULL,
-            current_turn TEXT NOT NULL,
-            status TEXT DEFAULT 'waiting',
-            winner TEXT,
-            created_at TEXT NOT NULL,
-            conversation_type TEXT NOT NULL,
-            conversation_id INTEGER
-        )"
-    ).execute(pool).await;
-
-    // Game moves table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS game_moves (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            game_id INTEGER NOT NULL,
-            player_username TEXT NOT NULL,
-            move_data TEXT NOT NULL,
-            timestamp TEXT NOT NULL,
-            FOREIGN KEY (game_id) REFERENCES games(id) ON DELETE CASCADE
-        )"
-    ).execute(pool).await;
-
-    // Trivia questions table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS trivia_questions (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            question TEXT NOT NULL,
-            options TEXT NOT NULL,
-            correct_answer INTEGER NOT NULL,
-            category TEXT NOT NULL
-        )"
-    ).execute(pool).await;
-
-    // Insert some sample trivia questions
-    let sample_questions = vec![
-        ("What is the capital of France?", "[\"Paris\", \"London\", \"Berlin\", \"Madrid\"]", 0, "Geography"),
-        ("What is 2 + 2?", "[\"3\", \"4\", \"5\", \"6\"]", 1, "Math"),
-        ("Who painted the Mona Lisa?", "[\"Van Gogh\", \"Picasso\", \"Da Vinci\", \"Monet\"]", 2, "Art"),
-        ("What year did World War II end?", "[\"1944\", \"1945\", \"1946\", \"1947\"]", 1, "History"),
-        ("What is the largest planet?", "[\"Earth\", \"Mars\", \"Jupiter\", \"Saturn\"]", 2, "Science"),
-    ];
-
-    for (question, options, correct, category) in sample_questions {
-        let _ = sqlx::query(
-            "INSERT OR IGNORE INTO trivia_questions (question, options, correct_answer, category) VALUES (?, ?, ?, ?)"
-        )
-        .bind(question)
-        .bind(options)
-        .bind(correct)
-        .bind(category)
-        .execute(pool)
-        .await;
-    }
-}
+type Users = Arc<ConnectionRegistry>;
 
 async fn create_game(
     pool: &SqlitePool,
-    game_type: &str,
+    engine: &dyn GameEngine,
     player1: &str,
     player2: Option<&str>,
     conversation_type: &str,
     conversation_id: Option<i64>,
-) -> Result<Game, sqlx::Error> {
+) -> Result<Game, String> {
     let now = get_current_time();
-    let initial_state = match game_type {
-        "chess" => create_initial_chess_state(),
-        "tictactoe" => create_initial_tictactoe_state(),
-        "trivia" => create_initial_trivia_state(pool).await?,
-        _ => "{}".to_string(),
-    };
+    let initial_state = engine.initial_state(pool).await?;
 
     let game_id = sqlx::query(
         "INSERT INTO games (game_type, player1_username, player2_username, game_state, current_turn, status, created_at, conversation_type, conversation_id)
          VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
-    .bind(game_type)
+    .bind(engine.game_type())
     .bind(player1)
     .bind(player2)
     .bind(&initial_state)
@@ -467,12 +292,13 @@ async fn create_game(
     .bind(conversation_type)
     .bind(conversation_id)
     .execute(pool)
-    .await?
+    .await
+    .map_err(|e| format!("Failed to save game: {}", e))?
     .last_insert_rowid();
 
     Ok(Game {
         id: game_id,
-        game_type: game_type.to_string(),
+        game_type: engine.game_type().to_string(),
         player1_username: player1.to_string(),
         player2_username: player2.map(|s| s.to_string()),
         game_state: initial_state,
@@ -485,100 +311,46 @@ async fn create_game(
     })
 }
 
-fn create_initial_chess_state() -> String {
-    serde_json::json!({
-        "board": [
-            ["r", "n", "b", "q", "k", "b", "n", "r"],
-            ["p", "p", "p", "p", "p", "p", "p", "p"],
-            [".", ".", ".", ".", ".", ".", ".", "."],
-            [".", ".", ".", ".", ".", ".", ".", "."],
-            [".", ".", ".", ".", ".", ".", ".", "."],
-            [".", ".", ".", ".", ".", ".", ".", "."],
-            ["P", "P", "P", "P", "P", "P", "P", "P"],
-            ["R", "N", "B", "Q", "K", "B", "N", "R"]
-        ],
-        "turn": "white",
-        "moves": []
-    }).to_string()
-}
-
-fn create_initial_tictactoe_state() -> String {
-    serde_json::json!({
-        "board": [
-            ["", "", ""],
-            ["", "", ""],
-            ["", "", ""]
-        ],
-        "turn": "X",
-        "moves": []
-    }).to_string()
-}
-
-async fn create_initial_trivia_state(pool: &SqlitePool) -> Result<String, sqlx::Error> {
-    let question = sqlx::query_as::<_, (i64, String, String, i32, String)>(
-        "SELECT id, question, options, correct_answer, category FROM trivia_questions ORDER BY RANDOM() LIMIT 1"
-    )
-    .fetch_one(pool)
-    .await?;
-
-    Ok(serde_json::json!({
-        "current_question": {
-            "id": question.0,
-            "question": question.1,
-            "options": serde_json::from_str::<Vec<String>>(&question.2).unwrap_or_default(),
-            "category": question.4
-        },
-        "scores": {},
-        "answered": []
-    }).to_string())
-}
-
 async fn process_game_move(
     pool: &SqlitePool,
+    games: &GameRegistry,
     game_id: i64,
     player: &str,
     move_data: &str,
 ) -> Result<Game, String> {
-    // Get current game
-    let game_row = sqlx::query(
-        "SELECT id, game_type, player1_username, player2_username, game_state, current_turn, status, winner, created_at, conversation_type, conversation_id
-         FROM games WHERE id = ?"
-    )
-    .bind(game_id)
-    .fetch_one(pool)
-    .await
-    .map_err(|_| "Game not found")?;
-
-    let mut game = Game {
-        id: game_row.get("id"),
-        game_type: game_row.get("game_type"),
-        player1_username: game_row.get("player1_username"),
-        player2_username: game_row.get("player2_username"),
-        game_state: game_row.get("game_state"),
-        current_turn: game_row.get("current_turn"),
-        status: game_row.get("status"),
-        winner: game_row.get("winner"),
-        created_at: game_row.get("created_at"),
-        conversation_type: game_row.get("conversation_type"),
-        conversation_id: game_row.get("conversation_id"),
-    };
+    let mut game = fetch_game(pool, game_id)
+        .await
+        .ok()
+        .flatten()
+        .ok_or("Game not found")?;
+    let engine = games.get(&game.game_type).ok_or("Unknown game type")?;
 
     // Validate player
     if game.status != "active" {
         return Err("Game is not active".to_string());
     }
-
-    if game.current_turn != player {
+    let players = Players { first: &game.player1_username, second: game.player2_username.as_deref() };
+    let seat = players.seat_of(player).ok_or("You are not playing in this game")?;
+    if engine.to_move(&game.game_state)?.is_some_and(|turn| turn != seat) {
         return Err("Not your turn".to_string());
     }
 
-    // Process move based on game type
-    match game.game_type.as_str() {
-        "chess" => process_chess_move(&mut game, player, move_data)?,
-        "tictactoe" => process_tictactoe_move(&mut game, player, move_data)?,
-        "trivia" => process_trivia_move(&mut game, player, move_data, pool).await?,
-        _ => return Err("Unknown game type".to_string()),
+    let state = engine.apply_move(&game.game_state, &players, seat, move_data)?;
+    match engine.outcome(&state, &players)? {
+        Some(outcome) => {
+            game.status = "finished".to_string();
+            game.winner = Some(match outcome {
+                Outcome::Winner(seat) => players.name(seat).to_string(),
+                Outcome::Draw => "draw".to_string(),
+            });
+        }
+        None => {
+            if let Some(turn) = engine.to_move(&state)? {
+                game.current_turn = players.name(turn).to_string();
+            }
+        }
     }
+    game.game_state = state;
 
     // Save move
     let now = get_current_time();
@@ -607,584 +379,151 @@ async fn process_game_move(
     Ok(game)
 }
 
-fn process_chess_move(game: &mut Game, _player: &str, move_data: &str) -> Result<(), String> {
-    let move_json: serde_json::Value = serde_json::from_str(move_data)
-        .map_err(|_| "Invalid move format")?;
-    
-    let mut state: serde_json::Value = serde_json::from_str(&game.game_state)
-        .map_err(|_| "Invalid game state")?;
-    
-    // Basic chess move validation (simplified)
-    let from = move_json["from"].as_array().ok_or("Invalid from position")?;
-    let to = move_json["to"].as_array().ok_or("Invalid to position")?;
-    
-    if from.len() != 2 || to.len() != 2 {
-        return Err("Invalid position format".to_string());
-    }
-
-    let from_row = from[0].as_u64().ok_or("Invalid row")? as usize;
-    let from_col = from[1].as_u64().ok_or("Invalid col")? as usize;
-    let to_row = to[0].as_u64().ok_or("Invalid row")? as usize;
-    let to_col = to[1].as_u64().ok_or("Invalid col")? as usize;
-
-    if from_row >= 8 || from_col >= 8 || to_row >= 8 || to_col >= 8 {
-        return Err("Position out of bounds".to_string());
-    }
-
-    // Move the piece (simplified)
-    let board = state["board"].as_array_mut().ok_or("Invalid board")?;
-    let piece = board[from_row][from_col].clone();
-    board[from_row][from_col] = serde_json::Value::String(".".to_string());
-    board[to_row][to_col] = piece;
-
-    // Switch turns
-    let current_turn = state["turn"].as_str().unwrap_or("white");
-    state["turn"] = serde_json::Value::String(
-        if current_turn == "white" { "black" } else { "white" }.to_string()
-    );
-
-    game.current_turn = if game.current_turn == game.player1_username {
-        game.player2_username.as_ref().unwrap_or(&game.player1_username).clone()
-    } else {
-        game.player1_username.clone()
-    };
 
-    game.game_state = state.to_string();
-    Ok(())
-}
+#[tokio::main]
+async fn main() {
+    // Initialize database
+    dotenv().ok();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let check_only = args.iter().any(|a| a == "--check");
+    let migrate_only = args.iter().any(|a| a == "--migrate-only");
 
-fn process_tictactoe_move(game: &mut Game, player: &str, move_data: &str) -> Result<(), String> {
-    let move_json: serde_json::Value = serde_json::from_str(move_data)
-        .map_err(|_| "Invalid move format")?;
-    
-    let mut state: serde_json::Value = serde_json::from_str(&game.game_state)
-        .map_err(|_| "Invalid game state")?;
-    
-    let row = move_json["row"].as_u64().ok_or("Invalid row")? as usize;
-    let col = move_json["col"].as_u64().ok_or("Invalid col")? as usize;
+    let database_url = "sqlite:./db/chat.db?mode=rwc";
+    let pool = SqlitePool::connect(database_url)
+        .await
+        .expect("Failed to connect to database");
 
-    if row >= 3 || col >= 3 {
-        return Err("Position out of bounds".to_string());
+    // --check: report the schema state without changing anything
+    if check_only {
+        println!("Schema migrations for {}:", database_url);
+        match migrations::check(&pool).await {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => {
+                println!("Database schema is not at the latest version; run with --migrate-only");
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Failed to read schema version: {}", e);
+                std::process::exit(2);
+            }
+        }
     }
 
-    let board = state["board"].as_array_mut().ok_or("Invalid board")?;
-    
-    if !board[row][col].as_str().unwrap_or("X").is_empty() {
-        return Err("Position already taken".to_string());
+    match migrations::migrate(&pool).await {
+        Ok(applied) => {
+            let version = migrations::MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+            if applied.is_empty() {
+                println!("Database schema is up to date (version {})", version);
+            } else {
+                let names: Vec<String> = applied.iter().map(|m| format!("{} {}", m.version, m.name)).collect();
+                println!("Applied {} migration(s): {}; schema is at version {}", applied.len(), names.join(", "), version);
+            }
+        }
+        Err(e) => {
+            eprintln!("Database migration failed, not starting: {}", e);
+            eprintln!("The failed migration was rolled back; earlier ones remain applied. Run with --check for the full state.");
+            std::process::exit(1);
+        }
+    }
+    if migrate_only {
+        return;
     }
 
-    let symbol = if player == game.player1_username { "X" } else { "O" };
-    board[row][col] = serde_json::Value::String(symbol.to_string());
+    env::var("GEMINI_API_KEY").expect("GEMINI_API_KEY must be set in .env file");
+    if let Err(e) = auth::init(&pool).await {
+        eprintln!("Warning: failed to load revoked sessions: {}", e);
+    }
 
-    // Check for win
-    if check_tictactoe_win(&board, symbol) {
-        game.status = "finished".to_string();
-        game.winner = Some(player.to_string());
-    } else if check_tictactoe_draw(&board) {
-        game.status = "finished".to_string();
-        game.winner = Some("draw".to_string());
-    } else {
-        // Switch turns
-        game.current_turn = if game.current_turn == game.player1_username {
-            game.player2_username.as_ref().unwrap_or(&game.player1_username).clone()
-        } else {
-            game.player1_username.clone()
-        };
+    if let Err(e) = std::fs::create_dir_all(uploads::THUMBNAIL_DIR) {
+        eprintln!("Warning: failed to create uploads dir: {:?}", e);
     }
 
-    game.game_state = state.to_string();
-    Ok(())
-}
+    // Live connections, keyed by username
+    let users: Users = Arc::new(ConnectionRegistry::new());
+    let presence = Arc::new(PresenceTracker::new());
+    // Game types that can be played; add more with `GameRegistry::register` before sharing it
+    let games = Arc::new(GameRegistry::with_builtin());
 
-async fn process_trivia_move(
-    game: &mut Game,
-    player: &str,
-    move_data: &str,
-    pool: &SqlitePool,
-) -> Result<(), String> {
-    let move_json: serde_json::Value =
-        serde_json::from_str(move_data).map_err(|_| "Invalid move format")?;
-
-    let mut state: serde_json::Value =
-        serde_json::from_str(&game.game_state).map_err(|_| "Invalid game state")?;
-
-    let answer = move_json["answer"]
-        .as_u64()
-        .ok_or("Invalid answer")? as i32;
-    let question_id = state["current_question"]["id"]
-        .as_i64()
-        .ok_or("No current question")?;
-
-    // Get correct answer
-    let correct_answer = sqlx::query_scalar::<_, i32>(
-        "SELECT correct_answer FROM trivia_questions WHERE id = ?",
-    )
-    .bind(question_id)
-    .fetch_one(pool)
-    .await
-    .map_err(|_| "Question not found")?;
+    // Clone for use in filters
+    let users_filter = {
+        let users = users.clone();
+        warp::any().map(move || users.clone())
+    };
+    let presence_filter = {
+        let presence = presence.clone();
+        warp::any().map(move || presence.clone())
+    };
+    let games_filter = warp::any().map(move || games.clone());
+    let pool_filter = warp::any().map({
+        let pool = pool.clone();
+        move || pool.clone()
+    });
 
-    // Update scores
+    // Background scheduler to dispatch due scheduled messages
     {
-        let scores = state["scores"]
-            .as_object_mut()
-            .ok_or("Invalid scores")?;
-
-        let current_score = scores
-            .get(player)
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-
-        if answer == correct_answer {
-            scores.insert(
-                player.to_string(),
-                serde_json::Value::Number(serde_json::Number::from(current_score + 1)),
-            );
-        }
+        let pool_sched = pool.clone();
+        let users_sched = users.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                scheduled::dispatch_due(&users_sched, &pool_sched).await;
+            }
+        });
     }
 
-    // Mark as answered
+    // Background reveal of messages whose reveal_at has passed, starting
+    // from where the last run stopped so reveals due during downtime go out
     {
-        let answered = state["answered"]
-            .as_array_mut()
-            .ok_or("Invalid answered list")?;
-
-        answered.push(serde_json::Value::String(player.to_string()));
+        let pool_reveal = pool.clone();
+        let users_reveal = users.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+            let mut since = reveal::revealed_through(&pool_reveal).await;
+            loop {
+                interval.tick().await;
+                let until = reveal::format(chrono::Utc::now());
+                reveal::reveal_due(&users_reveal, &pool_reveal, &since, &until).await;
+                reveal::set_revealed_through(&pool_reveal, &until).await;
+                since = until;
+            }
+        });
     }
 
-    // Check if both players answered
-    let both_answered = {
-        let answered = state["answered"]
-            .as_array()
-            .ok_or("Invalid answered list")?;
-
-        game.player2_username.is_none()
-            || answered.len() >= 2
-            || (answered.len() == 1
-                && game.player2_username.is_some()
-                && answered.iter().any(|v| {
-                    v.as_str() == game.player2_username.as_ref().map(|s| s.as_str())
-                }))
-    };
-
-    if both_answered {
-        // Get next question or end game
-        let next_question = sqlx::query_as::<_, (i64, String, String, i32, String)>(
-            "SELECT id, question, options, correct_answer, category FROM trivia_questions WHERE id != ? ORDER BY RANDOM() LIMIT 1",
-        )
-        .bind(question_id)
-        .fetch_optional(pool)
-        .await
-        .map_err(|_| "Database error")?;
-
-        if let Some(question) = next_question {
-            state["current_question"] = serde_json::json!({
-                "id": question.0,
-                "question": question.1,
-                "options": serde_json::from_str::<Vec<String>>(&question.2).unwrap_or_default(),
-                "category": question.4
-            });
-            state["answered"] = serde_json::json!([]);
-        } else {
-            game.status = "finished".to_string();
-
-            // Safe immutable borrow of scores here
-            let scores = state["scores"]
-                .as_object()
-                .ok_or("Invalid scores")?;
-
-            let p1_score = scores
-                .get(&game.player1_username)
-                .and_then(|v| v.as_i64())
-                .unwrap_or(0);
-            let p2_score = if let Some(p2) = &game.player2_username {
-                scores.get(p2).and_then(|v| v.as_i64()).unwrap_or(0)
-            } else {
-                0
-            };
-
-            game.winner = if p1_score > p2_score {
-                Some(game.player1_username.clone())
-            } else if p2_score > p1_score {
-                game.player2_username.clone()
-            } else {
-                Some("draw".to_string())
-            };
-        }
+    // Background close of polls past their expires_at
+    {
+        let pool_polls = pool.clone();
+        let users_polls = users.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                polls::close_expired(&users_polls, &pool_polls).await;
+            }
+        });
     }
 
-    game.game_state = state.to_string();
-    Ok(())
-}
-
-fn check_tictactoe_win(board: &[serde_json::Value], symbol: &str) -> bool {
-    // Check rows, columns, and diagonals
-    for i in 0..3 {
-        // Check row
-        if (0..3).all(|j| board[i][j].as_str() == Some(symbol)) {
-            return true;
-        }
-        // Check column
-        if (0..3).all(|j| board[j][i].as_str() == Some(symbol)) {
-            return true;
-        }
-    }
-    
-    // Check diagonals
-    if (0..3).all(|i| board[i][i].as_str() == Some(symbol)) {
-        return true;
-    }
-    if (0..3).all(|i| board[i][2-i].as_str() == Some(symbol)) {
-        return true;
-    }
-    
-    false
-}
-
-fn check_tictactoe_draw(board: &[serde_json::Value]) -> bool {
-    board.iter().all(|row| {
-        row.as_array().unwrap().iter().all(|cell| {
-            !cell.as_str().unwrap_or("").is_empty()
-        })
-    })
-}
-
-
-#[tokio::main]
-async fn main() {
-    // Initialize database
-    dotenv().ok();
-    env::var("GEMINI_API_KEY").expect("GEMINI_API_KEY must be set in .env file");
-    let database_url = "sqlite:./db/chat.db?mode=rwc";
-    let pool = SqlitePool::connect(database_url)
-        .await
-        .expect("Failed to connect to database");
-
-    // Create users table first (required for auth)
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            username TEXT NOT NULL UNIQUE,
-            password_hash TEXT NOT NULL,
-            created_at TEXT DEFAULT CURRENT_TIMESTAMP
-        )"
-    ).execute(&pool).await;
-
-    // Create groups table (needed by other tables with foreign keys)
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS groups (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            name TEXT NOT NULL,
-            owner_username TEXT NOT NULL,
-            description TEXT,
-            ghost_mode INTEGER DEFAULT 0,
-            created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
-        )"
-    ).execute(&pool).await;
-    
-    // Add columns if they don't exist (for existing databases)
-    let _ = sqlx::query("ALTER TABLE groups ADD COLUMN description TEXT").execute(&pool).await;
-    let _ = sqlx::query("ALTER TABLE groups ADD COLUMN ghost_mode INTEGER DEFAULT 0").execute(&pool).await;
-
-    // Create group_members table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS group_members (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            group_id INTEGER NOT NULL,
-            username TEXT NOT NULL,
-            joined_at TEXT DEFAULT CURRENT_TIMESTAMP,
-            UNIQUE(group_id, username),
-            FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
-        )"
-    ).execute(&pool).await;
-
-    // Scheduled messages table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS scheduled_messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            sender_username TEXT NOT NULL,
-            receiver_username TEXT,
-            group_id INTEGER,
-            message TEXT NOT NULL,
-            scheduled_at TEXT NOT NULL,
-            scheduled_at_epoch INTEGER,
-            sent INTEGER DEFAULT 0,
-            created_at TEXT NOT NULL,
-            sent_at TEXT
-        )"
-    ).execute(&pool).await;
-    // Migration for epoch column if missing
-    let _ = sqlx::query("ALTER TABLE scheduled_messages ADD COLUMN scheduled_at_epoch INTEGER").execute(&pool).await;
-
-    // Notes table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS notes (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            username TEXT NOT NULL,
-            title TEXT,
-            content TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            updated_at TEXT NOT NULL
-        )"
-    ).execute(&pool).await;
-
-    // Create direct messages table (for 1:1 chats)
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            sender_username TEXT NOT NULL,
-            receiver_username TEXT NOT NULL,
-            message TEXT NOT NULL,
-            timestamp TEXT NOT NULL,
-            group_id INTEGER,
-            reveal_at TEXT
-        )"
-    ).execute(&pool).await;
-
-    // Migrate existing messages table to add group_id if missing
-    let _ = sqlx::query(
-        "ALTER TABLE messages ADD COLUMN group_id INTEGER"
-    ).execute(&pool).await;
-    let _ = sqlx::query("ALTER TABLE messages ADD COLUMN reveal_at TEXT").execute(&pool).await;
-
-    // Helpful index for faster conversation lookups
-    let _ = sqlx::query(
-        "CREATE INDEX IF NOT EXISTS idx_messages_participants
-         ON messages(sender_username, receiver_username)"
-    ).execute(&pool).await;
-
-    // Create polls tables
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS polls (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            group_id INTEGER NOT NULL,
-            creator_username TEXT NOT NULL,
-            question TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            expires_at TEXT,
-            is_active BOOLEAN DEFAULT 1,
-            allow_multiple_choices BOOLEAN DEFAULT 0,
-            FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
-        )"
-    ).execute(&pool).await;
-
-    // Ensure group messages table exists (for group search/history)
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS group_messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            group_id INTEGER NOT NULL,
-            sender_username TEXT NOT NULL,
-            message TEXT NOT NULL,
-            timestamp TEXT NOT NULL,
-            reveal_at TEXT
-        )"
-    ).execute(&pool).await;
-    let _ = sqlx::query("ALTER TABLE group_messages ADD COLUMN reveal_at TEXT").execute(&pool).await;
-
-    // Message edit/delete migrations
-    let _ = sqlx::query("ALTER TABLE messages ADD COLUMN deleted INTEGER DEFAULT 0").execute(&pool).await;
-    let _ = sqlx::query("ALTER TABLE messages ADD COLUMN edited_at TEXT").execute(&pool).await;
-    let _ = sqlx::query("ALTER TABLE group_messages ADD COLUMN deleted INTEGER DEFAULT 0").execute(&pool).await;
-    let _ = sqlx::query("ALTER TABLE group_messages ADD COLUMN edited_at TEXT").execute(&pool).await;
-
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS poll_options (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            poll_id INTEGER NOT NULL,
-            option_text TEXT NOT NULL,
-            option_order INTEGER NOT NULL,
-            FOREIGN KEY (poll_id) REFERENCES polls(id) ON DELETE CASCADE
-        )"
-    ).execute(&pool).await;
-
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS poll_votes (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            poll_id INTEGER NOT NULL,
-            option_id INTEGER NOT NULL,
-            username TEXT NOT NULL,
-            voted_at TEXT NOT NULL,
-            UNIQUE(poll_id, option_id, username),
-            FOREIGN KEY (poll_id) REFERENCES polls(id) ON DELETE CASCADE,
-            FOREIGN KEY (option_id) REFERENCES poll_options(id) ON DELETE CASCADE
-        )"
-    ).execute(&pool).await;
-
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS highlights (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            user_username TEXT NOT NULL,
-            target_type TEXT NOT NULL,
-            target_id INTEGER,
-            target_name TEXT NOT NULL,
-            highlight_type TEXT NOT NULL,
-            summary TEXT NOT NULL,
-            key_topics TEXT NOT NULL,
-            message_count INTEGER NOT NULL,
-            participant_count INTEGER NOT NULL,
-            start_date TEXT NOT NULL,
-            end_date TEXT NOT NULL,
-            created_at TEXT NOT NULL
-        )"
-    ).execute(&pool).await;
-
-    // Create message reactions table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS message_reactions (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            message_id INTEGER NOT NULL,
-            username TEXT NOT NULL,
-            emoji TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            UNIQUE(message_id, username, emoji)
-        )"
-    ).execute(&pool).await;
-    
-    // Migrate existing table if needed (add created_at column if missing)
-    let _ = sqlx::query(
-        "ALTER TABLE message_reactions ADD COLUMN created_at TEXT"
-    ).execute(&pool).await;
-
-    // Create pinned messages table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS pinned_messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            message_id INTEGER NOT NULL,
-            pinned_by TEXT NOT NULL,
-            pinned_at TEXT NOT NULL,
-            UNIQUE(message_id)
-        )"
-    ).execute(&pool).await;
-
-    // Create DM locks table
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS dm_locks (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            owner_username TEXT NOT NULL,
-            peer_username TEXT NOT NULL,
-            hash TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            UNIQUE(owner_username, peer_username)
-        )"
-    ).execute(&pool).await;
-    // Add locked column if missing
-    let _ = sqlx::query(
-        "ALTER TABLE dm_locks ADD COLUMN locked INTEGER DEFAULT 1"
-    ).execute(&pool).await;
-
-    // Global user lock PIN table (one per user)
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS user_lock_pin (
-            username TEXT PRIMARY KEY,
-            hash TEXT NOT NULL,
-            created_at TEXT NOT NULL,
-            enabled INTEGER DEFAULT 0
-        )"
-    ).execute(&pool).await;
-
-    // Add missing columns for legacy installations
-    // Ensure 'enabled' exists on user_lock_pin
-    let _ = sqlx::query(
-        "ALTER TABLE user_lock_pin ADD COLUMN enabled INTEGER DEFAULT 0"
-    ).execute(&pool).await;
-
-    // Add ghost_mode to groups if missing
-    let _ = sqlx::query(
-        "ALTER TABLE groups ADD COLUMN ghost_mode INTEGER DEFAULT 0"
-    ).execute(&pool).await;
-
-    // Per-chat themes per user
-    let _ = sqlx::query(
-        "CREATE TABLE IF NOT EXISTS chat_themes (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            owner_username TEXT NOT NULL,
-            peer_username TEXT,
-            group_id INTEGER,
-            theme_key TEXT NOT NULL,
-            updated_at TEXT NOT NULL
-        )"
-    ).execute(&pool).await;
-    // Helpful indexes/unique constraints
-    let _ = sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS ux_chat_theme_dm ON chat_themes(owner_username, peer_username)").execute(&pool).await;
-    let _ = sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS ux_chat_theme_group ON chat_themes(owner_username, group_id)").execute(&pool).await;
-
-    initialize_games(&pool).await;
-
-    async fn initialize_games(pool: &SqlitePool) {
-    create_game_tables(pool).await;
-}
-
-
-
-    // Ensure uploads directory exists
-    if let Err(e) = std::fs::create_dir_all("./db/uploads") {
-        eprintln!("Warning: failed to create uploads dir: {:?}", e);
-    }
-
-    // Create a broadcast channel for messages
-    let (tx, _rx) = broadcast::channel::<ChatMessage>(100);
-    let users: Users = Arc::new(Mutex::new(HashMap::new()));
-    
-    // Clone for use in filters
-    let users_filter = warp::any().map(move || users.clone());
-    let tx_for_filter = tx.clone();
-    let tx_filter = warp::any().map(move || tx_for_filter.clone());
-    let pool_filter = warp::any().map({
-        let pool = pool.clone();
-        move || pool.clone()
-    });
-
-    // Background scheduler to dispatch due scheduled messages
-    {
-        let pool_sched = pool.clone();
-        let tx_sched = tx.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
-            loop {
-                interval.tick().await;
-                // Fetch due messages using epoch compare
-                let now_epoch = chrono::Utc::now().timestamp();
-                let rows = sqlx::query(
-                    "SELECT id, sender_username, receiver_username, group_id, message, scheduled_at FROM scheduled_messages WHERE sent = 0 AND (
-                        (scheduled_at_epoch IS NOT NULL AND scheduled_at_epoch <= ?) OR
-                        (scheduled_at_epoch IS NULL AND scheduled_at <= ?)
-                    ) LIMIT 20"
-                )
-                .bind(now_epoch)
-                .bind(get_current_time())
-                .fetch_all(&pool_sched)
-                .await
-                .unwrap_or_default();
-
-                for row in rows {
-                    let id: i64 = row.get("id");
-                    let sender: String = row.get("sender_username");
-                    let recv: Option<String> = row.get::<Option<String>, _>("receiver_username");
-                    let gid: Option<i64> = row.get::<Option<i64>, _>("group_id");
-                    let text: String = row.get("message");
-                    let ts = get_current_time();
-
-                    if let Some(group_id) = gid.filter(|&g| g > 0) {
-                        // Store and broadcast group message
-                        let _ = store_group_message(&pool_sched, group_id, &sender, &text, &ts, None).await;
-                        let chat_msg = ChatMessage { id: 0, sender_username: sender.clone(), receiver_username: "".to_string(), group_id: Some(group_id), message: text.clone(), timestamp: ts.clone(), reactions: None, reveal_at: None };
-                        let _ = tx_sched.send(chat_msg);
-                    } else if let Some(receiver) = recv {
-                        // Store and broadcast direct message
-                        let _ = store_message(&pool_sched, &sender, &receiver, &text, &ts, None).await;
-                        let chat_msg = ChatMessage { id: 0, sender_username: sender.clone(), receiver_username: receiver.clone(), group_id: None, message: text.clone(), timestamp: ts.clone(), reactions: None, reveal_at: None };
-                        let _ = tx_sched.send(chat_msg);
-                    }
-
-                    // Mark as sent
-                    let _ = sqlx::query("UPDATE scheduled_messages SET sent = 1, sent_at = ? WHERE id = ?")
-                        .bind(get_current_time())
-                        .bind(id)
-                        .execute(&pool_sched)
-                        .await;
-                }
-            }
-        });
+    // Background sweep for lapsed typing indicators and users going idle
+    {
+        let pool_presence = pool.clone();
+        let users_presence = users.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(2));
+            loop {
+                interval.tick().await;
+                for (username, scope) in presence.expire_typing() {
+                    send_typing_notice(&users_presence, &pool_presence, &username, &scope, false).await;
+                }
+                for username in users_presence.online_users() {
+                    let Some(idle) = users_presence.idle_secs(&username) else { continue };
+                    let status = presence::status_of(&users_presence, &username);
+                    if presence.announce(&username, status) {
+                        let last_active = chrono::Utc::now() - chrono::Duration::seconds(idle as i64);
+                        announce_presence(&users_presence, &pool_presence, &username, status, last_active).await;
+                    }
+                }
+            }
+        });
     }
 
     let ai_assistant = warp::path!("ai" / "assistant")
@@ -1269,68 +608,13 @@ async fn main() {
         .and(pool_filter.clone())
         .and_then(notes_delete_handler);
 
-    // Global search endpoint
-    async fn search_messages_handler(params: HashMap<String, String>, auth: Option<String>, pool: SqlitePool) -> Result<impl warp::Reply, warp::Rejection> {
-        let Some(auth_header) = auth else {
-            return Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"error":"Missing authorization"})), warp::http::StatusCode::UNAUTHORIZED));
-        };
-        let username = match extract_username_from_auth(auth_header) { Ok(u) => u, Err(_) => {
-            return Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"error":"Invalid token"})), warp::http::StatusCode::UNAUTHORIZED));
-        }};
-        let q = params.get("q").cloned().unwrap_or_default();
-        if q.trim().is_empty() {
-            return Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"results": []})), warp::http::StatusCode::OK));
-        }
-        let like = format!("%{}%", q);
-        // DM search
-        let dm_rows = sqlx::query(
-            "SELECT id, sender_username, receiver_username, message, timestamp FROM messages
-             WHERE (sender_username = ? OR receiver_username = ?) AND message LIKE ?
-             ORDER BY id DESC LIMIT 50"
-        )
-        .bind(&username).bind(&username).bind(&like)
-        .fetch_all(&pool).await.unwrap_or_default();
-
-        let mut results: Vec<serde_json::Value> = dm_rows.into_iter().map(|r| serde_json::json!({
-            "id": r.get::<i64,_>("id"),
-            "type": "dm",
-            "sender": r.get::<String,_>("sender_username"),
-            "receiver": r.get::<String,_>("receiver_username"),
-            "message": r.get::<String,_>("message"),
-            "timestamp": r.get::<String,_>("timestamp")
-        })).collect();
-
-        // Group search (only in groups user is a member of)
-        let grp_rows = sqlx::query(
-            "SELECT gm.id, gm.group_id, gm.sender_username, gm.message, gm.timestamp
-             FROM group_messages gm
-             JOIN group_members m ON gm.group_id = m.group_id
-             WHERE m.username = ? AND gm.message LIKE ?
-             ORDER BY gm.id DESC LIMIT 50"
-        )
-        .bind(&username).bind(&like)
-        .fetch_all(&pool).await.unwrap_or_default();
-
-        for r in grp_rows {
-            results.push(serde_json::json!({
-                "id": r.get::<i64,_>("id"),
-                "type": "group",
-                "group_id": r.get::<i64,_>("group_id"),
-                "sender": r.get::<String,_>("sender_username"),
-                "message": r.get::<String,_>("message"),
-                "timestamp": r.get::<String,_>("timestamp")
-            }));
-        }
-
-        Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"results": results})), warp::http::StatusCode::OK))
-    }
-
+    // Global search endpoint, see search.rs for the query syntax
     let search_messages = warp::path("search_messages")
         .and(warp::get())
         .and(warp::query::<HashMap<String, String>>())
         .and(warp::header::optional::<String>("authorization"))
         .and(pool_filter.clone())
-        .and_then(search_messages_handler);
+        .and_then(search::search_messages_handler);
 
     // Chat themes routes
     async fn chat_theme_get_handler(params: HashMap<String, String>, auth: String, pool: SqlitePool) -> Result<impl warp::Reply, warp::Rejection> {
@@ -1417,14 +701,14 @@ async fn main() {
             // Re-enable existing lock; ignore PIN
             let _ = sqlx::query("UPDATE dm_locks SET locked = 1 WHERE owner_username = ? AND peer_username = ? COLLATE NOCASE")
                 .bind(&username).bind(&req.peer_username).execute(&pool).await;
-            return Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"status":"locked"})), warp::http::StatusCode::OK));
+            Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"status":"locked"})), warp::http::StatusCode::OK))
         } else {
             if req.pin.is_empty() { return Ok(warp::reply::with_status(warp::reply::json(&ErrorResponse{ error:"PIN required".to_string()}), warp::http::StatusCode::BAD_REQUEST)); }
             let salt = SaltString::generate(&mut OsRng);
             let hash = Argon2::default().hash_password(req.pin.as_bytes(), &salt).map_err(|_| warp::reject())?.to_string();
             let _ = sqlx::query("INSERT INTO dm_locks (owner_username, peer_username, hash, created_at, locked) VALUES (?, ?, ?, ?, 1)")
                 .bind(&username).bind(&req.peer_username).bind(&hash).bind(get_current_time()).execute(&pool).await;
-            return Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"status":"locked"})), warp::http::StatusCode::CREATED));
+            Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"status":"locked"})), warp::http::StatusCode::CREATED))
         }
     }
 
@@ -1544,7 +828,7 @@ async fn main() {
             // Enable without changing PIN
             let _ = sqlx::query("UPDATE user_lock_pin SET enabled = 1 WHERE username = ?")
                 .bind(&username).execute(&pool).await;
-            return Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"status":"enabled"})), warp::http::StatusCode::OK));
+            Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"status":"enabled"})), warp::http::StatusCode::OK))
         } else {
             let pin = req.pin.unwrap_or_default();
             if pin.is_empty() { return Ok(warp::reply::with_status(warp::reply::json(&ErrorResponse{ error:"PIN required".to_string()}), warp::http::StatusCode::BAD_REQUEST)); }
@@ -1552,7 +836,7 @@ async fn main() {
             let hash = Argon2::default().hash_password(pin.as_bytes(), &salt).map_err(|_| warp::reject())?.to_string();
             let _ = sqlx::query("INSERT INTO user_lock_pin (username, hash, created_at, enabled) VALUES (?, ?, ?, 1)")
                 .bind(&username).bind(&hash).bind(get_current_time()).execute(&pool).await;
-            return Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"status":"enabled"})), warp::http::StatusCode::CREATED));
+            Ok(warp::reply::with_status(warp::reply::json(&serde_json::json!({"status":"enabled"})), warp::http::StatusCode::CREATED))
         }
     }
 
@@ -1644,8 +928,20 @@ async fn main() {
         .and(warp::fs::file("./static/index.html"))
         .or(warp::path("static").and(warp::fs::dir("./static")));
     
-    // Serve uploaded images
-    let uploads_files = warp::path("uploads").and(warp::fs::dir("./db/uploads"));
+    // Attachment upload and authorized download
+    let uploads_routes = uploads::routes(pool.clone());
+
+    // Mentions inbox
+    let mentions_routes = mentions::routes(pool.clone());
+
+    // Conversation list and per-conversation settings
+    let conversations_routes = conversations::routes(pool.clone(), users.clone());
+
+    // The sender's scheduled messages
+    let scheduled_routes = scheduled::routes(pool.clone(), users.clone());
+
+    // Polls: create, vote, read and close
+    let polls_routes = polls::routes(pool.clone(), users.clone());
 
     // Registration endpoint
     let register = warp::path("register")
@@ -1658,46 +954,66 @@ async fn main() {
     let login = warp::path("login")
         .and(warp::post())
         .and(warp::body::json())
+        .and(warp::header::optional::<String>("user-agent"))
         .and(pool_filter.clone())
         .and_then(handle_login);
 
-    // Users list endpoint
-    let users_list = warp::path("users")
-        .and(warp::get())
-        .and(warp::header::<String>("authorization"))
+    // Session endpoints: rotate a refresh token, end this or every session
+    let refresh = warp::path("refresh")
+        .and(warp::post())
+        .and(warp::body::json::<RefreshRequest>())
         .and(pool_filter.clone())
-        .and_then(handle_users_list);
+        .and(users_filter.clone())
+        .and_then(handle_refresh);
 
-    // Poll endpoints
-    let create_poll = warp::path!("polls" / "create")
+    let logout = warp::path("logout")
+        .and(warp::path::end())
         .and(warp::post())
-        .and(warp::body::json::<CreatePollRequest>())
         .and(warp::header::<String>("authorization"))
         .and(pool_filter.clone())
-        .and_then(create_poll_handler);
+        .and(users_filter.clone())
+        .and_then(|auth, pool, users| handle_logout(false, auth, pool, users));
 
-    let vote_poll = warp::path!("polls" / "vote")
+    let logout_all = warp::path!("logout" / "all")
         .and(warp::post())
-        .and(warp::body::json::<VotePollRequest>())
         .and(warp::header::<String>("authorization"))
         .and(pool_filter.clone())
-        .and_then(vote_poll_handler);
+        .and(users_filter.clone())
+        .and_then(|auth, pool, users| handle_logout(true, auth, pool, users));
+
+    // Users list endpoint
+    let users_list = warp::path("users")
+        .and(warp::get())
+        .and(warp::header::<String>("authorization"))
+        .and(pool_filter.clone())
+        .and(users_filter.clone())
+        .and_then(handle_users_list);
+
+    // History endpoints, paged with ?before_id=&after_id=&limit= (DMs also take ?peer=)
+    let dm_history = warp::path("messages")
+        .and(warp::get()).and(warp::path::end())
+        .and(warp::query::<DmHistoryQuery>())
+        .and(warp::header::<String>("authorization"))
+        .and(pool_filter.clone())
+        .and_then(dm_history_handler);
 
-    let get_poll = warp::path!("polls" / i64)
+    let group_history = warp::path!("groups" / i64 / "messages")
         .and(warp::get())
+        .and(warp::query::<HistoryPage>())
         .and(warp::header::<String>("authorization"))
         .and(pool_filter.clone())
-        .and_then(get_poll_handler);
+        .and_then(group_history_handler);
 
     // WebSocket route
     let websocket = warp::path("ws")
         .and(warp::ws())
         .and(warp::query::<HashMap<String, String>>())
         .and(users_filter)
-        .and(tx_filter)
+        .and(presence_filter)
+        .and(games_filter)
         .and(pool_filter.clone())
-        .map(|ws: warp::ws::Ws, params: HashMap<String, String>, users, tx, pool| {
-            ws.on_upgrade(move |socket| handle_websocket(socket, users, tx, params, pool))
+        .map(|ws: warp::ws::Ws, params: HashMap<String, String>, users, presence, games, pool| {
+            ws.on_upgrade(move |socket| handle_websocket(socket, users, presence, games, params, pool))
         });
 
     // CORS configuration
@@ -1707,7 +1023,7 @@ async fn main() {
         .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"]);
 
     // Group routes
-    let group_routes = groups::extended_routes(pool.clone());
+    let group_routes = groups::extended_routes(pool.clone(), users.clone());
 
     // Add this route for debugging
 
@@ -1734,7 +1050,11 @@ let get_highlights = warp::path!("highlights")
     });
 
     let routes = static_files
-        .or(uploads_files)
+        .or(uploads_routes)
+        .or(mentions_routes)
+        .or(conversations_routes)
+        .or(scheduled_routes)
+        .or(polls_routes)
         .or(favicon)
         .or(chat_theme_get)
         .or(chat_theme_set)
@@ -1755,10 +1075,12 @@ let get_highlights = warp::path!("highlights")
         .or(search_messages)
         .or(register)
         .or(login)
+        .or(refresh)
+        .or(logout)
+        .or(logout_all)
         .or(users_list)
-        .or(create_poll)
-        .or(vote_poll)
-        .or(get_poll)
+        .or(dm_history)
+        .or(group_history)
         .or(websocket)
         .or(group_routes)
         .or(generate_highlights)
@@ -1778,11 +1100,8 @@ let get_highlights = warp::path!("highlights")
         .await;
 }
 
-
-
-// Poll handlers
-async fn create_poll_handler(
-    request: CreatePollRequest,
+async fn dm_history_handler(
+    query: DmHistoryQuery,
     auth_header: String,
     pool: SqlitePool,
 ) -> Result<impl Reply, warp::Rejection> {
@@ -1790,84 +1109,19 @@ async fn create_poll_handler(
         Ok(u) => u,
         Err(_) => {
             return Ok(warp::reply::with_status(
-                warp::reply::json(&ErrorResponse {
-                    error: "Invalid or expired token".to_string(),
-                }),
+                warp::reply::json(&ErrorResponse { error: "Invalid or expired token".to_string() }),
                 warp::http::StatusCode::UNAUTHORIZED,
             ));
         }
     };
-
-    // Validate that user is member of the group
-    let member_check = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
-        .bind(request.group_id)
-        .bind(&username)
-        .fetch_optional(&pool)
-        .await
-        .map_err(|_| warp::reject::reject())?;
-
-    if member_check.is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&ErrorResponse {
-                error: "Not a member of this group".to_string(),
-            }),
-            warp::http::StatusCode::FORBIDDEN,
-        ));
-    }
-
-    // Validate poll data
-    if request.question.trim().is_empty() || request.options.len() < 2 || request.options.len() > 10 {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&ErrorResponse {
-                error: "Invalid poll data".to_string(),
-            }),
-            warp::http::StatusCode::BAD_REQUEST,
-        ));
-    }
-
-    let created_at = get_current_time();
-    let allow_multiple = request.allow_multiple_choices.unwrap_or(false);
-
-    // Create poll
-    let poll_id = sqlx::query(
-        "INSERT INTO polls (group_id, creator_username, question, created_at, expires_at, allow_multiple_choices) 
-         VALUES (?, ?, ?, ?, ?, ?)"
-    )
-    .bind(request.group_id)
-    .bind(&username)
-    .bind(&request.question)
-    .bind(&created_at)
-    .bind(&request.expires_at)
-    .bind(allow_multiple)
-    .execute(&pool)
-    .await
-    .map_err(|_| warp::reject::reject())?
-    .last_insert_rowid();
-
-    // Create poll options
-    for (index, option_text) in request.options.iter().enumerate() {
-        if !option_text.trim().is_empty() {
-            sqlx::query("INSERT INTO poll_options (poll_id, option_text, option_order) VALUES (?, ?, ?)")
-                .bind(poll_id)
-                .bind(option_text.trim())
-                .bind(index as i32)
-                .execute(&pool)
-                .await
-                .map_err(|_| warp::reject::reject())?;
-        }
-    }
-
-    // Get the created poll with options
-    let poll = get_poll_by_id(&pool, poll_id, &username).await?;
-
-    Ok(warp::reply::with_status(
-        warp::reply::json(&poll),
-        warp::http::StatusCode::CREATED,
-    ))
+    let page = HistoryPage { before_id: query.before_id, after_id: query.after_id, limit: query.limit };
+    let slice = get_conversation_messages(&pool, &username, &query.peer, page).await;
+    Ok(warp::reply::with_status(warp::reply::json(&slice), warp::http::StatusCode::OK))
 }
 
-async fn vote_poll_handler(
-    request: VotePollRequest,
+async fn group_history_handler(
+    group_id: i64,
+    page: HistoryPage,
     auth_header: String,
     pool: SqlitePool,
 ) -> Result<impl Reply, warp::Rejection> {
@@ -1875,205 +1129,31 @@ async fn vote_poll_handler(
         Ok(u) => u,
         Err(_) => {
             return Ok(warp::reply::with_status(
-                warp::reply::json(&ErrorResponse {
-                    error: "Invalid or expired token".to_string(),
-                }),
+                warp::reply::json(&ErrorResponse { error: "Invalid or expired token".to_string() }),
                 warp::http::StatusCode::UNAUTHORIZED,
             ));
         }
     };
 
-    // Get poll info
-    let poll_row = sqlx::query("SELECT group_id, allow_multiple_choices, is_active FROM polls WHERE id = ?")
-        .bind(request.poll_id)
-        .fetch_optional(&pool)
-        .await
-        .map_err(|_| warp::reject::reject())?;
-
-    let poll_row = match poll_row {
-        Some(row) => row,
-        None => {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&ErrorResponse {
-                    error: "Poll not found".to_string(),
-                }),
-                warp::http::StatusCode::NOT_FOUND,
-            ));
-        }
-    };
-
-    let group_id: i64 = poll_row.get("group_id");
-    let allow_multiple: bool = poll_row.get("allow_multiple_choices");
-    let is_active: bool = poll_row.get("is_active");
-
-    if !is_active {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&ErrorResponse {
-                error: "Poll is not active".to_string(),
-            }),
-            warp::http::StatusCode::BAD_REQUEST,
-        ));
-    }
-
-    // Check if user is member of the group
-    let member_check = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
-        .bind(group_id)
-        .bind(&username)
-        .fetch_optional(&pool)
-        .await
-        .map_err(|_| warp::reject::reject())?;
-
-    if member_check.is_none() {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&ErrorResponse {
-                error: "Not a member of this group".to_string(),
-            }),
-            warp::http::StatusCode::FORBIDDEN,
-        ));
-    }
-
-    // Validate vote options
-    if request.option_ids.is_empty() || (!allow_multiple && request.option_ids.len() > 1) {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&ErrorResponse {
-                error: "Invalid vote options".to_string(),
-            }),
-            warp::http::StatusCode::BAD_REQUEST,
-        ));
-    }
-
-    // Remove existing votes if not allowing multiple choices
-    if !allow_multiple {
-        sqlx::query("DELETE FROM poll_votes WHERE poll_id = ? AND username = ?")
-            .bind(request.poll_id)
-            .bind(&username)
-            .execute(&pool)
-            .await
-            .map_err(|_| warp::reject::reject())?;
-    }
-
-    let voted_at = get_current_time();
-
-    // Add new votes
-    for option_id in request.option_ids {
-        // Verify option belongs to this poll
-        let option_check = sqlx::query("SELECT 1 FROM poll_options WHERE id = ? AND poll_id = ?")
-            .bind(option_id)
-            .bind(request.poll_id)
-            .fetch_optional(&pool)
-            .await
-            .map_err(|_| warp::reject::reject())?;
-
-        if option_check.is_some() {
-            // Insert vote (ignore if duplicate due to UNIQUE constraint)
-            let _ = sqlx::query("INSERT OR IGNORE INTO poll_votes (poll_id, option_id, username, voted_at) VALUES (?, ?, ?, ?)")
-                .bind(request.poll_id)
-                .bind(option_id)
-                .bind(&username)
-                .bind(&voted_at)
-                .execute(&pool)
-                .await;
-        }
-    }
-
-    // Get updated poll
-    let poll = get_poll_by_id(&pool, request.poll_id, &username).await?;
-
-    Ok(warp::reply::with_status(
-        warp::reply::json(&poll),
-        warp::http::StatusCode::OK,
-    ))
-}
-
-async fn get_poll_handler(
-    poll_id: i64,
-    auth_header: String,
-    pool: SqlitePool,
-) -> Result<impl Reply, warp::Rejection> {
-    let username = match extract_username_from_auth(auth_header) {
-        Ok(u) => u,
-        Err(_) => {
+    match roles::require(&pool, group_id, &username, Permission::View).await {
+        Ok(_) => {}
+        Err(Denied::Database(_)) => return Err(warp::reject::reject()),
+        Err(denied) => {
             return Ok(warp::reply::with_status(
-                warp::reply::json(&ErrorResponse {
-                    error: "Invalid or expired token".to_string(),
-                }),
-                warp::http::StatusCode::UNAUTHORIZED,
+                warp::reply::json(&ErrorResponse { error: denied.to_string() }),
+                warp::http::StatusCode::FORBIDDEN,
             ));
         }
-    };
-
-    let poll = get_poll_by_id(&pool, poll_id, &username).await?;
-
-    Ok(warp::reply::with_status(
-        warp::reply::json(&poll),
-        warp::http::StatusCode::OK,
-    ))
-}
-
-// Helper function to get poll with options and vote counts
-async fn get_poll_by_id(pool: &SqlitePool, poll_id: i64, current_username: &str) -> Result<Poll, warp::Rejection> {
-    // Get poll info
-    let poll_row = sqlx::query(
-        "SELECT id, group_id, creator_username, question, created_at, expires_at, is_active, allow_multiple_choices 
-         FROM polls WHERE id = ?"
-    )
-    .bind(poll_id)
-    .fetch_one(pool)
-    .await
-    .map_err(|_| warp::reject::reject())?;
-
-    // Get poll options with vote counts
-    let options_rows = sqlx::query(
-        "SELECT po.id, po.option_text, 
-                COUNT(pv.id) as vote_count,
-                CASE WHEN user_votes.option_id IS NOT NULL THEN 1 ELSE 0 END as voted_by_current_user
-         FROM poll_options po
-         LEFT JOIN poll_votes pv ON po.id = pv.option_id
-         LEFT JOIN poll_votes user_votes ON po.id = user_votes.option_id AND user_votes.username = ?
-         WHERE po.poll_id = ?
-         GROUP BY po.id, po.option_text, po.option_order
-         ORDER BY po.option_order"
-    )
-    .bind(current_username)
-    .bind(poll_id)
-    .fetch_all(pool)
-    .await
-    .map_err(|_| warp::reject::reject())?;
-
-    let mut options = Vec::new();
-    let mut total_votes = 0;
-
-    for row in options_rows {
-        let vote_count: i64 = row.get("vote_count");
-        total_votes += vote_count;
-
-        options.push(PollOption {
-            id: row.get("id"),
-            option_text: row.get("option_text"),
-            vote_count,
-            voted_by_current_user: row.get::<i64, _>("voted_by_current_user") == 1,
-        });
     }
 
-    Ok(Poll {
-        id: poll_row.get("id"),
-        group_id: poll_row.get("group_id"),
-        creator_username: poll_row.get("creator_username"),
-        question: poll_row.get("question"),
-        created_at: poll_row.get("created_at"),
-        expires_at: poll_row.get("expires_at"),
-        is_active: poll_row.get("is_active"),
-        allow_multiple_choices: poll_row.get("allow_multiple_choices"),
-        options,
-        total_votes,
-    })
+    let slice = get_group_conversation_messages(&pool, group_id, &username, page).await;
+    Ok(warp::reply::with_status(warp::reply::json(&slice), warp::http::StatusCode::OK))
 }
 
 fn extract_username_from_auth(auth_header: String) -> Result<String, jsonwebtoken::errors::Error> {
-    let token = if auth_header.starts_with("Bearer ") {
-        &auth_header[7..]
-    } else {
-        return Err(jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken));
+    let token = match auth_header.strip_prefix("Bearer ") {
+        Some(token) => token,
+        None => return Err(jsonwebtoken::errors::Error::from(jsonwebtoken::errors::ErrorKind::InvalidToken)),
     };
 
     verify_jwt(token)
@@ -2114,6 +1194,8 @@ async fn handle_register(
             let response = AuthResponse {
                 message: "User registered successfully".to_string(),
                 token: None,
+                refresh_token: None,
+                expires_in: None,
             };
             Ok(warp::reply::with_status(
                 warp::reply::json(&response),
@@ -2143,6 +1225,7 @@ async fn handle_register(
 
 async fn handle_login(
     request: LoginRequest,
+    user_agent: Option<String>,
     pool: SqlitePool,
 ) -> Result<impl Reply, warp::Rejection> {
     // Get user from database
@@ -2162,27 +1245,16 @@ async fn handle_login(
                 .map_err(|_| warp::reject::reject())?;
             
             if argon2.verify_password(request.password.as_bytes(), &parsed_hash).is_ok() {
-                // Generate JWT
-                let expiration = Utc::now()
-                    .checked_add_signed(Duration::hours(24))
-                    .expect("valid timestamp")
-                    .timestamp() as usize;
-
-                let claims = Claims {
-                    sub: request.username.clone(),
-                    exp: expiration,
-                };
-
-                let token = encode(
-                    &Header::default(),
-                    &claims,
-                    &EncodingKey::from_secret(JWT_SECRET),
-                )
-                .map_err(|_| warp::reject::reject())?;
+                // Open a session: short-lived access token plus a refresh token
+                let tokens = auth::create_session(&pool, &request.username, user_agent.as_deref())
+                    .await
+                    .map_err(|_| warp::reject::reject())?;
 
                 let response = AuthResponse {
                     message: "Login successful".to_string(),
-                    token: Some(token),
+                    token: Some(tokens.token),
+                    refresh_token: Some(tokens.refresh_token),
+                    expires_in: Some(tokens.expires_in),
                 };
 
                 Ok(warp::reply::with_status(
@@ -2211,36 +1283,95 @@ async fn handle_login(
     }
 }
 
-async fn handle_users_list(
-    auth_header: String,
+/// Swap a refresh token for a new access token and refresh token.
+async fn handle_refresh(
+    request: RefreshRequest,
     pool: SqlitePool,
+    connections: Users,
 ) -> Result<impl Reply, warp::Rejection> {
-    // Extract token from Authorization header
-    let token = if auth_header.starts_with("Bearer ") {
-        &auth_header[7..]
-    } else {
-        return Ok(warp::reply::with_status(
-            warp::reply::json(&ErrorResponse {
-                error: "Invalid authorization header".to_string(),
+    match auth::refresh(&pool, &request.refresh_token).await {
+        Ok((_, tokens)) => Ok(warp::reply::with_status(
+            warp::reply::json(&AuthResponse {
+                message: "Token refreshed".to_string(),
+                token: Some(tokens.token),
+                refresh_token: Some(tokens.refresh_token),
+                expires_in: Some(tokens.expires_in),
             }),
+            warp::http::StatusCode::OK,
+        )),
+        Err(auth::RefreshError::Reused { username, session_id }) => {
+            connections.close_sessions(&username, Some(&session_id));
+            Ok(warp::reply::with_status(
+                warp::reply::json(&ErrorResponse { error: "Refresh token was already used; session revoked".to_string() }),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ))
+        }
+        Err(auth::RefreshError::Invalid) => Ok(warp::reply::with_status(
+            warp::reply::json(&ErrorResponse { error: "Invalid or expired refresh token".to_string() }),
             warp::http::StatusCode::UNAUTHORIZED,
-        ));
-    };
-
+        )),
+        Err(auth::RefreshError::Database(e)) => Ok(warp::reply::with_status(
+            warp::reply::json(&ErrorResponse { error: format!("Failed to refresh session: {}", e) }),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
 
-    // Add this line near the top of main.rs, just before the verify_jwt function:
+/// End the session the access token belongs to, or with `all`, every
+/// session of the user. Their open sockets are closed too.
+async fn handle_logout(
+    all: bool,
+    auth_header: String,
+    pool: SqlitePool,
+    connections: Users,
+) -> Result<impl Reply, warp::Rejection> {
+    let claims = match auth_header.strip_prefix("Bearer ").map(auth::verify_access_token) {
+        Some(Ok(claims)) => claims,
+        _ => {
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&ErrorResponse { error: "Invalid or expired token".to_string() }),
+                warp::http::StatusCode::UNAUTHORIZED,
+            ));
+        }
+    };
 
-pub fn verify_jwt(token: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let validation = Validation::new(Algorithm::HS256);
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(JWT_SECRET),
-        &validation,
-    )?;
-    Ok(token_data.claims.sub)
+    let result = if all {
+        auth::revoke_all_sessions(&pool, &claims.sub).await
+    } else {
+        auth::revoke_session(&pool, &claims.sid).await.map(|_| 1)
+    };
+    match result {
+        Ok(revoked) => {
+            let closed = connections.close_sessions(&claims.sub, if all { None } else { Some(&claims.sid) });
+            println!("DEBUG: {} logged out of {} session(s), closed {} socket(s)", claims.sub, revoked, closed);
+            Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "message": "Logged out", "sessions_revoked": revoked })),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        Err(e) => Ok(warp::reply::with_status(
+            warp::reply::json(&ErrorResponse { error: format!("Failed to log out: {}", e) }),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
 }
 
-
+async fn handle_users_list(
+    auth_header: String,
+    pool: SqlitePool,
+    connections: Users,
+) -> Result<impl Reply, warp::Rejection> {
+    // Extract token from Authorization header
+    let token = if let Some(token) = auth_header.strip_prefix("Bearer ") {
+        token
+    } else {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&ErrorResponse {
+                error: "Invalid authorization header".to_string(),
+            }),
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    };
 
     // Verify JWT token
     let current_username = match verify_jwt(token) {
@@ -2256,1491 +1387,1558 @@ pub fn verify_jwt(token: &str) -> Result<String, jsonwebtoken::errors::Error> {
     };
 
     // Get all users except the current user
-    let rows = sqlx::query("SELECT username FROM users WHERE username != ? ORDER BY username")
+    let rows = sqlx::query("SELECT username, last_seen_at FROM users WHERE username != ? ORDER BY username")
         .bind(&current_username)
         .fetch_all(&pool)
         .await
         .map_err(|_| warp::reject::reject())?;
 
-    let users: Vec<String> = rows
+    let peers: std::collections::HashSet<String> = presence_audience(&pool, &current_username)
+        .await
         .into_iter()
-        .map(|row| row.get("username"))
+        .map(|u| u.to_lowercase())
         .collect();
 
-    let response = UserListResponse { users };
+    let mut users = Vec::new();
+    let mut presence = HashMap::new();
+    for row in rows {
+        let username: String = row.get("username");
+        if peers.contains(&username.to_lowercase()) {
+            presence.insert(username.clone(), UserPresence {
+                status: presence::status_of(&connections, &username),
+                last_seen_at: row.get("last_seen_at"),
+            });
+        }
+        users.push(username);
+    }
+
+    let response = UserListResponse { users, presence };
     Ok(warp::reply::with_status(
         warp::reply::json(&response),
         warp::http::StatusCode::OK,
     ))
 }
 
-fn verify_jwt(token: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let validation = Validation::new(Algorithm::HS256);
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(JWT_SECRET),
-        &validation,
-    )?;
-    Ok(token_data.claims.sub)
+async fn store_message(
+    pool: &SqlitePool,
+    sender_username: &str,
+    receiver_username: &str,
+    message: &str,
+    timestamp: &str,
+    reveal_at: Option<&str>,
+    thread: Option<threads::Placement>,
+) -> Result<ChatMessage, sqlx::Error> {
+    // The next seq is computed inside the INSERT so concurrent writers can't share one.
+    // conversation_key is built as in the messages_conversation_key migration.
+    let row = sqlx::query(
+        "WITH pair(a, b) AS (SELECT lower(?), lower(?)),
+              conversation(key) AS (SELECT json_array(min(a, b), max(a, b)) FROM pair)
+         INSERT INTO messages (sender_username, receiver_username, message, timestamp, reveal_at, reply_to, thread_root, in_channel,
+                               conversation_key, seq)
+         SELECT ?, ?, ?, ?, ?, ?, ?, ?, conversation.key,
+                (SELECT COALESCE(MAX(seq), 0) + 1 FROM messages WHERE conversation_key = conversation.key)
+         FROM conversation
+         RETURNING id, seq"
+    )
+    .bind(sender_username)
+    .bind(receiver_username)
+    .bind(sender_username)
+    .bind(receiver_username)
+    .bind(message)
+    .bind(timestamp)
+    .bind(reveal_at)
+    .bind(thread.map(|t| t.reply_to))
+    .bind(thread.map(|t| t.thread_root))
+    .bind(thread.is_none_or(|t| t.in_channel))
+    .fetch_one(pool)
+    .await?;
+
+    Ok(ChatMessage {
+        id: row.get("id"),
+        group_id: None,
+        sender_username: sender_username.to_string(),
+        receiver_username: receiver_username.to_string(),
+        message: message.to_string(),
+        timestamp: timestamp.to_string(),
+        reactions: None,
+        reveal_at: reveal_at.map(str::to_string),
+        withheld: false,
+        seq: row.get("seq"),
+        delivered_at: None,
+        read_at: None,
+        read_by: None,
+        attachments: Vec::new(),
+        reply_to: thread.map(|t| t.reply_to),
+        thread_root: thread.map(|t| t.thread_root),
+        thread_only: thread.is_some_and(|t| !t.in_channel),
+        quoted: None,
+        thread: None,
+        poll_id: None,
+    })
 }
 
-async fn store_message(pool: &SqlitePool, sender_username: &str, receiver_username: &str, message: &str, timestamp: &str, reveal_at: Option<&str>) -> Result<i64, sqlx::Error> {
-    let _ = sqlx::query("INSERT INTO messages (sender_username, receiver_username, message, timestamp, reveal_at) VALUES (?, ?, ?, ?, ?)")
-        .bind(sender_username)
-        .bind(receiver_username)
-        .bind(message)
-        .bind(timestamp)
-        .bind(reveal_at)
-        .execute(pool)
-        .await?;
-    Ok(sqlx::query_scalar("SELECT last_insert_rowid()").fetch_one(pool).await?)
+/// Build a message from a `messages` or `group_messages` row, showing
+/// recalled messages as such and marking edited ones.
+fn chat_message_from_row(row: &sqlx::sqlite::SqliteRow, group_id: Option<i64>) -> ChatMessage {
+    let deleted: i64 = row.try_get("deleted").ok().flatten().unwrap_or(0);
+    let edited_at: Option<String> = row.try_get("edited_at").ok().flatten();
+    let mut msg_text: String = if deleted == 1 { "Message recalled by sender".to_string() } else { row.get("message") };
+    if deleted == 0 {
+        if let Some(ed) = &edited_at { if !ed.is_empty() { msg_text = format!("{} (edited)", msg_text); } }
+    }
+
+    ChatMessage {
+        id: row.get("id"),
+        group_id,
+        sender_username: row.get("sender_username"),
+        receiver_username: row.try_get("receiver_username").unwrap_or_default(),
+        message: msg_text,
+        timestamp: row.get("timestamp"),
+        reactions: None,
+        reveal_at: row.try_get("reveal_at").ok().flatten(),
+        withheld: false,
+        seq: row.try_get::<Option<i64>, _>("seq").ok().flatten().unwrap_or(0),
+        delivered_at: None,
+        read_at: None,
+        read_by: None,
+        attachments: Vec::new(),
+        reply_to: row.try_get("reply_to").ok().flatten(),
+        thread_root: row.try_get("thread_root").ok().flatten(),
+        thread_only: row.try_get::<Option<i64>, _>("in_channel").ok().flatten() == Some(0),
+        quoted: None,
+        thread: None,
+        poll_id: row.try_get("poll_id").ok().flatten(),
+    }
+}
+
+/// Which slice of a conversation's history to load: `before_id` pages back
+/// from a message, `after_id` forward from one, and with neither the newest
+/// page is returned. Used as the query string of the REST history routes.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+struct HistoryPage {
+    #[serde(default)]
+    before_id: Option<i64>,
+    #[serde(default)]
+    after_id: Option<i64>,
+    #[serde(default)]
+    limit: Option<i64>,
 }
 
-async fn get_conversation_messages(pool: &SqlitePool, user1: &str, user2: &str, limit: i32) -> Vec<ChatMessage> {
-    let rows = sqlx::query(
-        "SELECT id, sender_username, receiver_username, message, timestamp, group_id, deleted, edited_at, reveal_at FROM messages \n         WHERE (sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE) \n            OR (sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE) \n         ORDER BY id DESC LIMIT ?"
-    )
-    .bind(user1)
-    .bind(user2)
-    .bind(user2)
-    .bind(user1)
-    .bind(limit)
-    .fetch_all(pool)
-    .await
-    .unwrap_or_default();
+impl HistoryPage {
+    const DEFAULT_LIMIT: i64 = 50;
+    const MAX_LIMIT: i64 = 200;
+
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(Self::DEFAULT_LIMIT).clamp(1, Self::MAX_LIMIT)
+    }
+
+    /// Cursor conditions and ordering to append to a history query; binds
+    /// `before_id` twice, `after_id` twice, then the row limit.
+    fn sql(&self) -> &'static str {
+        if self.after_id.is_some() {
+            " AND (? IS NULL OR id < ?) AND (? IS NULL OR id > ?) ORDER BY id ASC LIMIT ?"
+        } else {
+            " AND (? IS NULL OR id < ?) AND (? IS NULL OR id > ?) ORDER BY id DESC LIMIT ?"
+        }
+    }
+
+    /// Trim the extra probe row, report whether it existed, and put the page
+    /// in chronological order.
+    fn finish<T>(&self, mut rows: Vec<T>) -> (Vec<T>, bool) {
+        let has_more = rows.len() as i64 > self.limit();
+        rows.truncate(self.limit() as usize);
+        if self.after_id.is_none() {
+            rows.reverse();
+        }
+        (rows, has_more)
+    }
+}
+
+/// Query string of `GET /messages`: the DM peer plus a [`HistoryPage`].
+#[derive(Debug, Deserialize)]
+struct DmHistoryQuery {
+    peer: String,
+    #[serde(default)]
+    before_id: Option<i64>,
+    #[serde(default)]
+    after_id: Option<i64>,
+    #[serde(default)]
+    limit: Option<i64>,
+}
+
+/// A page of history; `has_more` says whether older messages exist (or newer
+/// ones, when paging forward with `after_id`).
+#[derive(Debug, Serialize)]
+struct HistorySlice {
+    messages: Vec<ChatMessage>,
+    has_more: bool,
+}
+
+async fn get_conversation_messages(pool: &SqlitePool, user1: &str, user2: &str, page: HistoryPage) -> HistorySlice {
+    let sql = format!(
+        "SELECT id, sender_username, receiver_username, message, timestamp, group_id, deleted, edited_at, reveal_at, seq,
+                reply_to, thread_root, in_channel, poll_id FROM messages
+         WHERE ((sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE)
+             OR (sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE))
+           AND in_channel = 1{}",
+        page.sql()
+    );
+    let rows = sqlx::query(&sql)
+        .bind(user1)
+        .bind(user2)
+        .bind(user2)
+        .bind(user1)
+        .bind(page.before_id)
+        .bind(page.before_id)
+        .bind(page.after_id)
+        .bind(page.after_id)
+        .bind(page.limit() + 1)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    let (rows, has_more) = page.finish(rows);
 
     let mut messages: Vec<ChatMessage> = Vec::new();
     for row in rows {
-        let message_id: i64 = row.get("id");
-        let reactions_rows = sqlx::query("SELECT username, emoji FROM message_reactions WHERE message_id = ?")
+        let mut msg = chat_message_from_row(&row, row.try_get("group_id").ok().flatten());
+        msg.reactions = reactions_by_user(pool, MessageKind::Direct, msg.id).await;
+        attach_receipts(pool, &mut msg).await;
+        msg.attachments = uploads::for_message(pool, MessageKind::Direct, msg.id).await;
+        reveal::withhold(&mut msg, user1);
+        messages.push(msg);
+    }
+    threads::decorate(pool, threads::Conversation::Direct { user: user1, peer: user2 }, &mut messages, false).await;
+
+    HistorySlice { messages, has_more }
+}
+
+/// Usernames that may see a message.
+async fn message_audience(pool: &SqlitePool, kind: MessageKind, message_id: i64) -> Vec<String> {
+    match kind {
+        MessageKind::Direct => sqlx::query("SELECT sender_username, receiver_username FROM messages WHERE id = ?")
             .bind(message_id)
-            .fetch_all(pool)
+            .fetch_optional(pool)
             .await
-            .unwrap_or_default();
-
-        let mut reactions_map: HashMap<String, String> = HashMap::new();
-        for reaction_row in reactions_rows {
-            reactions_map.insert(reaction_row.get("username"), reaction_row.get("emoji"));
+            .unwrap_or(None)
+            .map(|row| vec![row.get("sender_username"), row.get("receiver_username")])
+            .unwrap_or_default(),
+        MessageKind::Group => {
+            match sqlx::query_scalar::<_, i64>("SELECT group_id FROM group_messages WHERE id = ?")
+                .bind(message_id)
+                .fetch_optional(pool)
+                .await
+            {
+                Ok(Some(group_id)) => handlers::groups::get_group_members(pool, group_id).await,
+                _ => Vec::new(),
+            }
         }
+    }
+}
+
+/// Each user's reaction to a message, for history.
+async fn reactions_by_user(pool: &SqlitePool, kind: MessageKind, message_id: i64) -> Option<HashMap<String, String>> {
+    let rows = sqlx::query("SELECT username, emoji FROM message_reactions WHERE message_kind = ? AND message_id = ?")
+        .bind(kind.as_str())
+        .bind(message_id)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    let reactions: HashMap<String, String> = rows.iter().map(|row| (row.get("username"), row.get("emoji"))).collect();
+    (!reactions.is_empty()).then_some(reactions)
+}
 
-        // Build message text reflecting edits/deletes
-        let deleted: i64 = row.try_get("deleted").unwrap_or(0);
-        let edited_at: Option<String> = row.try_get("edited_at").ok();
-        let mut msg_text: String = if deleted == 1 { "Message recalled by sender".to_string() } else { row.get("message") };
-        if deleted == 0 {
-            if let Some(ed) = &edited_at { if !ed.is_empty() { msg_text = format!("{} (edited)", msg_text); } }
+/// Push a stored chat message to the sockets of everyone in its conversation
+/// and record delivery receipts for the recipients that were online.
+async fn deliver_chat_message(users: &Users, pool: &SqlitePool, msg: ChatMessage) {
+    let message = [(msg.id, msg.sender_username.clone())];
+    let group_id = msg.group_id;
+    for recipient in push_chat_message(users, pool, msg.clone()).await {
+        if !recipient.eq_ignore_ascii_case(&message[0].1) {
+            record_receipts(users, pool, Receipt::Delivered, &recipient, group_id, &message).await;
         }
+    }
+    conversations::message_stored(users, pool, &msg).await;
+}
 
-        let reveal_at: Option<String> = row.try_get("reveal_at").ok();
-        messages.push(ChatMessage {
-            id: message_id,
-            group_id: row.try_get("group_id").ok(),
-            sender_username: row.get("sender_username"),
-            receiver_username: row.get("receiver_username"),
-            message: msg_text,
-            timestamp: row.get("timestamp"),
-            reactions: if reactions_map.is_empty() { None } else { Some(reactions_map) },
-            reveal_at,
-        });
+/// Send a chat message to its conversation without recording receipts.
+/// Returns the usernames that had at least one socket open.
+async fn push_chat_message(users: &Users, pool: &SqlitePool, mut msg: ChatMessage) -> Vec<String> {
+    let sender = msg.sender_username.clone();
+    let recipients = match msg.group_id {
+        Some(group_id) => {
+            // Mask sender for ghost groups
+            let ghost_flag = sqlx::query("SELECT ghost_mode FROM groups WHERE id = ?")
+                .bind(group_id)
+                .fetch_one(pool)
+                .await
+                .map(|r| r.get::<i32, _>("ghost_mode")).unwrap_or(0);
+            if ghost_flag != 0 {
+                msg.sender_username = "Anonymous".to_string();
+            }
+            handlers::groups::get_group_members(pool, group_id).await
+        }
+        None => vec![msg.sender_username.clone(), msg.receiver_username.clone()],
+    };
+    if !reveal::is_pending(msg.reveal_at.as_deref()) {
+        return users.send_to_users(recipients.iter().map(String::as_str), &ServerEvent::ChatMessage(msg));
     }
+    // Only the sender gets the content before reveal_at
+    let (own, others): (Vec<&String>, Vec<&String>) = recipients.iter().partition(|r| r.eq_ignore_ascii_case(&sender));
+    let mut online = users.send_to_users(own.into_iter().map(String::as_str), &ServerEvent::ChatMessage(msg.clone()));
+    reveal::hide(&mut msg);
+    online.extend(users.send_to_users(others.into_iter().map(String::as_str), &ServerEvent::ChatMessage(msg)));
+    online
+}
 
-    // Reverse to get chronological order (oldest first)
-    messages.reverse();
-    messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Receipt {
+    Delivered,
+    Read,
 }
 
-async fn handle_websocket(
-    websocket: WebSocket,
-    users: Users,
-    tx: broadcast::Sender<ChatMessage>,
-    params: HashMap<String, String>,
-    pool: SqlitePool,
+/// Record `username`'s receipt for messages of one conversation, given as
+/// `(message_id, sender)` pairs, and tell each sender which of their messages
+/// changed state. Receipts already on record are not announced again.
+async fn record_receipts(
+    users: &Users,
+    pool: &SqlitePool,
+    receipt: Receipt,
+    username: &str,
+    group_id: Option<i64>,
+    messages: &[(i64, String)],
 ) {
-    let (mut ws_tx, mut ws_rx) = websocket.split();
-    let mut rx = tx.subscribe();
-
-    // Extract and verify JWT token
-    let username = match params.get("token") {
-        Some(token) => match verify_jwt(token) {
-            Ok(username) => username,
-            Err(_) => {
-                let _ = ws_tx.send(Message::text(r#"{"error": "Invalid or expired token"}"#)).await;
-                return;
-            }
-        },
-        None => {
-            let _ = ws_tx.send(Message::text(r#"{"error": "Authentication required"}"#)).await;
-            return;
+    let kind = MessageKind::of(group_id).as_str();
+    let sql = match receipt {
+        Receipt::Delivered => {
+            "INSERT INTO message_receipts (message_kind, message_id, username, delivered_at, read_at)
+             VALUES (?, ?, ?, ?, NULL)
+             ON CONFLICT (message_kind, message_id, username) DO NOTHING"
+        }
+        Receipt::Read => {
+            "INSERT INTO message_receipts (message_kind, message_id, username, delivered_at, read_at)
+             VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT (message_kind, message_id, username)
+             DO UPDATE SET read_at = excluded.read_at, delivered_at = COALESCE(delivered_at, excluded.delivered_at)
+             WHERE read_at IS NULL"
         }
     };
 
-    println!("DEBUG: WebSocket connected for user: {}", username);
+    let now = get_current_time();
+    let mut changed: HashMap<String, (String, Vec<i64>)> = HashMap::new();
+    for (message_id, sender) in messages {
+        let affected = sqlx::query(sql)
+            .bind(kind)
+            .bind(message_id)
+            .bind(username)
+            .bind(&now)
+            .execute(pool)
+            .await
+            .map(|r| r.rows_affected())
+            .unwrap_or(0);
+        if affected > 0 {
+            changed
+                .entry(sender.to_lowercase())
+                .or_insert_with(|| (sender.clone(), Vec::new()))
+                .1
+                .push(*message_id);
+        }
+    }
 
-    let connection_id = format!(
-        "{}_{}",
-        username,
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() % 10000
-    );
+    for (sender, message_ids) in changed.into_values() {
+        let update = protocol::ReceiptUpdate {
+            username: username.to_string(),
+            group_id,
+            message_ids,
+            timestamp: now.clone(),
+        };
+        let event = match receipt {
+            Receipt::Delivered => ServerEvent::Delivered(update),
+            Receipt::Read => ServerEvent::Read(update),
+        };
+        users.send_to_user(&sender, &event);
+    }
+}
 
-    // Add user to users map
+/// Attach receipt state to history: the recipient's delivered/read times for
+/// DMs, the members who have read it for group messages.
+async fn attach_receipts(pool: &SqlitePool, msg: &mut ChatMessage) {
+    if msg.group_id.is_some() && msg.receiver_username.is_empty() {
+        let read_by: Vec<String> = sqlx::query_scalar(
+            "SELECT username FROM message_receipts
+             WHERE message_kind = 'group' AND message_id = ? AND read_at IS NOT NULL
+             ORDER BY read_at"
+        )
+        .bind(msg.id)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+        msg.read_by = Some(read_by);
+    } else if let Ok(Some(row)) = sqlx::query(
+        "SELECT delivered_at, read_at FROM message_receipts
+         WHERE message_kind = 'direct' AND message_id = ? AND username = ?"
+    )
+    .bind(msg.id)
+    .bind(&msg.receiver_username)
+    .fetch_optional(pool)
+    .await
     {
-        let mut users_lock = users.lock().await;
-        users_lock.insert(connection_id.clone(), User {
-            username: username.clone(),
-            sender: tx.clone(),
-        });
-        println!("DEBUG: Added user to connections. Total connections: {}", users_lock.len());
+        msg.delivered_at = row.get("delivered_at");
+        msg.read_at = row.get("read_at");
     }
+}
 
-    let tx_clone = tx.clone();
-    let username_clone = username.clone();
-    let ws_tx_clone = Arc::new(Mutex::new(ws_tx));
+/// Users who may see `username`'s presence: DM peers and group co-members.
+async fn presence_audience(pool: &SqlitePool, username: &str) -> Vec<String> {
+    sqlx::query_scalar(
+        "SELECT receiver_username FROM messages WHERE sender_username = ? COLLATE NOCASE
+         UNION SELECT sender_username FROM messages WHERE receiver_username = ? COLLATE NOCASE
+         UNION SELECT gm2.username FROM group_members gm1
+               JOIN group_members gm2 ON gm2.group_id = gm1.group_id
+               WHERE gm1.username = ? COLLATE NOCASE"
+    )
+    .bind(username)
+    .bind(username)
+    .bind(username)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default()
+    .into_iter()
+    .filter(|u: &String| !u.eq_ignore_ascii_case(username))
+    .collect()
+}
 
-    // Clone pool and users for incoming task
-    let pool_incoming = pool.clone();
-    let users_incoming = users.clone();
-    let ws_tx_for_incoming = ws_tx_clone.clone();
+/// Persist when the user was last active and tell their peers the new status.
+async fn announce_presence(
+    users: &Users,
+    pool: &SqlitePool,
+    username: &str,
+    status: Status,
+    last_active: chrono::DateTime<chrono::Utc>,
+) {
+    let last_seen_at = last_active.to_rfc3339();
+    let _ = sqlx::query("UPDATE users SET last_seen_at = ? WHERE username = ?")
+        .bind(&last_seen_at)
+        .bind(username)
+        .execute(pool)
+        .await;
+    println!("DEBUG: Presence of {} is now {:?}", username, status);
 
-    let incoming_task = tokio::spawn(async move {
-        while let Some(result) = ws_rx.next().await {
-            match result {
-                Ok(msg) => {
-                    // TEXT messages
-                    if let Ok(text) = msg.to_str() {
-                        println!("DEBUG: Received WebSocket message: {}", text);
-                        match serde_json::from_str::<IncomingMessage>(text) {
-                            Ok(incoming_msg) => {
-                                println!("DEBUG: Successfully parsed message type: {}", incoming_msg.message_type);
-                                match incoming_msg.message_type.as_str() {
-                                    "edit_message" => {
-                                        if let (Some(mid), Some(new_text)) = (incoming_msg.message_id, incoming_msg.message.clone()) {
-                                            let now = get_current_time();
-                                            // Try DM first
-                                            let res = sqlx::query("UPDATE messages SET message = ?, edited_at = ? WHERE id = ? AND sender_username = ? AND deleted = 0")
-                                                .bind(&new_text).bind(&now).bind(mid).bind(&username_clone)
-                                                .execute(&pool_incoming).await;
-                                            let mut affected = res.as_ref().map(|r| r.rows_affected()).unwrap_or(0);
-                                            if affected == 0 {
-                                                // Try group
-                                                let res2 = sqlx::query("UPDATE group_messages SET message = ?, edited_at = ? WHERE id = ? AND sender_username = ? AND deleted = 0")
-                                                    .bind(&new_text).bind(&now).bind(mid).bind(&username_clone)
-                                                    .execute(&pool_incoming).await;
-                                                affected = res2.as_ref().map(|r| r.rows_affected()).unwrap_or(0);
-                                                if affected > 0 {
-                                                    let payload = serde_json::json!({"type":"message_edited","message_id": mid, "group": true, "message": new_text, "edited_at": now});
-                                                    let _ = tx_clone.send(ChatMessage{ id:0, group_id: None, sender_username: "system".to_string(), receiver_username: username_clone.clone(), message: payload.to_string(), timestamp: now.clone(), reactions: None, reveal_at: None});
-                                                }
-                                            } else {
-                                                let payload = serde_json::json!({"type":"message_edited","message_id": mid, "group": false, "message": new_text, "edited_at": now});
-                                                let _ = tx_clone.send(ChatMessage{ id:0, group_id: None, sender_username: "system".to_string(), receiver_username: username_clone.clone(), message: payload.to_string(), timestamp: now.clone(), reactions: None, reveal_at: None});
-                                            }
-                                        }
-                                    }
-                                    "delete_message" => {
-                                        if let Some(mid) = incoming_msg.message_id {
-                                            let now = get_current_time();
-                                            let res = sqlx::query("UPDATE messages SET deleted = 1 WHERE id = ? AND sender_username = ? AND deleted = 0")
-                                                .bind(mid).bind(&username_clone)
-                                                .execute(&pool_incoming).await;
-                                            let mut affected = res.as_ref().map(|r| r.rows_affected()).unwrap_or(0);
-                                            if affected == 0 {
-                                                let res2 = sqlx::query("UPDATE group_messages SET deleted = 1 WHERE id = ? AND sender_username = ? AND deleted = 0")
-                                                    .bind(mid).bind(&username_clone)
-                                                    .execute(&pool_incoming).await;
-                                                affected = res2.as_ref().map(|r| r.rows_affected()).unwrap_or(0);
-                                                if affected > 0 {
-                                                    let payload = serde_json::json!({"type":"message_deleted","message_id": mid, "group": true});
-                                                    let _ = tx_clone.send(ChatMessage{ id:0, group_id: None, sender_username: "system".to_string(), receiver_username: username_clone.clone(), message: payload.to_string(), timestamp: now.clone(), reactions: None, reveal_at: None});
-                                                }
-                                            } else {
-                                                let payload = serde_json::json!({"type":"message_deleted","message_id": mid, "group": false});
-                                                let _ = tx_clone.send(ChatMessage{ id:0, group_id: None, sender_username: "system".to_string(), receiver_username: username_clone.clone(), message: payload.to_string(), timestamp: now.clone(), reactions: None, reveal_at: None});
-                                            }
-                                        }
-                                    }
-                                    "schedule_message" => {
-                                        // Validate input
-                                        if let (Some(msg_text), sched_at) = (incoming_msg.message.clone(), incoming_msg.scheduled_at.clone()) {
-                                            let now = get_current_time();
-                                            // One of receiver_username or group_id must be present
-                                            if incoming_msg.receiver_username.is_none() && incoming_msg.group_id.is_none() {
-                                                // Ack error
-                                                let ack = serde_json::json!({
-                                                    "type": "schedule_ack",
-                                                    "ok": false,
-                                                    "error": "Missing receiver or group_id"
-                                                });
-                                                if let Ok(json) = serde_json::to_string(&ack) {
-                                                    let mut ws = ws_tx_for_incoming.lock().await;
-                                                    let _ = ws.send(Message::text(json)).await;
-                                                }
-                                            } else {
-                                                // Prefer client-provided epoch (local time pick) if present
-                                                let sched_epoch: i64 = if let Some(ep) = incoming_msg.scheduled_at_epoch {
-                                                    ep
-                                                } else if let Some(sa) = sched_at.clone() {
-                                                    chrono::DateTime::parse_from_rfc3339(&sa).map(|dt| dt.timestamp()).unwrap_or_else(|_| chrono::Utc::now().timestamp() + 60)
-                                                } else {
-                                                    chrono::Utc::now().timestamp() + 60
-                                                };
-                                                // Compute ISO from epoch to store consistently
-                                                let sched_iso = chrono::DateTime::<chrono::Utc>::from_utc(chrono::NaiveDateTime::from_timestamp_opt(sched_epoch, 0).unwrap_or_else(|| chrono::NaiveDateTime::from_timestamp_opt(chrono::Utc::now().timestamp()+60,0).unwrap()), chrono::Utc).to_rfc3339();
-                                                // Insert schedule
-                                                let _ = sqlx::query(
-                                                    "INSERT INTO scheduled_messages (sender_username, receiver_username, group_id, message, scheduled_at, scheduled_at_epoch, sent, created_at) VALUES (?, ?, ?, ?, ?, ?, 0, ?)"
-                                                )
-                                                .bind(&username_clone)
-                                                .bind(incoming_msg.receiver_username.clone())
-                                                .bind(incoming_msg.group_id)
-                                                .bind(msg_text)
-                                                .bind(sched_iso)
-                                                .bind(sched_epoch)
-                                                .bind(now)
-                                                .execute(&pool_incoming)
-                                                .await;
-
-                                                let ack = serde_json::json!({
-                                                    "type": "schedule_ack",
-                                                    "ok": true,
-                                                    "scheduled_for_epoch": sched_epoch
-                                                });
-                                                if let Ok(json) = serde_json::to_string(&ack) {
-                                                    let mut ws = ws_tx_for_incoming.lock().await;
-                                                    let _ = ws.send(Message::text(json)).await;
-                                                }
-                                            }
-                                        }
-                                    }
-                                    // WebRTC signaling passthrough
-                                    "call_offer" | "call_answer" | "call_ice" | "call_end" | "call_need_offer" => {
-                                        if let Some(target) = incoming_msg.target_username {
-                                            // Wrap and send via broadcast; receiver filter will route by username
-                                            let payload = serde_json::json!({
-                                                "type": incoming_msg.message_type,
-                                                "from": username_clone,
-                                                "to": target,
-                                                "sdp": incoming_msg.sdp,
-                                                "candidate": incoming_msg.candidate,
-                                            });
-
-                                            let chat_msg = ChatMessage {
-                                                id: 0,
-                                                group_id: None,
-                                                sender_username: "system".to_string(),
-                                                receiver_username: target,
-                                                message: payload.to_string(),
-                                                timestamp: get_current_time(),
-                                                reactions: None,
-                                                reveal_at: None,
-                                            };
-                                            let _ = tx_clone.send(chat_msg);
-                                        }
-                                    }
-                                    "get_conversation" => {
-                                        if let Some(receiver_username) = incoming_msg.receiver_username {
-                                            println!("DEBUG: Getting conversation history for: {}", receiver_username);
-                                            let messages = get_conversation_messages(&pool_incoming, &username_clone, &receiver_username, 50).await;
-                                            
-                                            let history_response = ConversationHistoryResponse {
-                                                message_type: "conversation_history".to_string(),
-                                                conversation_with: receiver_username.clone(),
-                                                messages,
-                                            };
-                                            
-                                            if let Ok(json) = serde_json::to_string(&history_response) {
-                                                let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                                                let _ = ws_tx_lock.send(Message::text(json)).await;
-                                                println!("DEBUG: Sent conversation history for: {}", receiver_username);
-                                            }
-                                        }
-                                    }
-
-                                    "get_group_conversation" => {
-                                        if let Some(group_id) = incoming_msg.group_id {
-                                            println!("DEBUG: Getting group conversation history for group: {}", group_id);
-                                            let messages = get_group_conversation_messages(&pool_incoming, group_id, 50).await;
-                                            
-                                            let history_response = GroupHistoryResponse {
-                                                message_type: "group_conversation_history".to_string(),
-                                                group_id,
-                                                messages,
-                                            };
-                                            
-                                            if let Ok(json) = serde_json::to_string(&history_response) {
-                                                let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                                                let _ = ws_tx_lock.send(Message::text(json)).await;
-                                                println!("DEBUG: Sent group conversation history for group: {}", group_id);
-                                            }
-                                        }
-                                    }
-
-                                    "chat_message" => {
-                                        println!("DEBUG: Processing private chat message");
-                                        if let (Some(message_text), Some(receiver_username)) =
-                                            (incoming_msg.message, incoming_msg.receiver_username)
-                                        {
-                                            // Compute reveal_at
-                                            let reveal_at_iso = if let Some(ep) = incoming_msg.reveal_after_secs { Some(chrono::Utc::now() + chrono::Duration::seconds(ep)) } else if let Some(iso) = incoming_msg.reveal_at.clone() { chrono::DateTime::parse_from_rfc3339(&iso).ok().map(|dt| dt.with_timezone(&chrono::Utc)) } else { None };
-                                            let reveal_at_str = reveal_at_iso.map(|d| d.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
-                                            let message = ChatMessage {
-                                                id: 0,
-                                                sender_username: username_clone.clone(),
-                                                receiver_username: receiver_username.clone(),
-                                                group_id: None,
-                                                message: message_text.clone(),
-                                                timestamp: get_current_time(),
-                                                reactions: None,
-                                                reveal_at: reveal_at_str.clone(),
-                                            };
-
-                                            let message_id = store_message(
-                                                &pool_incoming,
-                                                &message.sender_username,
-                                                &message.receiver_username,
-                                                &message.message,
-                                                &message.timestamp,
-                                                reveal_at_str.as_deref()
-                                            ).await.unwrap_or(0);
-
-                                            let mut message_with_id = message.clone();
-                                            message_with_id.id = message_id;
-                                            let _ = tx_clone.send(message_with_id);
-                                            println!("DEBUG: Sent private message via broadcast");
-                                        }
-                                    }
-
-                                    "group_message" => {
-                                        println!("DEBUG: Received group message request");
-                                        if let (Some(group_id), Some(message_text)) = (incoming_msg.group_id, incoming_msg.message) {
-                                            println!("DEBUG: Group ID: {}, Message: {}", group_id, message_text);
-                                            let timestamp = get_current_time();
-                                            let reveal_at_iso = if let Some(ep) = incoming_msg.reveal_after_secs { Some(chrono::Utc::now() + chrono::Duration::seconds(ep)) } else if let Some(iso) = incoming_msg.reveal_at.clone() { chrono::DateTime::parse_from_rfc3339(&iso).ok().map(|dt| dt.with_timezone(&chrono::Utc)) } else { None };
-                                            let reveal_at_str = reveal_at_iso.map(|d| d.to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
-
-                                            // Store message in DB
-                                            let message_id = store_group_message(
-                                                &pool_incoming,
-                                                group_id,
-                                                &username_clone,
-                                                &message_text,
-                                                &timestamp,
-                                                reveal_at_str.as_deref()
-                                            ).await.unwrap_or(0);
-                                            
-                                            println!("DEBUG: Stored group message with ID: {}", message_id);
-
-                                            // Prepare chat message
-                                            let chat_msg = ChatMessage {
-                                                id: message_id,
-                                                sender_username: username_clone.clone(),
-                                                receiver_username: "".to_string(),
-                                                group_id: Some(group_id),
-                                                message: message_text,
-                                                timestamp,
-                                                reactions: None,
-                                                reveal_at: reveal_at_str,
-                                            };
-
-                                            // Just send via broadcast channel - don't manually send to individual users
-                                            println!("DEBUG: Sending group message via broadcast channel");
-                                            let _ = tx_clone.send(chat_msg);
-                                        } else {
-                                            println!("DEBUG: Missing group_id or message in group_message request");
-                                        }
-                                    }
-
-                                    "create_poll" => {
-                                        println!("DEBUG: Creating poll in group");
-                                        println!("DEBUG: group_id: {:?}", incoming_msg.group_id);
-                                        println!("DEBUG: poll_question: {:?}", incoming_msg.poll_question);
-                                        println!("DEBUG: poll_options: {:?}", incoming_msg.poll_options);
-                                        
-                                        if let (Some(group_id), Some(question), Some(options)) = 
-                                            (incoming_msg.group_id, incoming_msg.poll_question, incoming_msg.poll_options) {
-                                            
-                                            println!("DEBUG: Poll creation data validated");
-                                            
-                                            // Validate user is member of group
-                                            let member_check = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
-                                                .bind(group_id)
-                                                .bind(&username_clone)
-                                                .fetch_optional(&pool_incoming)
-                                                .await
-                                                .unwrap_or(None);
-                                                
-                                            if member_check.is_none() {
-                                                println!("DEBUG: User {} not member of group {}", username_clone, group_id);
-                                                continue;
-                                            }
-                                            
-                                            println!("DEBUG: User is member of group");
-                                            
-                                            if question.trim().is_empty() || options.len() < 2 || options.len() > 10 {
-                                                println!("DEBUG: Invalid poll data - question: '{}', options count: {}", question, options.len());
-                                                continue;
-                                            }
-                                            
-                                            println!("DEBUG: Poll data is valid");
-                                            
-                                            let created_at = get_current_time();
-                                            let allow_multiple = incoming_msg.poll_allow_multiple.unwrap_or(false);
-                                            
-                                            // Create poll
-                                            let poll_result = sqlx::query(
-                                                "INSERT INTO polls (group_id, creator_username, question, created_at, expires_at, allow_multiple_choices) 
-                                                 VALUES (?, ?, ?, ?, ?, ?)"
-                                            )
-                                            .bind(group_id)
-                                            .bind(&username_clone)
-                                            .bind(&question)
-                                            .bind(&created_at)
-                                            .bind(&incoming_msg.poll_expires_at)
-                                            .bind(allow_multiple)
-                                            .execute(&pool_incoming)
-                                            .await;
-                                            
-                                            match poll_result {
-                                                Ok(result) => {
-                                                    let poll_id = result.last_insert_rowid();
-                                                    println!("DEBUG: Poll created with ID: {}", poll_id);
-                                                    
-                                                    // Create poll options
-                                                    for (index, option_text) in options.iter().enumerate() {
-                                                        if !option_text.trim().is_empty() {
-                                                            let option_result = sqlx::query("INSERT INTO poll_options (poll_id, option_text, option_order) VALUES (?, ?, ?)")
-                                                                .bind(poll_id)
-                                                                .bind(option_text.trim())
-                                                                .bind(index as i32)
-                                                                .execute(&pool_incoming)
-                                                                .await;
-                                                            
-                                                            match option_result {
-                                                                Ok(_) => println!("DEBUG: Created option: {}", option_text),
-                                                                Err(e) => println!("DEBUG: Failed to create option: {:?}", e),
-                                                            }
-                                                        }
-                                                    }
-                                                    
-                                                    // Store the poll creation as a group message
-                                                    let poll_message_text = format!("📊 Poll created: {}", question);
-                                                    let message_result = store_group_message(
-                                                        &pool_incoming,
-                                                        group_id,
-                                                        &username_clone,
-                                                        &poll_message_text,
-                                                        &created_at,
-                                                        None
-                                                    ).await;
-                                                    
-                                                    match message_result {
-                                                        Ok(message_id) => {
-                                                            println!("DEBUG: Stored group message with ID: {}", message_id);
-                                                            
-                                                            // Broadcast poll creation as a group message
-                                                let poll_message = ChatMessage {
-                                                                id: poll_id, // Use poll_id for poll identification
-                                                                sender_username: username_clone.clone(),
-                                                                receiver_username: "".to_string(),
-                                                                group_id: Some(group_id),
-                                                                message: poll_message_text,
-                                                                timestamp: created_at,
-                                                    reactions: None,
-                                                    reveal_at: None,
-                                                            };
-                                                            
-                                                            println!("DEBUG: Broadcasting poll creation to group {}", group_id);
-                                                            match tx_clone.send(poll_message) {
-                                                                Ok(_) => println!("DEBUG: Poll message broadcast successfully"),
-                                                                Err(e) => println!("DEBUG: Failed to broadcast poll message: {:?}", e),
-                                                            }
-                                                        }
-                                                        Err(e) => println!("DEBUG: Failed to store group message: {:?}", e),
-                                                    }
-                                                }
-                                                Err(e) => println!("DEBUG: Failed to create poll in database: {:?}", e),
-                                            }
-                                        } else {
-                                            println!("DEBUG: Missing required poll data");
-                                        }
-                                    }
-
-                                    "vote_poll" => {
-                                        println!("DEBUG: Voting on poll");
-                                        if let (Some(poll_id), Some(option_ids)) = (incoming_msg.poll_id, incoming_msg.poll_option_ids) {
-                                            
-                                            // Get poll info and validate
-                                            let poll_check = sqlx::query(
-                                                "SELECT p.group_id, p.allow_multiple_choices, p.is_active, p.question, gm.username as member_check
-                                                 FROM polls p
-                                                 LEFT JOIN group_members gm ON p.group_id = gm.group_id AND gm.username = ?
-                                                 WHERE p.id = ?"
-                                            )
-                                            .bind(&username_clone)
-                                            .bind(poll_id)
-                                            .fetch_optional(&pool_incoming)
-                                            .await
-                                            .unwrap_or(None);
-                                            
-                                            if let Some(poll_row) = poll_check {
-                                                let group_id: i64 = poll_row.get("group_id");
-                                                let allow_multiple: bool = poll_row.get("allow_multiple_choices");
-                                                let is_active: bool = poll_row.get("is_active");
-                                                let question: String = poll_row.get("question");
-                                                let member_check: Option<String> = poll_row.try_get("member_check").ok().flatten();
-                                                
-                                                if member_check.is_none() {
-                                                    println!("DEBUG: User not member of group for voting");
-                                                    continue;
-                                                }
-                                                
-                                                if !is_active {
-                                                    println!("DEBUG: Poll not active");
-                                                    continue;
-                                                }
-                                                
-                                                if option_ids.is_empty() || (!allow_multiple && option_ids.len() > 1) {
-                                                    println!("DEBUG: Invalid vote options");
-                                                    continue;
-                                                }
-                                                
-                                                // Remove existing votes if not allowing multiple choices
-                                                if !allow_multiple {
-                                                    let _ = sqlx::query("DELETE FROM poll_votes WHERE poll_id = ? AND username = ?")
-                                                        .bind(poll_id)
-                                                        .bind(&username_clone)
-                                                        .execute(&pool_incoming)
-                                                        .await;
-                                                }
-                                                
-                                                let voted_at = get_current_time();
-                                                
-                                                // Add new votes
-                                                for option_id in option_ids {
-                                                    // Verify option belongs to this poll
-                                                    let option_check = sqlx::query("SELECT 1 FROM poll_options WHERE id = ? AND poll_id = ?")
-                                                        .bind(option_id)
-                                                        .bind(poll_id)
-                                                        .fetch_optional(&pool_incoming)
-                                                        .await
-                                                        .unwrap_or(None);
-                                                    
-                                                    if option_check.is_some() {
-                                                        let _ = sqlx::query("INSERT OR IGNORE INTO poll_votes (poll_id, option_id, username, voted_at) VALUES (?, ?, ?, ?)")
-                                                            .bind(poll_id)
-                                                            .bind(option_id)
-                                                            .bind(&username_clone)
-                                                            .bind(&voted_at)
-                                                            .execute(&pool_incoming)
-                                                            .await;
-                                                    }
-                                                }
-                                                
-                                                println!("DEBUG: Vote recorded, broadcasting update");
-                                                
-                                                // Store the vote update as a group message
-                                                let vote_message_text = format!("📊 Poll updated: {} voted on \"{}\"", username_clone, question);
-                                                let message_id = store_group_message(
-                                                    &pool_incoming,
-                                                    group_id,
-                                                    &username_clone,
-                                                    &vote_message_text,
-                                                    &voted_at,
-                                                    None
-                                                ).await.unwrap_or(0);
-                                                
-                                                // Broadcast poll update
-                                                let update_message = ChatMessage {
-                                                    id: poll_id, // Use poll_id for consistency
-                                                    sender_username: username_clone.clone(),
-                                                    receiver_username: "".to_string(),
-                                                    group_id: Some(group_id),
-                                                    message: vote_message_text,
-                                                    timestamp: voted_at,
-                                                    reactions: None,
-                                                    reveal_at: None,
-                                                };
-                                                
-                                                println!("DEBUG: Broadcasting poll vote update to group {}", group_id);
-                                                let _ = tx_clone.send(update_message);
-                                            }
-                                        }
-                                    }
-
-                                    "get_poll_details" => {
-                                        println!("DEBUG: Getting poll details");
-                                        if let Some(poll_id) = incoming_msg.poll_id {
-                                            // Get full poll details and send back to requesting user
-                                            let poll_details = get_poll_details(&pool_incoming, poll_id, &username_clone).await;
-                                            
-                                            if let Ok(poll_data) = poll_details {
-                                                let response = serde_json::json!({
-                                                    "type": "poll_details",
-                                                    "poll": poll_data
-                                                });
-                                                
-                                                if let Ok(json) = serde_json::to_string(&response) {
-                                                    let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                                                    let _ = ws_tx_lock.send(Message::text(json)).await;
-                                                }
-                                            }
-                                        }
-                                    }
-
-                                    "create_game" => {
-    println!("DEBUG: Creating game");
-    if let Some(game_type) = &incoming_msg.game_type {
-        println!("DEBUG: Game type: {}", game_type);
-        
-        let (conversation_type, conversation_id, target) = if let Some(group_id) = incoming_msg.group_id {
-            println!("DEBUG: Creating game in group {}", group_id);
-            
-            // Verify user is member of group
-            let member_check = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
+    let audience = presence_audience(pool, username).await;
+    users.send_to_users(audience.iter().map(String::as_str), &ServerEvent::Presence {
+        username: username.to_string(),
+        status,
+        last_seen_at: Some(last_seen_at),
+    });
+}
+
+/// Tell the other side of a DM, or the rest of a group, that `username`
+/// started or stopped typing. Ghost groups hide who it is.
+async fn send_typing_notice(users: &Users, pool: &SqlitePool, username: &str, scope: &TypingScope, typing: bool) {
+    let (audience, notice) = match scope {
+        TypingScope::Direct(peer) => (
+            vec![peer.clone()],
+            protocol::TypingNotice { username: username.to_string(), group_id: None },
+        ),
+        TypingScope::Group(group_id) => {
+            let ghost_flag: i32 = sqlx::query_scalar("SELECT ghost_mode FROM groups WHERE id = ?")
                 .bind(group_id)
-                .bind(&username_clone)
-                .fetch_optional(&pool_incoming)
+                .fetch_one(pool)
+                .await
+                .unwrap_or(0);
+            let members = handlers::groups::get_group_members(pool, *group_id)
+                .await
+                .into_iter()
+                .filter(|m| !m.eq_ignore_ascii_case(username))
+                .collect();
+            let shown_as = if ghost_flag != 0 { "Anonymous".to_string() } else { username.to_string() };
+            (members, protocol::TypingNotice { username: shown_as, group_id: Some(*group_id) })
+        }
+    };
+    let event = if typing { ServerEvent::TypingStart(notice) } else { ServerEvent::TypingStop(notice) };
+    users.send_to_users(audience.iter().map(String::as_str), &event);
+}
+
+/// Per-connection state shared by every command handler on one socket.
+struct WsSession {
+    username: String,
+    connection_id: ConnectionId,
+    pool: SqlitePool,
+    users: Users,
+    presence: Arc<PresenceTracker>,
+    games: Arc<GameRegistry>,
+}
+
+impl WsSession {
+    /// Send an event to this socket only.
+    fn send_event(&self, event: &ServerEvent) {
+        self.users.send_to_connection(&self.username, self.connection_id, event);
+    }
+
+    /// Note activity on this socket, announcing a return from away.
+    async fn touch(&self) {
+        self.users.touch(&self.username, self.connection_id);
+        let status = presence::status_of(&self.users, &self.username);
+        if self.presence.announce(&self.username, status) {
+            announce_presence(&self.users, &self.pool, &self.username, status, chrono::Utc::now()).await;
+        }
+    }
+
+    /// A game as this user may see it: its engine decides what a player, or
+    /// someone watching, is shown of the state.
+    fn game_view(&self, mut game: Game) -> Game {
+        if let Some(engine) = self.games.get(&game.game_type) {
+            let players = Players { first: &game.player1_username, second: game.player2_username.as_deref() };
+            game.game_state = engine.view(&game.game_state, players.seat_of(&self.username));
+        }
+        game
+    }
+
+    /// Check that this user holds `permission` in the group.
+    async fn require_group(&self, group_id: i64, permission: Permission) -> Result<Role, CommandError> {
+        roles::require(&self.pool, group_id, &self.username, permission)
+            .await
+            .map_err(|denied| match denied {
+                Denied::Database(e) => CommandError::internal(format!("Failed to check group role: {}", e)),
+                denied => CommandError::forbidden(denied.to_string()),
+            })
+    }
+}
+
+fn parse_reveal_at(reveal_after_secs: Option<i64>, reveal_at: Option<&str>) -> Option<String> {
+    let reveal_at_dt = if let Some(secs) = reveal_after_secs {
+        Some(chrono::Utc::now() + chrono::Duration::seconds(secs))
+    } else if let Some(iso) = reveal_at {
+        chrono::DateTime::parse_from_rfc3339(iso).ok().map(|dt| dt.with_timezone(&chrono::Utc))
+    } else {
+        None
+    };
+    reveal_at_dt.map(reveal::format)
+}
+
+async fn fetch_game(pool: &SqlitePool, game_id: i64) -> Result<Option<Game>, sqlx::Error> {
+    let row = sqlx::query(
+        "SELECT id, game_type, player1_username, player2_username, game_state, current_turn, status, winner, created_at, conversation_type, conversation_id
+         FROM games WHERE id = ?"
+    )
+    .bind(game_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| Game {
+        id: row.get("id"),
+        game_type: row.get("game_type"),
+        player1_username: row.get("player1_username"),
+        player2_username: row.get("player2_username"),
+        game_state: row.get("game_state"),
+        current_turn: row.get("current_turn"),
+        status: row.get("status"),
+        winner: row.get("winner"),
+        created_at: row.get("created_at"),
+        conversation_type: row.get("conversation_type"),
+        conversation_id: row.get("conversation_id"),
+    }))
+}
+
+/// A game `session`'s user may look at. Players can always see their game;
+/// group games are visible to members.
+async fn viewable_game(session: &WsSession, game_id: i64) -> Result<Game, CommandError> {
+    let game = fetch_game(&session.pool, game_id)
+        .await
+        .unwrap_or(None)
+        .ok_or_else(|| CommandError::not_found("Game not found"))?;
+
+    let username = session.username.as_str();
+    let is_player = game.player1_username == username || game.player2_username.as_deref() == Some(username);
+    let group_id = game.conversation_id.filter(|_| game.conversation_type == "group");
+    match group_id {
+        _ if is_player => {}
+        Some(group_id) => {
+            session.require_group(group_id, Permission::View).await?;
+        }
+        None => return Err(CommandError::forbidden("You cannot access this game")),
+    }
+    Ok(game)
+}
+
+/// Store a game announcement in the game's conversation and broadcast it.
+async fn post_game_message(
+    session: &WsSession,
+    group_id: Option<i64>,
+    receiver: Option<&str>,
+    text: String,
+) {
+    let timestamp = get_current_time();
+    let stored = if let Some(group_id) = group_id {
+        store_group_message(&session.pool, group_id, &session.username, &text, &timestamp, None, None).await
+    } else if let Some(receiver) = receiver {
+        store_message(&session.pool, &session.username, receiver, &text, &timestamp, None, None).await
+    } else {
+        return;
+    };
+    match stored {
+        Ok(msg) => deliver_chat_message(&session.users, &session.pool, msg).await,
+        Err(e) => println!("DEBUG: Failed to store game message: {:?}", e),
+    }
+}
+
+async fn handle_client_command(session: &WsSession, command: ClientCommand) -> Result<(), CommandError> {
+    let username = session.username.as_str();
+    let pool = &session.pool;
+
+    match command {
+        ClientCommand::EditMessage { message_id, kind, message } => {
+            let kind = own_message_kind(session, message_id, kind).await?;
+            let now = get_current_time();
+            let sql = format!("UPDATE {} SET message = ?, edited_at = ? WHERE id = ? AND sender_username = ? AND deleted = 0", kind.table());
+            let affected = sqlx::query(&sql)
+                .bind(&message).bind(&now).bind(message_id).bind(username)
+                .execute(pool).await
+                .map(|r| r.rows_affected()).unwrap_or(0);
+            if affected == 0 {
+                return Err(CommandError::not_found("No editable message with that id"));
+            }
+            let reveal_at: Option<String> = sqlx::query_scalar(&format!("SELECT reveal_at FROM {} WHERE id = ?", kind.table()))
+                .bind(message_id)
+                .fetch_one(pool)
                 .await
                 .unwrap_or(None);
-                
-            if member_check.is_none() {
-                println!("DEBUG: User {} not member of group {}", username_clone, group_id);
-                continue;
+            if reveal::is_pending(reveal_at.as_deref()) {
+                // Recipients keep the placeholder until the reveal brings the new text
+                session.users.send_to_user(username, &ServerEvent::MessageEdited { message_id, kind, message, edited_at: now });
+            } else {
+                let audience = message_audience(pool, kind, message_id).await;
+                session.users.send_to_users(audience.iter().map(String::as_str), &ServerEvent::MessageEdited { message_id, kind, message, edited_at: now });
+            }
+            conversations::message_changed(&session.users, pool, kind, message_id, false).await;
+        }
+
+        ClientCommand::DeleteMessage { message_id, kind } => {
+            let kind = own_message_kind(session, message_id, kind).await?;
+            let sql = format!("UPDATE {} SET deleted = 1 WHERE id = ? AND sender_username = ? AND deleted = 0", kind.table());
+            let affected = sqlx::query(&sql)
+                .bind(message_id).bind(username)
+                .execute(pool).await
+                .map(|r| r.rows_affected()).unwrap_or(0);
+            if affected == 0 {
+                return Err(CommandError::not_found("No deletable message with that id"));
+            }
+            let audience = message_audience(pool, kind, message_id).await;
+            session.users.send_to_users(audience.iter().map(String::as_str), &ServerEvent::MessageDeleted { message_id, kind });
+            conversations::message_changed(&session.users, pool, kind, message_id, true).await;
+        }
+
+        ClientCommand::ScheduleMessage { message, receiver_username, group_id, scheduled_at, scheduled_at_epoch, local_time, time_zone, recurrence } => {
+            // One of receiver_username or group_id must be present
+            if receiver_username.is_none() && group_id.is_none() {
+                session.send_event(&ServerEvent::ScheduleAck {
+                    ok: false,
+                    id: None,
+                    scheduled_for_epoch: None,
+                    error: Some("Missing receiver or group_id".to_string()),
+                });
+                return Ok(());
+            }
+            if let Some(group_id) = group_id {
+                session.require_group(group_id, Permission::Post).await?;
             }
-            
-            ("group".to_string(), Some(group_id), None)
-        } else if let Some(target_username) = &incoming_msg.target_username {
-            println!("DEBUG: Creating private game with {}", target_username);
-            ("private".to_string(), None, Some(target_username.as_str()))
-        } else {
-            // For cases where no explicit target is provided
-            println!("DEBUG: No valid target for game");
-            continue;
-        };
 
-        // For private games without explicit target, use current conversation
-        let actual_target = if conversation_type == "private" && target.is_none() {
-            // This should be handled by frontend, but adding safety
-            None
-        } else {
-            target
-        };
+            let timing = scheduled::Timing { scheduled_at, scheduled_at_epoch, local_time, time_zone, recurrence };
+            match scheduled::create(pool, username, receiver_username.as_deref(), group_id, &message, &timing).await {
+                Ok(entry) => session.send_event(&ServerEvent::ScheduleAck {
+                    ok: true,
+                    id: Some(entry.id),
+                    scheduled_for_epoch: Some(entry.scheduled_at_epoch),
+                    error: None,
+                }),
+                Err(scheduled::ScheduleError::Invalid(error)) => session.send_event(&ServerEvent::ScheduleAck {
+                    ok: false,
+                    id: None,
+                    scheduled_for_epoch: None,
+                    error: Some(error),
+                }),
+                Err(e) => return Err(e.into()),
+            }
+        }
 
-        match create_game(&pool_incoming, game_type, &username_clone, actual_target, &conversation_type, conversation_id).await {
-            Ok(game) => {
-                println!("DEBUG: Game created successfully: {:?}", game);
-                
-                let game_icon = match game_type.as_str() {
-                    "chess" => "♟️ Chess",
-                    "tictactoe" => "⭕ Tic-Tac-Toe", 
-                    "trivia" => "🧠 Trivia",
-                    _ => "Game"
-                };
-                
-                let game_message = if game.player2_username.is_some() {
-                    format!("🎮 {} game started! Game ID: {}", game_icon, game.id)
-                } else {
-                    format!("🎮 {} game created! Waiting for players. Game ID: {}", game_icon, game.id)
-                };
+        ClientCommand::ListScheduledMessages { include_finished } => {
+            let scheduled = scheduled::list(pool, username, include_finished)
+                .await
+                .map_err(|e| CommandError::internal(format!("Failed to load scheduled messages: {}", e)))?;
+            session.send_event(&ServerEvent::ScheduledMessages { scheduled });
+        }
 
-                let timestamp = get_current_time();
-                
-                if conversation_type == "group" && conversation_id.is_some() {
-                    let message_id = store_group_message(&pool_incoming, conversation_id.unwrap(), &username_clone, &game_message, &timestamp, None).await.unwrap_or(0);
-                    
-                    let chat_msg = ChatMessage {
-                        id: message_id,
-                        sender_username: username_clone.clone(),
-                        receiver_username: "".to_string(),
-                        group_id: conversation_id,
-                        message: game_message,
-                        timestamp,
-                        reactions: None,
-                        reveal_at: None,
-                    };
-                    let _ = tx_clone.send(chat_msg);
-                } else if let Some(target_user) = actual_target {
-                    let message_id = store_message(&pool_incoming, &username_clone, target_user, &game_message, &timestamp, None).await.unwrap_or(0);
-                    
-                    let chat_msg = ChatMessage {
-                        id: message_id,
-                        sender_username: username_clone.clone(),
-                        receiver_username: target_user.to_string(),
-                        group_id: None,
-                        message: game_message,
-                        timestamp,
-                        reactions: None,
-                        reveal_at: None,
-                    };
-                    let _ = tx_clone.send(chat_msg);
-                }
-                
-                // Send game creation confirmation
-                let game_response = serde_json::json!({
-                    "type": "game_created",
-                    "game": game
-                });
+        ClientCommand::UpdateScheduledMessage { id, changes } => {
+            let entry = scheduled::update(pool, username, id, &changes).await?;
+            session.users.send_to_user(username, &ServerEvent::ScheduledMessageUpdated(entry));
+        }
 
-                if let Ok(json) = serde_json::to_string(&game_response) {
-                    let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                    let _ = ws_tx_lock.send(Message::text(json)).await;
+        ClientCommand::CancelScheduledMessage { id } => {
+            let entry = scheduled::cancel(pool, username, id).await?;
+            session.users.send_to_user(username, &ServerEvent::ScheduledMessageUpdated(entry));
+        }
+
+        // WebRTC signaling passthrough, delivered to the target's sockets only
+        ClientCommand::CallOffer(req) => forward_call_signal(session, req, ServerEvent::CallOffer),
+        ClientCommand::CallAnswer(req) => forward_call_signal(session, req, ServerEvent::CallAnswer),
+        ClientCommand::CallIce(req) => forward_call_signal(session, req, ServerEvent::CallIce),
+        ClientCommand::CallEnd(req) => forward_call_signal(session, req, ServerEvent::CallEnd),
+        ClientCommand::CallNeedOffer(req) => forward_call_signal(session, req, ServerEvent::CallNeedOffer),
+
+        ClientCommand::GetConversation { receiver_username, before_id, after_id, limit } => {
+            println!("DEBUG: Getting conversation history for: {}", receiver_username);
+            let page = HistoryPage { before_id, after_id, limit };
+            let HistorySlice { messages, has_more } = get_conversation_messages(pool, username, &receiver_username, page).await;
+            session.send_event(&ServerEvent::ConversationHistory { conversation_with: receiver_username, messages, has_more, before_id, after_id });
+        }
+
+        ClientCommand::GetGroupConversation { group_id, before_id, after_id, limit } => {
+            println!("DEBUG: Getting group conversation history for group: {}", group_id);
+            session.require_group(group_id, Permission::View).await?;
+            let page = HistoryPage { before_id, after_id, limit };
+            let HistorySlice { messages, has_more } = get_group_conversation_messages(pool, group_id, username, page).await;
+            session.send_event(&ServerEvent::GroupConversationHistory { group_id, messages, has_more, before_id, after_id });
+        }
+
+        ClientCommand::GetThread { receiver_username, group_id, root_id, before_id, after_id, limit } => {
+            let conversation = match (group_id, receiver_username.as_deref()) {
+                (Some(group_id), _) => {
+                    session.require_group(group_id, Permission::View).await?;
+                    threads::Conversation::Group(group_id)
                 }
+                (None, Some(peer)) => threads::Conversation::Direct { user: username, peer },
+                (None, None) => return Err(CommandError::invalid("get_thread needs a receiver_username or group_id")),
+            };
+            let page = HistoryPage { before_id, after_id, limit };
+            let (root, HistorySlice { messages, has_more }) = get_thread_messages(pool, conversation, username, root_id, page)
+                .await
+                .ok_or_else(|| CommandError::not_found("No such thread in this conversation"))?;
+            session.send_event(&ServerEvent::Thread { root, messages, has_more, before_id, after_id });
+        }
+
+        ClientCommand::Read { receiver_username, group_id, up_to_seq } => {
+            // Everything from others up to `up_to_seq` that this user hasn't read yet
+            let rows = if let Some(group_id) = group_id {
+                session.require_group(group_id, Permission::View).await?;
+                sqlx::query(
+                    "SELECT id, sender_username FROM group_messages g
+                     WHERE group_id = ? AND seq <= ? AND sender_username != ? COLLATE NOCASE
+                       AND NOT EXISTS (SELECT 1 FROM message_receipts r
+                                       WHERE r.message_kind = 'group' AND r.message_id = g.id
+                                         AND r.username = ? AND r.read_at IS NOT NULL)"
+                )
+                .bind(group_id)
+                .bind(up_to_seq)
+                .bind(username)
+                .bind(username)
+                .fetch_all(pool)
+                .await
+            } else if let Some(peer) = receiver_username.as_deref() {
+                sqlx::query(
+                    "SELECT id, sender_username FROM messages m
+                     WHERE sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE AND seq <= ?
+                       AND NOT EXISTS (SELECT 1 FROM message_receipts r
+                                       WHERE r.message_kind = 'direct' AND r.message_id = m.id
+                                         AND r.username = ? AND r.read_at IS NOT NULL)"
+                )
+                .bind(peer)
+                .bind(username)
+                .bind(up_to_seq)
+                .bind(username)
+                .fetch_all(pool)
+                .await
+            } else {
+                return Err(CommandError::invalid("read needs a receiver_username or group_id"));
             }
-            Err(e) => {
-                println!("DEBUG: Failed to create game: {:?}", e);
-                
-                let error_response = serde_json::json!({
-                    "type": "game_error",
-                    "error": format!("Failed to create game: {:?}", e)
-                });
+            .map_err(|e| CommandError::internal(format!("Failed to load unread messages: {}", e)))?;
 
-                if let Ok(json) = serde_json::to_string(&error_response) {
-                    let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                    let _ = ws_tx_lock.send(Message::text(json)).await;
-                }
+            let unread: Vec<(i64, String)> = rows.iter().map(|row| (row.get("id"), row.get("sender_username"))).collect();
+            record_receipts(&session.users, pool, Receipt::Read, username, group_id, &unread).await;
+            if let Some(group_id) = group_id {
+                mentions::mark_read_up_to(pool, username, group_id, up_to_seq).await;
             }
+            let target = match (group_id, receiver_username) {
+                (Some(group_id), _) => conversations::Target::Group(group_id),
+                (None, peer) => conversations::Target::Direct(peer.unwrap_or_default()),
+            };
+            conversations::push(&session.users, pool, username, &target).await;
         }
-    } else {
-        println!("DEBUG: No game type specified");
-    }
-}
-"join_game" => {
-    println!("DEBUG: Joining game");
-    if let Some(game_id) = incoming_msg.game_id {
-        println!("DEBUG: Attempting to join game {}", game_id);
-        
-        // Check if game exists and is waiting for players
-        let game_check = sqlx::query(
-            "SELECT id, player1_username, player2_username, status, conversation_type, conversation_id 
-             FROM games WHERE id = ?"
-        )
-        .bind(game_id)
-        .fetch_optional(&pool_incoming)
-        .await
-        .unwrap_or(None);
-
-        if let Some(game_row) = game_check {
-            let player1: String = game_row.get("player1_username");
-            let player2: Option<String> = game_row.get("player2_username");
-            let status: String = game_row.get("status");
-            let conv_type: String = game_row.get("conversation_type");
-            let conv_id: Option<i64> = game_row.get("conversation_id");
-
-            if player1 == username_clone {
-                println!("DEBUG: Player trying to join their own game");
-                continue;
+
+        ClientCommand::GetConversations => {
+            let conversations = conversations::list(pool, username)
+                .await
+                .map_err(|e| CommandError::internal(format!("Failed to load conversations: {}", e)))?;
+            session.send_event(&ServerEvent::Conversations { conversations });
+        }
+
+        ClientCommand::TypingStart(target) => {
+            let scope = typing_scope(session, target).await?;
+            if session.presence.start_typing(username, scope.clone()) {
+                send_typing_notice(&session.users, pool, username, &scope, true).await;
             }
+        }
 
-            if player2.is_some() {
-                println!("DEBUG: Game already has two players");
-                continue;
+        ClientCommand::TypingStop(target) => {
+            let scope = typing_scope(session, target).await?;
+            if session.presence.stop_typing(username, scope.clone()) {
+                send_typing_notice(&session.users, pool, username, &scope, false).await;
             }
+        }
 
-            if status != "waiting" {
-                println!("DEBUG: Game is not waiting for players");
-                continue;
+        ClientCommand::Sync { direct, groups } => {
+            println!("DEBUG: Sync for {}: {} DMs, {} groups", username, direct.len(), groups.len());
+            for group_id in groups.keys().filter_map(|id| id.parse::<i64>().ok()) {
+                session.require_group(group_id, Permission::View).await?;
             }
+            replay_missed_messages(session, direct, groups)
+                .await
+                .map_err(|e| CommandError::internal(format!("Sync failed: {}", e)))?;
+        }
 
-            // For group games, verify user is member
-            if conv_type == "group" && conv_id.is_some() {
-                let member_check = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
-                    .bind(conv_id.unwrap())
-                    .bind(&username_clone)
-                    .fetch_optional(&pool_incoming)
-                    .await
-                    .unwrap_or(None);
-                    
-                if member_check.is_none() {
-                    println!("DEBUG: User {} not member of group for game", username_clone);
-                    continue;
+        ClientCommand::ChatMessage { receiver_username, message, reveal_after_secs, reveal_at, attachment_ids, reply_to, also_send_to_channel } => {
+            uploads::validate_for_message(pool, username, Some(&receiver_username), None, &attachment_ids)
+                .await
+                .map_err(CommandError::invalid)?;
+            let conversation = threads::Conversation::Direct { user: username, peer: &receiver_username };
+            let thread = match reply_to {
+                Some(reply_to) => Some(
+                    threads::place_reply(pool, conversation, reply_to, also_send_to_channel)
+                        .await
+                        .map_err(CommandError::invalid)?,
+                ),
+                None => None,
+            };
+            let reveal_at = parse_reveal_at(reveal_after_secs, reveal_at.as_deref());
+            let timestamp = get_current_time();
+            let mut msg = store_message(pool, username, &receiver_username, &message, &timestamp, reveal_at.as_deref(), thread)
+                .await
+                .map_err(|e| CommandError::internal(format!("Failed to store message: {}", e)))?;
+            uploads::link_to_message(pool, MessageKind::Direct, msg.id, &attachment_ids).await;
+            msg.attachments = uploads::for_message(pool, MessageKind::Direct, msg.id).await;
+            if thread.is_some() {
+                threads::decorate(pool, conversation, std::slice::from_mut(&mut msg), false).await;
+            }
+
+            deliver_chat_message(&session.users, pool, msg).await;
+        }
+
+        ClientCommand::GroupMessage { group_id, message, reveal_after_secs, reveal_at, attachment_ids, reply_to, also_send_to_channel } => {
+            session.require_group(group_id, Permission::Post).await?;
+            uploads::validate_for_message(pool, username, None, Some(group_id), &attachment_ids)
+                .await
+                .map_err(CommandError::invalid)?;
+            let conversation = threads::Conversation::Group(group_id);
+            let thread = match reply_to {
+                Some(reply_to) => Some(
+                    threads::place_reply(pool, conversation, reply_to, also_send_to_channel)
+                        .await
+                        .map_err(CommandError::invalid)?,
+                ),
+                None => None,
+            };
+            let reveal_at = parse_reveal_at(reveal_after_secs, reveal_at.as_deref());
+            let timestamp = get_current_time();
+            let mut msg = store_group_message(pool, group_id, username, &message, &timestamp, reveal_at.as_deref(), thread)
+                .await
+                .map_err(|e| CommandError::internal(format!("Failed to store group message: {}", e)))?;
+            uploads::link_to_message(pool, MessageKind::Group, msg.id, &attachment_ids).await;
+            msg.attachments = uploads::for_message(pool, MessageKind::Group, msg.id).await;
+            if thread.is_some() {
+                let ghost = is_ghost_group(pool, group_id).await;
+                threads::decorate(pool, conversation, std::slice::from_mut(&mut msg), ghost).await;
+            }
+
+            mentions::notify(&session.users, pool, &msg).await;
+            deliver_chat_message(&session.users, pool, msg).await;
+        }
+
+        ClientCommand::CreatePoll {
+            group_id,
+            receiver_username,
+            poll_question,
+            poll_options,
+            poll_allow_multiple,
+            poll_expires_at,
+            poll_type,
+            poll_anonymous,
+            poll_correct_option,
+        } => {
+            let new_poll = polls::NewPoll {
+                group_id,
+                receiver_username,
+                question: poll_question,
+                options: poll_options,
+                allow_multiple_choices: poll_allow_multiple.unwrap_or(false),
+                expires_at: poll_expires_at,
+                poll_type: poll_type.unwrap_or_default(),
+                anonymous: poll_anonymous.unwrap_or(false),
+                correct_option: poll_correct_option,
+            };
+            polls::create(&session.users, pool, username, &new_poll).await?;
+        }
+
+        ClientCommand::VotePoll { poll_id, poll_option_ids } => {
+            polls::vote(&session.users, pool, username, poll_id, &poll_option_ids).await?;
+        }
+
+        ClientCommand::GetPollDetails { poll_id } => {
+            let poll = polls::get(pool, username, poll_id).await?;
+            session.send_event(&ServerEvent::PollDetails { poll });
+        }
+
+        ClientCommand::ClosePoll { poll_id } => {
+            polls::close(&session.users, pool, username, poll_id).await?;
+        }
+
+        ClientCommand::GetQuizScores { group_id } => {
+            let scores = polls::quiz_scores(pool, username, group_id).await?;
+            session.send_event(&ServerEvent::QuizScores { group_id, scores });
+        }
+
+        ClientCommand::CreateGame { game_type, group_id, target_username } => {
+            let (conversation_type, target) = if let Some(group_id) = group_id {
+                session.require_group(group_id, Permission::StartGame).await?;
+                ("group", None)
+            } else if let Some(target_username) = target_username.as_deref() {
+                ("private", Some(target_username))
+            } else {
+                return Err(CommandError::invalid("create_game needs a group_id or target_username"));
+            };
+
+            let engine = session
+                .games
+                .get(&game_type)
+                .ok_or_else(|| CommandError::invalid(format!("Unknown game type: {}", game_type)))?;
+
+            match create_game(pool, engine, username, target, conversation_type, group_id).await {
+                Ok(game) => {
+                    let game_message = if game.player2_username.is_some() {
+                        format!("🎮 {} game started! Game ID: {}", engine.label(), game.id)
+                    } else {
+                        format!("🎮 {} game created! Waiting for players. Game ID: {}", engine.label(), game.id)
+                    };
+                    post_game_message(session, group_id, target, game_message).await;
+                    session.send_event(&ServerEvent::GameCreated { game: session.game_view(game) });
+                }
+                Err(e) => {
+                    println!("DEBUG: Failed to create game: {:?}", e);
+                    session.send_event(&ServerEvent::GameError { error: format!("Failed to create game: {}", e) });
                 }
             }
+        }
+
+        ClientCommand::JoinGame { game_id } => {
+            let game = fetch_game(pool, game_id)
+                .await
+                .unwrap_or(None)
+                .ok_or_else(|| CommandError::not_found("Game not found"))?;
+
+            if game.player1_username == username {
+                return Err(CommandError::invalid("You cannot join your own game"));
+            }
+            if game.player2_username.is_some() || game.status != "waiting" {
+                return Err(CommandError::invalid("Game is not waiting for players"));
+            }
 
-            let result = sqlx::query(
+            // Joining a group game announces it there, like starting one
+            let group_id = game.conversation_id.filter(|_| game.conversation_type == "group");
+            if let Some(group_id) = group_id {
+                session.require_group(group_id, Permission::StartGame).await?;
+            }
+
+            let affected = sqlx::query(
                 "UPDATE games SET player2_username = ?, status = 'active' WHERE id = ? AND player2_username IS NULL"
             )
-            .bind(&username_clone)
+            .bind(username)
             .bind(game_id)
-            .execute(&pool_incoming)
-            .await;
-
-            if result.is_ok() && result.unwrap().rows_affected() > 0 {
-                println!("DEBUG: Successfully joined game {}", game_id);
-                
-                let join_message = format!("🎮 {} joined the game! Game is now active.", username_clone);
-                let timestamp = get_current_time();
-
-                if conv_type == "group" && conv_id.is_some() {
-                    let message_id = store_group_message(&pool_incoming, conv_id.unwrap(), &username_clone, &join_message, &timestamp, None).await.unwrap_or(0);
-                    
-                    let chat_msg = ChatMessage {
-                        id: message_id,
-                        sender_username: username_clone.clone(),
-                        receiver_username: "".to_string(),
-                        group_id: conv_id,
-                        message: join_message,
-                        timestamp,
-                        reactions: None,
-                        reveal_at: None,
-                    };
-                    let _ = tx_clone.send(chat_msg);
-                } else {
-                    // For private games, send to both players
-                    let message_id = store_message(&pool_incoming, &username_clone, &player1, &join_message, &timestamp, None).await.unwrap_or(0);
-                    
-                    let chat_msg = ChatMessage {
-                        id: message_id,
-                        sender_username: username_clone.clone(),
-                        receiver_username: player1.clone(),
-                        group_id: None,
-                        message: join_message,
-                        timestamp,
-                        reactions: None,
-                        reveal_at: None,
-                    };
-                    let _ = tx_clone.send(chat_msg);
-                }
+            .execute(pool)
+            .await
+            .map(|r| r.rows_affected())
+            .unwrap_or(0);
 
-                // Send updated game state to both players
-                if let Ok(updated_game_row) = sqlx::query(
-                    "SELECT id, game_type, player1_username, player2_username, game_state, current_turn, status, winner, created_at, conversation_type, conversation_id
-                     FROM games WHERE id = ?"
-                )
-                .bind(game_id)
-                .fetch_one(&pool_incoming)
-                .await {
-                    
-                    let updated_game = Game {
-                        id: updated_game_row.get("id"),
-                        game_type: updated_game_row.get("game_type"),
-                        player1_username: updated_game_row.get("player1_username"),
-                        player2_username: updated_game_row.get("player2_username"),
-                        game_state: updated_game_row.get("game_state"),
-                        current_turn: updated_game_row.get("current_turn"),
-                        status: updated_game_row.get("status"),
-                        winner: updated_game_row.get("winner"),
-                        created_at: updated_game_row.get("created_at"),
-                        conversation_type: updated_game_row.get("conversation_type"),
-                        conversation_id: updated_game_row.get("conversation_id"),
-                    };
+            if affected == 0 {
+                return Err(CommandError::invalid("Failed to join game"));
+            }
 
-                    let game_update = serde_json::json!({
-                        "type": "game_joined",
-                        "game": updated_game
-                    });
+            let join_message = format!("🎮 {} joined the game! Game is now active.", username);
+            post_game_message(session, group_id, Some(&game.player1_username), join_message).await;
 
-                    if let Ok(json) = serde_json::to_string(&game_update) {
-                        let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                        let _ = ws_tx_lock.send(Message::text(json)).await;
-                    }
-                }
-            } else {
-                println!("DEBUG: Failed to join game {}", game_id);
+            if let Ok(Some(updated_game)) = fetch_game(pool, game_id).await {
+                session.send_event(&ServerEvent::GameJoined { game: session.game_view(updated_game) });
             }
-        } else {
-            println!("DEBUG: Game {} not found", game_id);
         }
-    }
-}
 
-"game_move" => {
-    println!("DEBUG: Processing game move");
-    if let (Some(game_id), Some(move_data)) = (incoming_msg.game_id, &incoming_msg.game_move) {
-        println!("DEBUG: Game ID: {}, Move: {}", game_id, move_data);
-        
-        match process_game_move(&pool_incoming, game_id, &username_clone, move_data).await {
-            Ok(updated_game) => {
-                println!("DEBUG: Game move processed successfully");
-                
-                let game_icon = match updated_game.game_type.as_str() {
-                    "chess" => "♟️ Chess",
-                    "tictactoe" => "⭕ Tic-Tac-Toe", 
-                    "trivia" => "🧠 Trivia",
-                    _ => "Game"
-                };
-                
-                let move_message = if updated_game.status == "finished" {
-                    if let Some(ref winner) = updated_game.winner {
-                        if winner == "draw" {
-                            format!("🎮 {} game #{} ended in a draw!", game_icon, game_id)
-                        } else {
-                            format!("🎮 {} game #{} finished! 🏆 {} wins!", game_icon, game_id, winner)
+        ClientCommand::GameMove { game_id, game_move } => {
+            // A player who has left a group game's group can no longer move in it
+            if let Ok(Some(game)) = fetch_game(pool, game_id).await {
+                if let Some(group_id) = game.conversation_id.filter(|_| game.conversation_type == "group") {
+                    session.require_group(group_id, Permission::View).await?;
+                }
+            }
+            match process_game_move(pool, &session.games, game_id, username, &game_move).await {
+                Ok(updated_game) => {
+                    let game_icon = session.games.label(&updated_game.game_type);
+                    let move_message = if updated_game.status == "finished" {
+                        match updated_game.winner.as_deref() {
+                            Some("draw") => format!("🎮 {} game #{} ended in a draw!", game_icon, game_id),
+                            Some(winner) => format!("🎮 {} game #{} finished! 🏆 {} wins!", game_icon, game_id, winner),
+                            None => format!("🎮 {} game #{} finished!", game_icon, game_id),
                         }
                     } else {
-                        format!("🎮 {} game #{} finished!", game_icon, game_id)
-                    }
-                } else {
-                    format!("🎮 {} made a move in {} game #{}", username_clone, game_icon, game_id)
-                };
-
-                let timestamp = get_current_time();
-                
-                if updated_game.conversation_type == "group" && updated_game.conversation_id.is_some() {
-                    let message_id = store_group_message(&pool_incoming, updated_game.conversation_id.unwrap(), &username_clone, &move_message, &timestamp, None).await.unwrap_or(0);
-                    
-                    let chat_msg = ChatMessage {
-                        id: message_id,
-                        sender_username: username_clone.clone(),
-                        receiver_username: "".to_string(),
-                        group_id: updated_game.conversation_id,
-                        message: move_message,
-                        timestamp,
-                        reactions: None,
-                        reveal_at: None,
+                        format!("🎮 {} made a move in {} game #{}", username, game_icon, game_id)
                     };
-                    let _ = tx_clone.send(chat_msg);
-                } else {
+
+                    let group_id = updated_game.conversation_id.filter(|_| updated_game.conversation_type == "group");
                     // For private games, send to the other player
-                    let other_player = if updated_game.player1_username == username_clone {
-                        updated_game.player2_username.as_ref().unwrap_or(&updated_game.player1_username)
+                    let other_player = if updated_game.player1_username == username {
+                        updated_game.player2_username.as_deref().unwrap_or(&updated_game.player1_username)
                     } else {
                         &updated_game.player1_username
                     };
-                    
-                    let message_id = store_message(&pool_incoming, &username_clone, other_player, &move_message, &timestamp, None).await.unwrap_or(0);
-                    
-                    let chat_msg = ChatMessage {
-                        id: message_id,
-                        sender_username: username_clone.clone(),
-                        receiver_username: other_player.clone(),
-                        group_id: None,
-                        message: move_message,
-                        timestamp,
-                        reactions: None,
-                        reveal_at: None,
-                    };
-                    let _ = tx_clone.send(chat_msg);
-                }
+                    post_game_message(session, group_id, Some(other_player), move_message).await;
 
-                // Send game state update
-                let game_update = serde_json::json!({
-                    "type": "game_update",
-                    "game": updated_game
-                });
-
-                if let Ok(json) = serde_json::to_string(&game_update) {
-                    let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                    let _ = ws_tx_lock.send(Message::text(json)).await;
+                    session.send_event(&ServerEvent::GameUpdate { game: session.game_view(updated_game) });
                 }
-            }
-            Err(e) => {
-                println!("DEBUG: Game move failed: {}", e);
-                
-                let error_response = serde_json::json!({
-                    "type": "game_error",
-                    "error": e
-                });
-
-                if let Ok(json) = serde_json::to_string(&error_response) {
-                    let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                    let _ = ws_tx_lock.send(Message::text(json)).await;
+                Err(e) => {
+                    println!("DEBUG: Game move failed: {}", e);
+                    session.send_event(&ServerEvent::GameError { error: e });
                 }
             }
         }
-    } else {
-        println!("DEBUG: Invalid game move request");
-    }
-}
 
-"get_game_state" => {
-    println!("DEBUG: Getting game state");
-    if let Some(game_id) = incoming_msg.game_id {
-        println!("DEBUG: Requesting state for game {}", game_id);
-        
-        let game_row = sqlx::query(
-            "SELECT id, game_type, player1_username, player2_username, game_state, current_turn, status, winner, created_at, conversation_type, conversation_id
-             FROM games WHERE id = ?"
-        )
-        .bind(game_id)
-        .fetch_optional(&pool_incoming)
-        .await
-        .unwrap_or(None);
-
-        if let Some(row) = game_row {
-            // Verify user is involved in this game
-            let player1: String = row.get("player1_username");
-            let player2: Option<String> = row.get("player2_username");
-            let conv_type: String = row.get("conversation_type");
-            let conv_id: Option<i64> = row.get("conversation_id");
-
-            let mut can_access = false;
+        ClientCommand::GetGameState { game_id } => {
+            let game = viewable_game(session, game_id).await?;
+            session.send_event(&ServerEvent::GameState { game: session.game_view(game) });
+        }
 
-            // Check if user is a player
-            if player1 == username_clone || player2.as_ref() == Some(&username_clone) {
-                can_access = true;
+        ClientCommand::GetGamePgn { game_id } => {
+            let game = viewable_game(session, game_id).await?;
+            if game.game_type != "chess" {
+                return Err(CommandError::invalid("Only chess games have PGN"));
             }
+            let move_data: Vec<String> = sqlx::query_scalar("SELECT move_data FROM game_moves WHERE game_id = ? ORDER BY id")
+                .bind(game_id)
+                .fetch_all(pool)
+                .await
+                .map_err(|e| CommandError::internal(format!("Failed to load moves: {}", e)))?;
+            let date = chrono::DateTime::parse_from_rfc3339(&game.created_at)
+                .map(|at| at.format("%Y.%m.%d").to_string())
+                .unwrap_or_else(|_| "????.??.??".to_string());
+            let pgn = games::chess::pgn(
+                &format!("Chess game #{}", game_id),
+                &date,
+                &game.player1_username,
+                game.player2_username.as_deref().unwrap_or("?"),
+                &game.game_state,
+                &move_data,
+            )
+            .map_err(CommandError::invalid)?;
+            session.send_event(&ServerEvent::GamePgn { game_id, pgn });
+        }
 
-            // For group games, check if user is a member
-            if conv_type == "group" && conv_id.is_some() {
-                let member_check = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
-                    .bind(conv_id.unwrap())
-                    .bind(&username_clone)
-                    .fetch_optional(&pool_incoming)
-                    .await
-                    .unwrap_or(None);
-                    
-                if member_check.is_some() {
-                    can_access = true;
-                }
-            }
+        ClientCommand::AddReaction { message_id, kind, emoji } => {
+            let kind = authorize_message(session, message_id, kind, Permission::View).await?;
+            sqlx::query(
+                "INSERT OR IGNORE INTO message_reactions (message_kind, message_id, username, emoji, created_at)
+                 VALUES (?, ?, ?, ?, ?)"
+            )
+            .bind(kind.as_str())
+            .bind(message_id)
+            .bind(username)
+            .bind(&emoji)
+            .bind(get_current_time())
+            .execute(pool)
+            .await
+            .map_err(|e| CommandError::internal(format!("Failed to add reaction: {}", e)))?;
 
-            if can_access {
-                let game = Game {
-                    id: row.get("id"),
-                    game_type: row.get("game_type"),
-                    player1_username: row.get("player1_username"),
-                    player2_username: row.get("player2_username"),
-                    game_state: row.get("game_state"),
-                    current_turn: row.get("current_turn"),
-                    status: row.get("status"),
-                    winner: row.get("winner"),
-                    created_at: row.get("created_at"),
-                    conversation_type: row.get("conversation_type"),
-                    conversation_id: row.get("conversation_id"),
-                };
+            let audience = message_audience(pool, kind, message_id).await;
+            session.users.send_to_users(audience.iter().map(String::as_str), &ServerEvent::ReactionAdded { message_id, kind, username: username.to_string(), emoji });
+        }
 
-                let game_response = serde_json::json!({
-                    "type": "game_state",
-                    "game": game
-                });
+        ClientCommand::RemoveReaction { message_id, kind, emoji } => {
+            let kind = authorize_message(session, message_id, kind, Permission::View).await?;
+            sqlx::query("DELETE FROM message_reactions WHERE message_kind = ? AND message_id = ? AND username = ? AND emoji = ?")
+                .bind(kind.as_str())
+                .bind(message_id)
+                .bind(username)
+                .bind(&emoji)
+                .execute(pool)
+                .await
+                .map_err(|e| CommandError::internal(format!("Failed to remove reaction: {}", e)))?;
 
-                if let Ok(json) = serde_json::to_string(&game_response) {
-                    let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-                    let _ = ws_tx_lock.send(Message::text(json)).await;
-                }
-            } else {
-                println!("DEBUG: User {} cannot access game {}", username_clone, game_id);
-            }
-        } else {
-            println!("DEBUG: Game {} not found", game_id);
+            let audience = message_audience(pool, kind, message_id).await;
+            session.users.send_to_users(audience.iter().map(String::as_str), &ServerEvent::ReactionRemoved { message_id, kind, username: username.to_string(), emoji });
         }
-    }
-}
 
-"add_reaction" => {
-    if let (Some(message_id), Some(emoji)) = (incoming_msg.message_id, incoming_msg.emoji.as_ref()) {
-        let timestamp = Utc::now().to_rfc3339();
+        ClientCommand::PinMessage { message_id, kind } => {
+            let kind = authorize_message(session, message_id, kind, Permission::Pin).await?;
+            sqlx::query(
+                "INSERT OR IGNORE INTO pinned_messages (message_kind, message_id, pinned_by, pinned_at)
+                 VALUES (?, ?, ?, ?)"
+            )
+            .bind(kind.as_str())
+            .bind(message_id)
+            .bind(username)
+            .bind(get_current_time())
+            .execute(pool)
+            .await
+            .map_err(|e| CommandError::internal(format!("Failed to pin message: {}", e)))?;
 
-        // Add reaction to database
-        let result = sqlx::query(
-            "INSERT OR IGNORE INTO message_reactions (message_id, username, emoji, created_at)
-             VALUES (?, ?, ?, ?)"
-        )
-        .bind(message_id)
-        .bind(&username_clone)
-        .bind(emoji)
-        .bind(&timestamp)
-        .execute(&pool_incoming)
-        .await;
+            let audience = message_audience(pool, kind, message_id).await;
+            session.users.send_to_users(audience.iter().map(String::as_str), &ServerEvent::MessagePinned { message_id, kind, pinned_by: username.to_string() });
+        }
 
-        match result {
-            Ok(_) => {
-                println!("DEBUG: Reaction added successfully, broadcasting to all users");
-                // Broadcast reaction to all connected users
-                let reaction_msg = serde_json::json!({
-                    "type": "reaction_added",
-                    "message_id": message_id,
-                    "username": username_clone,
-                    "emoji": emoji
-                });
+        ClientCommand::UnpinMessage { message_id, kind } => {
+            let kind = authorize_message(session, message_id, kind, Permission::Pin).await?;
+            sqlx::query("DELETE FROM pinned_messages WHERE message_kind = ? AND message_id = ?")
+                .bind(kind.as_str())
+                .bind(message_id)
+                .execute(pool)
+                .await
+                .map_err(|e| CommandError::internal(format!("Failed to unpin message: {}", e)))?;
 
-                if let Ok(json) = serde_json::to_string(&reaction_msg) {
-                    println!("DEBUG: Serialized reaction message: {}", json);
-                    let users_lock = users_incoming.lock().await;
-                    println!("DEBUG: Broadcasting to {} users", users_lock.len());
-                    for (connection_id, user) in users_lock.iter() {
-                        println!("DEBUG: Sending reaction to connection: {} (user: {})", connection_id, user.username);
-                        let send_result = user.sender.send(ChatMessage {
-                            id: 0,
-                            group_id: None,
-                            sender_username: "system".to_string(),
-                            receiver_username: user.username.clone(),
-                            message: json.clone(),
-                            timestamp: timestamp.clone(),
-                            reactions: None,
-                            reveal_at: None,
-                        });
-                        if send_result.is_err() {
-                            println!("DEBUG: Failed to send reaction to {}", user.username);
-                        } else {
-                            println!("DEBUG: Successfully queued reaction for {}", user.username);
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                println!("DEBUG: Failed to add reaction to database: {:?}", e);
-            }
+            let audience = message_audience(pool, kind, message_id).await;
+            session.users.send_to_users(audience.iter().map(String::as_str), &ServerEvent::MessageUnpinned { message_id, kind });
         }
-    }
-}
-
-"remove_reaction" => {
-    if let (Some(message_id), Some(emoji)) = (incoming_msg.message_id, incoming_msg.emoji.as_ref()) {
-        // Remove reaction from database
-        let result = sqlx::query(
-            "DELETE FROM message_reactions WHERE message_id = ? AND username = ? AND emoji = ?"
-        )
-        .bind(message_id)
-        .bind(&username_clone)
-        .bind(emoji)
-        .execute(&pool_incoming)
-        .await;
 
-        if result.is_ok() {
-            // Broadcast reaction removal to all connected users
-            let reaction_msg = serde_json::json!({
-                "type": "reaction_removed",
-                "message_id": message_id,
-                "username": username_clone,
-                "emoji": emoji
-            });
+        ClientCommand::GetReactions { message_id, kind } => {
+            let kind = authorize_message(session, message_id, kind, Permission::View).await?;
+            let reactions_rows = sqlx::query("SELECT username, emoji FROM message_reactions WHERE message_kind = ? AND message_id = ?")
+                .bind(kind.as_str())
+                .bind(message_id)
+                .fetch_all(pool)
+                .await
+                .unwrap_or_default();
 
-            if let Ok(json) = serde_json::to_string(&reaction_msg) {
-                let users_lock = users_incoming.lock().await;
-                for (user_name, user) in users_lock.iter() {
-                    let _ = user.sender.send(ChatMessage {
-                        id: 0,
-                        group_id: None,
-                        sender_username: "system".to_string(),
-                        receiver_username: user_name.clone(),
-                        message: json.clone(),
-                        timestamp: Utc::now().to_rfc3339(),
-                        reactions: None,
-                        reveal_at: None,
-                    });
-                }
+            let mut reactions: HashMap<String, Vec<String>> = HashMap::new();
+            for row in reactions_rows {
+                let emoji: String = row.get("emoji");
+                reactions.entry(emoji).or_default().push(row.get("username"));
             }
+
+            session.send_event(&ServerEvent::ReactionsList { message_id, kind, reactions });
+        }
+
+        ClientCommand::GetPinnedMessages => {
+            // Only pins in this user's DMs and groups
+            let pinned_rows = sqlx::query(
+                "SELECT p.message_kind, p.message_id, p.pinned_by, p.pinned_at FROM pinned_messages p
+                 WHERE (p.message_kind = 'direct'
+                        AND EXISTS (SELECT 1 FROM messages m WHERE m.id = p.message_id
+                                      AND (m.sender_username = ? COLLATE NOCASE OR m.receiver_username = ? COLLATE NOCASE)))
+                    OR (p.message_kind = 'group'
+                        AND EXISTS (SELECT 1 FROM group_messages g
+                                    JOIN group_members gm ON gm.group_id = g.group_id AND gm.username = ?
+                                    WHERE g.id = p.message_id))
+                 ORDER BY p.pinned_at DESC"
+            )
+                .bind(username)
+                .bind(username)
+                .bind(username)
+                .fetch_all(pool)
+                .await
+                .unwrap_or_default();
+
+            let pinned_messages: Vec<serde_json::Value> = pinned_rows.iter().map(|row| {
+                serde_json::json!({
+                    "kind": row.get::<String, _>("message_kind"),
+                    "message_id": row.get::<i64, _>("message_id"),
+                    "pinned_by": row.get::<String, _>("pinned_by"),
+                    "pinned_at": row.get::<String, _>("pinned_at")
+                })
+            }).collect();
+
+            session.send_event(&ServerEvent::PinnedMessagesList { pinned_messages });
         }
     }
+
+    Ok(())
 }
 
-"pin_message" => {
-    if let Some(message_id) = incoming_msg.message_id {
-        let timestamp = Utc::now().to_rfc3339();
+/// Whether this user is either side of a DM message.
+async fn in_direct_message(session: &WsSession, message_id: i64) -> bool {
+    sqlx::query(
+        "SELECT 1 FROM messages WHERE id = ? AND (sender_username = ? COLLATE NOCASE OR receiver_username = ? COLLATE NOCASE)"
+    )
+    .bind(message_id)
+    .bind(&session.username)
+    .bind(&session.username)
+    .fetch_optional(&session.pool)
+    .await
+    .unwrap_or(None)
+    .is_some()
+}
 
-        // Add pin to database
-        let result = sqlx::query(
-            "INSERT OR IGNORE INTO pinned_messages (message_id, pinned_by, pinned_at)
-             VALUES (?, ?, ?)"
-        )
+/// The group of a group message, if there is one with that id.
+async fn group_of_message(pool: &SqlitePool, message_id: i64) -> Option<i64> {
+    sqlx::query_scalar("SELECT group_id FROM group_messages WHERE id = ?")
         .bind(message_id)
-        .bind(&username_clone)
-        .bind(&timestamp)
-        .execute(&pool_incoming)
-        .await;
-
-        if result.is_ok() {
-            // Broadcast pin to all connected users
-            let pin_msg = serde_json::json!({
-                "type": "message_pinned",
-                "message_id": message_id,
-                "pinned_by": username_clone
-            });
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)
+}
+
+/// Check that this user may act on a message and settle which one it is:
+/// either side of a DM always may; in a group it takes `permission`. Without
+/// a `kind` the id must name only one message the user can see.
+async fn authorize_message(
+    session: &WsSession,
+    message_id: i64,
+    kind: Option<MessageKind>,
+    permission: Permission,
+) -> Result<MessageKind, CommandError> {
+    let direct = kind != Some(MessageKind::Group) && in_direct_message(session, message_id).await;
+    let group = match kind {
+        Some(MessageKind::Direct) => None,
+        // Only groups the user can see count towards ambiguity
+        None => match group_of_message(&session.pool, message_id).await {
+            Some(group_id) if roles::require(&session.pool, group_id, &session.username, Permission::View).await.is_ok() => Some(group_id),
+            _ => None,
+        },
+        Some(MessageKind::Group) => group_of_message(&session.pool, message_id).await,
+    };
+    match (direct, group) {
+        (true, Some(_)) => Err(CommandError::invalid(format!(
+            "Message id {} is both a direct and a group message; say which with kind",
+            message_id
+        ))),
+        (true, None) => Ok(MessageKind::Direct),
+        (false, Some(group_id)) => {
+            session.require_group(group_id, permission).await?;
+            Ok(MessageKind::Group)
+        }
+        (false, None) => Err(CommandError::not_found("Message not found")),
+    }
+}
+
+/// Settle which of the user's own messages an edit or recall is about.
+/// Former members can no longer touch what they posted in a group.
+async fn own_message_kind(session: &WsSession, message_id: i64, kind: Option<MessageKind>) -> Result<MessageKind, CommandError> {
+    let sent = |table: &'static str| async move {
+        let sql = format!("SELECT 1 FROM {} WHERE id = ? AND sender_username = ?", table);
+        sqlx::query(&sql)
+            .bind(message_id)
+            .bind(&session.username)
+            .fetch_optional(&session.pool)
+            .await
+            .unwrap_or(None)
+            .is_some()
+    };
+    let kind = match kind {
+        Some(kind) => kind,
+        None => match (sent(MessageKind::Direct.table()).await, sent(MessageKind::Group.table()).await) {
+            (true, true) => {
+                return Err(CommandError::invalid(format!(
+                    "You sent both a direct and a group message with id {}; say which with kind",
+                    message_id
+                )));
+            }
+            (false, true) => MessageKind::Group,
+            _ => MessageKind::Direct,
+        },
+    };
+    // Outsiders are refused before learning whether the message is theirs
+    if kind == MessageKind::Group {
+        if let Some(group_id) = group_of_message(&session.pool, message_id).await {
+            session.require_group(group_id, Permission::View).await?;
+        }
+    }
+    Ok(kind)
+}
+
+/// Most messages one `sync` replays per conversation.
+const SYNC_REPLAY_LIMIT: i64 = 200;
+
+/// Answer a `sync` frame: send every message stored after the client's
+/// last-seen seq as a regular `chat_message`, oldest first, then report the
+/// current head of each conversation. Conversations the client did not name
+/// are not replayed; their heads tell it what exists.
+async fn replay_missed_messages(
+    session: &WsSession,
+    direct: HashMap<String, i64>,
+    groups: HashMap<String, i64>,
+) -> Result<(), sqlx::Error> {
+    let username = session.username.as_str();
+    let pool = &session.pool;
+    let mut truncated = false;
+
+    let dm_heads: HashMap<String, i64> = sqlx::query(
+        "SELECT CASE WHEN sender_username = ? COLLATE NOCASE THEN receiver_username ELSE sender_username END AS peer,
+                MAX(seq) AS head
+         FROM messages
+         WHERE sender_username = ? COLLATE NOCASE OR receiver_username = ? COLLATE NOCASE
+         GROUP BY lower(peer)"
+    )
+    .bind(username)
+    .bind(username)
+    .bind(username)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.get("peer"), row.try_get::<Option<i64>, _>("head").ok().flatten().unwrap_or(0)))
+    .collect();
+
+    let group_heads: HashMap<i64, i64> = sqlx::query(
+        "SELECT gm.group_id, COALESCE(MAX(g.seq), 0) AS head
+         FROM group_members gm
+         LEFT JOIN group_messages g ON g.group_id = gm.group_id
+         WHERE gm.username = ?
+         GROUP BY gm.group_id"
+    )
+    .bind(username)
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| (row.get("group_id"), row.get("head")))
+    .collect();
+
+    for (peer, last_seq) in direct {
+        let rows = sqlx::query(
+            "SELECT id, sender_username, receiver_username, message, timestamp, deleted, edited_at, reveal_at, seq,
+                    reply_to, thread_root, in_channel, poll_id FROM messages
+             WHERE ((sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE)
+                 OR (sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE))
+               AND seq > ?
+             ORDER BY seq LIMIT ?"
+        )
+        .bind(username)
+        .bind(&peer)
+        .bind(&peer)
+        .bind(username)
+        .bind(last_seq)
+        .bind(SYNC_REPLAY_LIMIT + 1)
+        .fetch_all(pool)
+        .await?;
 
-            if let Ok(json) = serde_json::to_string(&pin_msg) {
-                let users_lock = users_incoming.lock().await;
-                for user in users_lock.values() {
-                    let _ = user.sender.send(ChatMessage {
-                        id: 0,
-                        group_id: None,
-                        sender_username: "system".to_string(),
-                        receiver_username: "".to_string(),
-                        message: json.clone(),
-                        timestamp: timestamp.clone(),
-                        reactions: None,
-                        reveal_at: None,
-                    });
-                }
+        truncated |= rows.len() as i64 > SYNC_REPLAY_LIMIT;
+        let mut received = Vec::new();
+        for row in rows.iter().take(SYNC_REPLAY_LIMIT as usize) {
+            let mut msg = chat_message_from_row(row, None);
+            msg.attachments = uploads::for_message(pool, MessageKind::Direct, msg.id).await;
+            reveal::withhold(&mut msg, username);
+            let conversation = threads::Conversation::Direct { user: username, peer: &peer };
+            threads::decorate(pool, conversation, std::slice::from_mut(&mut msg), false).await;
+            if !msg.sender_username.eq_ignore_ascii_case(username) {
+                received.push((msg.id, msg.sender_username.clone()));
             }
+            session.send_event(&ServerEvent::ChatMessage(msg));
         }
+        record_receipts(&session.users, pool, Receipt::Delivered, username, None, &received).await;
     }
-}
 
-"unpin_message" => {
-    if let Some(message_id) = incoming_msg.message_id {
-        // Remove pin from database
-        let result = sqlx::query(
-            "DELETE FROM pinned_messages WHERE message_id = ?"
+    for (group_id, last_seq) in groups {
+        // Only groups the user belongs to have a head
+        let Ok(group_id) = group_id.parse::<i64>() else { continue };
+        if !group_heads.contains_key(&group_id) {
+            continue;
+        }
+        let ghost_flag: i32 = sqlx::query_scalar("SELECT ghost_mode FROM groups WHERE id = ?")
+            .bind(group_id)
+            .fetch_one(pool)
+            .await
+            .unwrap_or(0);
+        let rows = sqlx::query(
+            "SELECT id, sender_username, message, timestamp, deleted, edited_at, reveal_at, seq,
+                    reply_to, thread_root, in_channel, poll_id FROM group_messages
+             WHERE group_id = ? AND seq > ?
+             ORDER BY seq LIMIT ?"
         )
-        .bind(message_id)
-        .execute(&pool_incoming)
-        .await;
-
-        if result.is_ok() {
-            // Broadcast unpin to all connected users
-            let unpin_msg = serde_json::json!({
-                "type": "message_unpinned",
-                "message_id": message_id
-            });
+        .bind(group_id)
+        .bind(last_seq)
+        .bind(SYNC_REPLAY_LIMIT + 1)
+        .fetch_all(pool)
+        .await?;
 
-            if let Ok(json) = serde_json::to_string(&unpin_msg) {
-                let users_lock = users_incoming.lock().await;
-                for user in users_lock.values() {
-                    let _ = user.sender.send(ChatMessage {
-                        id: 0,
-                        group_id: None,
-                        sender_username: "system".to_string(),
-                        receiver_username: "".to_string(),
-                        message: json.clone(),
-                        timestamp: Utc::now().to_rfc3339(),
-                        reactions: None,
-                        reveal_at: None,
-                    });
-                }
+        truncated |= rows.len() as i64 > SYNC_REPLAY_LIMIT;
+        let mut received = Vec::new();
+        for row in rows.iter().take(SYNC_REPLAY_LIMIT as usize) {
+            let mut msg = chat_message_from_row(row, Some(group_id));
+            msg.attachments = uploads::for_message(pool, MessageKind::Group, msg.id).await;
+            reveal::withhold(&mut msg, username);
+            threads::decorate(pool, threads::Conversation::Group(group_id), std::slice::from_mut(&mut msg), ghost_flag != 0).await;
+            if !msg.sender_username.eq_ignore_ascii_case(username) {
+                received.push((msg.id, msg.sender_username.clone()));
+            }
+            if ghost_flag != 0 {
+                msg.sender_username = "Anonymous".to_string();
             }
+            session.send_event(&ServerEvent::ChatMessage(msg));
         }
+        record_receipts(&session.users, pool, Receipt::Delivered, username, Some(group_id), &received).await;
     }
+
+    session.send_event(&ServerEvent::SyncComplete { direct: dm_heads, groups: group_heads, truncated });
+    Ok(())
 }
 
-"get_reactions" => {
-    if let Some(message_id) = incoming_msg.message_id {
-        // Get all reactions for this message
-        let reactions_rows = sqlx::query(
-            "SELECT username, emoji FROM message_reactions WHERE message_id = ?"
-        )
-        .bind(message_id)
-        .fetch_all(&pool_incoming)
-        .await
-        .unwrap_or_default();
+async fn typing_scope(session: &WsSession, target: protocol::TypingTarget) -> Result<TypingScope, CommandError> {
+    if let Some(group_id) = target.group_id {
+        session.require_group(group_id, Permission::View).await?;
+        Ok(TypingScope::Group(group_id))
+    } else if let Some(peer) = target.receiver_username {
+        Ok(TypingScope::Direct(peer.to_lowercase()))
+    } else {
+        Err(CommandError::invalid("Typing frames need a receiver_username or group_id"))
+    }
+}
 
-        let mut reactions: HashMap<String, Vec<String>> = HashMap::new();
-        for row in reactions_rows {
-            let emoji: String = row.get("emoji");
-            let username: String = row.get("username");
-            reactions.entry(emoji).or_insert_with(Vec::new).push(username);
-        }
+fn forward_call_signal(
+    session: &WsSession,
+    req: protocol::CallSignalRequest,
+    make_event: fn(CallSignal) -> ServerEvent,
+) {
+    let event = make_event(CallSignal {
+        from: session.username.clone(),
+        to: req.target_username.clone(),
+        sdp: req.sdp,
+        candidate: req.candidate,
+    });
+    session.users.send_to_user(&req.target_username, &event);
+}
 
-        let reactions_msg = serde_json::json!({
-            "type": "reactions_list",
-            "message_id": message_id,
-            "reactions": reactions
-        });
+async fn handle_websocket(
+    websocket: WebSocket,
+    users: Users,
+    presence: Arc<PresenceTracker>,
+    games: Arc<GameRegistry>,
+    params: HashMap<String, String>,
+    pool: SqlitePool,
+) {
+    let (mut ws_tx, mut ws_rx) = websocket.split();
 
-        if let Ok(json) = serde_json::to_string(&reactions_msg) {
-            let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-            let _ = ws_tx_lock.send(Message::text(json)).await;
+    // Extract and verify JWT token
+    // Revoked sessions are refused here, and their open sockets closed on logout
+    let (username, session_id) = match params.get("token") {
+        Some(token) => match auth::verify_access_token(token) {
+            Ok(claims) => (claims.sub, claims.sid),
+            Err(_) => {
+                let _ = ws_tx.send(Message::text(r#"{"error": "Invalid or expired token"}"#)).await;
+                return;
+            }
+        },
+        None => {
+            let _ = ws_tx.send(Message::text(r#"{"error": "Authentication required"}"#)).await;
+            return;
         }
-    }
-}
-
-"get_pinned_messages" => {
-    // Get all pinned message IDs
-    let pinned_rows = sqlx::query(
-        "SELECT message_id, pinned_by, pinned_at FROM pinned_messages ORDER BY pinned_at DESC"
-    )
-    .fetch_all(&pool_incoming)
-    .await
-    .unwrap_or_default();
+    };
 
-    let pinned_messages: Vec<serde_json::Value> = pinned_rows.iter().map(|row| {
-        serde_json::json!({
-            "message_id": row.get::<i64, _>("message_id"),
-            "pinned_by": row.get::<String, _>("pinned_by"),
-            "pinned_at": row.get::<String, _>("pinned_at")
-        })
-    }).collect();
+    println!("DEBUG: WebSocket connected for user: {}", username);
 
-    let pinned_msg = serde_json::json!({
-        "type": "pinned_messages_list",
-        "pinned_messages": pinned_messages
-    });
+    let (connection_id, mut rx) = users.register(&username, &session_id);
+    println!("DEBUG: Added connection {} for {}. Total connections: {}", connection_id, username, users.connection_count());
 
-    if let Ok(json) = serde_json::to_string(&pinned_msg) {
-        let mut ws_tx_lock = ws_tx_for_incoming.lock().await;
-        let _ = ws_tx_lock.send(Message::text(json)).await;
+    if presence.announce(&username, Status::Online) {
+        announce_presence(&users, &pool, &username, Status::Online, chrono::Utc::now()).await;
     }
-}
 
-                                    // Other message types
-                                    _ => {
-                                        println!("DEBUG: Unhandled message type: {}", incoming_msg.message_type);
-                                    }
-                                }
-                            }
-                            Err(parse_error) => {
-                                println!("DEBUG: Failed to parse WebSocket message as JSON: {:?}", parse_error);
-                                println!("DEBUG: Raw message was: {}", text);
-                            }
-                        }
-                    }
-                }
+    let session = WsSession {
+        username: username.clone(),
+        connection_id,
+        pool: pool.clone(),
+        users: users.clone(),
+        presence: presence.clone(),
+        games,
+    };
+
+    let incoming_task = tokio::spawn(async move {
+        while let Some(result) = ws_rx.next().await {
+            let msg = match result {
+                Ok(msg) => msg,
                 Err(e) => {
                     println!("DEBUG: WebSocket error: {:?}", e);
                     break;
                 }
+            };
+            // Only TEXT frames carry commands
+            let Ok(text) = msg.to_str() else { continue };
+            println!("DEBUG: Received WebSocket message: {}", text);
+            session.touch().await;
+
+            match protocol::parse_frame(text) {
+                Ok(frame) => {
+                    if let Err(err) = handle_client_command(&session, frame.command).await {
+                        println!("DEBUG: Command from {} failed: {:?}", session.username, err);
+                        session.send_event(&ServerEvent::error(frame.request_id, err));
+                    }
+                }
+                Err(frame_error) => {
+                    println!("DEBUG: Rejected WebSocket frame from {}: {:?}", session.username, frame_error);
+                    session.send_event(&ServerEvent::from(frame_error));
+                }
             }
         }
-        println!("DEBUG: WebSocket incoming task ended for user: {}", username_clone);
+        println!("DEBUG: WebSocket incoming task ended for user: {}", session.username);
     });
 
-    // Clone pool and users for outgoing task
-    let pool_outgoing = pool.clone();
+    // Drain this socket's queue; events were already addressed to this user
     let username_outgoing = username.clone();
-    let ws_tx_outgoing = ws_tx_clone.clone();
-
     let outgoing_task = tokio::spawn(async move {
         println!("DEBUG: Started outgoing task for user: {}", username_outgoing);
-        while let Ok(msg) = rx.recv().await {
-            println!("DEBUG: Received broadcast message for user {}: {:?}", username_outgoing, msg);
-            let mut send_to_user = false;
-
-            // Check if it's a group message first
-            if let Some(group_id) = msg.group_id {
-                println!("DEBUG: Checking if {} is member of group {}", username_outgoing, group_id);
-                let row = sqlx::query("SELECT 1 FROM group_members WHERE group_id = ? AND username = ?")
-                    .bind(group_id)
-                    .bind(&username_outgoing)
-                    .fetch_optional(&pool_outgoing)
-                    .await
-                    .unwrap_or(None);
-                if row.is_some() { 
-                    send_to_user = true; 
-                    println!("DEBUG: User {} is member of group {} - sending group message", username_outgoing, group_id);
-                } else {
-                    println!("DEBUG: User {} is NOT member of group {}", username_outgoing, group_id);
-                }
-            }
-            // Only check for 1:1 message if it's NOT a group message (group_id is None)
-            else if msg.group_id.is_none() {
-                if msg.sender_username == "system" {
-                    // Deliver system messages only to intended receiver (case-insensitive)
-                    if msg.receiver_username.eq_ignore_ascii_case(&username_outgoing) {
-                        send_to_user = true;
-                    }
-                } else if msg.sender_username.eq_ignore_ascii_case(&username_outgoing)
-                    || msg.receiver_username.eq_ignore_ascii_case(&username_outgoing) {
-                    send_to_user = true;
-                    println!("DEBUG: Message is for private chat with {}", username_outgoing);
-                }
-            }
-
-            if send_to_user {
-                // Mask sender for ghost groups
-                let mut msg_to_send = msg.clone();
-                if let Some(gid) = msg_to_send.group_id {
-                    let ghost_flag = sqlx::query("SELECT ghost_mode FROM groups WHERE id = ?")
-                        .bind(gid)
-                        .fetch_one(&pool_outgoing)
-                        .await
-                        .map(|r| r.get::<i32, _>("ghost_mode")).unwrap_or(0);
-                    if ghost_flag != 0 {
-                        msg_to_send.sender_username = "Anonymous".to_string();
-                    }
-                }
-                if let Ok(json) = serde_json::to_string(&msg_to_send) {
-                    println!("DEBUG: Sending message to {}: {}", username_outgoing, json);
-                    let mut ws_tx_lock = ws_tx_outgoing.lock().await;
-                    if ws_tx_lock.send(Message::text(json)).await.is_err() {
-                        println!("DEBUG: Failed to send WebSocket message to {}", username_outgoing);
-                        break;
-                    } else {
-                        println!("DEBUG: Successfully sent WebSocket message to {}", username_outgoing);
-                    }
-                }
-            } else {
-                println!("DEBUG: Message not for user {}: sender={}, receiver={}, group_id={:?}", 
-                    username_outgoing, msg.sender_username, msg.receiver_username, msg.group_id);
+        loop {
+            let Some(json) = rx.recv().await else {
+                // The registry dropped this socket: its session was revoked
+                let _ = ws_tx.send(Message::close_with(4001u16, "session revoked")).await;
+                break;
+            };
+            if ws_tx.send(Message::text(json)).await.is_err() {
+                println!("DEBUG: Failed to send WebSocket message to {}", username_outgoing);
+                break;
             }
         }
         println!("DEBUG: WebSocket outgoing task ended for user: {}", username_outgoing);
     });
 
+    let (mut incoming_task, mut outgoing_task) = (incoming_task, outgoing_task);
     tokio::select! {
-        _ = incoming_task => {
+        _ = &mut incoming_task => {
             println!("DEBUG: Incoming task completed for {}", username);
         },
-        _ = outgoing_task => {
+        _ = &mut outgoing_task => {
             println!("DEBUG: Outgoing task completed for {}", username);
         },
     }
+    // Stop reading commands from a socket that can no longer be written to
+    incoming_task.abort();
+    outgoing_task.abort();
 
-    // Remove user from users map
-    {
-        let mut users_lock = users.lock().await;
-        users_lock.remove(&connection_id);
-        println!("DEBUG: Removed user {} from connections. Remaining: {}", username, users_lock.len());
-    }
-}
-
-// Helper function to get poll details
-async fn get_poll_details(
-    pool: &SqlitePool, 
-    poll_id: i64, 
-    current_username: &str
-) -> Result<serde_json::Value, sqlx::Error> {
-    // Get poll info
-    let poll_row = sqlx::query(
-        "SELECT id, group_id, creator_username, question, created_at, expires_at, is_active, allow_multiple_choices 
-         FROM polls WHERE id = ?"
-    )
-    .bind(poll_id)
-    .fetch_one(pool)
-    .await?;
-
-    // Get poll options with vote counts
-    let options_rows = sqlx::query(
-        "SELECT po.id, po.option_text, 
-                COUNT(pv.id) as vote_count,
-                CASE WHEN user_votes.option_id IS NOT NULL THEN 1 ELSE 0 END as voted_by_current_user
-         FROM poll_options po
-         LEFT JOIN poll_votes pv ON po.id = pv.option_id
-         LEFT JOIN poll_votes user_votes ON po.id = user_votes.option_id AND user_votes.username = ?
-         WHERE po.poll_id = ?
-         GROUP BY po.id, po.option_text, po.option_order
-         ORDER BY po.option_order"
-    )
-    .bind(current_username)
-    .bind(poll_id)
-    .fetch_all(pool)
-    .await?;
+    let idle = users.idle_secs(&username).unwrap_or(0);
+    let last_socket = users.unregister(&username, connection_id);
+    println!("DEBUG: Removed connection {} for {}. Remaining: {}", connection_id, username, users.connection_count());
 
-    let mut options = Vec::new();
-    let mut total_votes = 0i64;
-
-    for row in options_rows {
-        let vote_count: i64 = row.get("vote_count");
-        total_votes += vote_count;
-
-        options.push(serde_json::json!({
-            "id": row.get::<i64, _>("id"),
-            "option_text": row.get::<String, _>("option_text"),
-            "vote_count": vote_count,
-            "voted_by_current_user": row.get::<i64, _>("voted_by_current_user") == 1
-        }));
+    if last_socket {
+        for scope in presence.stop_all_typing(&username) {
+            send_typing_notice(&users, &pool, &username, &scope, false).await;
+        }
+        if presence.announce(&username, Status::Offline) {
+            let last_active = chrono::Utc::now() - chrono::Duration::seconds(idle as i64);
+            announce_presence(&users, &pool, &username, Status::Offline, last_active).await;
+        }
     }
-
-    Ok(serde_json::json!({
-        "id": poll_row.get::<i64, _>("id"),
-        "group_id": poll_row.get::<i64, _>("group_id"),
-        "creator_username": poll_row.get::<String, _>("creator_username"),
-        "question": poll_row.get::<String, _>("question"),
-        "created_at": poll_row.get::<String, _>("created_at"),
-        "expires_at": poll_row.get::<Option<String>, _>("expires_at"),
-        "is_active": poll_row.get::<bool, _>("is_active"),
-        "allow_multiple_choices": poll_row.get::<bool, _>("allow_multiple_choices"),
-        "options": options,
-        "total_votes": total_votes
-    }))
 }
 
+// Helper function to get poll details
 fn get_current_time() -> String {
     // RFC3339 UTC time, lexicographically sortable and comparable to ISO strings
     chrono::Utc::now().to_rfc3339()
 }
 
-async fn get_group_conversation_messages(pool: &SqlitePool, group_id: i64, limit: i32) -> Vec<ChatMessage> {
-    let rows = sqlx::query(
-        "SELECT id, sender_username, message, timestamp, reveal_at FROM group_messages 
-         WHERE group_id = ? ORDER BY id DESC LIMIT ?"
-    )
-    .bind(group_id)
-    .bind(limit)
-    .fetch_all(pool)
-    .await
-    .unwrap_or_default();
+async fn get_group_conversation_messages(pool: &SqlitePool, group_id: i64, viewer: &str, page: HistoryPage) -> HistorySlice {
+    let sql = format!(
+        "SELECT id, sender_username, message, timestamp, deleted, edited_at, reveal_at, seq,
+                reply_to, thread_root, in_channel, poll_id FROM group_messages
+         WHERE group_id = ? AND in_channel = 1{}",
+        page.sql()
+    );
+    let rows = sqlx::query(&sql)
+        .bind(group_id)
+        .bind(page.before_id)
+        .bind(page.before_id)
+        .bind(page.after_id)
+        .bind(page.after_id)
+        .bind(page.limit() + 1)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    let (rows, has_more) = page.finish(rows);
 
     let mut messages: Vec<ChatMessage> = Vec::new();
     // Check ghost flag once
@@ -3751,26 +2949,95 @@ async fn get_group_conversation_messages(pool: &SqlitePool, group_id: i64, limit
         .map(|r| r.get::<i32, _>("ghost_mode")).unwrap_or(0);
 
     for row in rows {
-        let reveal_at: Option<String> = row.try_get("reveal_at").ok();
-        let mut msg = ChatMessage {
-            id: row.get("id"),
-            group_id: Some(group_id),
-            sender_username: row.get("sender_username"),
-            receiver_username: "".to_string(), // Empty for group messages
-            message: row.get("message"),
-            timestamp: row.get("timestamp"),
-            reactions: None, // Could be enhanced later
-            reveal_at,
-        };
+        let mut msg = chat_message_from_row(&row, Some(group_id));
+        msg.reactions = reactions_by_user(pool, MessageKind::Group, msg.id).await;
+        attach_receipts(pool, &mut msg).await;
+        msg.attachments = uploads::for_message(pool, MessageKind::Group, msg.id).await;
+        reveal::withhold(&mut msg, viewer);
         if ghost_flag != 0 {
             msg.sender_username = "Anonymous".to_string();
         }
         messages.push(msg);
     }
+    threads::decorate(pool, threads::Conversation::Group(group_id), &mut messages, ghost_flag != 0).await;
+
+    HistorySlice { messages, has_more }
+}
+
+async fn is_ghost_group(pool: &SqlitePool, group_id: i64) -> bool {
+    sqlx::query_scalar::<_, i32>("SELECT ghost_mode FROM groups WHERE id = ?")
+        .bind(group_id)
+        .fetch_one(pool)
+        .await
+        .unwrap_or(0)
+        != 0
+}
+
+/// The root of the thread `message_id` belongs to, with a page of its
+/// replies. `None` when there is no such message in the conversation.
+async fn get_thread_messages(
+    pool: &SqlitePool,
+    conversation: threads::Conversation<'_>,
+    viewer: &str,
+    message_id: i64,
+    page: HistoryPage,
+) -> Option<(ChatMessage, HistorySlice)> {
+    let table = conversation.table();
+    let columns = "id, sender_username, message, timestamp, deleted, edited_at, reveal_at, seq, reply_to, thread_root, in_channel, poll_id";
+    let ghost = match conversation.group_id() {
+        Some(group_id) => is_ghost_group(pool, group_id).await,
+        None => false,
+    };
+    let sql = format!(
+        "SELECT {}{} FROM {} WHERE id = (SELECT COALESCE(thread_root, id) FROM {} WHERE id = ? AND {})",
+        columns,
+        if conversation.group_id().is_none() { ", receiver_username" } else { "" },
+        table,
+        table,
+        conversation.filter()
+    );
+    let root_row = conversation.bind(sqlx::query(&sql).bind(message_id)).fetch_optional(pool).await.ok().flatten()?;
+    let mut root = chat_message_from_row(&root_row, conversation.group_id());
+
+    let sql = format!(
+        "SELECT {}{} FROM {} WHERE thread_root = ?{}",
+        columns,
+        if conversation.group_id().is_none() { ", receiver_username" } else { "" },
+        table,
+        page.sql()
+    );
+    let rows = sqlx::query(&sql)
+        .bind(root.id)
+        .bind(page.before_id)
+        .bind(page.before_id)
+        .bind(page.after_id)
+        .bind(page.after_id)
+        .bind(page.limit() + 1)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+    let (rows, has_more) = page.finish(rows);
 
-    // Reverse to get chronological order (oldest first)
-    messages.reverse();
-    messages
+    let kind = MessageKind::of(conversation.group_id());
+    let mut messages = Vec::new();
+    for row in rows {
+        let mut msg = chat_message_from_row(&row, conversation.group_id());
+        attach_receipts(pool, &mut msg).await;
+        msg.attachments = uploads::for_message(pool, kind, msg.id).await;
+        messages.push(msg);
+    }
+    root.attachments = uploads::for_message(pool, kind, root.id).await;
+    threads::decorate(pool, conversation, std::slice::from_mut(&mut root), ghost).await;
+    threads::decorate(pool, conversation, &mut messages, ghost).await;
+    for msg in std::iter::once(&mut root).chain(messages.iter_mut()) {
+        reveal::withhold(msg, viewer);
+    }
+    if ghost {
+        for msg in std::iter::once(&mut root).chain(messages.iter_mut()) {
+            msg.sender_username = "Anonymous".to_string();
+        }
+    }
+    Some((root, HistorySlice { messages, has_more }))
 }
 
 async fn store_group_message(
@@ -3780,23 +3047,50 @@ async fn store_group_message(
     message: &str,
     timestamp: &str,
     reveal_at: Option<&str>,
-) -> Result<i64, sqlx::Error> {
+    thread: Option<threads::Placement>,
+) -> Result<ChatMessage, sqlx::Error> {
     println!("DEBUG: Storing group message in database");
-    let rec = sqlx::query(
-        "INSERT INTO group_messages (group_id, sender_username, message, timestamp, reveal_at)
-         VALUES (?, ?, ?, ?, ?)"
+    let row = sqlx::query(
+        "INSERT INTO group_messages (group_id, sender_username, message, timestamp, reveal_at, reply_to, thread_root, in_channel, seq)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, (SELECT COALESCE(MAX(seq), 0) + 1 FROM group_messages WHERE group_id = ?))
+         RETURNING id, seq"
     )
     .bind(group_id)
     .bind(sender_username)
     .bind(message)
     .bind(timestamp)
     .bind(reveal_at)
-    .execute(pool)
+    .bind(thread.map(|t| t.reply_to))
+    .bind(thread.map(|t| t.thread_root))
+    .bind(thread.is_none_or(|t| t.in_channel))
+    .bind(group_id)
+    .fetch_one(pool)
     .await?;
 
-    let message_id = rec.last_insert_rowid();
+    let message_id: i64 = row.get("id");
     println!("DEBUG: Group message stored with ID: {}", message_id);
-    Ok(message_id)
+    Ok(ChatMessage {
+        id: message_id,
+        group_id: Some(group_id),
+        sender_username: sender_username.to_string(),
+        receiver_username: "".to_string(),
+        message: message.to_string(),
+        timestamp: timestamp.to_string(),
+        reactions: None,
+        reveal_at: reveal_at.map(str::to_string),
+        withheld: false,
+        seq: row.get("seq"),
+        delivered_at: None,
+        read_at: None,
+        read_by: None,
+        attachments: Vec::new(),
+        reply_to: thread.map(|t| t.reply_to),
+        thread_root: thread.map(|t| t.thread_root),
+        thread_only: thread.is_some_and(|t| !t.in_channel),
+        quoted: None,
+        thread: None,
+        poll_id: None,
+    })
 }
 
 
@@ -3836,7 +3130,16 @@ async fn generate_highlights_handler(
 }
         "group" => {
             if let Some(group_id) = request.target_id {
-                // Generate for specific group - we'll need to create this function
+                match roles::require(&pool, group_id, &username, Permission::View).await {
+                    Ok(_) => {}
+                    Err(Denied::Database(_)) => return Err(warp::reject::reject()),
+                    Err(denied) => {
+                        return Ok(warp::reply::with_status(
+                            warp::reply::json(&ErrorResponse { error: denied.to_string() }),
+                            warp::http::StatusCode::FORBIDDEN,
+                        ));
+                    }
+                }
                 vec![generate_single_group_highlight(&pool, group_id, &username, &request.highlight_type).await
                     .map_err(|_| warp::reject::reject())?]
             } else {
@@ -3845,7 +3148,7 @@ async fn generate_highlights_handler(
                     .map_err(|_| warp::reject::reject())?
             }
         }
-        "all" | _ => {
+        _ => {
             // Generate both personal and group highlights
             let mut all_highlights = Vec::new();
             
@@ -3924,21 +3227,6 @@ async fn get_highlights_handler(
     ))
 }
 
-fn get_daily_range() -> (String, String) {
-    let now = chrono::Utc::now();
-    let start = now.date_naive().and_hms_opt(0, 0, 0).unwrap();
-    let end = now.date_naive().and_hms_opt(23, 59, 59).unwrap();
-    (start.format("%Y-%m-%d %H:%M:%S").to_string(), end.format("%Y-%m-%d %H:%M:%S").to_string())
-}
-
-fn get_weekly_range() -> (String, String) {
-    let now = chrono::Utc::now();
-    let days_since_monday = now.weekday().num_days_from_monday();
-    let start_of_week = now.date_naive() - chrono::Duration::days(days_since_monday as i64);
-    let end_of_week = start_of_week + chrono::Duration::days(6);
-    (start_of_week.and_hms_opt(0, 0, 0).unwrap().format("%Y-%m-%d %H:%M:%S").to_string(), end_of_week.and_hms_opt(23, 59, 59).unwrap().format("%Y-%m-%d %H:%M:%S").to_string())
-}
-
 async fn generate_personal_highlights(
     pool: &SqlitePool,
     username: &str,
@@ -3971,18 +3259,20 @@ async fn generate_personal_highlights(
         let msg_count: i64 = conv_row.get("message_count");
 
         // Get actual messages for this conversation from the last 500
-        let messages = sqlx::query(
+        let messages = sqlx::query(&format!(
             "SELECT sender_username, message, timestamp 
              FROM (
                  SELECT sender_username, receiver_username, message, timestamp
                  FROM messages 
                  WHERE ((sender_username = ? AND receiver_username = ?) 
                         OR (sender_username = ? AND receiver_username = ?))
+                   AND {}
                  ORDER BY id DESC 
                  LIMIT 100
              ) conversation_messages
-             ORDER BY timestamp ASC"
-        )
+             ORDER BY timestamp ASC",
+            reveal::REVEALED
+        ))
         .bind(username).bind(&other_user)
         .bind(&other_user).bind(username)
         .fetch_all(pool).await?;
@@ -3996,7 +3286,7 @@ async fn generate_personal_highlights(
                  VALUES (?, 'personal', NULL, ?, ?, ?, ?, ?, 2, ?, ?, ?)"
             )
             .bind(username).bind(&other_user).bind(highlight_type).bind(&summary)
-            .bind(&serde_json::to_string(&key_topics).unwrap()).bind(msg_count)
+            .bind(serde_json::to_string(&key_topics).unwrap()).bind(msg_count)
             .bind("recent").bind("recent").bind(&now)
             .execute(pool).await?.last_insert_rowid();
 
@@ -4056,7 +3346,7 @@ fn extract_enhanced_topics(text: &str) -> Vec<String> {
     let mut word_freq: HashMap<String, usize> = HashMap::new();
     
     let words: Vec<String> = text.split_whitespace()
-        .map(|w| clean_word(w))
+        .map(clean_word)
         .filter(|w| w.len() > 2 && !is_stop_word(w))
         .collect();
     
@@ -4079,47 +3369,6 @@ fn is_stop_word(word: &str) -> bool {
 }
 
 
-async fn generate_group_highlight(
-    pool: &SqlitePool,
-    group_id: i64,
-    username: &str,
-    highlight_type: &str,
-    start_date: &str,
-    end_date: &str,
-) -> Result<Highlight, sqlx::Error> {
-    let group_row = sqlx::query("SELECT name FROM groups WHERE id = ?").bind(group_id).fetch_one(pool).await?;
-    let group_name: String = group_row.get("name");
-
-    let messages = sqlx::query("SELECT sender_username, message, timestamp FROM group_messages WHERE group_id = ? AND timestamp BETWEEN ? AND ? ORDER BY timestamp ASC")
-        .bind(group_id).bind(start_date).bind(end_date).fetch_all(pool).await?;
-
-    let participants: std::collections::HashSet<String> = messages.iter().map(|row| row.get::<String, _>("sender_username")).collect();
-    let message_count = messages.len() as i64;
-    let participant_count = participants.len() as i64;
-
-    let (summary, key_topics) = generate_rule_based_summary(&messages, &group_name, highlight_type).await;
-
-    let highlight_id = sqlx::query("INSERT INTO highlights (user_username, target_type, target_id, target_name, highlight_type, summary, key_topics, message_count, participant_count, start_date, end_date, created_at) VALUES (?, 'group', ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
-        .bind(username).bind(group_id).bind(&group_name).bind(highlight_type).bind(&summary).bind(&serde_json::to_string(&key_topics).unwrap()).bind(message_count).bind(participant_count).bind(start_date).bind(end_date).bind(&get_current_time())
-        .execute(pool).await?.last_insert_rowid();
-
-    Ok(Highlight {
-        id: highlight_id,
-        user_username: username.to_string(),
-        target_type: "group".to_string(),
-        target_id: Some(group_id),
-        target_name: group_name,
-        highlight_type: highlight_type.to_string(),
-        summary,
-        key_topics,
-        message_count,
-        participant_count,
-        start_date: start_date.to_string(),
-        end_date: end_date.to_string(),
-        created_at: get_current_time(),
-    })
-}
-
 async fn generate_all_group_highlights(
     pool: &SqlitePool,
     username: &str,
@@ -4136,13 +3385,14 @@ async fn generate_all_group_highlights(
         let group_name: String = group_row.get("name");
         
         // Get last 200 messages for this group
-        let messages = sqlx::query(
+        let messages = sqlx::query(&format!(
             "SELECT sender_username, message, timestamp 
              FROM group_messages 
-             WHERE group_id = ? 
+             WHERE group_id = ? AND {}
              ORDER BY id DESC 
-             LIMIT 200"
-        )
+             LIMIT 200",
+            reveal::REVEALED
+        ))
         .bind(group_id).fetch_all(pool).await?;
 
         if !messages.is_empty() {
@@ -4159,7 +3409,7 @@ async fn generate_all_group_highlights(
                  VALUES (?, 'group', ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
             )
             .bind(username).bind(group_id).bind(&group_name).bind(highlight_type)
-            .bind(&summary).bind(&serde_json::to_string(&key_topics).unwrap())
+            .bind(&summary).bind(serde_json::to_string(&key_topics).unwrap())
             .bind(message_count).bind(participant_count)
             .bind("recent").bind("recent").bind(&now)
             .execute(pool).await?.last_insert_rowid();
@@ -4194,13 +3444,14 @@ async fn generate_single_group_highlight(
     let group_name: String = group_row.get("name");
     
     // Get last 200 messages for this specific group
-    let messages = sqlx::query(
+    let messages = sqlx::query(&format!(
         "SELECT sender_username, message, timestamp 
          FROM group_messages 
-         WHERE group_id = ? 
+         WHERE group_id = ? AND {}
          ORDER BY id DESC 
-         LIMIT 200"
-    )
+         LIMIT 200",
+        reveal::REVEALED
+    ))
     .bind(group_id).fetch_all(pool).await?;
 
     let participants: std::collections::HashSet<String> = messages.iter()
@@ -4216,7 +3467,7 @@ async fn generate_single_group_highlight(
          VALUES (?, 'group', ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(username).bind(group_id).bind(&group_name).bind(highlight_type)
-    .bind(&summary).bind(&serde_json::to_string(&key_topics).unwrap())
+    .bind(&summary).bind(serde_json::to_string(&key_topics).unwrap())
     .bind(message_count).bind(participant_count)
     .bind("recent").bind("recent").bind(&now)
     .execute(pool).await?.last_insert_rowid();
@@ -4238,44 +3489,6 @@ async fn generate_single_group_highlight(
     })
 }
 
-async fn debug_messages_handler(
-    auth_header: String,
-    pool: SqlitePool,
-) -> Result<impl Reply, warp::Rejection> {
-    let username = match extract_username_from_auth(auth_header) {
-        Ok(u) => u,
-        Err(_) => {
-            return Ok(warp::reply::with_status(
-                warp::reply::json(&ErrorResponse {
-                    error: "Invalid token".to_string(),
-                }),
-                warp::http::StatusCode::UNAUTHORIZED,
-            ));
-        }
-    };
-
-    let messages = sqlx::query("SELECT sender_username, receiver_username, message, timestamp FROM messages WHERE sender_username = ? OR receiver_username = ? ORDER BY id DESC LIMIT 10")
-        .bind(&username).bind(&username).fetch_all(&pool).await.map_err(|_| warp::reject::reject())?;
-
-    let debug_info = serde_json::json!({
-        "user": username,
-        "recent_messages": messages.iter().map(|row| {
-            serde_json::json!({
-                "sender": row.get::<String, _>("sender_username"),
-                "receiver": row.get::<String, _>("receiver_username"),
-                "message": row.get::<String, _>("message"),
-                "timestamp": row.get::<String, _>("timestamp")
-            })
-        }).collect::<Vec<_>>(),
-        "total_count": messages.len()
-    });
-
-    Ok(warp::reply::with_status(
-        warp::reply::json(&debug_info),
-        warp::http::StatusCode::OK,
-    ))
-}
-
 async fn generate_specific_user_highlights(
     pool: &SqlitePool,
     username: &str,
@@ -4283,14 +3496,16 @@ async fn generate_specific_user_highlights(
     highlight_type: &str,
 ) -> Result<Vec<Highlight>, sqlx::Error> {
     // Get last 200 messages between these two users specifically
-    let messages = sqlx::query(
+    let messages = sqlx::query(&format!(
         "SELECT sender_username, message, timestamp 
          FROM messages 
          WHERE ((sender_username = ? AND receiver_username = ?) 
                 OR (sender_username = ? AND receiver_username = ?))
+           AND {}
          ORDER BY id DESC 
-         LIMIT 200"
-    )
+         LIMIT 200",
+        reveal::REVEALED
+    ))
     .bind(username).bind(target_user)
     .bind(target_user).bind(username)
     .fetch_all(pool).await?;
@@ -4308,7 +3523,7 @@ async fn generate_specific_user_highlights(
          VALUES (?, 'personal', NULL, ?, ?, ?, ?, ?, 2, ?, ?, ?)"
     )
     .bind(username).bind(target_user).bind(highlight_type).bind(&summary)
-    .bind(&serde_json::to_string(&key_topics).unwrap()).bind(message_count)
+    .bind(serde_json::to_string(&key_topics).unwrap()).bind(message_count)
     .bind("recent").bind("recent").bind(&now)
     .execute(pool).await?.last_insert_rowid();
 
@@ -4518,14 +3733,16 @@ async fn get_specific_conversation_context(
     username: &str,
     target: &str,
 ) -> Option<String> {
-    let personal_messages = sqlx::query(
+    let personal_messages = sqlx::query(&format!(
         "SELECT sender_username, message, timestamp 
          FROM messages 
          WHERE ((sender_username = ? AND receiver_username = ?) 
                 OR (sender_username = ? AND receiver_username = ?))
+           AND {}
          ORDER BY id DESC 
-         LIMIT 30"
-    )
+         LIMIT 30",
+        reveal::REVEALED
+    ))
     .bind(username).bind(target)
     .bind(target).bind(username)
     .fetch_all(pool)
@@ -4607,14 +3824,16 @@ async fn generate_conversation_summary(
     target_name: &str,
 ) -> AIAssistantResponse {
     // First check if it's a personal conversation
-    let personal_messages = sqlx::query(
+    let personal_messages = sqlx::query(&format!(
         "SELECT sender_username, message, timestamp 
          FROM messages 
          WHERE ((sender_username = ? AND receiver_username = ?) 
                 OR (sender_username = ? AND receiver_username = ?))
+           AND {}
          ORDER BY id DESC 
-         LIMIT 50"
-    )
+         LIMIT 50",
+        reveal::REVEALED
+    ))
     .bind(username).bind(target_name)
     .bind(target_name).bind(username)
     .fetch_all(pool)
@@ -4643,13 +3862,14 @@ async fn generate_conversation_summary(
 
     if let Some(group_row) = group_check {
         let group_id: i64 = group_row.get("id");
-        let group_messages = sqlx::query(
+        let group_messages = sqlx::query(&format!(
             "SELECT sender_username, message, timestamp 
              FROM group_messages 
-             WHERE group_id = ? 
+             WHERE group_id = ? AND {}
              ORDER BY id DESC 
-             LIMIT 50"
-        )
+             LIMIT 50",
+            reveal::REVEALED
+        ))
         .bind(group_id)
         .fetch_all(pool)
         .await
@@ -4748,16 +3968,3 @@ async fn get_recent_activity_summary(
     }
 }
 
-
-// Then add .or(ai_assistant) to your routes before .with(cors)
-
-async fn store_reaction(pool: &SqlitePool, message_id: i64, username: &str, emoji: &str) -> Result<(), sqlx::Error> {
-    sqlx::query("INSERT INTO message_reactions (message_id, username, emoji, timestamp) VALUES (?, ?, ?, ?)")
-        .bind(message_id)
-        .bind(username)
-        .bind(emoji)
-        .bind(get_current_time())
-        .execute(pool)
-        .await?;
-    Ok(())
-}
\ No newline at end of file
@@ -0,0 +1,877 @@
+// src/migrations.rs
+//! Versioned schema migrations.
+//!
+//! Every change to the schema is a numbered [`Migration`] in [`MIGRATIONS`].
+//! At startup the ones newer than the database's `schema_version` are applied
+//! in order, each inside its own transaction together with its
+//! `schema_version` row, so a migration either lands completely or not at
+//! all. The first failure stops the run and is reported; the server does not
+//! start on a half-migrated database.
+//!
+//! Databases from before versioning already have some of these tables and
+//! columns, so migrations are written to be safe to re-run: tables and
+//! indexes use `IF NOT EXISTS` and columns are added with
+//! [`Step::AddColumn`], which skips columns that are already there.
+//!
+//! New schema changes go at the end of the list with the next version
+//! number; applied migrations must never be edited.
+use std::fmt;
+
+use sqlx::{Row, SqliteConnection, SqlitePool};
+
+pub enum Step {
+    Sql(&'static str),
+    /// `ALTER TABLE .. ADD COLUMN`, unless the column already exists.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+}
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub steps: &'static [Step],
+}
+
+use Step::{AddColumn, Sql};
+
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        steps: &[
+            Sql("CREATE TABLE IF NOT EXISTS users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL UNIQUE,
+                password_hash TEXT NOT NULL,
+                created_at TEXT DEFAULT CURRENT_TIMESTAMP
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS groups (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                owner_username TEXT NOT NULL,
+                description TEXT,
+                ghost_mode INTEGER DEFAULT 0,
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )"),
+            AddColumn { table: "groups", column: "description", definition: "TEXT" },
+            AddColumn { table: "groups", column: "ghost_mode", definition: "INTEGER DEFAULT 0" },
+            Sql("CREATE TABLE IF NOT EXISTS group_members (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id INTEGER NOT NULL,
+                username TEXT NOT NULL,
+                joined_at TEXT DEFAULT CURRENT_TIMESTAMP,
+                UNIQUE(group_id, username),
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS scheduled_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender_username TEXT NOT NULL,
+                receiver_username TEXT,
+                group_id INTEGER,
+                message TEXT NOT NULL,
+                scheduled_at TEXT NOT NULL,
+                scheduled_at_epoch INTEGER,
+                sent INTEGER DEFAULT 0,
+                created_at TEXT NOT NULL,
+                sent_at TEXT
+            )"),
+            AddColumn { table: "scheduled_messages", column: "scheduled_at_epoch", definition: "INTEGER" },
+            Sql("CREATE TABLE IF NOT EXISTS notes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL,
+                title TEXT,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sender_username TEXT NOT NULL,
+                receiver_username TEXT NOT NULL,
+                message TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                group_id INTEGER,
+                reveal_at TEXT
+            )"),
+            AddColumn { table: "messages", column: "group_id", definition: "INTEGER" },
+            AddColumn { table: "messages", column: "reveal_at", definition: "TEXT" },
+            AddColumn { table: "messages", column: "deleted", definition: "INTEGER DEFAULT 0" },
+            AddColumn { table: "messages", column: "edited_at", definition: "TEXT" },
+            Sql("CREATE INDEX IF NOT EXISTS idx_messages_participants ON messages(sender_username, receiver_username)"),
+            Sql("CREATE TABLE IF NOT EXISTS group_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id INTEGER NOT NULL,
+                sender_username TEXT NOT NULL,
+                message TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                reveal_at TEXT
+            )"),
+            AddColumn { table: "group_messages", column: "reveal_at", definition: "TEXT" },
+            AddColumn { table: "group_messages", column: "deleted", definition: "INTEGER DEFAULT 0" },
+            AddColumn { table: "group_messages", column: "edited_at", definition: "TEXT" },
+            Sql("CREATE TABLE IF NOT EXISTS polls (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id INTEGER NOT NULL,
+                creator_username TEXT NOT NULL,
+                question TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                is_active BOOLEAN DEFAULT 1,
+                allow_multiple_choices BOOLEAN DEFAULT 0,
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS poll_options (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                poll_id INTEGER NOT NULL,
+                option_text TEXT NOT NULL,
+                option_order INTEGER NOT NULL,
+                FOREIGN KEY (poll_id) REFERENCES polls(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS poll_votes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                poll_id INTEGER NOT NULL,
+                option_id INTEGER NOT NULL,
+                username TEXT NOT NULL,
+                voted_at TEXT NOT NULL,
+                UNIQUE(poll_id, option_id, username),
+                FOREIGN KEY (poll_id) REFERENCES polls(id) ON DELETE CASCADE,
+                FOREIGN KEY (option_id) REFERENCES poll_options(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS highlights (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_username TEXT NOT NULL,
+                target_type TEXT NOT NULL,
+                target_id INTEGER,
+                target_name TEXT NOT NULL,
+                highlight_type TEXT NOT NULL,
+                summary TEXT NOT NULL,
+                key_topics TEXT NOT NULL,
+                message_count INTEGER NOT NULL,
+                participant_count INTEGER NOT NULL,
+                start_date TEXT NOT NULL,
+                end_date TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS message_reactions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id INTEGER NOT NULL,
+                username TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                UNIQUE(message_id, username, emoji)
+            )"),
+            AddColumn { table: "message_reactions", column: "created_at", definition: "TEXT" },
+            Sql("CREATE TABLE IF NOT EXISTS pinned_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id INTEGER NOT NULL,
+                pinned_by TEXT NOT NULL,
+                pinned_at TEXT NOT NULL,
+                UNIQUE(message_id)
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS dm_locks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                owner_username TEXT NOT NULL,
+                peer_username TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                UNIQUE(owner_username, peer_username)
+            )"),
+            AddColumn { table: "dm_locks", column: "locked", definition: "INTEGER DEFAULT 1" },
+            Sql("CREATE TABLE IF NOT EXISTS user_lock_pin (
+                username TEXT PRIMARY KEY,
+                hash TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                enabled INTEGER DEFAULT 0
+            )"),
+            AddColumn { table: "user_lock_pin", column: "enabled", definition: "INTEGER DEFAULT 0" },
+            Sql("CREATE TABLE IF NOT EXISTS chat_themes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                owner_username TEXT NOT NULL,
+                peer_username TEXT,
+                group_id INTEGER,
+                theme_key TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )"),
+            Sql("CREATE UNIQUE INDEX IF NOT EXISTS ux_chat_theme_dm ON chat_themes(owner_username, peer_username)"),
+            Sql("CREATE UNIQUE INDEX IF NOT EXISTS ux_chat_theme_group ON chat_themes(owner_username, group_id)"),
+            Sql("CREATE TABLE IF NOT EXISTS games (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                game_type TEXT NOT NULL,
+                player1_username TEXT NOT NULL,
+                player2_username TEXT,
+                game_state TEXT NOT NULL,
+                current_turn TEXT NOT NULL,
+                status TEXT DEFAULT 'waiting',
+                winner TEXT,
+                created_at TEXT NOT NULL,
+                conversation_type TEXT NOT NULL,
+                conversation_id INTEGER
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS game_moves (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                game_id INTEGER NOT NULL,
+                player_username TEXT NOT NULL,
+                move_data TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                FOREIGN KEY (game_id) REFERENCES games(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS trivia_questions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                question TEXT NOT NULL,
+                options TEXT NOT NULL,
+                correct_answer INTEGER NOT NULL,
+                category TEXT NOT NULL
+            )"),
+            // Seed questions, unless an older server already inserted them
+            Sql("INSERT INTO trivia_questions (question, options, correct_answer, category)
+                SELECT * FROM (
+                    SELECT 'What is the capital of France?', '[\"Paris\", \"London\", \"Berlin\", \"Madrid\"]', 0, 'Geography'
+                    UNION ALL SELECT 'What is 2 + 2?', '[\"3\", \"4\", \"5\", \"6\"]', 1, 'Math'
+                    UNION ALL SELECT 'Who painted the Mona Lisa?', '[\"Van Gogh\", \"Picasso\", \"Da Vinci\", \"Monet\"]', 2, 'Art'
+                    UNION ALL SELECT 'What year did World War II end?', '[\"1944\", \"1945\", \"1946\", \"1947\"]', 1, 'History'
+                    UNION ALL SELECT 'What is the largest planet?', '[\"Earth\", \"Mars\", \"Jupiter\", \"Saturn\"]', 2, 'Science'
+                )
+                WHERE NOT EXISTS (SELECT 1 FROM trivia_questions)"),
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "message_seq",
+        steps: &[
+            // Per-conversation sequence numbers, used by clients to resume after a reconnect
+            AddColumn { table: "messages", column: "seq", definition: "INTEGER" },
+            AddColumn { table: "group_messages", column: "seq", definition: "INTEGER" },
+            Sql("UPDATE messages SET seq = numbered.seq
+                FROM (
+                    SELECT id, ROW_NUMBER() OVER (
+                        PARTITION BY min(lower(sender_username), lower(receiver_username)),
+                                     max(lower(sender_username), lower(receiver_username))
+                        ORDER BY id
+                    ) AS seq
+                    FROM messages
+                ) AS numbered
+                WHERE numbered.id = messages.id AND messages.seq IS NULL"),
+            Sql("UPDATE group_messages SET seq = numbered.seq
+                FROM (
+                    SELECT id, ROW_NUMBER() OVER (PARTITION BY group_id ORDER BY id) AS seq
+                    FROM group_messages
+                ) AS numbered
+                WHERE numbered.id = group_messages.id AND group_messages.seq IS NULL"),
+            Sql("CREATE UNIQUE INDEX IF NOT EXISTS idx_group_messages_seq ON group_messages(group_id, seq)"),
+        ],
+    },
+    Migration {
+        version: 3,
+        name: "message_receipts",
+        steps: &[
+            // message_kind is 'direct' or 'group' since ids overlap
+            Sql("CREATE TABLE IF NOT EXISTS message_receipts (
+                message_kind TEXT NOT NULL,
+                message_id INTEGER NOT NULL,
+                username TEXT NOT NULL COLLATE NOCASE,
+                delivered_at TEXT,
+                read_at TEXT,
+                PRIMARY KEY (message_kind, message_id, username)
+            )"),
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "users_last_seen_at",
+        steps: &[AddColumn { table: "users", column: "last_seen_at", definition: "TEXT" }],
+    },
+    Migration {
+        version: 5,
+        name: "attachments",
+        steps: &[
+            // One row per upload; identical content shares `stored_name` on disk
+            Sql("CREATE TABLE IF NOT EXISTS attachments (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                owner_username TEXT NOT NULL COLLATE NOCASE,
+                file_name TEXT NOT NULL,
+                stored_name TEXT NOT NULL,
+                mime_type TEXT NOT NULL,
+                size_bytes INTEGER NOT NULL,
+                sha256 TEXT NOT NULL,
+                receiver_username TEXT COLLATE NOCASE,
+                group_id INTEGER,
+                thumbnail_name TEXT,
+                created_at TEXT NOT NULL
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS message_attachments (
+                message_kind TEXT NOT NULL,
+                message_id INTEGER NOT NULL,
+                attachment_id INTEGER NOT NULL,
+                PRIMARY KEY (message_kind, message_id, attachment_id)
+            )"),
+        ],
+    },
+    Migration {
+        version: 6,
+        name: "search_index",
+        steps: &[
+            // Rebuilt from scratch: an unversioned database may hold an index
+            // that can't be told apart from a complete one
+            Sql("DROP TABLE IF EXISTS messages_fts"),
+            Sql("DROP TABLE IF EXISTS group_messages_fts"),
+            Sql("CREATE VIRTUAL TABLE messages_fts USING fts5(
+                message, content='messages', content_rowid='id', tokenize='unicode61 remove_diacritics 2'
+            )"),
+            Sql("CREATE VIRTUAL TABLE group_messages_fts USING fts5(
+                message, content='group_messages', content_rowid='id', tokenize='unicode61 remove_diacritics 2'
+            )"),
+            // Only live messages are indexed, so the 'delete' of an old row
+            // must be skipped when that row was already recalled
+            Sql("DROP TRIGGER IF EXISTS messages_fts_ai"),
+            Sql("CREATE TRIGGER messages_fts_ai AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts(rowid, message) SELECT new.id, new.message WHERE COALESCE(new.deleted, 0) = 0;
+            END"),
+            Sql("DROP TRIGGER IF EXISTS messages_fts_ad"),
+            Sql("CREATE TRIGGER messages_fts_ad AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts(messages_fts, rowid, message) SELECT 'delete', old.id, old.message WHERE COALESCE(old.deleted, 0) = 0;
+            END"),
+            Sql("DROP TRIGGER IF EXISTS messages_fts_au"),
+            Sql("CREATE TRIGGER messages_fts_au AFTER UPDATE OF message, deleted ON messages BEGIN
+                INSERT INTO messages_fts(messages_fts, rowid, message) SELECT 'delete', old.id, old.message WHERE COALESCE(old.deleted, 0) = 0;
+                INSERT INTO messages_fts(rowid, message) SELECT new.id, new.message WHERE COALESCE(new.deleted, 0) = 0;
+            END"),
+            Sql("DROP TRIGGER IF EXISTS group_messages_fts_ai"),
+            Sql("CREATE TRIGGER group_messages_fts_ai AFTER INSERT ON group_messages BEGIN
+                INSERT INTO group_messages_fts(rowid, message) SELECT new.id, new.message WHERE COALESCE(new.deleted, 0) = 0;
+            END"),
+            Sql("DROP TRIGGER IF EXISTS group_messages_fts_ad"),
+            Sql("CREATE TRIGGER group_messages_fts_ad AFTER DELETE ON group_messages BEGIN
+                INSERT INTO group_messages_fts(group_messages_fts, rowid, message) SELECT 'delete', old.id, old.message WHERE COALESCE(old.deleted, 0) = 0;
+            END"),
+            Sql("DROP TRIGGER IF EXISTS group_messages_fts_au"),
+            Sql("CREATE TRIGGER group_messages_fts_au AFTER UPDATE OF message, deleted ON group_messages BEGIN
+                INSERT INTO group_messages_fts(group_messages_fts, rowid, message) SELECT 'delete', old.id, old.message WHERE COALESCE(old.deleted, 0) = 0;
+                INSERT INTO group_messages_fts(rowid, message) SELECT new.id, new.message WHERE COALESCE(new.deleted, 0) = 0;
+            END"),
+            Sql("INSERT INTO messages_fts(rowid, message) SELECT id, message FROM messages WHERE COALESCE(deleted, 0) = 0"),
+            Sql("INSERT INTO group_messages_fts(rowid, message) SELECT id, message FROM group_messages WHERE COALESCE(deleted, 0) = 0"),
+        ],
+    },
+    Migration {
+        version: 7,
+        name: "sessions",
+        steps: &[
+            // Refresh tokens are stored hashed; the previous hash is kept to
+            // spot a rotated token being replayed
+            Sql("CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                username TEXT NOT NULL COLLATE NOCASE,
+                refresh_hash TEXT NOT NULL UNIQUE,
+                previous_refresh_hash TEXT,
+                created_at TEXT NOT NULL,
+                last_used_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL,
+                revoked_at TEXT,
+                user_agent TEXT
+            )"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_sessions_username ON sessions(username)"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_sessions_previous_refresh ON sessions(previous_refresh_hash)"),
+        ],
+    },
+    Migration {
+        version: 8,
+        name: "group_roles",
+        steps: &[
+            AddColumn { table: "group_members", column: "role", definition: "TEXT NOT NULL DEFAULT 'member'" },
+            // The recorded owner keeps the group where they are still a member
+            Sql("UPDATE group_members SET role = 'owner'
+                 WHERE EXISTS (SELECT 1 FROM groups g
+                               WHERE g.id = group_members.group_id
+                                 AND g.owner_username = group_members.username COLLATE NOCASE)"),
+            // Elsewhere the longest-standing member takes over
+            Sql("UPDATE group_members SET role = 'owner'
+                 WHERE id IN (SELECT MIN(id) FROM group_members
+                              WHERE group_id NOT IN (SELECT group_id FROM group_members WHERE role = 'owner')
+                              GROUP BY group_id)"),
+            Sql("UPDATE groups SET owner_username = (SELECT username FROM group_members
+                                                     WHERE group_id = groups.id AND role = 'owner')
+                 WHERE id IN (SELECT group_id FROM group_members WHERE role = 'owner')"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_group_members_user ON group_members(username, group_id)"),
+        ],
+    },
+    Migration {
+        version: 9,
+        name: "group_invites_and_join_requests",
+        steps: &[
+            // Existing groups stay joinable by anyone, as they were
+            AddColumn { table: "groups", column: "join_policy", definition: "TEXT NOT NULL DEFAULT 'open'" },
+            Sql("CREATE TABLE IF NOT EXISTS group_invites (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id INTEGER NOT NULL,
+                token TEXT NOT NULL UNIQUE,
+                created_by TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                max_uses INTEGER,
+                uses INTEGER NOT NULL DEFAULT 0,
+                revoked_at TEXT,
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_group_invites_group ON group_invites(group_id)"),
+            Sql("CREATE TABLE IF NOT EXISTS group_join_requests (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id INTEGER NOT NULL,
+                username TEXT NOT NULL COLLATE NOCASE,
+                message TEXT,
+                status TEXT NOT NULL DEFAULT 'pending',
+                created_at TEXT NOT NULL,
+                decided_by TEXT,
+                decided_at TEXT,
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )"),
+            // At most one open request per user and group
+            Sql("CREATE UNIQUE INDEX IF NOT EXISTS ux_group_join_requests_pending
+                 ON group_join_requests(group_id, username) WHERE status = 'pending'"),
+        ],
+    },
+    Migration {
+        version: 10,
+        name: "group_moderation",
+        steps: &[
+            AddColumn { table: "group_members", column: "muted_until", definition: "TEXT" },
+            Sql("CREATE TABLE IF NOT EXISTS group_bans (
+                group_id INTEGER NOT NULL,
+                username TEXT NOT NULL COLLATE NOCASE,
+                banned_by TEXT NOT NULL,
+                reason TEXT,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                PRIMARY KEY (group_id, username),
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE TABLE IF NOT EXISTS group_member_audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id INTEGER NOT NULL,
+                actor TEXT NOT NULL,
+                target TEXT NOT NULL,
+                action TEXT NOT NULL,
+                role TEXT,
+                until TEXT,
+                reason TEXT,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_group_member_audit_group ON group_member_audit(group_id, id)"),
+        ],
+    },
+    Migration {
+        version: 11,
+        name: "message_threads",
+        steps: &[
+            AddColumn { table: "messages", column: "reply_to", definition: "INTEGER" },
+            AddColumn { table: "messages", column: "thread_root", definition: "INTEGER" },
+            AddColumn { table: "messages", column: "in_channel", definition: "INTEGER NOT NULL DEFAULT 1" },
+            AddColumn { table: "group_messages", column: "reply_to", definition: "INTEGER" },
+            AddColumn { table: "group_messages", column: "thread_root", definition: "INTEGER" },
+            AddColumn { table: "group_messages", column: "in_channel", definition: "INTEGER NOT NULL DEFAULT 1" },
+            Sql("CREATE INDEX IF NOT EXISTS idx_messages_thread_root ON messages(thread_root, id) WHERE thread_root IS NOT NULL"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_group_messages_thread_root ON group_messages(thread_root, id) WHERE thread_root IS NOT NULL"),
+        ],
+    },
+    Migration {
+        version: 12,
+        name: "mentions",
+        steps: &[
+            Sql("CREATE TABLE IF NOT EXISTS mentions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id INTEGER NOT NULL,
+                group_id INTEGER NOT NULL,
+                username TEXT NOT NULL,
+                mentioned_by TEXT NOT NULL,
+                everyone INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL,
+                read_at TEXT,
+                UNIQUE (message_id, username),
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_mentions_username ON mentions(username, id)"),
+            AddColumn { table: "group_members", column: "notifications_muted", definition: "INTEGER NOT NULL DEFAULT 0" },
+        ],
+    },
+    // Reactions and pins used to name a message by id alone, which matched a
+    // DM and a group message with the same id. Existing rows go to the DM when
+    // whoever reacted or pinned is part of it, and otherwise to the group
+    // message; rows matching neither are dropped.
+    Migration {
+        version: 13,
+        name: "message_kinds",
+        steps: &[
+            Sql("CREATE TABLE message_reactions_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_kind TEXT NOT NULL,
+                message_id INTEGER NOT NULL,
+                username TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                UNIQUE(message_kind, message_id, username, emoji)
+            )"),
+            Sql("INSERT OR IGNORE INTO message_reactions_new (message_kind, message_id, username, emoji, created_at)
+                SELECT CASE WHEN EXISTS (SELECT 1 FROM messages m WHERE m.id = r.message_id
+                                           AND (m.sender_username = r.username COLLATE NOCASE
+                                                OR m.receiver_username = r.username COLLATE NOCASE))
+                            THEN 'direct' ELSE 'group' END,
+                       r.message_id, r.username, r.emoji, COALESCE(r.created_at, '')
+                FROM message_reactions r
+                WHERE EXISTS (SELECT 1 FROM messages m WHERE m.id = r.message_id
+                                AND (m.sender_username = r.username COLLATE NOCASE
+                                     OR m.receiver_username = r.username COLLATE NOCASE))
+                   OR EXISTS (SELECT 1 FROM group_messages g WHERE g.id = r.message_id)"),
+            Sql("DROP TABLE message_reactions"),
+            Sql("ALTER TABLE message_reactions_new RENAME TO message_reactions"),
+            Sql("CREATE TABLE pinned_messages_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_kind TEXT NOT NULL,
+                message_id INTEGER NOT NULL,
+                pinned_by TEXT NOT NULL,
+                pinned_at TEXT NOT NULL,
+                UNIQUE(message_kind, message_id)
+            )"),
+            Sql("INSERT OR IGNORE INTO pinned_messages_new (message_kind, message_id, pinned_by, pinned_at)
+                SELECT CASE WHEN EXISTS (SELECT 1 FROM messages m WHERE m.id = p.message_id
+                                           AND (m.sender_username = p.pinned_by COLLATE NOCASE
+                                                OR m.receiver_username = p.pinned_by COLLATE NOCASE))
+                            THEN 'direct' ELSE 'group' END,
+                       p.message_id, p.pinned_by, p.pinned_at
+                FROM pinned_messages p
+                WHERE EXISTS (SELECT 1 FROM messages m WHERE m.id = p.message_id
+                                AND (m.sender_username = p.pinned_by COLLATE NOCASE
+                                     OR m.receiver_username = p.pinned_by COLLATE NOCASE))
+                   OR EXISTS (SELECT 1 FROM group_messages g WHERE g.id = p.message_id)"),
+            Sql("DROP TABLE pinned_messages"),
+            Sql("ALTER TABLE pinned_messages_new RENAME TO pinned_messages"),
+        ],
+    },
+    Migration {
+        version: 14,
+        name: "conversation_settings",
+        steps: &[
+            // One row per user and DM peer or group, like chat_themes; a
+            // group's mute stays on group_members.notifications_muted
+            Sql("CREATE TABLE IF NOT EXISTS conversation_settings (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL COLLATE NOCASE,
+                peer_username TEXT COLLATE NOCASE,
+                group_id INTEGER,
+                pinned INTEGER NOT NULL DEFAULT 0,
+                archived INTEGER NOT NULL DEFAULT 0,
+                muted INTEGER NOT NULL DEFAULT 0,
+                updated_at TEXT NOT NULL
+            )"),
+            Sql("CREATE UNIQUE INDEX IF NOT EXISTS ux_conversation_settings_dm ON conversation_settings(username, peer_username)"),
+            Sql("CREATE UNIQUE INDEX IF NOT EXISTS ux_conversation_settings_group ON conversation_settings(username, group_id)"),
+        ],
+    },
+    // `sent` stays in step with status = 'sent'; a recurring entry stays
+    // pending with scheduled_at moved to its next occurrence
+    Migration {
+        version: 15,
+        name: "scheduled_message_management",
+        steps: &[
+            AddColumn { table: "scheduled_messages", column: "status", definition: "TEXT NOT NULL DEFAULT 'pending'" },
+            AddColumn { table: "scheduled_messages", column: "recurrence", definition: "TEXT" },
+            AddColumn { table: "scheduled_messages", column: "time_zone", definition: "TEXT" },
+            // Wall-clock time of the first occurrence in time_zone
+            AddColumn { table: "scheduled_messages", column: "local_start", definition: "TEXT" },
+            AddColumn { table: "scheduled_messages", column: "attempts", definition: "INTEGER NOT NULL DEFAULT 0" },
+            AddColumn { table: "scheduled_messages", column: "last_error", definition: "TEXT" },
+            AddColumn { table: "scheduled_messages", column: "sent_count", definition: "INTEGER NOT NULL DEFAULT 0" },
+            AddColumn { table: "scheduled_messages", column: "updated_at", definition: "TEXT" },
+            Sql("UPDATE scheduled_messages SET status = 'sent', sent_count = 1 WHERE sent = 1"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_scheduled_messages_due ON scheduled_messages(status, scheduled_at_epoch)"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_scheduled_messages_sender ON scheduled_messages(sender_username, id)"),
+        ],
+    },
+    // The reveal sweep looks up messages by reveal_at every second
+    Migration {
+        version: 16,
+        name: "reveal_at_indexes",
+        steps: &[
+            Sql("CREATE INDEX IF NOT EXISTS idx_messages_reveal_at ON messages(reveal_at) WHERE reveal_at IS NOT NULL"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_group_messages_reveal_at ON group_messages(reveal_at) WHERE reveal_at IS NOT NULL"),
+        ],
+    },
+    // closed_by stays NULL when a poll expired on its own
+    Migration {
+        version: 17,
+        name: "poll_closing",
+        steps: &[
+            AddColumn { table: "polls", column: "closed_at", definition: "TEXT" },
+            AddColumn { table: "polls", column: "closed_by", definition: "TEXT" },
+            Sql("CREATE INDEX IF NOT EXISTS idx_polls_active ON polls(is_active, expires_at)"),
+        ],
+    },
+    // Ranked-choice and quiz polls, and anonymous voting. A ranked ballot is
+    // one poll_votes row per ranked option, with rank 1 the first preference.
+    Migration {
+        version: 18,
+        name: "poll_types",
+        steps: &[
+            AddColumn { table: "polls", column: "poll_type", definition: "TEXT NOT NULL DEFAULT 'standard'" },
+            AddColumn { table: "polls", column: "anonymous", definition: "INTEGER NOT NULL DEFAULT 0" },
+            AddColumn { table: "polls", column: "correct_option_id", definition: "INTEGER" },
+            AddColumn { table: "poll_votes", column: "rank", definition: "INTEGER" },
+            Sql("CREATE INDEX IF NOT EXISTS idx_poll_votes_user ON poll_votes(poll_id, username)"),
+        ],
+    },
+    // Polls in DMs: a poll belongs to a group or, with receiver_username, to
+    // the DM between its creator and that user. Making group_id nullable
+    // means rebuilding the poll tables. The old ones are renamed out of the
+    // way first, which also points their foreign keys at each other, so that
+    // dropping them cannot cascade into the copies.
+    Migration {
+        version: 19,
+        name: "dm_polls",
+        steps: &[
+            Sql("ALTER TABLE poll_votes RENAME TO poll_votes_old"),
+            Sql("ALTER TABLE poll_options RENAME TO poll_options_old"),
+            Sql("ALTER TABLE polls RENAME TO polls_old"),
+            Sql("CREATE TABLE polls (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                group_id INTEGER,
+                receiver_username TEXT,
+                creator_username TEXT NOT NULL,
+                question TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                expires_at TEXT,
+                is_active BOOLEAN DEFAULT 1,
+                allow_multiple_choices BOOLEAN DEFAULT 0,
+                closed_at TEXT,
+                closed_by TEXT,
+                poll_type TEXT NOT NULL DEFAULT 'standard',
+                anonymous INTEGER NOT NULL DEFAULT 0,
+                correct_option_id INTEGER,
+                CHECK ((group_id IS NULL) <> (receiver_username IS NULL)),
+                FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE TABLE poll_options (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                poll_id INTEGER NOT NULL,
+                option_text TEXT NOT NULL,
+                option_order INTEGER NOT NULL,
+                FOREIGN KEY (poll_id) REFERENCES polls(id) ON DELETE CASCADE
+            )"),
+            Sql("CREATE TABLE poll_votes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                poll_id INTEGER NOT NULL,
+                option_id INTEGER NOT NULL,
+                username TEXT NOT NULL,
+                voted_at TEXT NOT NULL,
+                rank INTEGER,
+                UNIQUE(poll_id, option_id, username),
+                FOREIGN KEY (poll_id) REFERENCES polls(id) ON DELETE CASCADE,
+                FOREIGN KEY (option_id) REFERENCES poll_options(id) ON DELETE CASCADE
+            )"),
+            Sql("INSERT INTO polls (id, group_id, creator_username, question, created_at, expires_at, is_active,
+                                   allow_multiple_choices, closed_at, closed_by, poll_type, anonymous, correct_option_id)
+                SELECT id, group_id, creator_username, question, created_at, expires_at, is_active,
+                       allow_multiple_choices, closed_at, closed_by, poll_type, anonymous, correct_option_id
+                FROM polls_old"),
+            Sql("INSERT INTO poll_options (id, poll_id, option_text, option_order)
+                SELECT id, poll_id, option_text, option_order FROM poll_options_old"),
+            Sql("INSERT INTO poll_votes (id, poll_id, option_id, username, voted_at, rank)
+                SELECT id, poll_id, option_id, username, voted_at, rank FROM poll_votes_old"),
+            Sql("DROP TABLE poll_votes_old"),
+            Sql("DROP TABLE poll_options_old"),
+            Sql("DROP TABLE polls_old"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_polls_active ON polls(is_active, expires_at)"),
+            Sql("CREATE INDEX IF NOT EXISTS idx_poll_votes_user ON poll_votes(poll_id, username)"),
+        ],
+    },
+    // Trivia games now carry their questions' answers in their state, so
+    // that moves need no lookups. Games in progress get the answer to the
+    // question they are on.
+    Migration {
+        version: 20,
+        name: "trivia_answers_in_state",
+        steps: &[
+            Sql("UPDATE games SET game_state = json_set(game_state, '$.current_question.answer',
+                    (SELECT correct_answer FROM trivia_questions
+                     WHERE id = json_extract(games.game_state, '$.current_question.id')))
+                WHERE game_type = 'trivia' AND status != 'finished'
+                  AND json_extract(game_state, '$.current_question.id') IS NOT NULL"),
+        ],
+    },
+    // Poll announcements remember their poll, so clients can open it from
+    // history. Older announcements stay without one.
+    Migration {
+        version: 21,
+        name: "poll_id_on_messages",
+        steps: &[
+            AddColumn { table: "messages", column: "poll_id", definition: "INTEGER" },
+            AddColumn { table: "group_messages", column: "poll_id", definition: "INTEGER" },
+        ],
+    },
+    // Each DM names its pair of users in one indexed column, so the next seq
+    // is a lookup rather than a scan of both directions of the pair. The key
+    // is the two lowercased usernames in order, as a JSON array.
+    Migration {
+        version: 22,
+        name: "messages_conversation_key",
+        steps: &[
+            AddColumn { table: "messages", column: "conversation_key", definition: "TEXT" },
+            Sql("UPDATE messages SET conversation_key = json_array(
+                    min(lower(sender_username), lower(receiver_username)),
+                    max(lower(sender_username), lower(receiver_username)))"),
+            Sql("CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_conversation_seq ON messages(conversation_key, seq)"),
+        ],
+    },
+    // How far delayed reveals have been announced, so a restart picks up
+    // where the last run stopped. It starts now: reveals already past when
+    // this is applied were never going to be announced.
+    Migration {
+        version: 23,
+        name: "reveal_progress",
+        steps: &[
+            Sql("CREATE TABLE IF NOT EXISTS reveal_progress (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                revealed_through TEXT NOT NULL
+            )"),
+            Sql("INSERT OR IGNORE INTO reveal_progress (id, revealed_through) VALUES (1, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))"),
+        ],
+    },
+];
+
+/// The migration that failed, and why. Nothing of it was applied.
+#[derive(Debug)]
+pub struct MigrationError {
+    pub version: i64,
+    pub name: &'static str,
+    // 1-based index of the failing step, 0 when it wasn't a step that failed
+    pub step: usize,
+    pub error: sqlx::Error,
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.step > 0 {
+            write!(f, "migration {} ({}) failed at step {}: {}", self.version, self.name, self.step, self.error)
+        } else {
+            write!(f, "migration {} ({}) failed: {}", self.version, self.name, self.error)
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+/// An applied migration as recorded in `schema_version`.
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    pub applied_at: String,
+}
+
+async fn ensure_version_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        )"
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn applied(pool: &SqlitePool) -> Result<Vec<AppliedMigration>, sqlx::Error> {
+    ensure_version_table(pool).await?;
+    let rows = sqlx::query("SELECT version, name, applied_at FROM schema_version ORDER BY version")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| AppliedMigration { version: row.get("version"), name: row.get("name"), applied_at: row.get("applied_at") })
+        .collect())
+}
+
+async fn column_exists(conn: &mut SqliteConnection, table: &str, column: &str) -> Result<bool, sqlx::Error> {
+    let found: Option<i64> = sqlx::query_scalar("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")
+        .bind(table)
+        .bind(column)
+        .fetch_optional(conn)
+        .await?;
+    Ok(found.is_some())
+}
+
+async fn apply(pool: &SqlitePool, migration: &Migration) -> Result<(), MigrationError> {
+    let fail = |step: usize| move |error: sqlx::Error| MigrationError { version: migration.version, name: migration.name, step, error };
+
+    let mut tx = pool.begin().await.map_err(fail(0))?;
+    for (index, step) in migration.steps.iter().enumerate() {
+        match step {
+            Sql(sql) => {
+                sqlx::query(sql).execute(&mut *tx).await.map_err(fail(index + 1))?;
+            }
+            AddColumn { table, column, definition } => {
+                if !column_exists(&mut tx, table, column).await.map_err(fail(index + 1))? {
+                    let sql = format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition);
+                    sqlx::query(&sql).execute(&mut *tx).await.map_err(fail(index + 1))?;
+                }
+            }
+        }
+    }
+    sqlx::query("INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)")
+        .bind(migration.version)
+        .bind(migration.name)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await
+        .map_err(fail(0))?;
+    tx.commit().await.map_err(fail(0))
+}
+
+/// Apply every migration the database hasn't seen yet, returning the ones
+/// applied now. Stops at the first failure.
+pub async fn migrate(pool: &SqlitePool) -> Result<Vec<&'static Migration>, MigrationError> {
+    let current = applied(pool)
+        .await
+        .map_err(|error| MigrationError { version: 0, name: "schema_version", step: 0, error })?
+        .last()
+        .map(|m| m.version)
+        .unwrap_or(0);
+
+    let mut newly_applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        println!("DEBUG: Applying migration {} ({})", migration.version, migration.name);
+        apply(pool, migration).await?;
+        newly_applied.push(migration);
+    }
+    Ok(newly_applied)
+}
+
+/// Print which migrations are applied and which are pending. Returns true
+/// when the database is exactly at the latest version.
+pub async fn check(pool: &SqlitePool) -> Result<bool, sqlx::Error> {
+    let applied = applied(pool).await?;
+    let mut up_to_date = true;
+
+    for migration in MIGRATIONS {
+        match applied.iter().find(|a| a.version == migration.version) {
+            Some(a) if a.name == migration.name => {
+                println!("  applied   {:>3}  {}  ({})", migration.version, migration.name, a.applied_at);
+            }
+            Some(a) => {
+                up_to_date = false;
+                println!("  MISMATCH  {:>3}  {}  (database has `{}`)", migration.version, migration.name, a.name);
+            }
+            None => {
+                up_to_date = false;
+                println!("  pending   {:>3}  {}", migration.version, migration.name);
+            }
+        }
+    }
+    // Versions only a newer build knows about
+    for a in applied.iter().filter(|a| MIGRATIONS.iter().all(|m| m.version != a.version)) {
+        up_to_date = false;
+        println!("  UNKNOWN   {:>3}  {}  ({})", a.version, a.name, a.applied_at);
+    }
+    Ok(up_to_date)
+}
@@ -0,0 +1,138 @@
+// src/presence.rs
+//! Presence (online / away / offline) and typing indicators.
+//!
+//! Status is derived from the connection registry: a user with an open socket
+//! that saw a frame recently is online, one whose sockets have all gone quiet
+//! is away, and one with no socket is offline. The tracker remembers what was
+//! last announced for each user so that only transitions are fanned out.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::registry::ConnectionRegistry;
+
+/// Idle time after which a connected user is shown as away.
+pub const AWAY_AFTER_SECS: u64 = 5 * 60;
+/// A typing indicator lapses this long after the last `typing_start`.
+pub const TYPING_TTL: Duration = Duration::from_secs(6);
+/// Repeated `typing_start` frames closer together than this are dropped.
+pub const TYPING_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Online,
+    Away,
+    Offline,
+}
+
+pub fn status_of(registry: &ConnectionRegistry, username: &str) -> Status {
+    match registry.idle_secs(username) {
+        None => Status::Offline,
+        Some(idle) if idle >= AWAY_AFTER_SECS => Status::Away,
+        Some(_) => Status::Online,
+    }
+}
+
+/// Where someone is typing: a DM with a peer, or a group.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TypingScope {
+    Direct(String),
+    Group(i64),
+}
+
+#[derive(Debug)]
+struct Typing {
+    // As given by the typist, for the expiry notice
+    username: String,
+    last_start: Instant,
+    expires: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct PresenceTracker {
+    // Lower-cased username -> last status announced to their peers
+    announced: Mutex<HashMap<String, Status>>,
+    // (lower-cased typist, scope) -> typing state
+    typing: Mutex<HashMap<(String, TypingScope), Typing>>,
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `status` as the user's current one. Returns true when it
+    /// differs from what was last announced, i.e. when peers need telling.
+    pub fn announce(&self, username: &str, status: Status) -> bool {
+        let mut announced = self.announced.lock().unwrap();
+        let key = username.to_lowercase();
+        let previous = if status == Status::Offline {
+            announced.remove(&key)
+        } else {
+            announced.insert(key, status)
+        };
+        previous.unwrap_or(Status::Offline) != status
+    }
+
+    /// Register a `typing_start`. Returns true when the user was not
+    /// already typing there and the start should be fanned out; repeats
+    /// only push the expiry back, and ones that come too fast are ignored.
+    pub fn start_typing(&self, username: &str, scope: TypingScope) -> bool {
+        let now = Instant::now();
+        let mut typing = self.typing.lock().unwrap();
+        match typing.get_mut(&(username.to_lowercase(), scope.clone())) {
+            Some(entry) if entry.expires > now => {
+                if now.duration_since(entry.last_start) >= TYPING_MIN_INTERVAL {
+                    entry.last_start = now;
+                    entry.expires = now + TYPING_TTL;
+                }
+                false
+            }
+            _ => {
+                typing.insert(
+                    (username.to_lowercase(), scope),
+                    Typing { username: username.to_string(), last_start: now, expires: now + TYPING_TTL },
+                );
+                true
+            }
+        }
+    }
+
+    /// Register a `typing_stop`. Returns true when the user was typing there.
+    pub fn stop_typing(&self, username: &str, scope: TypingScope) -> bool {
+        let mut typing = self.typing.lock().unwrap();
+        typing
+            .remove(&(username.to_lowercase(), scope))
+            .is_some_and(|entry| entry.expires > Instant::now())
+    }
+
+    /// Clear every indicator the user has open, e.g. when they disconnect.
+    pub fn stop_all_typing(&self, username: &str) -> Vec<TypingScope> {
+        let key = username.to_lowercase();
+        let mut typing = self.typing.lock().unwrap();
+        let scopes: Vec<TypingScope> = typing.keys().filter(|(u, _)| *u == key).map(|(_, s)| s.clone()).collect();
+        for scope in &scopes {
+            typing.remove(&(key.clone(), scope.clone()));
+        }
+        scopes
+    }
+
+    /// Remove indicators whose TTL has passed, returning `(username, scope)`.
+    pub fn expire_typing(&self) -> Vec<(String, TypingScope)> {
+        let now = Instant::now();
+        let mut typing = self.typing.lock().unwrap();
+        let mut expired = Vec::new();
+        typing.retain(|(_, scope), entry| {
+            if entry.expires <= now {
+                expired.push((entry.username.clone(), scope.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+}
@@ -0,0 +1,638 @@
+// src/protocol.rs
+//! Typed WebSocket protocol for `/ws`.
+//!
+//! Every text frame a client sends is a JSON object tagged by `type` and is
+//! decoded into a [`ClientCommand`]. Everything the server pushes back is a
+//! [`ServerEvent`], tagged the same way. Frames that cannot be decoded, and
+//! commands that fail, are answered with an `error` event echoing the
+//! client-supplied `request_id`.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::handlers::conversations::{ConversationActivity, ConversationSummary};
+use crate::handlers::groups::{JoinRequest, MemberChange};
+use crate::handlers::mentions::Mention;
+use crate::handlers::polls::{Poll, PollType, QuizScore};
+use crate::handlers::scheduled::{ScheduleChanges, ScheduledMessage};
+use crate::handlers::uploads::Attachment;
+use crate::presence::Status;
+use crate::{ChatMessage, Game};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    ChatMessage {
+        receiver_username: String,
+        message: String,
+        // Ids returned by `POST /uploads` for this conversation
+        #[serde(default)]
+        attachment_ids: Vec<i64>,
+        #[serde(default)]
+        reveal_after_secs: Option<i64>,
+        #[serde(default)]
+        reveal_at: Option<String>,
+        // Id of an earlier message in this conversation being replied to
+        #[serde(default)]
+        reply_to: Option<i64>,
+        // Show the reply in the main history too, not only in its thread
+        #[serde(default)]
+        also_send_to_channel: bool,
+    },
+    GroupMessage {
+        group_id: i64,
+        message: String,
+        #[serde(default)]
+        attachment_ids: Vec<i64>,
+        #[serde(default)]
+        reveal_after_secs: Option<i64>,
+        #[serde(default)]
+        reveal_at: Option<String>,
+        #[serde(default)]
+        reply_to: Option<i64>,
+        #[serde(default)]
+        also_send_to_channel: bool,
+    },
+    EditMessage {
+        message_id: i64,
+        // Which table `message_id` is in; may be left out when only one
+        // message with that id is visible to the user
+        #[serde(default)]
+        kind: Option<MessageKind>,
+        message: String,
+    },
+    DeleteMessage {
+        message_id: i64,
+        #[serde(default)]
+        kind: Option<MessageKind>,
+    },
+    ScheduleMessage {
+        message: String,
+        #[serde(default)]
+        receiver_username: Option<String>,
+        #[serde(default)]
+        group_id: Option<i64>,
+        #[serde(default)]
+        scheduled_at: Option<String>,
+        #[serde(default)]
+        scheduled_at_epoch: Option<i64>,
+        // Wall-clock time in an IANA time zone, instead of the fields above
+        #[serde(default)]
+        local_time: Option<String>,
+        #[serde(default)]
+        time_zone: Option<String>,
+        // RRULE such as `FREQ=WEEKLY;BYDAY=MO,WE,FR`
+        #[serde(default)]
+        recurrence: Option<String>,
+    },
+    /// The user's pending and failed scheduled messages, or all of them.
+    ListScheduledMessages {
+        #[serde(default)]
+        include_finished: bool,
+    },
+    UpdateScheduledMessage {
+        id: i64,
+        #[serde(flatten)]
+        changes: ScheduleChanges,
+    },
+    CancelScheduledMessage {
+        id: i64,
+    },
+    TypingStart(TypingTarget),
+    TypingStop(TypingTarget),
+    // WebRTC signaling passthrough
+    CallOffer(CallSignalRequest),
+    CallAnswer(CallSignalRequest),
+    CallIce(CallSignalRequest),
+    CallEnd(CallSignalRequest),
+    CallNeedOffer(CallSignalRequest),
+    // History paging: `before_id` goes back from a message, `after_id`
+    // forward from one; with neither, the newest page
+    GetConversation {
+        receiver_username: String,
+        #[serde(default)]
+        before_id: Option<i64>,
+        #[serde(default)]
+        after_id: Option<i64>,
+        #[serde(default)]
+        limit: Option<i64>,
+    },
+    GetGroupConversation {
+        group_id: i64,
+        #[serde(default)]
+        before_id: Option<i64>,
+        #[serde(default)]
+        after_id: Option<i64>,
+        #[serde(default)]
+        limit: Option<i64>,
+    },
+    /// A page of the replies to `root_id`, in the DM with `receiver_username`
+    /// or in `group_id`. Pages like the history frames.
+    GetThread {
+        #[serde(default)]
+        receiver_username: Option<String>,
+        #[serde(default)]
+        group_id: Option<i64>,
+        root_id: i64,
+        #[serde(default)]
+        before_id: Option<i64>,
+        #[serde(default)]
+        after_id: Option<i64>,
+        #[serde(default)]
+        limit: Option<i64>,
+    },
+    /// Every DM and group of the user with its last message and unread count.
+    GetConversations,
+    /// Mark everything in a conversation up to `up_to_seq` as read.
+    Read {
+        #[serde(default)]
+        receiver_username: Option<String>,
+        #[serde(default)]
+        group_id: Option<i64>,
+        up_to_seq: i64,
+    },
+    /// Replay messages stored after the given per-conversation sequence
+    /// numbers: `direct` is keyed by peer username, `groups` by group id.
+    /// Naming a group the user is not in is forbidden.
+    Sync {
+        #[serde(default)]
+        direct: HashMap<String, i64>,
+        // Keyed by the group id as a string, as JSON object keys always are
+        #[serde(default)]
+        groups: HashMap<String, i64>,
+    },
+    // Polls
+    /// A poll in a group, or with `receiver_username` in the DM with them.
+    CreatePoll {
+        #[serde(default)]
+        group_id: Option<i64>,
+        #[serde(default)]
+        receiver_username: Option<String>,
+        poll_question: String,
+        poll_options: Vec<String>,
+        #[serde(default)]
+        poll_allow_multiple: Option<bool>,
+        #[serde(default)]
+        poll_expires_at: Option<String>,
+        #[serde(default)]
+        poll_type: Option<PollType>,
+        #[serde(default)]
+        poll_anonymous: Option<bool>,
+        // Index into poll_options of a quiz's answer
+        #[serde(default)]
+        poll_correct_option: Option<usize>,
+    },
+    /// A ranked poll takes every ranked option, most preferred first.
+    VotePoll {
+        poll_id: i64,
+        poll_option_ids: Vec<i64>,
+    },
+    GetPollDetails {
+        poll_id: i64,
+    },
+    /// Close a poll now; its creator and group admins may.
+    ClosePoll {
+        poll_id: i64,
+    },
+    /// Scores across a group's closed quizzes.
+    GetQuizScores {
+        group_id: i64,
+    },
+    // Games
+    CreateGame {
+        game_type: String,
+        #[serde(default)]
+        group_id: Option<i64>,
+        #[serde(default)]
+        target_username: Option<String>,
+    },
+    JoinGame {
+        game_id: i64,
+    },
+    GameMove {
+        game_id: i64,
+        game_move: String,
+    },
+    GetGameState {
+        game_id: i64,
+    },
+    /// A chess game's moves so far as PGN.
+    GetGamePgn {
+        game_id: i64,
+    },
+    // Reactions and pins
+    AddReaction {
+        message_id: i64,
+        #[serde(default)]
+        kind: Option<MessageKind>,
+        emoji: String,
+    },
+    RemoveReaction {
+        message_id: i64,
+        #[serde(default)]
+        kind: Option<MessageKind>,
+        emoji: String,
+    },
+    PinMessage {
+        message_id: i64,
+        #[serde(default)]
+        kind: Option<MessageKind>,
+    },
+    UnpinMessage {
+        message_id: i64,
+        #[serde(default)]
+        kind: Option<MessageKind>,
+    },
+    GetReactions {
+        message_id: i64,
+        #[serde(default)]
+        kind: Option<MessageKind>,
+    },
+    GetPinnedMessages,
+}
+
+/// Which table a message lives in. DM and group messages are numbered
+/// separately, so a message is named by its kind and id together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Direct,
+    Group,
+}
+
+impl MessageKind {
+    pub fn of(group_id: Option<i64>) -> MessageKind {
+        if group_id.is_some() { MessageKind::Group } else { MessageKind::Direct }
+    }
+
+    /// The value stored in `message_kind` columns.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageKind::Direct => "direct",
+            MessageKind::Group => "group",
+        }
+    }
+
+    pub fn table(self) -> &'static str {
+        match self {
+            MessageKind::Direct => "messages",
+            MessageKind::Group => "group_messages",
+        }
+    }
+}
+
+/// The conversation a typing frame is about: a DM peer or a group.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TypingTarget {
+    #[serde(default)]
+    pub receiver_username: Option<String>,
+    #[serde(default)]
+    pub group_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CallSignalRequest {
+    pub target_username: String,
+    #[serde(default)]
+    pub sdp: Option<String>,
+    #[serde(default)]
+    pub candidate: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallSignal {
+    pub from: String,
+    pub to: String,
+    pub sdp: Option<String>,
+    pub candidate: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TypingNotice {
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<i64>,
+}
+
+/// Receipt news for a message sender: `username` received or read these.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReceiptUpdate {
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<i64>,
+    pub message_ids: Vec<i64>,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    ChatMessage(ChatMessage),
+    // The cursors are echoed so a client can tell a first page from an older one
+    ConversationHistory {
+        conversation_with: String,
+        messages: Vec<ChatMessage>,
+        has_more: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        before_id: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        after_id: Option<i64>,
+    },
+    GroupConversationHistory {
+        group_id: i64,
+        messages: Vec<ChatMessage>,
+        has_more: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        before_id: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        after_id: Option<i64>,
+    },
+    Thread {
+        root: ChatMessage,
+        messages: Vec<ChatMessage>,
+        has_more: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        before_id: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        after_id: Option<i64>,
+    },
+    Conversations {
+        conversations: Vec<ConversationSummary>,
+    },
+    /// One conversation's fresh summary, after a read or a settings change.
+    ConversationUpdated(ConversationSummary),
+    /// A message landed in a conversation or changed: its last message now
+    /// and how the recipient's unread count moved.
+    ConversationActivity(ConversationActivity),
+    Presence {
+        username: String,
+        status: Status,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        last_seen_at: Option<String>,
+    },
+    TypingStart(TypingNotice),
+    TypingStop(TypingNotice),
+    Delivered(ReceiptUpdate),
+    Read(ReceiptUpdate),
+    MessageEdited {
+        message_id: i64,
+        kind: MessageKind,
+        message: String,
+        edited_at: String,
+    },
+    MessageDeleted {
+        message_id: i64,
+        kind: MessageKind,
+    },
+    /// The content of a message sent with `reveal_at`, once that time has
+    /// passed. Until then recipients only had a placeholder.
+    MessageRevealed {
+        message_id: i64,
+        kind: MessageKind,
+        message: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<Attachment>,
+    },
+    /// Sent after a `sync` replay with the latest seq of every conversation
+    /// the user is in. `truncated` means some conversation had more missed
+    /// messages than one replay sends; sync again from the new positions.
+    SyncComplete {
+        direct: HashMap<String, i64>,
+        groups: HashMap<i64, i64>,
+        truncated: bool,
+    },
+    ScheduleAck {
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        scheduled_for_epoch: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    ScheduledMessages {
+        scheduled: Vec<ScheduledMessage>,
+    },
+    /// To the sender's sockets whenever one of their scheduled messages is
+    /// edited, cancelled, sent, re-armed or retried.
+    ScheduledMessageUpdated(ScheduledMessage),
+    CallOffer(CallSignal),
+    CallAnswer(CallSignal),
+    CallIce(CallSignal),
+    CallEnd(CallSignal),
+    CallNeedOffer(CallSignal),
+    PollDetails {
+        poll: Poll,
+    },
+    /// To a group when one of its polls closes, with the final tallies.
+    PollClosed {
+        poll: Poll,
+    },
+    QuizScores {
+        group_id: i64,
+        scores: Vec<QuizScore>,
+    },
+    GameCreated {
+        game: Game,
+    },
+    GameJoined {
+        game: Game,
+    },
+    GameUpdate {
+        game: Game,
+    },
+    GameState {
+        game: Game,
+    },
+    GamePgn {
+        game_id: i64,
+        pgn: String,
+    },
+    GameError {
+        error: String,
+    },
+    ReactionAdded {
+        message_id: i64,
+        kind: MessageKind,
+        username: String,
+        emoji: String,
+    },
+    ReactionRemoved {
+        message_id: i64,
+        kind: MessageKind,
+        username: String,
+        emoji: String,
+    },
+    ReactionsList {
+        message_id: i64,
+        kind: MessageKind,
+        reactions: HashMap<String, Vec<String>>,
+    },
+    MessagePinned {
+        message_id: i64,
+        kind: MessageKind,
+        pinned_by: String,
+    },
+    MessageUnpinned {
+        message_id: i64,
+        kind: MessageKind,
+    },
+    PinnedMessagesList {
+        pinned_messages: Vec<serde_json::Value>,
+    },
+    /// To a group's moderators and above: someone asked to join.
+    JoinRequested {
+        request: JoinRequest,
+    },
+    /// To the requester and the group's reviewers once a request is settled.
+    JoinRequestDecided {
+        request: JoinRequest,
+    },
+    /// To a group and the member concerned: someone was kicked, banned,
+    /// muted, promoted or handed ownership.
+    MemberChanged(MemberChange),
+    /// To a member named in a group message, or reached through `@all`;
+    /// sent whether or not they muted the group.
+    Mentioned(Mention),
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<serde_json::Value>,
+        code: String,
+        message: String,
+    },
+}
+
+impl ServerEvent {
+    pub fn error(request_id: Option<serde_json::Value>, err: CommandError) -> Self {
+        ServerEvent::Error {
+            request_id,
+            code: err.code.to_string(),
+            message: err.message,
+        }
+    }
+}
+
+/// A decoded client frame together with the optional id the client attached
+/// so it can correlate the `error` event with the frame that caused it.
+#[derive(Debug)]
+pub struct ClientFrame {
+    pub request_id: Option<serde_json::Value>,
+    pub command: ClientCommand,
+}
+
+/// Why a command could not be carried out; sent back as an `error` event.
+#[derive(Debug)]
+pub struct CommandError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn invalid(message: impl Into<String>) -> Self {
+        CommandError { code: "invalid_frame", message: message.into() }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        CommandError { code: "not_found", message: message.into() }
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        CommandError { code: "forbidden", message: message.into() }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        CommandError { code: "internal_error", message: message.into() }
+    }
+}
+
+/// A frame that could not be decoded, with whatever `request_id` could be
+/// recovered from it.
+#[derive(Debug)]
+pub struct FrameError {
+    pub request_id: Option<serde_json::Value>,
+    pub error: CommandError,
+}
+
+impl From<FrameError> for ServerEvent {
+    fn from(e: FrameError) -> Self {
+        ServerEvent::error(e.request_id, e.error)
+    }
+}
+
+/// Whether `frame_type` names a command. Only the tag is decoded, so a bad
+/// value further in, such as an unknown `kind`, cannot pass for a bad type.
+fn is_known_type(frame_type: &str) -> bool {
+    match serde_json::from_value::<ClientCommand>(serde_json::json!({ "type": frame_type })) {
+        Ok(_) => true,
+        Err(e) => !e.to_string().starts_with("unknown variant"),
+    }
+}
+
+/// Decode one text frame into a command.
+pub fn parse_frame(text: &str) -> Result<ClientFrame, FrameError> {
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|e| FrameError {
+        request_id: None,
+        error: CommandError { code: "malformed_json", message: e.to_string() },
+    })?;
+
+    let request_id = value.get("request_id").filter(|v| !v.is_null()).cloned();
+
+    let frame_type = match value.get("type").and_then(|t| t.as_str()) {
+        Some(t) => t.to_string(),
+        None => {
+            return Err(FrameError {
+                request_id,
+                error: CommandError::invalid("Frame must be a JSON object with a string `type` field"),
+            });
+        }
+    };
+
+    if !is_known_type(&frame_type) {
+        return Err(FrameError {
+            request_id,
+            error: CommandError { code: "unknown_type", message: format!("Unknown frame type `{}`", frame_type) },
+        });
+    }
+
+    match serde_json::from_value::<ClientCommand>(value) {
+        Ok(command) => Ok(ClientFrame { request_id, command }),
+        Err(e) => Err(FrameError {
+            request_id,
+            error: CommandError::invalid(format!("Invalid `{}` frame: {}", frame_type, e)),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> CommandError {
+        parse_frame(text).unwrap_err().error
+    }
+
+    #[test]
+    fn an_unknown_type_is_reported_as_such() {
+        let e = error(r#"{"type":"edit_mesage","message_id":1,"message":"hi"}"#);
+        assert_eq!(e.code, "unknown_type");
+        assert_eq!(e.message, "Unknown frame type `edit_mesage`");
+    }
+
+    #[test]
+    fn a_bad_field_of_a_known_type_is_an_invalid_frame() {
+        let e = error(r#"{"type":"edit_message","message_id":1,"kind":"dm","message":"hi"}"#);
+        assert_eq!(e.code, "invalid_frame");
+        assert!(e.message.starts_with("Invalid `edit_message` frame: unknown variant `dm`"), "{}", e.message);
+
+        let e = error(r#"{"type":"edit_message","message":"hi"}"#);
+        assert_eq!(e.code, "invalid_frame");
+        assert!(e.message.contains("missing field `message_id`"), "{}", e.message);
+    }
+
+    #[test]
+    fn request_ids_are_echoed_on_errors() {
+        let frame = parse_frame(r#"{"type":"nope","request_id":"r1"}"#).unwrap_err();
+        assert_eq!(frame.request_id, Some(serde_json::json!("r1")));
+        assert!(parse_frame(r#"{"type":"edit_message","message_id":1,"kind":"group","message":"hi"}"#).is_ok());
+    }
+}
@@ -0,0 +1,351 @@
+// src/recurrence.rs
+//! RRULE-style repeat rules for scheduled messages.
+//!
+//! A rule is a subset of RFC 5545: `FREQ=DAILY|WEEKLY|MONTHLY` with optional
+//! `INTERVAL`, `BYDAY` (weekly only), `COUNT` and `UNTIL`, e.g.
+//! `FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR`. Occurrences are wall-clock times in
+//! the series' time zone, so a 09:00 standup stays at 09:00 across DST.
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+// Stops a rule whose occurrences all fall before `after` from looping forever
+const MAX_STEPS: u32 = 100_000;
+
+// Largest INTERVAL accepted; larger ones only serve to overflow the calendar
+const MAX_INTERVAL: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub frequency: Frequency,
+    pub interval: u32,
+    // Weekly only; empty means the weekday the series starts on
+    pub by_day: Vec<Weekday>,
+    pub count: Option<u32>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// Why a rule string was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleError(pub String);
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+fn parse_weekday(s: &str) -> Option<Weekday> {
+    Some(match s {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+fn weekday_code(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+// UNTIL is a date (`20261231`) or a UTC date-time (`20261231T170000Z`)
+fn parse_until(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(at) = NaiveDateTime::parse_from_str(s.trim_end_matches('Z'), "%Y%m%dT%H%M%S") {
+        return Some(Utc.from_utc_datetime(&at));
+    }
+    let day = NaiveDate::parse_from_str(s, "%Y%m%d").ok()?;
+    Some(Utc.from_utc_datetime(&day.and_time(NaiveTime::from_hms_opt(23, 59, 59)?)))
+}
+
+impl FromStr for Rule {
+    type Err = RuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let body = s.trim();
+        let body = body.strip_prefix("RRULE:").unwrap_or(body);
+        let mut frequency = None;
+        let mut rule = Rule { frequency: Frequency::Daily, interval: 1, by_day: Vec::new(), count: None, until: None };
+
+        for part in body.split(';').filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| RuleError(format!("Expected KEY=VALUE, got `{}`", part)))?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    frequency = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        other => return Err(RuleError(format!("Unsupported FREQ `{}`; use DAILY, WEEKLY or MONTHLY", other))),
+                    });
+                }
+                "INTERVAL" => {
+                    rule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|&n| (1..=MAX_INTERVAL).contains(&n))
+                        .ok_or_else(|| RuleError(format!("INTERVAL must be 1 to {}, got `{}`", MAX_INTERVAL, value)))?;
+                }
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        let day = parse_weekday(&day.to_ascii_uppercase())
+                            .ok_or_else(|| RuleError(format!("Unknown BYDAY weekday `{}`", day)))?;
+                        if !rule.by_day.contains(&day) {
+                            rule.by_day.push(day);
+                        }
+                    }
+                }
+                "COUNT" => {
+                    rule.count = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|&n| n >= 1)
+                            .ok_or_else(|| RuleError(format!("COUNT must be a positive number, got `{}`", value)))?,
+                    );
+                }
+                "UNTIL" => {
+                    rule.until = Some(parse_until(value).ok_or_else(|| RuleError(format!("Invalid UNTIL `{}`", value)))?);
+                }
+                other => return Err(RuleError(format!("Unsupported rule part `{}`", other))),
+            }
+        }
+
+        rule.frequency = frequency.ok_or_else(|| RuleError("A rule needs FREQ".to_string()))?;
+        if !rule.by_day.is_empty() && rule.frequency != Frequency::Weekly {
+            return Err(RuleError("BYDAY is only supported with FREQ=WEEKLY".to_string()));
+        }
+        if rule.count.is_some() && rule.until.is_some() {
+            return Err(RuleError("Use either COUNT or UNTIL, not both".to_string()));
+        }
+        rule.by_day.sort_by_key(|d| d.num_days_from_monday());
+        Ok(rule)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frequency = match self.frequency {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+        };
+        write!(f, "FREQ={}", frequency)?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<&str> = self.by_day.iter().map(|d| weekday_code(*d)).collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        if let Some(until) = self.until {
+            write!(f, ";UNTIL={}", until.format("%Y%m%dT%H%M%SZ"))?;
+        }
+        Ok(())
+    }
+}
+
+/// The instant a wall-clock time falls on in `tz`. A time skipped by a DST
+/// jump moves forward by the jump; a repeated one takes its first instance.
+pub fn resolve_local(tz: Tz, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => Some(at.with_timezone(&Utc)),
+        LocalResult::None => match tz.from_local_datetime(&local.checked_add_signed(Duration::hours(1))?) {
+            LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => Some(at.with_timezone(&Utc)),
+            LocalResult::None => None,
+        },
+    }
+}
+
+impl Rule {
+    /// The series' occurrences in order, as wall-clock times, starting with
+    /// `start` itself when it matches the rule. Ends where the calendar does.
+    fn occurrences(&self, start: NaiveDateTime) -> impl Iterator<Item = NaiveDateTime> + '_ {
+        let time = start.time();
+        let first = start.date();
+        // Monday of the week the series starts in
+        let week_start = first - Duration::days(first.weekday().num_days_from_monday() as i64);
+        let by_day = if self.by_day.is_empty() { vec![first.weekday()] } else { self.by_day.clone() };
+
+        (0..MAX_STEPS)
+            .map_while(move |step| {
+                let period = step as i64 * self.interval as i64;
+                let days: Vec<NaiveDate> = match self.frequency {
+                    Frequency::Daily => vec![first.checked_add_signed(Duration::try_days(period)?)?],
+                    Frequency::Weekly => {
+                        let week = week_start.checked_add_signed(Duration::try_weeks(period)?)?;
+                        by_day
+                            .iter()
+                            .map(|d| week.checked_add_signed(Duration::days(d.num_days_from_monday() as i64)))
+                            .collect::<Option<_>>()?
+                    }
+                    // Months too short for the start day are skipped, as RFC 5545 does
+                    Frequency::Monthly => {
+                        let month = first.with_day(1)?.checked_add_months(Months::new(u32::try_from(period).ok()?))?;
+                        month.with_day(first.day()).into_iter().collect()
+                    }
+                };
+                Some(days)
+            })
+            .flatten()
+            .filter(move |day| *day >= first)
+            .map(move |day| day.and_time(time))
+    }
+
+    /// The first occurrence strictly after `after` of a series that starts
+    /// at the wall-clock time `start` in `tz`, or `None` once COUNT or UNTIL
+    /// has run out.
+    pub fn next_after(&self, start: NaiveDateTime, tz: Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        for (index, local) in self.occurrences(start).enumerate() {
+            if self.count.is_some_and(|count| index as u32 >= count) {
+                return None;
+            }
+            let Some(at) = resolve_local(tz, local) else { continue };
+            if self.until.is_some_and(|until| at > until) {
+                return None;
+            }
+            if at > after {
+                return Some(at);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&local(s))
+    }
+
+    /// The first `n` occurrences after `after`, in UTC.
+    fn next(rule: &str, start: &str, tz: Tz, after: &str, n: usize) -> Vec<DateTime<Utc>> {
+        let rule: Rule = rule.parse().unwrap();
+        let mut after = utc(after);
+        let mut found = Vec::new();
+        while found.len() < n {
+            let Some(at) = rule.next_after(local(start), tz, after) else { break };
+            found.push(at);
+            after = at;
+        }
+        found
+    }
+
+    #[test]
+    fn parses_and_prints_rules() {
+        let rule: Rule = "RRULE:freq=weekly;INTERVAL=2;BYDAY=FR,MO,MO;COUNT=5".parse().unwrap();
+        assert_eq!(rule.frequency, Frequency::Weekly);
+        assert_eq!(rule.interval, 2);
+        assert_eq!(rule.by_day, vec![Weekday::Mon, Weekday::Fri]);
+        assert_eq!(rule.count, Some(5));
+        assert_eq!(rule.to_string(), "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,FR;COUNT=5");
+
+        let rule: Rule = "FREQ=DAILY;UNTIL=20261231".parse().unwrap();
+        assert_eq!(rule.until, Some(utc("2026-12-31 23:59") + Duration::seconds(59)));
+        assert_eq!(rule.to_string(), "FREQ=DAILY;UNTIL=20261231T235959Z");
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        let error = |s: &str| s.parse::<Rule>().unwrap_err().0;
+        assert_eq!(error("INTERVAL=2"), "A rule needs FREQ");
+        assert_eq!(error("FREQ=YEARLY"), "Unsupported FREQ `YEARLY`; use DAILY, WEEKLY or MONTHLY");
+        assert_eq!(error("FREQ=DAILY;INTERVAL=0"), "INTERVAL must be 1 to 1000, got `0`");
+        assert_eq!(error("FREQ=DAILY;INTERVAL=100000000"), "INTERVAL must be 1 to 1000, got `100000000`");
+        assert_eq!(error("FREQ=DAILY;BYDAY=MO"), "BYDAY is only supported with FREQ=WEEKLY");
+        assert_eq!(error("FREQ=WEEKLY;BYDAY=XX"), "Unknown BYDAY weekday `XX`");
+        assert_eq!(error("FREQ=DAILY;COUNT=2;UNTIL=20261231"), "Use either COUNT or UNTIL, not both");
+        assert_eq!(error("FREQ=DAILY;UNTIL=tomorrow"), "Invalid UNTIL `tomorrow`");
+        assert_eq!(error("FREQ=DAILY;WKST=MO"), "Unsupported rule part `WKST`");
+    }
+
+    #[test]
+    fn daily_keeps_the_wall_clock_time_across_dst() {
+        // New York moves to daylight time on 8 March 2026
+        let found = next("FREQ=DAILY", "2026-03-07 09:00", chrono_tz::America::New_York, "2026-03-07 00:00", 3);
+        assert_eq!(found, vec![utc("2026-03-07 14:00"), utc("2026-03-08 13:00"), utc("2026-03-09 13:00")]);
+    }
+
+    #[test]
+    fn a_time_skipped_by_dst_moves_forward() {
+        let found = next("FREQ=DAILY", "2026-03-07 02:30", chrono_tz::America::New_York, "2026-03-07 00:00", 2);
+        assert_eq!(found, vec![utc("2026-03-07 07:30"), utc("2026-03-08 07:30")]);
+    }
+
+    #[test]
+    fn weekly_by_day_walks_the_chosen_weekdays() {
+        // 2026-10-14 is a Wednesday; the Monday of its week is not an occurrence
+        let found = next("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE,FR", "2026-10-14 08:00", Tz::UTC, "2026-01-01 00:00", 4);
+        assert_eq!(
+            found,
+            vec![utc("2026-10-14 08:00"), utc("2026-10-16 08:00"), utc("2026-10-26 08:00"), utc("2026-10-28 08:00")]
+        );
+    }
+
+    #[test]
+    fn monthly_skips_months_without_the_day() {
+        let found = next("FREQ=MONTHLY", "2026-01-31 12:00", Tz::UTC, "2026-01-01 00:00", 3);
+        assert_eq!(found, vec![utc("2026-01-31 12:00"), utc("2026-03-31 12:00"), utc("2026-05-31 12:00")]);
+    }
+
+    #[test]
+    fn count_and_until_end_the_series() {
+        let found = next("FREQ=DAILY;COUNT=3", "2026-10-01 09:00", Tz::UTC, "2026-01-01 00:00", 10);
+        assert_eq!(found.len(), 3);
+        // COUNT includes occurrences that are already past
+        assert_eq!(next("FREQ=DAILY;COUNT=3", "2026-10-01 09:00", Tz::UTC, "2026-10-02 12:00", 10), vec![utc("2026-10-03 09:00")]);
+
+        let found = next("FREQ=DAILY;UNTIL=20261003T090000Z", "2026-10-01 09:00", Tz::UTC, "2026-01-01 00:00", 10);
+        assert_eq!(found.last(), Some(&utc("2026-10-03 09:00")));
+        assert_eq!(found.len(), 3);
+    }
+
+    #[test]
+    fn the_series_ends_where_the_calendar_does() {
+        let end = NaiveDate::MAX.and_hms_opt(0, 0, 0).unwrap() - Duration::days(500);
+        let after = Utc.from_utc_datetime(&(end + Duration::weeks(1)));
+        for rule in ["FREQ=DAILY;INTERVAL=1000", "FREQ=WEEKLY;INTERVAL=1000;BYDAY=MO,SU", "FREQ=MONTHLY;INTERVAL=1000"] {
+            let rule: Rule = rule.parse().unwrap();
+            assert_eq!(rule.next_after(end, Tz::UTC, after), None, "{}", rule);
+        }
+
+        // Rules built in code skip the INTERVAL cap
+        for frequency in [Frequency::Daily, Frequency::Weekly, Frequency::Monthly] {
+            let rule = Rule { frequency, interval: u32::MAX, by_day: Vec::new(), count: None, until: None };
+            let start = local("2026-10-01 09:00");
+            assert_eq!(rule.next_after(start, Tz::UTC, utc("2026-10-02 00:00")), None, "{}", rule);
+        }
+    }
+}
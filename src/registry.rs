@@ -0,0 +1,168 @@
+// src/registry.rs
+//! Live WebSocket connections, keyed by username.
+//!
+//! Each socket gets its own unbounded queue of serialized events, drained by
+//! that socket's writer task. Delivery is targeted: callers name the users an
+//! event is for and only those users' sockets receive it.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+pub type ConnectionId = u64;
+
+#[derive(Debug)]
+struct Connection {
+    id: ConnectionId,
+    // The login session the socket authenticated with
+    session_id: String,
+    sender: mpsc::UnboundedSender<String>,
+    // Unix seconds of the last frame received on this socket
+    last_active: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct UserConnections {
+    // The username as given when the first socket registered
+    username: String,
+    connections: Vec<Connection>,
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    // Lower-cased username -> that user's live sockets
+    users: RwLock<HashMap<String, UserConnections>>,
+}
+
+fn key(username: &str) -> String {
+    username.to_lowercase()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new socket for `username`, returning its id and the queue
+    /// the socket's writer task should drain.
+    pub fn register(&self, username: &str, session_id: &str) -> (ConnectionId, mpsc::UnboundedReceiver<String>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut users = self.users.write().unwrap();
+        let entry = users.entry(key(username)).or_insert_with(|| UserConnections {
+            username: username.to_string(),
+            connections: Vec::new(),
+        });
+        entry.connections.push(Connection { id, session_id: session_id.to_string(), sender, last_active: AtomicU64::new(now_secs()) });
+        (id, receiver)
+    }
+
+    /// Drop one socket. Returns true when it was the user's last one.
+    pub fn unregister(&self, username: &str, id: ConnectionId) -> bool {
+        let mut users = self.users.write().unwrap();
+        let k = key(username);
+        let Some(entry) = users.get_mut(&k) else { return true };
+        entry.connections.retain(|c| c.id != id);
+        if entry.connections.is_empty() {
+            users.remove(&k);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drop the queues of the user's sockets opened with `session_id`, or of
+    /// all their sockets when `None`, so their writer tasks close them.
+    /// Returns how many were dropped.
+    pub fn close_sessions(&self, username: &str, session_id: Option<&str>) -> usize {
+        let mut users = self.users.write().unwrap();
+        let Some(entry) = users.get_mut(&key(username)) else { return 0 };
+        let before = entry.connections.len();
+        entry.connections.retain(|c| session_id.is_some_and(|sid| c.session_id != sid));
+        before - entry.connections.len()
+    }
+
+    pub fn connection_count(&self) -> usize {
+        self.users.read().unwrap().values().map(|u| u.connections.len()).sum()
+    }
+
+    /// Note activity on one socket.
+    pub fn touch(&self, username: &str, id: ConnectionId) {
+        let users = self.users.read().unwrap();
+        if let Some(conn) = users.get(&key(username)).and_then(|u| u.connections.iter().find(|c| c.id == id)) {
+            conn.last_active.store(now_secs(), Ordering::Relaxed);
+        }
+    }
+
+    /// Seconds since any of the user's sockets last saw a frame, or `None`
+    /// when the user has no socket open.
+    pub fn idle_secs(&self, username: &str) -> Option<u64> {
+        let users = self.users.read().unwrap();
+        let last_active = users
+            .get(&key(username))?
+            .connections
+            .iter()
+            .map(|c| c.last_active.load(Ordering::Relaxed))
+            .max()?;
+        Some(now_secs().saturating_sub(last_active))
+    }
+
+    /// Usernames with at least one socket open.
+    pub fn online_users(&self) -> Vec<String> {
+        self.users.read().unwrap().values().map(|u| u.username.clone()).collect()
+    }
+
+    /// Queue an already-serialized frame on every socket of `username`.
+    /// Returns how many sockets it was queued on.
+    pub fn send_raw(&self, username: &str, json: &str) -> usize {
+        let users = self.users.read().unwrap();
+        let Some(entry) = users.get(&key(username)) else { return 0 };
+        entry
+            .connections
+            .iter()
+            .filter(|c| c.sender.send(json.to_string()).is_ok())
+            .count()
+    }
+
+    /// Queue a frame on one specific socket of `username`.
+    pub fn send_to_connection<T: Serialize>(&self, username: &str, id: ConnectionId, event: &T) {
+        let Ok(json) = serde_json::to_string(event) else { return };
+        let users = self.users.read().unwrap();
+        if let Some(conn) = users.get(&key(username)).and_then(|u| u.connections.iter().find(|c| c.id == id)) {
+            let _ = conn.sender.send(json);
+        }
+    }
+
+    pub fn send_to_user<T: Serialize>(&self, username: &str, event: &T) -> usize {
+        match serde_json::to_string(event) {
+            Ok(json) => self.send_raw(username, &json),
+            Err(_) => 0,
+        }
+    }
+
+    /// Queue a frame for each named user once, however often they are named.
+    /// Returns the users that had at least one socket to queue it on.
+    pub fn send_to_users<'a, T, I>(&self, usernames: I, event: &T) -> Vec<String>
+    where
+        T: Serialize,
+        I: IntoIterator<Item = &'a str>,
+    {
+        let Ok(json) = serde_json::to_string(event) else { return Vec::new() };
+        let mut seen = std::collections::HashSet::new();
+        let mut reached = Vec::new();
+        for username in usernames {
+            if seen.insert(key(username)) && self.send_raw(username, &json) > 0 {
+                reached.push(username.to_string());
+            }
+        }
+        reached
+    }
+}
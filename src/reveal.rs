@@ -0,0 +1,107 @@
+// src/reveal.rs
+//! Delayed reveal.
+//!
+//! A message sent with `reveal_at` is stored in full, but until that time
+//! only its sender may read it. Everyone else gets [`PLACEHOLDER`] in live
+//! delivery, history, sync and threads, and search, highlights and the AI
+//! assistant leave it out. Once the time passes, [`reveal_due`] sends the
+//! content to the conversation as `message_revealed`. How far that has got
+//! is kept in `reveal_progress`, so reveals that fell due while the server
+//! was down are sent when it starts again.
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::{Row, SqlitePool};
+
+use crate::handlers::{conversations, uploads};
+use crate::protocol::{MessageKind, ServerEvent};
+use crate::{ChatMessage, Users};
+
+/// Text sent in place of a message that is not revealed yet.
+pub const PLACEHOLDER: &str = "🔒 This message will be revealed later";
+
+/// SQL condition on a message table that holds once a row may be read.
+/// Stored `reveal_at` values are UTC with milliseconds (see [`format`]), so
+/// they compare as text against SQLite's clock in the same shape.
+pub const REVEALED: &str = "(reveal_at IS NULL OR reveal_at <= strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))";
+
+/// The form `reveal_at` is stored and compared in.
+pub fn format(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Whether a message with this `reveal_at` is still withheld.
+pub fn is_pending(reveal_at: Option<&str>) -> bool {
+    reveal_at
+        .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+        .is_some_and(|at| at > Utc::now())
+}
+
+/// Replace a message's content with the placeholder.
+pub fn hide(msg: &mut ChatMessage) {
+    msg.message = PLACEHOLDER.to_string();
+    msg.attachments.clear();
+    msg.withheld = true;
+}
+
+/// Hide `msg` from `viewer` unless they sent it or it is revealed. Call it
+/// before a ghost group's sender is masked.
+pub fn withhold(msg: &mut ChatMessage, viewer: &str) {
+    if is_pending(msg.reveal_at.as_deref()) && !msg.sender_username.eq_ignore_ascii_case(viewer) {
+        hide(msg);
+    }
+}
+
+/// The time reveals have been announced through, in the [`format`] shape.
+/// Now, if that was never recorded.
+pub async fn revealed_through(pool: &SqlitePool) -> String {
+    match sqlx::query_scalar::<_, String>("SELECT revealed_through FROM reveal_progress WHERE id = 1").fetch_optional(pool).await {
+        Ok(Some(through)) => through,
+        Ok(None) => format(Utc::now()),
+        Err(e) => {
+            println!("DEBUG: Failed to load reveal progress: {}", e);
+            format(Utc::now())
+        }
+    }
+}
+
+/// Record that reveals up to `through` have been announced.
+pub async fn set_revealed_through(pool: &SqlitePool, through: &str) {
+    if let Err(e) = sqlx::query("INSERT OR REPLACE INTO reveal_progress (id, revealed_through) VALUES (1, ?)")
+        .bind(through)
+        .execute(pool)
+        .await
+    {
+        println!("DEBUG: Failed to save reveal progress: {}", e);
+    }
+}
+
+/// Send `message_revealed` for each message whose `reveal_at` falls in
+/// `(since, until]`, both in the [`format`] shape. Recalled messages stay
+/// hidden.
+pub async fn reveal_due(users: &Users, pool: &SqlitePool, since: &str, until: &str) {
+    for kind in [MessageKind::Direct, MessageKind::Group] {
+        let sql = format!(
+            "SELECT id, message FROM {} WHERE reveal_at > ? AND reveal_at <= ? AND COALESCE(deleted, 0) = 0 ORDER BY id",
+            kind.table()
+        );
+        let rows = match sqlx::query(&sql).bind(since).bind(until).fetch_all(pool).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                println!("DEBUG: Failed to load messages due for reveal: {}", e);
+                continue;
+            }
+        };
+        for row in rows {
+            let message_id: i64 = row.get("id");
+            let audience = crate::message_audience(pool, kind, message_id).await;
+            let event = ServerEvent::MessageRevealed {
+                message_id,
+                kind,
+                message: row.get("message"),
+                attachments: uploads::for_message(pool, kind, message_id).await,
+            };
+            users.send_to_users(audience.iter().map(String::as_str), &event);
+            // Conversation previews can show it now
+            conversations::message_changed(users, pool, kind, message_id, false).await;
+        }
+    }
+}
@@ -0,0 +1,206 @@
+// src/roles.rs
+//! Group roles and what each one may do.
+//!
+//! This is the one place group access is decided: every group-scoped
+//! WebSocket command and REST route goes through [`require`].
+//!
+//! Every row in `group_members` carries a role. Roles are ranked
+//! owner > admin > moderator > member, and each [`Permission`] has a lowest
+//! role that holds it. Acting on another member (removing them, changing
+//! their role) additionally requires outranking them.
+//!
+//! Two moderation states sit alongside roles: a muted member keeps their
+//! role but cannot post until the mute lapses, and a banned user cannot
+//! rejoin until their ban expires.
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        match s {
+            "member" => Some(Role::Member),
+            "moderator" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn can(self, permission: Permission) -> bool {
+        self >= permission.min_role()
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    // Read history, polls, reactions and attachments, and send receipts and typing
+    View,
+    Post,
+    CreatePoll,
+    // Close polls other members created
+    ClosePolls,
+    StartGame,
+    Pin,
+    AddMembers,
+    RemoveMembers,
+    EditInfo,
+    ToggleGhostMode,
+    ManageRoles,
+    Mute,
+    Ban,
+    ViewAudit,
+    TransferOwnership,
+    DeleteGroup,
+}
+
+impl Permission {
+    pub fn min_role(self) -> Role {
+        match self {
+            Permission::View | Permission::Post | Permission::CreatePoll | Permission::StartGame => Role::Member,
+            Permission::Pin | Permission::AddMembers | Permission::RemoveMembers | Permission::Mute => Role::Moderator,
+            Permission::EditInfo
+            | Permission::ToggleGhostMode
+            | Permission::ManageRoles
+            | Permission::Ban
+            | Permission::ViewAudit
+            | Permission::ClosePolls => Role::Admin,
+            Permission::TransferOwnership | Permission::DeleteGroup => Role::Owner,
+        }
+    }
+
+    /// Whether a mute takes this permission away.
+    fn is_posting(self) -> bool {
+        matches!(self, Permission::Post | Permission::CreatePoll | Permission::StartGame)
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Permission::View => "see this group",
+            Permission::Post => "post in this group",
+            Permission::CreatePoll => "create polls in this group",
+            Permission::ClosePolls => "close other members' polls in this group",
+            Permission::StartGame => "start games in this group",
+            Permission::Pin => "pin messages in this group",
+            Permission::AddMembers => "add members to this group",
+            Permission::RemoveMembers => "remove members from this group",
+            Permission::EditInfo => "edit this group",
+            Permission::ToggleGhostMode => "change ghost mode for this group",
+            Permission::ManageRoles => "change roles in this group",
+            Permission::Mute => "mute members of this group",
+            Permission::Ban => "ban people from this group",
+            Permission::ViewAudit => "view this group's audit log",
+            Permission::TransferOwnership => "transfer ownership of this group",
+            Permission::DeleteGroup => "delete this group",
+        }
+    }
+}
+
+/// Why a group action was refused.
+#[derive(Debug)]
+pub enum Denied {
+    NotMember,
+    // `until` is None for a permanent ban
+    Banned { until: Option<String> },
+    Muted { until: String },
+    Lacks { role: Role, permission: Permission },
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::NotMember => f.write_str("Not a member of this group"),
+            Denied::Banned { until: None } => f.write_str("You are banned from this group"),
+            Denied::Banned { until: Some(until) } => write!(f, "You are banned from this group until {}", until),
+            Denied::Muted { until } => write!(f, "You are muted in this group until {}", until),
+            Denied::Lacks { role, permission } => write!(
+                f,
+                "Cannot {}: requires {} or above (you are {})",
+                permission.describe(),
+                permission.min_role(),
+                role
+            ),
+            Denied::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+/// The user's role in a group, or `None` when they are not a member.
+pub async fn role_of(pool: &SqlitePool, group_id: i64, username: &str) -> Result<Option<Role>, sqlx::Error> {
+    let role: Option<String> = sqlx::query_scalar("SELECT role FROM group_members WHERE group_id = ? AND username = ? COLLATE NOCASE")
+        .bind(group_id)
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+    // Unknown values are treated as the least privileged role
+    Ok(role.map(|r| Role::parse(&r).unwrap_or(Role::Member)))
+}
+
+/// The expiry of the user's ban from a group if one is in force: `Some(None)`
+/// for a permanent ban.
+pub async fn active_ban(pool: &SqlitePool, group_id: i64, username: &str) -> Result<Option<Option<String>>, sqlx::Error> {
+    let ban: Option<(Option<String>,)> = sqlx::query_as(
+        "SELECT expires_at FROM group_bans
+         WHERE group_id = ? AND username = ? COLLATE NOCASE AND (expires_at IS NULL OR expires_at > ?)"
+    )
+    .bind(group_id)
+    .bind(username)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .fetch_optional(pool)
+    .await?;
+    Ok(ban.map(|(until,)| until))
+}
+
+/// Check that the user is a member holding `permission`, returning their role.
+/// Muted members lose the posting permissions until their mute lapses.
+pub async fn require(pool: &SqlitePool, group_id: i64, username: &str, permission: Permission) -> Result<Role, Denied> {
+    let row: Option<(String, Option<String>)> =
+        sqlx::query_as("SELECT role, muted_until FROM group_members WHERE group_id = ? AND username = ? COLLATE NOCASE")
+            .bind(group_id)
+            .bind(username)
+            .fetch_optional(pool)
+            .await
+            .map_err(Denied::Database)?;
+    let Some((role, muted_until)) = row else {
+        return match active_ban(pool, group_id, username).await {
+            Ok(Some(until)) => Err(Denied::Banned { until }),
+            Ok(None) => Err(Denied::NotMember),
+            Err(e) => Err(Denied::Database(e)),
+        };
+    };
+
+    let role = Role::parse(&role).unwrap_or(Role::Member);
+    if !role.can(permission) {
+        return Err(Denied::Lacks { role, permission });
+    }
+    if let Some(until) = muted_until.filter(|until| permission.is_posting() && *until > chrono::Utc::now().to_rfc3339()) {
+        return Err(Denied::Muted { until });
+    }
+    Ok(role)
+}
@@ -0,0 +1,439 @@
+// src/search.rs
+//! Full-text message search over SQLite FTS5.
+//!
+//! `messages_fts` and `group_messages_fts` index the text of their tables and
+//! are kept in step by triggers (see the `search_index` migration): inserts
+//! and edits are indexed, and recalled (deleted) messages drop out. A query is a list of words and `"quoted
+//! phrases"` plus optional filters:
+//!
+//! * `from:alice` - sent by alice
+//! * `in:team` / `in:42` - in a group, by name or id; `in:@bob` - the DM with bob
+//! * `before:2024-05-01` / `after:2024-05-01` - by day, or an RFC 3339 time
+//!
+//! Results are ordered by relevance (or by time with `sort=recent`) and paged
+//! with the opaque `next_cursor` of the previous page.
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{Row, SqlitePool};
+use warp::http::StatusCode;
+
+use crate::protocol::MessageKind;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+// Highlight markers as FTS5 emits them; swapped for <mark> once escaped
+const MARK_START: char = '\u{2}';
+const MARK_END: char = '\u{3}';
+
+/// A parsed search string.
+#[derive(Debug, Default)]
+struct SearchQuery {
+    // FTS5 query terms, already quoted
+    terms: Vec<String>,
+    from: Option<String>,
+    within: Option<String>,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+/// Split on whitespace, keeping `"quoted runs"` (also after `key:`) whole.
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for c in input.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+fn unquote(s: &str) -> &str {
+    let s = s.strip_prefix('"').unwrap_or(s);
+    s.strip_suffix('"').unwrap_or(s)
+}
+
+/// Quote a word or phrase for FTS5 so its operators can't be injected; a
+/// trailing `*` on a bare word is kept as a prefix search.
+fn fts_term(token: &str) -> Option<String> {
+    let (text, prefix) = match token.strip_suffix('*') {
+        Some(rest) if !token.starts_with('"') => (rest, true),
+        _ => (unquote(token), false),
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    let quoted = format!("\"{}\"", text.replace('"', "\"\""));
+    Some(if prefix { format!("{}*", quoted) } else { quoted })
+}
+
+/// A date filter as a bound on the RFC 3339 `timestamp` column. `after:` a
+/// day means from the start of the next one.
+fn date_bound(value: &str, is_after: bool) -> Result<String, String> {
+    if let Ok(day) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let day = if is_after { day + Duration::days(1) } else { day };
+        return Ok(day.format("%Y-%m-%d").to_string());
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| t.with_timezone(&Utc).to_rfc3339())
+        .map_err(|_| format!("Can't read date `{}`; use YYYY-MM-DD", value))
+}
+
+fn parse_query(input: &str) -> Result<SearchQuery, String> {
+    let mut query = SearchQuery::default();
+    for token in tokenize(input) {
+        let filter = token.split_once(':').filter(|(key, value)| {
+            !value.is_empty() && matches!(key.to_ascii_lowercase().as_str(), "from" | "in" | "before" | "after")
+        });
+        match filter {
+            Some((key, value)) => {
+                let value = unquote(value).trim().to_string();
+                match key.to_ascii_lowercase().as_str() {
+                    "from" => query.from = Some(value.trim_start_matches('@').to_string()),
+                    "in" => query.within = Some(value),
+                    "before" => query.before = Some(date_bound(&value, false)?),
+                    _ => query.after = Some(date_bound(&value, true)?),
+                }
+            }
+            None => query.terms.extend(fts_term(&token)),
+        }
+    }
+    if query.terms.is_empty() {
+        return Err("Search needs at least one word or phrase".to_string());
+    }
+    Ok(query)
+}
+
+/// Where `in:` points, once resolved against the searcher's conversations.
+enum Within {
+    Group(i64),
+    Direct(String),
+}
+
+async fn resolve_within(pool: &SqlitePool, username: &str, value: &str) -> Option<Within> {
+    if let Some(peer) = value.strip_prefix('@') {
+        return Some(Within::Direct(peer.to_string()));
+    }
+    let group_id: Option<i64> = sqlx::query_scalar(
+        "SELECT g.id FROM groups g
+         JOIN group_members m ON m.group_id = g.id AND m.username = ? COLLATE NOCASE
+         WHERE g.name = ? COLLATE NOCASE OR CAST(g.id AS TEXT) = ?
+         ORDER BY g.id LIMIT 1"
+    )
+    .bind(username)
+    .bind(value)
+    .bind(value)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None);
+    if let Some(group_id) = group_id {
+        return Some(Within::Group(group_id));
+    }
+    let peer: Option<String> = sqlx::query_scalar("SELECT username FROM users WHERE username = ? COLLATE NOCASE")
+        .bind(value)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None);
+    peer.map(Within::Direct)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sort {
+    Relevance,
+    Recent,
+}
+
+/// Position after the last result of a page: the sort key, then the
+/// (kind, id) tie-breakers.
+struct Cursor {
+    key: String,
+    kind: String,
+    id: i64,
+}
+
+impl Cursor {
+    fn parse(s: &str) -> Option<Cursor> {
+        let mut parts = s.rsplitn(3, '~');
+        let id = parts.next()?.parse().ok()?;
+        let kind = parts.next()?.to_string();
+        let key = parts.next()?.to_string();
+        Some(Cursor { key, kind, id })
+    }
+
+    fn encode(&self) -> String {
+        format!("{}~{}~{}", self.key, self.kind, self.id)
+    }
+}
+
+#[derive(Clone)]
+enum Bind {
+    Text(String),
+    Int(i64),
+    Real(f64),
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    id: i64,
+    kind: MessageKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_name: Option<String>,
+    sender: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receiver: Option<String>,
+    message: String,
+    // HTML-escaped, with matches wrapped in <mark>
+    snippet: String,
+    timestamp: String,
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            MARK_START => out.push_str("<mark>"),
+            MARK_END => out.push_str("</mark>"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn json_error(status: StatusCode, error: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&serde_json::json!({ "error": error })), status)
+}
+
+/// `GET /search_messages?q=&sort=relevance|recent&limit=&cursor=`
+pub async fn search_messages_handler(
+    params: HashMap<String, String>,
+    auth: Option<String>,
+    pool: SqlitePool,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let Some(auth_header) = auth else {
+        return Ok(json_error(StatusCode::UNAUTHORIZED, "Missing authorization"));
+    };
+    let Ok(username) = crate::extract_username_from_auth(auth_header) else {
+        return Ok(json_error(StatusCode::UNAUTHORIZED, "Invalid token"));
+    };
+
+    let q = params.get("q").cloned().unwrap_or_default();
+    if q.trim().is_empty() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "results": [], "next_cursor": null })),
+            StatusCode::OK,
+        ));
+    }
+    let query = match parse_query(&q) {
+        Ok(query) => query,
+        Err(e) => return Ok(json_error(StatusCode::BAD_REQUEST, &e)),
+    };
+    let sort = match params.get("sort").map(String::as_str) {
+        None | Some("relevance") => Sort::Relevance,
+        Some("recent") => Sort::Recent,
+        Some(_) => return Ok(json_error(StatusCode::BAD_REQUEST, "sort must be relevance or recent")),
+    };
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+        .clamp(1, MAX_LIMIT);
+    let cursor = match params.get("cursor").filter(|c| !c.is_empty()) {
+        Some(c) => match Cursor::parse(c) {
+            Some(cursor) => Some(cursor),
+            None => return Ok(json_error(StatusCode::BAD_REQUEST, "Invalid cursor")),
+        },
+        None => None,
+    };
+    let within = match &query.within {
+        Some(value) => match resolve_within(&pool, &username, value).await {
+            Some(within) => Some(within),
+            None => return Ok(json_error(StatusCode::BAD_REQUEST, &format!("No conversation called `{}`", value))),
+        },
+        None => None,
+    };
+
+    let fts_query = query.terms.join(" ");
+    let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let mut arms = Vec::new();
+    let mut binds = Vec::new();
+
+    if !matches!(within, Some(Within::Group(_))) {
+        let mut sql = format!(
+            "SELECT 'direct' AS kind, m.id AS id, NULL AS group_id, NULL AS group_name, 0 AS ghost_mode,
+                    m.sender_username AS sender, m.receiver_username AS receiver, m.message AS message,
+                    m.timestamp AS timestamp,
+                    snippet(messages_fts, 0, char({start}), char({end}), '…', 16) AS snippet,
+                    bm25(messages_fts) AS score
+             FROM messages_fts JOIN messages m ON m.id = messages_fts.rowid
+             WHERE messages_fts MATCH ?
+               AND (m.sender_username = ? COLLATE NOCASE OR m.receiver_username = ? COLLATE NOCASE)
+               AND COALESCE(m.deleted, 0) = 0
+               AND (m.reveal_at IS NULL OR m.reveal_at <= ? OR m.sender_username = ? COLLATE NOCASE)",
+            start = MARK_START as u32,
+            end = MARK_END as u32,
+        );
+        binds.push(Bind::Text(fts_query.clone()));
+        binds.push(Bind::Text(username.clone()));
+        binds.push(Bind::Text(username.clone()));
+        binds.push(Bind::Text(now.clone()));
+        binds.push(Bind::Text(username.clone()));
+        if let Some(Within::Direct(peer)) = &within {
+            sql.push_str(" AND (m.sender_username = ? COLLATE NOCASE OR m.receiver_username = ? COLLATE NOCASE)");
+            binds.push(Bind::Text(peer.clone()));
+            binds.push(Bind::Text(peer.clone()));
+        }
+        if let Some(from) = &query.from {
+            sql.push_str(" AND m.sender_username = ? COLLATE NOCASE");
+            binds.push(Bind::Text(from.clone()));
+        }
+        push_date_filters(&mut sql, &mut binds, &query, "m");
+        arms.push(sql);
+    }
+
+    if !matches!(within, Some(Within::Direct(_))) {
+        let mut sql = format!(
+            "SELECT 'group' AS kind, g.id AS id, g.group_id AS group_id, gr.name AS group_name, gr.ghost_mode AS ghost_mode,
+                    g.sender_username AS sender, NULL AS receiver, g.message AS message,
+                    g.timestamp AS timestamp,
+                    snippet(group_messages_fts, 0, char({start}), char({end}), '…', 16) AS snippet,
+                    bm25(group_messages_fts) AS score
+             FROM group_messages_fts
+             JOIN group_messages g ON g.id = group_messages_fts.rowid
+             JOIN group_members mem ON mem.group_id = g.group_id AND mem.username = ? COLLATE NOCASE
+             JOIN groups gr ON gr.id = g.group_id
+             WHERE group_messages_fts MATCH ?
+               AND COALESCE(g.deleted, 0) = 0
+               AND (g.reveal_at IS NULL OR g.reveal_at <= ? OR g.sender_username = ? COLLATE NOCASE)",
+            start = MARK_START as u32,
+            end = MARK_END as u32,
+        );
+        binds.push(Bind::Text(username.clone()));
+        binds.push(Bind::Text(fts_query.clone()));
+        binds.push(Bind::Text(now.clone()));
+        binds.push(Bind::Text(username.clone()));
+        if let Some(Within::Group(group_id)) = &within {
+            sql.push_str(" AND g.group_id = ?");
+            binds.push(Bind::Int(*group_id));
+        }
+        if let Some(from) = &query.from {
+            // Searching a ghost group by sender would unmask them
+            sql.push_str(" AND g.sender_username = ? COLLATE NOCASE AND COALESCE(gr.ghost_mode, 0) = 0");
+            binds.push(Bind::Text(from.clone()));
+        }
+        push_date_filters(&mut sql, &mut binds, &query, "g");
+        arms.push(sql);
+    }
+
+    // bm25 is lower for better matches
+    let (sort_column, past) = match sort {
+        Sort::Relevance => ("score", ">"),
+        Sort::Recent => ("timestamp", "<"),
+    };
+    let mut sql = format!("SELECT * FROM ({})", arms.join(" UNION ALL "));
+    if let Some(cursor) = &cursor {
+        sql.push_str(&format!(
+            " WHERE {col} {past} ? OR ({col} = ? AND (kind > ? OR (kind = ? AND id < ?)))",
+            col = sort_column,
+            past = past,
+        ));
+        let key = match sort {
+            Sort::Relevance => match cursor.key.parse::<f64>() {
+                Ok(score) => Bind::Real(score),
+                Err(_) => return Ok(json_error(StatusCode::BAD_REQUEST, "Invalid cursor")),
+            },
+            Sort::Recent => Bind::Text(cursor.key.clone()),
+        };
+        binds.push(key.clone());
+        binds.push(key);
+        binds.push(Bind::Text(cursor.kind.clone()));
+        binds.push(Bind::Text(cursor.kind.clone()));
+        binds.push(Bind::Int(cursor.id));
+    }
+    let direction = if sort == Sort::Relevance { "ASC" } else { "DESC" };
+    sql.push_str(&format!(" ORDER BY {} {}, kind ASC, id DESC LIMIT ?", sort_column, direction));
+    binds.push(Bind::Int(limit + 1));
+
+    let mut db_query = sqlx::query(&sql);
+    for bind in binds {
+        db_query = match bind {
+            Bind::Text(s) => db_query.bind(s),
+            Bind::Int(i) => db_query.bind(i),
+            Bind::Real(f) => db_query.bind(f),
+        };
+    }
+    let mut rows = match db_query.fetch_all(&pool).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Search failed for {:?}: {:?}", q, e);
+            return Ok(json_error(StatusCode::BAD_REQUEST, "Could not run this search"));
+        }
+    };
+
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    let next_cursor = rows.last().filter(|_| has_more).map(|row| {
+        let key = match sort {
+            Sort::Relevance => row.get::<f64, _>("score").to_string(),
+            Sort::Recent => row.get::<String, _>("timestamp"),
+        };
+        Cursor { key, kind: row.get("kind"), id: row.get("id") }.encode()
+    });
+
+    let results: Vec<SearchResult> = rows
+        .iter()
+        .map(|row| {
+            let is_group = row.get::<String, _>("kind") == "group";
+            let ghost: i64 = row.try_get::<Option<i64>, _>("ghost_mode").ok().flatten().unwrap_or(0);
+            SearchResult {
+                id: row.get("id"),
+                kind: if is_group { MessageKind::Group } else { MessageKind::Direct },
+                group_id: row.get("group_id"),
+                group_name: row.get("group_name"),
+                sender: if ghost != 0 { "Anonymous".to_string() } else { row.get("sender") },
+                receiver: row.get("receiver"),
+                message: row.get("message"),
+                snippet: escape_html(&row.get::<String, _>("snippet")),
+                timestamp: row.get("timestamp"),
+            }
+        })
+        .collect();
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "results": results, "next_cursor": next_cursor })),
+        StatusCode::OK,
+    ))
+}
+
+fn push_date_filters(sql: &mut String, binds: &mut Vec<Bind>, query: &SearchQuery, alias: &str) {
+    if let Some(before) = &query.before {
+        sql.push_str(&format!(" AND {}.timestamp < ?", alias));
+        binds.push(Bind::Text(before.clone()));
+    }
+    if let Some(after) = &query.after {
+        sql.push_str(&format!(" AND {}.timestamp >= ?", alias));
+        binds.push(Bind::Text(after.clone()));
+    }
+}
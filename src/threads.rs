@@ -0,0 +1,188 @@
+// src/threads.rs
+//! Replies and threads.
+//!
+//! A message may reply to an earlier one in the same conversation. It keeps
+//! the message it quotes (`reply_to`) and the root of the thread it joins
+//! (`thread_root`); replying to a reply joins the same thread, so threads are
+//! one level deep. A reply shows up in the conversation's main history only
+//! when sent with `also_send_to_channel`. Otherwise it lives in its thread,
+//! read with `get_thread`, and the root carries a summary of its replies.
+//!
+//! Quotes are built whenever messages are read rather than copied at send
+//! time, so they follow the quoted message: edits, recalls and a pending
+//! `reveal_at` all carry through.
+use serde::{Deserialize, Serialize};
+use sqlx::query::Query;
+use sqlx::sqlite::{Sqlite, SqliteArguments};
+use sqlx::{Row, SqlitePool};
+
+use crate::handlers::mentions::preview;
+use crate::ChatMessage;
+
+/// The conversation a message belongs to.
+#[derive(Debug, Clone, Copy)]
+pub enum Conversation<'a> {
+    Direct { user: &'a str, peer: &'a str },
+    Group(i64),
+}
+
+impl<'a> Conversation<'a> {
+    pub fn table(&self) -> &'static str {
+        match self {
+            Conversation::Direct { .. } => "messages",
+            Conversation::Group(_) => "group_messages",
+        }
+    }
+
+    /// SQL condition matching this conversation's rows; fill it in with
+    /// [`Conversation::bind`].
+    pub fn filter(&self) -> &'static str {
+        match self {
+            Conversation::Direct { .. } => {
+                "((sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE)
+                  OR (sender_username = ? COLLATE NOCASE AND receiver_username = ? COLLATE NOCASE))"
+            }
+            Conversation::Group(_) => "group_id = ?",
+        }
+    }
+
+    pub fn bind<'q>(self, query: Query<'q, Sqlite, SqliteArguments<'q>>) -> Query<'q, Sqlite, SqliteArguments<'q>>
+    where
+        'a: 'q,
+    {
+        match self {
+            Conversation::Direct { user, peer } => query.bind(user).bind(peer).bind(peer).bind(user),
+            Conversation::Group(group_id) => query.bind(group_id),
+        }
+    }
+
+    pub fn group_id(&self) -> Option<i64> {
+        match self {
+            Conversation::Direct { .. } => None,
+            Conversation::Group(group_id) => Some(*group_id),
+        }
+    }
+}
+
+/// Where a new reply goes.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    pub reply_to: i64,
+    pub thread_root: i64,
+    pub in_channel: bool,
+}
+
+/// A preview of the message a reply quotes, as it reads now.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotedMessage {
+    pub id: i64,
+    pub sender_username: String,
+    // None while the quoted message is recalled or not yet revealed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub edited: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reveal_at: Option<String>,
+}
+
+/// Reply counts carried by a thread's root message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadSummary {
+    pub reply_count: i64,
+    pub last_reply_at: Option<String>,
+    // The first few distinct repliers, oldest first
+    pub participants: Vec<String>,
+}
+
+/// Check that `reply_to` is a message of this conversation and work out the
+/// thread the reply joins.
+pub async fn place_reply(
+    pool: &SqlitePool,
+    conversation: Conversation<'_>,
+    reply_to: i64,
+    also_send_to_channel: bool,
+) -> Result<Placement, String> {
+    let sql = format!(
+        "SELECT id, thread_root, deleted FROM {} WHERE id = ? AND {}",
+        conversation.table(),
+        conversation.filter()
+    );
+    let row = conversation
+        .bind(sqlx::query(&sql).bind(reply_to))
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| format!("Failed to look up the replied-to message: {}", e))?
+        .ok_or_else(|| "The replied-to message is not in this conversation".to_string())?;
+
+    if row.try_get::<Option<i64>, _>("deleted").ok().flatten().unwrap_or(0) != 0 {
+        return Err("Cannot reply to a recalled message".to_string());
+    }
+    let thread_root: Option<i64> = row.get("thread_root");
+    Ok(Placement {
+        reply_to,
+        thread_root: thread_root.unwrap_or(reply_to),
+        in_channel: also_send_to_channel,
+    })
+}
+
+/// Quote one message of `table` as it currently reads.
+async fn quote(pool: &SqlitePool, table: &str, message_id: i64, ghost: bool) -> Option<QuotedMessage> {
+    let sql = format!("SELECT id, sender_username, message, deleted, edited_at, reveal_at FROM {} WHERE id = ?", table);
+    let row = sqlx::query(&sql).bind(message_id).fetch_optional(pool).await.ok().flatten()?;
+
+    let deleted = row.try_get::<Option<i64>, _>("deleted").ok().flatten().unwrap_or(0) != 0;
+    let edited = row.try_get::<Option<String>, _>("edited_at").ok().flatten().is_some_and(|e| !e.is_empty());
+    let reveal_at: Option<String> = row
+        .try_get::<Option<String>, _>("reveal_at")
+        .ok()
+        .flatten()
+        .filter(|at| crate::reveal::is_pending(Some(at)));
+    let message = (!deleted && reveal_at.is_none()).then(|| preview(&row.get::<String, _>("message")));
+    Some(QuotedMessage {
+        id: row.get("id"),
+        sender_username: if ghost { "Anonymous".to_string() } else { row.get("sender_username") },
+        message,
+        deleted,
+        edited: edited && !deleted,
+        reveal_at,
+    })
+}
+
+async fn summarize(pool: &SqlitePool, table: &str, root_id: i64, ghost: bool) -> Option<ThreadSummary> {
+    let sql = format!(
+        "SELECT COUNT(*) AS reply_count, MAX(timestamp) AS last_reply_at FROM {} WHERE thread_root = ?",
+        table
+    );
+    let row = sqlx::query(&sql).bind(root_id).fetch_one(pool).await.ok()?;
+    let reply_count: i64 = row.get("reply_count");
+    if reply_count == 0 {
+        return None;
+    }
+    let participants = if ghost {
+        Vec::new()
+    } else {
+        let sql = format!(
+            "SELECT sender_username FROM {} WHERE thread_root = ? GROUP BY lower(sender_username) ORDER BY MIN(id) LIMIT 5",
+            table
+        );
+        sqlx::query_scalar(&sql).bind(root_id).fetch_all(pool).await.unwrap_or_default()
+    };
+    Some(ThreadSummary { reply_count, last_reply_at: row.get("last_reply_at"), participants })
+}
+
+/// Fill in quotes for replies and summaries for thread roots. `ghost` hides
+/// who wrote quoted messages and replies, for ghost-mode groups.
+pub async fn decorate(pool: &SqlitePool, conversation: Conversation<'_>, messages: &mut [ChatMessage], ghost: bool) {
+    let table = conversation.table();
+    for msg in messages.iter_mut() {
+        if let Some(reply_to) = msg.reply_to {
+            msg.quoted = quote(pool, table, reply_to, ghost).await;
+        }
+        if msg.thread_root.is_none() {
+            msg.thread = summarize(pool, table, msg.id, ghost).await;
+        }
+    }
+}
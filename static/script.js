@@ -4,14 +4,28 @@
 let socket = null;
 let currentUser = null;
 let authToken = null;
+let refreshToken = null;
+let refreshTimer = null;
 let currentConversation = null;
 let currentGroup = null;
 let contacts = [];
+let contactPresence = {};
 let memberGroups = []; // Groups user is a member of
 let availableGroups = []; // Groups user can join
 let reactionPickerTimeout = null;
 // Reactions that arrived before the message DOM exists
-const pendingReactions = new Map(); // messageId -> reactions object
+const pendingReactions = new Map(); // messageKey(kind, id) -> reactions object
+
+// DM and group message ids overlap, so a message is named by kind and id
+function messageKind(message) {
+    return message.group_id ? 'group' : 'direct';
+}
+function messageKey(kind, id) {
+    return `${kind}:${id}`;
+}
+function findMessageEl(kind, id) {
+    return document.querySelector(`.message[data-message-kind="${kind}"][data-message-id="${id}"]`);
+}
 
 // WebRTC state
 let pc = null;
@@ -23,6 +37,14 @@ let currentCallPeer = null; // username of the peer for the active call
 let revealAtISO = null; // ISO datetime for next message reveal
 let pendingRevealISO = null; // apply to next own displayed message if server didn't include
 let pendingGhostNotice = null; // show a history-style banner after history loads
+let replyTarget = null; // message the next one replies to
+let mentionsUnread = 0;
+let oldestMentionId = null;
+const unreadGroups = new Set(); // groups with unseen messages
+const mentionedGroups = new Set(); // groups with unread mentions
+let openThread = null; // { rootId, groupId, peer } of the thread modal
+const seenThreadReplies = new Set();
+const conversationSummaries = new Map(); // conversationKey -> entry of the conversation list
 
 // ---- Ghost mode banner persistence helpers ----
 function ghostBannerKey(groupId) {
@@ -178,7 +200,6 @@ async function endCall(sendSignal = true) {
         closeCallModal();
     } catch {}
 }
-let pendingFile = null;
 let gameModal, closeGameModal, gameContainer, gameBoard, gameInfo, gameControls;
 let currentGame = null;
 
@@ -203,7 +224,7 @@ let viewMembersModal, closeViewMembersModal, membersList;
 // Poll elements
 let createPollModal, closePollModal, submitPollBtn;
 let pollQuestionInput, pollOptionsContainer, addPollOptionBtn;
-let allowMultipleCheckbox, pollExpiresInput;
+let allowMultipleCheckbox, pollExpiresInput, pollTypeSelect, pollAnonymousCheckbox, pollCorrectOptionInput;
 let activePollsContainer;
 
 // AI Assistant variables
@@ -549,7 +570,7 @@ function createGame(gameType) {
 // Display game message in chat - IMPROVED FOR GAME JOINING
 function displayGameMessage(message, historical = false) {
     if (message.message && message.message.includes('🎮')) {
-        const existingMessage = document.querySelector(`[data-message-id="${message.id}"]`);
+        const existingMessage = findMessageEl(messageKind(message), message.id);
         if (existingMessage && message.id) {
             return;
         }
@@ -557,6 +578,7 @@ function displayGameMessage(message, historical = false) {
         const msgDiv = document.createElement('div');
         msgDiv.className = `message ${message.sender_username === currentUser ? 'sent' : 'received'} game-message`;
         msgDiv.dataset.messageId = message.id;
+        msgDiv.dataset.messageKind = messageKind(message);
         if (historical) msgDiv.classList.add('historical');
 
         const header = document.createElement('div');
@@ -726,6 +748,15 @@ function displayGame(game) {
                 console.log('Displaying trivia game');
                 displayTriviaGame(game);
                 break;
+            case 'connect_four':
+                displayConnectFourBoard(game);
+                break;
+            case 'checkers':
+                displayCheckersBoard(game);
+                break;
+            case 'hangman':
+                displayHangmanGame(game);
+                break;
             default:
                 console.warn('Unknown game type:', game.game_type);
         }
@@ -744,6 +775,9 @@ function getGameIcon(gameType) {
         case 'chess': return '♟️';
         case 'tictactoe': return '⭕';
         case 'trivia': return '🧠';
+        case 'connect_four': return '🔴';
+        case 'checkers': return '⛀';
+        case 'hangman': return '🪢';
         default: return '🎮';
     }
 }
@@ -753,6 +787,9 @@ function getGameName(gameType) {
         case 'chess': return 'Chess';
         case 'tictactoe': return 'Tic-Tac-Toe';
         case 'trivia': return 'Trivia';
+        case 'connect_four': return 'Connect Four';
+        case 'checkers': return 'Checkers';
+        case 'hangman': return 'Hangman';
         default: return 'Game';
     }
 }
@@ -796,12 +833,39 @@ function getGameStatus(game) {
 }
 
 // Chess board display
+function chessSquareName(row, col) {
+    return 'abcdefgh'[col] + (8 - row);
+}
+
+const CHESS_END_REASONS = {
+    checkmate: 'Checkmate',
+    stalemate: 'Stalemate',
+    threefold_repetition: 'Draw by threefold repetition',
+    fifty_move_rule: 'Draw by the fifty-move rule',
+    insufficient_material: 'Draw by insufficient material'
+};
+
 function displayChessBoard(game) {
     const gameState = JSON.parse(game.game_state);
     const board = gameState.board;
+    const legalMoves = gameState.legal_moves || [];
+    const lastMove = gameState.last_move;
+    // The creator plays white
+    const myColor = game.player1_username === currentUser ? 'white' : 'black';
+    const myTurn = game.current_turn === currentUser && game.status === 'active';
+    selectedChessSquare = null;
     
-    gameBoard.innerHTML = '<div class="chess-board"></div>';
+    gameBoard.innerHTML = '<div class="chess-status"></div><div class="chess-board"></div><div class="chess-moves"></div>';
     const chessBoard = gameBoard.querySelector('.chess-board');
+
+    const status = gameBoard.querySelector('.chess-status');
+    if (gameState.end_reason) {
+        status.textContent = `${CHESS_END_REASONS[gameState.end_reason] || gameState.end_reason} (${gameState.result})`;
+    } else if (gameState.check) {
+        status.textContent = `Check! ${gameState.turn === 'white' ? 'White' : 'Black'} to move`;
+    } else if (gameState.turn) {
+        status.textContent = `${gameState.turn === 'white' ? 'White' : 'Black'} to move`;
+    }
     
     for (let row = 0; row < 8; row++) {
         for (let col = 0; col < 8; col++) {
@@ -809,20 +873,59 @@ function displayChessBoard(game) {
             square.className = `chess-square ${(row + col) % 2 === 0 ? 'light' : 'dark'}`;
             square.dataset.row = row;
             square.dataset.col = col;
+            square.dataset.square = chessSquareName(row, col);
             
             const piece = board[row][col];
             if (piece !== '.') {
                 square.textContent = getChessPieceSymbol(piece);
                 square.classList.add('has-piece');
+                const pieceColor = piece === piece.toUpperCase() ? 'white' : 'black';
+                if (pieceColor === myColor) square.classList.add('own-piece');
+                if (gameState.check && piece.toLowerCase() === 'k' && pieceColor === gameState.turn) {
+                    square.classList.add('in-check');
+                }
+            }
+            if (lastMove && [lastMove.from, lastMove.to].some(([r, c]) => r === row && c === col)) {
+                square.classList.add('last-move');
             }
             
-            if (game.current_turn === currentUser && game.status === 'active') {
-                square.addEventListener('click', () => handleChessSquareClick(row, col));
+            if (myTurn) {
+                square.addEventListener('click', () => handleChessSquareClick(row, col, legalMoves));
             }
             
             chessBoard.appendChild(square);
         }
     }
+
+    const movesDiv = gameBoard.querySelector('.chess-moves');
+    const moves = gameState.moves || [];
+    if (moves.length) {
+        const numbered = [];
+        for (let i = 0; i < moves.length; i += 2) {
+            numbered.push(`${i / 2 + 1}. ${moves[i]}${moves[i + 1] ? ' ' + moves[i + 1] : ''}`);
+        }
+        movesDiv.textContent = numbered.join('  ');
+    }
+    const pgnBtn = document.createElement('button');
+    pgnBtn.className = 'chess-pgn-btn';
+    pgnBtn.textContent = '⬇️ Download PGN';
+    pgnBtn.addEventListener('click', () => {
+        if (socket && socket.readyState === WebSocket.OPEN) {
+            socket.send(JSON.stringify({ type: 'get_game_pgn', game_id: game.id }));
+        }
+    });
+    movesDiv.appendChild(pgnBtn);
+}
+
+function downloadGamePgn(data) {
+    const url = URL.createObjectURL(new Blob([data.pgn], { type: 'application/x-chess-pgn' }));
+    const link = document.createElement('a');
+    link.href = url;
+    link.download = `chess-game-${data.game_id}.pgn`;
+    document.body.appendChild(link);
+    link.click();
+    link.remove();
+    URL.revokeObjectURL(url);
 }
 
 function getChessPieceSymbol(piece) {
@@ -835,27 +938,38 @@ function getChessPieceSymbol(piece) {
 
 let selectedChessSquare = null;
 
-function handleChessSquareClick(row, col) {
-    if (!selectedChessSquare) {
-        // Select piece
-        const square = document.querySelector(`[data-row="${row}"][data-col="${col}"]`);
-        if (square.classList.contains('has-piece')) {
-            selectedChessSquare = { row, col };
-            square.classList.add('selected');
-        }
-    } else {
-        // Make move
-        const moveData = {
-            from: [selectedChessSquare.row, selectedChessSquare.col],
-            to: [row, col]
-        };
-        
-        makeGameMove(JSON.stringify(moveData));
-        
-        // Clear selection
-        document.querySelector('.selected')?.classList.remove('selected');
-        selectedChessSquare = null;
+function clearChessSelection() {
+    gameBoard.querySelectorAll('.selected, .legal-target').forEach(el => el.classList.remove('selected', 'legal-target'));
+    selectedChessSquare = null;
+}
+
+function handleChessSquareClick(row, col, legalMoves) {
+    const square = gameBoard.querySelector(`.chess-square[data-row="${row}"][data-col="${col}"]`);
+    const name = chessSquareName(row, col);
+
+    // Picking (or switching to) one of our own pieces
+    if (square.classList.contains('own-piece') && !(selectedChessSquare && selectedChessSquare.name === name)) {
+        clearChessSelection();
+        selectedChessSquare = { row, col, name };
+        square.classList.add('selected');
+        legalMoves.filter(m => m.startsWith(name)).forEach(m => {
+            const target = gameBoard.querySelector(`.chess-square[data-square="${m.slice(2, 4)}"]`);
+            if (target) target.classList.add('legal-target');
+        });
+        return;
     }
+    if (!selectedChessSquare) return;
+
+    const from = selectedChessSquare.name;
+    clearChessSelection();
+    if (from === name) return;
+
+    const moveData = { from, to: name };
+    if (legalMoves.includes(`${from}${name}q`)) {
+        const choice = (prompt('Promote to (q)ueen, (r)ook, (b)ishop or k(n)ight?', 'q') || 'q').trim().toLowerCase();
+        moveData.promotion = ['q', 'r', 'b', 'n'].includes(choice) ? choice : 'q';
+    }
+    makeGameMove(JSON.stringify(moveData));
 }
 
 // Tic-tac-toe board display - IMPROVED FOR WAITING STATE
@@ -939,12 +1053,19 @@ function displayTriviaGame(game) {
                 </div>
             </div>
             
+            ${gameState.last_round ? `
+                <div class="trivia-last-round">
+                    Last question: ${escapeHtml(gameState.last_round.question)} — answer: <strong>${escapeHtml(gameState.last_round.answer || '?')}</strong>
+                    ${gameState.last_round.correct.length ? `(right: ${gameState.last_round.correct.map(escapeHtml).join(', ')})` : '(nobody got it)'}
+                </div>
+            ` : ''}
+            ${game.status === 'active' ? `<div class="trivia-remaining">Questions left after this one: ${gameState.questions_left || 0}</div>` : ''}
             <div class="trivia-question">
-                <div class="question-category">${question ? question.category : 'Loading...'}</div>
-                <div class="question-text">${question ? question.question : 'Loading question...'}</div>
+                <div class="question-category">${question ? question.category : (game.status === 'finished' ? 'Game over' : 'Loading...')}</div>
+                <div class="question-text">${question ? question.question : (game.status === 'finished' ? 'All questions answered!' : 'Loading question...')}</div>
                 
                 ${!question ? 
-                    '<div class="loading-notice">Loading next question...</div>' :
+                    (game.status === 'finished' ? '' : '<div class="loading-notice">Loading next question...</div>') :
                     answered.includes(currentUser) ? 
                         `<div class="answered-notice">
                             <p><strong>You have answered this question!</strong></p>
@@ -957,9 +1078,6 @@ function displayTriviaGame(game) {
                             <div class="answered-players">
                                 <p>Answered: ${answered.join(', ')}</p>
                             </div>
-                            ${answered.length >= 2 ? 
-                                '<button class="next-question-btn" onclick="requestNextQuestion()">Continue to Next Question</button>' : ''
-                            }
                         </div>` : 
                         `<div class="question-options">
                             ${question.options.map((option, index) => `
@@ -988,89 +1106,162 @@ function displayTriviaGame(game) {
     }
 }
 
-// Add function to request next question
-function requestNextQuestion() {
-    if (!currentGame || !socket || socket.readyState !== WebSocket.OPEN) {
-        console.error('Cannot request next question: no game or connection');
-        return;
-    }
-    
-    console.log('Requesting next question for game:', currentGame.id);
-    
-    socket.send(JSON.stringify({
-        type: 'game_move',
-        game_id: currentGame.id,
-        game_move: JSON.stringify({
-            action: 'next_question',
-            player: currentUser
-        })
-    }));
-}
-
 function handleTriviaAnswer(answer) {
     if (!currentGame) {
         console.error('No current game set');
         return;
     }
     
-    console.log('=== TRIVIA ANSWER WORKAROUND ===');
-    console.log('Using chat-based trivia answer system');
-    console.log('Answer selected:', answer);
-    
-    // Check if user has already answered
     const gameState = JSON.parse(currentGame.game_state);
-    const answered = gameState.answered || [];
-    
-    if (answered.includes(currentUser)) {
-        console.log('User has already answered this question');
+    if ((gameState.answered || []).includes(currentUser)) {
         alert('You have already answered this question!');
         return;
     }
     
-    // Instead of using game_move, send as a special chat message
-    // This bypasses the turn validation completely
-    if (socket && socket.readyState === WebSocket.OPEN) {
-        const triviaAnswerMessage = {
-            type: 'chat_message',
-            receiver_username: currentConversation,
-            group_id: currentGroup ? currentGroup.id : null,
-            message: `🧠 TRIVIA_ANSWER:${currentGame.id}:${answer}:${currentUser}:${Date.now()}`,
-            timestamp: getCurrentTime()
-        };
-        
-        console.log('Sending trivia answer via chat message:', triviaAnswerMessage);
-        socket.send(JSON.stringify(triviaAnswerMessage));
-        
-        // Disable answer options immediately
-        const options = gameBoard.querySelectorAll('.trivia-option');
-        options.forEach(option => {
-            option.disabled = true;
-            option.style.opacity = '0.5';
-            option.style.cursor = 'not-allowed';
+    // Either player may answer at any time; the server scores the round once both have
+    makeGameMove(JSON.stringify({ answer }));
+    
+    const answerPrompt = gameBoard.querySelector('.answer-prompt');
+    if (answerPrompt) {
+        answerPrompt.innerHTML = `<div style="color: #28a745; font-weight: bold;">Answer ${answer + 1} submitted! Waiting for other players...</div>`;
+    }
+}
+
+// Connect Four board display
+function displayConnectFourBoard(game) {
+    const gameState = JSON.parse(game.game_state);
+    const myTurn = game.status === 'active' && game.current_turn === currentUser;
+    const winning = (gameState.winning_line || []).map(([r, c]) => `${r},${c}`);
+    const last = gameState.last_move ? gameState.last_move.join(',') : null;
+
+    gameBoard.innerHTML = '<div class="c4-board"></div>';
+    const board = gameBoard.querySelector('.c4-board');
+    gameState.board.forEach((cells, row) => {
+        cells.forEach((cell, col) => {
+            const slot = document.createElement('div');
+            slot.className = 'c4-cell';
+            if (cell) slot.classList.add(cell === 'R' ? 'red' : 'yellow');
+            if (winning.includes(`${row},${col}`)) slot.classList.add('winning');
+            else if (last === `${row},${col}`) slot.classList.add('last-move');
+            // A click anywhere in a column drops into it
+            if (myTurn && !gameState.board[0][col]) {
+                slot.classList.add('clickable');
+                slot.addEventListener('click', () => makeGameMove(JSON.stringify({ column: col })));
+            }
+            board.appendChild(slot);
         });
-        
-        // Show immediate feedback
-        const answerPrompt = gameBoard.querySelector('.answer-prompt');
-        if (answerPrompt) {
-            answerPrompt.innerHTML = `<div style="color: #28a745; font-weight: bold;">Answer ${answer + 1} submitted! Waiting for other players...</div>`;
-        }
-        
-        // Update local game state to show user has answered
-        const updatedGameState = JSON.parse(currentGame.game_state);
-        updatedGameState.answered = updatedGameState.answered || [];
-        if (!updatedGameState.answered.includes(currentUser)) {
-            updatedGameState.answered.push(currentUser);
-        }
-        currentGame.game_state = JSON.stringify(updatedGameState);
-        
-        console.log('Updated local game state:', updatedGameState);
-        console.log('=== END TRIVIA ANSWER WORKAROUND ===');
-        
-        // Refresh the display to show updated state
-        displayTriviaGame(currentGame);
-    } else {
-        console.error('WebSocket not connected');
-        alert('Connection error. Please try again.');
+    });
+}
+
+// Checkers board display
+let selectedCheckersSquare = null;
+
+function displayCheckersBoard(game) {
+    const gameState = JSON.parse(game.game_state);
+    const legalMoves = gameState.legal_moves || [];
+    const myTurn = game.status === 'active' && game.current_turn === currentUser;
+    const last = gameState.last_move;
+    const symbols = { b: '⛂', B: '⛃', w: '⛀', W: '⛁' };
+    selectedCheckersSquare = null;
+
+    gameBoard.innerHTML = '<div class="checkers-status"></div><div class="checkers-board"></div>';
+    const status = gameBoard.querySelector('.checkers-status');
+    const myColor = game.player1_username === currentUser ? 'black' : 'white';
+    status.textContent = gameState.continue_from && myTurn
+        ? 'Keep capturing with the same piece!'
+        : `You play ${myColor}`;
+
+    const board = gameBoard.querySelector('.checkers-board');
+    gameState.board.forEach((cells, row) => {
+        cells.forEach((cell, col) => {
+            const square = document.createElement('div');
+            square.className = `checkers-square ${(row + col) % 2 === 1 ? 'dark' : 'light'}`;
+            square.dataset.row = row;
+            square.dataset.col = col;
+            if (cell) {
+                square.textContent = symbols[cell] || cell;
+                square.classList.add(cell.toLowerCase() === 'b' ? 'black-piece' : 'white-piece');
+            }
+            if (last && [last.from, last.to].some(([r, c]) => r === row && c === col)) {
+                square.classList.add('last-move');
+            }
+            if (myTurn) {
+                square.addEventListener('click', () => handleCheckersSquareClick(row, col, legalMoves));
+            }
+            board.appendChild(square);
+        });
+    });
+
+    if (myTurn && gameState.continue_from) {
+        handleCheckersSquareClick(gameState.continue_from[0], gameState.continue_from[1], legalMoves);
+    }
+}
+
+function handleCheckersSquareClick(row, col, legalMoves) {
+    const from = legalMoves.filter(m => m.from[0] === row && m.from[1] === col);
+    if (from.length) {
+        gameBoard.querySelectorAll('.selected, .legal-target').forEach(el => el.classList.remove('selected', 'legal-target'));
+        selectedCheckersSquare = [row, col];
+        gameBoard.querySelector(`.checkers-square[data-row="${row}"][data-col="${col}"]`).classList.add('selected');
+        from.forEach(m => {
+            const target = gameBoard.querySelector(`.checkers-square[data-row="${m.to[0]}"][data-col="${m.to[1]}"]`);
+            if (target) target.classList.add('legal-target');
+        });
+        return;
+    }
+    if (!selectedCheckersSquare) return;
+    const [fromRow, fromCol] = selectedCheckersSquare;
+    if (legalMoves.some(m => m.from[0] === fromRow && m.from[1] === fromCol && m.to[0] === row && m.to[1] === col)) {
+        selectedCheckersSquare = null;
+        makeGameMove(JSON.stringify({ from: [fromRow, fromCol], to: [row, col] }));
+    }
+}
+
+// Hangman display
+function displayHangmanGame(game) {
+    const gameState = JSON.parse(game.game_state);
+    const scores = gameState.scores || {};
+    const guessed = gameState.guessed || [];
+    const misses = gameState.misses || [];
+    const myTurn = game.status === 'active' && game.current_turn === currentUser;
+    const players = [game.player1_username, game.player2_username].filter(Boolean);
+
+    gameBoard.innerHTML = `
+        <div class="hangman-game">
+            <div class="hangman-pattern">${escapeHtml(gameState.pattern || '')}</div>
+            ${gameState.word ? `<div class="hangman-word">The word was <strong>${escapeHtml(gameState.word)}</strong></div>` : ''}
+            <div class="hangman-misses">❌ ${misses.length} / ${gameState.max_misses}${misses.length ? ': ' + misses.map(escapeHtml).join(', ') : ''}</div>
+            <div class="hangman-scores">${players.map(p => `<span>${escapeHtml(p)}: ${scores[p] || 0}</span>`).join('')}</div>
+            <div class="hangman-letters"></div>
+            ${myTurn ? `
+                <div class="hangman-word-guess">
+                    <input type="text" class="hangman-word-input" placeholder="Guess the whole word">
+                    <button class="hangman-word-btn">Guess word</button>
+                </div>
+            ` : ''}
+        </div>
+    `;
+
+    const letters = gameBoard.querySelector('.hangman-letters');
+    'abcdefghijklmnopqrstuvwxyz'.split('').forEach(letter => {
+        const btn = document.createElement('button');
+        btn.className = 'hangman-letter';
+        btn.textContent = letter;
+        btn.disabled = !myTurn || guessed.includes(letter);
+        if (guessed.includes(letter)) btn.classList.add(misses.includes(letter) ? 'miss' : 'hit');
+        btn.addEventListener('click', () => makeGameMove(JSON.stringify({ letter })));
+        letters.appendChild(btn);
+    });
+
+    const wordBtn = gameBoard.querySelector('.hangman-word-btn');
+    if (wordBtn) {
+        const input = gameBoard.querySelector('.hangman-word-input');
+        const guessWord = () => {
+            const word = input.value.trim();
+            if (word) makeGameMove(JSON.stringify({ word }));
+        };
+        wordBtn.addEventListener('click', guessWord);
+        input.addEventListener('keypress', e => { if (e.key === 'Enter') guessWord(); });
     }
 }
 
@@ -1232,12 +1423,18 @@ function setupEventListeners() {
     showLoginLink.addEventListener('click', (e) => { e.preventDefault(); showLoginForm(); });
     loginBtn.addEventListener('click', handleLogin);
     registerBtn.addEventListener('click', handleRegister);
-    logoutBtn.addEventListener('click', handleLogout);
+    logoutBtn.addEventListener('click', () => handleLogout());
+    const logoutAllBtn = document.getElementById('logout-all-btn');
+    if (logoutAllBtn) logoutAllBtn.addEventListener('click', () => {
+        if (confirm('Log out on every device?')) handleLogout({ everywhere: true });
+    });
     sendBtn.addEventListener('click', sendMessage);
 
     messageInput.addEventListener('keypress', (e) => {
         if (e.key === 'Enter' && !e.shiftKey) { e.preventDefault(); sendMessage(); }
     });
+    messageInput.addEventListener('input', notifyTyping);
+    messageInput.addEventListener('blur', stopTyping);
 
     imageBtn.addEventListener('click', () => imageInput.click());
     imageInput.addEventListener('change', sendImage);
@@ -1299,6 +1496,14 @@ function setupEventListeners() {
         });
     }
 
+    const inviteLinkBtn = document.getElementById('invite-link-btn');
+    if (inviteLinkBtn) {
+        inviteLinkBtn.addEventListener('click', () => {
+            groupMenuDropdown.classList.remove('show');
+            createInviteLink();
+        });
+    }
+
     if (viewMembersBtn) {
         viewMembersBtn.addEventListener('click', () => {
             groupMenuDropdown.classList.remove('show');
@@ -1361,6 +1566,25 @@ function setupEventListeners() {
         });
     }
 
+    if (pollTypeSelect) {
+        pollTypeSelect.addEventListener('change', updatePollTypeFields);
+    }
+
+    const dmPollBtn = document.getElementById('dm-poll-btn');
+    if (dmPollBtn) {
+        dmPollBtn.addEventListener('click', showCreatePollModal);
+    }
+
+    const quizScoresMenuBtn = document.getElementById('quiz-scores-menu-btn');
+    if (quizScoresMenuBtn) {
+        quizScoresMenuBtn.addEventListener('click', () => {
+            groupMenuDropdown.classList.remove('show');
+            if (currentGroup && socket && socket.readyState === WebSocket.OPEN) {
+                socket.send(JSON.stringify({ type: 'get_quiz_scores', group_id: currentGroup.id }));
+            }
+        });
+    }
+
     // Add members modal listeners
     if (closeAddMembersModal) {
         closeAddMembersModal.addEventListener('click', () => {
@@ -1411,8 +1635,8 @@ function setupEventListeners() {
 // Poll Functions
 // ==========================
 function showCreatePollModal() {
-    if (!currentGroup) {
-        alert('No group selected');
+    if (!currentGroup && !currentConversation) {
+        alert('No chat selected');
         return;
     }
     
@@ -1424,6 +1648,10 @@ function resetPollForm() {
     pollQuestionInput.value = '';
     allowMultipleCheckbox.checked = false;
     pollExpiresInput.value = '';
+    pollTypeSelect.value = 'standard';
+    pollAnonymousCheckbox.checked = false;
+    pollCorrectOptionInput.value = '';
+    updatePollTypeFields();
     
     // Reset poll options to default 2 options
     pollOptionsContainer.innerHTML = `
@@ -1438,6 +1666,14 @@ function resetPollForm() {
     `;
 }
 
+// Multiple choices only apply to standard polls; only quizzes have an answer
+function updatePollTypeFields() {
+    const type = pollTypeSelect.value;
+    document.getElementById('allow-multiple-group').style.display = type === 'standard' ? '' : 'none';
+    document.getElementById('poll-correct-group').style.display = type === 'quiz' ? '' : 'none';
+    if (type !== 'standard') allowMultipleCheckbox.checked = false;
+}
+
 function addPollOption() {
     const optionCount = pollOptionsContainer.children.length;
     if (optionCount >= 10) {
@@ -1471,8 +1707,8 @@ function removePollOption(button) {
 }
 
 function createPoll() {
-    if (!currentGroup) {
-        alert('No group selected');
+    if (!currentGroup && !currentConversation) {
+        alert('No chat selected');
         return;
     }
     
@@ -1482,9 +1718,8 @@ function createPoll() {
         return;
     }
     
-    const options = Array.from(pollOptionsContainer.children)
-        .map(div => div.querySelector('input').value.trim())
-        .filter(option => option.length > 0);
+    const optionInputs = Array.from(pollOptionsContainer.children).map(div => div.querySelector('input').value.trim());
+    const options = optionInputs.filter(option => option.length > 0);
     
     if (options.length < 2) {
         alert('At least 2 options are required');
@@ -1492,17 +1727,33 @@ function createPoll() {
     }
     
     const allowMultiple = allowMultipleCheckbox.checked;
-    const expiresAt = pollExpiresInput.value || null;
+    const pollType = pollTypeSelect.value;
+    let correctOption = null;
+    if (pollType === 'quiz') {
+        // The number refers to the inputs as shown; blank ones are not sent
+        const number = parseInt(pollCorrectOptionInput.value, 10);
+        if (!number || !optionInputs[number - 1]) {
+            alert('Enter the number of the correct option');
+            return;
+        }
+        correctOption = optionInputs.slice(0, number - 1).filter(option => option.length > 0).length;
+    }
+    // The picker gives local time; the server wants an absolute one
+    const expiresAt = pollExpiresInput.value ? new Date(pollExpiresInput.value).toISOString() : null;
     
     if (socket && socket.readyState === WebSocket.OPEN) {
         const pollMessage = {
             type: 'create_poll',
-            group_id: currentGroup.id,
+            // A poll goes to the open group, or to the open DM
+            group_id: currentGroup ? currentGroup.id : null,
             poll_question: question,
             poll_options: options,
             poll_allow_multiple: allowMultiple,
             poll_expires_at: expiresAt,
-            receiver_username: null,
+            poll_type: pollType,
+            poll_anonymous: pollAnonymousCheckbox.checked,
+            poll_correct_option: correctOption,
+            receiver_username: currentGroup ? null : currentConversation,
             message: null,
             timestamp: null,
             message_id: null,
@@ -1526,7 +1777,7 @@ function createPoll() {
 
 function displayPollMessage(message, historical = false) {
     if (message.message && message.message.includes('📊 Poll')) {
-        const existingMessage = document.querySelector(`[data-message-id="${message.id}"]`);
+        const existingMessage = findMessageEl(messageKind(message), message.id);
         if (existingMessage && message.id) {
             console.log('Duplicate poll message prevented (DOM check):', message.id);
             return;
@@ -1535,6 +1786,7 @@ function displayPollMessage(message, historical = false) {
         const msgDiv = document.createElement('div');
         msgDiv.className = `message ${message.sender_username === currentUser ? 'sent' : 'received'} poll-message`;
         msgDiv.dataset.messageId = message.id;
+        msgDiv.dataset.messageKind = messageKind(message);
         if (historical) msgDiv.classList.add('historical');
 
         const header = document.createElement('div');
@@ -1546,14 +1798,16 @@ function displayPollMessage(message, historical = false) {
         content.className = 'message-content';
         content.textContent = message.message;
 
-        const pollButton = document.createElement('button');
-        pollButton.className = 'poll-view-btn';
-        pollButton.textContent = 'View Poll';
-        pollButton.addEventListener('click', () => loadPollDetails(message.id));
-
         msgDiv.appendChild(header);
         msgDiv.appendChild(content);
-        msgDiv.appendChild(pollButton);
+        // Announcements from before polls were linked to their messages have no poll_id
+        if (message.poll_id) {
+            const pollButton = document.createElement('button');
+            pollButton.className = 'poll-view-btn';
+            pollButton.textContent = 'View Poll';
+            pollButton.addEventListener('click', () => loadPollDetails(message.poll_id));
+            msgDiv.appendChild(pollButton);
+        }
         
         messagesDiv.appendChild(msgDiv);
         if (!historical) scrollToBottom();
@@ -1571,40 +1825,103 @@ function loadPollDetails(pollId) {
     }
 }
 
+function pollOptionText(pollData, optionId) {
+    const option = pollData.options.find(o => o.id === optionId);
+    return option ? option.option_text : '?';
+}
+
+// The instant-runoff outcome of a ranked poll, one line per round
+function rankedResultHtml(pollData) {
+    const ranked = pollData.ranked;
+    if (!ranked || ranked.rounds.length === 0) return '';
+    const rounds = ranked.rounds.map((round, index) => {
+        const tallies = round.tallies.map(t => `${escapeHtml(pollOptionText(pollData, t.option_id))}: ${t.votes}`).join(', ');
+        const out = round.eliminated.length
+            ? ` — out: ${round.eliminated.map(id => escapeHtml(pollOptionText(pollData, id))).join(', ')}`
+            : '';
+        return `<li>Round ${index + 1}: ${tallies}${out}</li>`;
+    }).join('');
+    let outcome = '';
+    if (ranked.winner) {
+        outcome = `<p><strong>${pollData.is_active ? 'Leading' : 'Winner'}: ${escapeHtml(pollOptionText(pollData, ranked.winner))}</strong></p>`;
+    } else if (ranked.tied && ranked.tied.length) {
+        outcome = `<p><strong>Tie: ${ranked.tied.map(id => escapeHtml(pollOptionText(pollData, id))).join(', ')}</strong></p>`;
+    }
+    return `${outcome}<ol class="poll-type-note">${rounds}</ol>`;
+}
+
 function displayPollDetails(pollData) {
     const pollModal = document.createElement('div');
     pollModal.className = 'poll-modal';
     
     const totalVotes = pollData.total_votes || 0;
+    const pollType = pollData.poll_type || 'standard';
+    const ranked = pollType === 'ranked';
+    const quiz = pollType === 'quiz';
+    // A quiz answer is final
+    const canVote = pollData.is_active && !(quiz && pollData.has_voted);
+
+    let typeNote = '';
+    if (pollData.allow_multiple_choices) typeNote = '<p><em>Multiple choices allowed</em></p>';
+    if (ranked) typeNote = '<p class="poll-type-note">Ranked choice: number the options in order of preference (1 = first). Counts show first preferences.</p>';
+    if (quiz) typeNote = '<p class="poll-type-note">Quiz: one answer, and it is final.</p>';
+
+    let quizResult = '';
+    if (quiz && pollData.answered_correctly !== undefined) {
+        quizResult = `<p class="quiz-result">${pollData.answered_correctly ? '✅ You answered correctly' : '❌ That was not the right answer'}</p>`;
+    } else if (quiz && pollData.has_voted) {
+        quizResult = '<p class="quiz-result">You have answered this quiz</p>';
+    }
+
+    const optionInput = option => {
+        if (ranked) {
+            return `<input type="number" class="poll-rank-input" name="poll-rank" min="1" max="${pollData.options.length}"
+                           data-option-id="${option.id}"
+                           value="${option.current_user_rank || ''}"
+                           ${canVote ? '' : 'disabled'}>`;
+        }
+        return `<input type="${pollData.allow_multiple_choices ? 'checkbox' : 'radio'}" 
+                       name="poll-vote" 
+                       value="${option.id}"
+                       ${option.voted_by_current_user ? 'checked' : ''}
+                       ${canVote ? '' : 'disabled'}>`;
+    };
     
     pollModal.innerHTML = `
         <div class="poll-modal-content">
             <span class="close-poll-details">&times;</span>
             <h3>${pollData.question}</h3>
             <p>Created by: ${pollData.creator_username} at ${pollData.created_at}</p>
-            ${pollData.allow_multiple_choices ? '<p><em>Multiple choices allowed</em></p>' : ''}
+            ${typeNote}
+            ${pollData.anonymous ? `<p class="poll-type-note">Anonymous: nobody can see who voted for what${pollData.has_voted ? ' (you have voted)' : ''}.</p>` : ''}
+            ${pollData.is_active
+                ? (pollData.expires_at ? `<p><em>Closes ${new Date(pollData.expires_at).toLocaleString()}</em></p>` : '')
+                : `<p class="poll-closed-note">Closed${pollData.closed_at ? ' ' + new Date(pollData.closed_at).toLocaleString() : ''}</p>`}
+            ${quizResult}
             <div class="poll-options">
                 ${pollData.options.map(option => `
-                    <div class="poll-option" data-option-id="${option.id}">
+                    <div class="poll-option${quiz && option.id === pollData.correct_option_id ? ' correct-option' : ''}" data-option-id="${option.id}">
                         <label>
-                            <input type="${pollData.allow_multiple_choices ? 'checkbox' : 'radio'}" 
-                                   name="poll-vote" 
-                                   value="${option.id}"
-                                   ${option.voted_by_current_user ? 'checked' : ''}>
-                            ${option.option_text}
+                            ${optionInput(option)}
+                            ${option.option_text}${quiz && option.id === pollData.correct_option_id ? ' ✅' : ''}
                         </label>
-                        <div class="vote-count">${option.vote_count} votes</div>
+                        <div class="vote-count">${option.vote_count} ${ranked ? 'first preferences' : 'votes'}</div>
                         <div class="vote-bar">
                             <div class="vote-progress" style="width: ${totalVotes > 0 ? (option.vote_count / totalVotes * 100) : 0}%"></div>
                         </div>
                     </div>
                 `).join('')}
             </div>
-            <p>Total votes: ${totalVotes}</p>
-            <button class="vote-poll-btn" onclick="submitVote(${pollData.id}, ${pollData.allow_multiple_choices})">Submit Vote</button>
+            <p>Total ${ranked || quiz ? 'voters' : 'votes'}: ${totalVotes}</p>
+            ${ranked ? rankedResultHtml(pollData) : ''}
+            ${canVote ? `<button class="vote-poll-btn" onclick="submitVote(${pollData.id}, ${pollData.allow_multiple_choices}, '${pollType}')">${quiz ? 'Submit Answer' : 'Submit Vote'}</button>` : ''}
+            ${pollData.is_active && pollData.creator_username === currentUser ? `<button class="vote-poll-btn close-poll-btn" onclick="closePoll(${pollData.id})">Close Poll</button>` : ''}
+            <button class="vote-poll-btn export-poll-btn" onclick="exportPoll(${pollData.id}, 'csv')">Export CSV</button>
+            <button class="vote-poll-btn export-poll-btn" onclick="exportPoll(${pollData.id}, 'json')">Export JSON</button>
         </div>
     `;
     
+    pollModal.dataset.pollId = pollData.id;
     document.body.appendChild(pollModal);
     pollModal.style.display = 'block';
     
@@ -1619,16 +1936,78 @@ function displayPollDetails(pollData) {
     });
 }
 
-function submitVote(pollId, allowMultiple) {
-    const checkedInputs = document.querySelectorAll('input[name="poll-vote"]:checked');
-    const optionIds = Array.from(checkedInputs).map(input => parseInt(input.value));
+// Download a poll's results; the route needs the auth header, so no plain link
+async function exportPoll(pollId, format) {
+    try {
+        const response = await fetch(`/polls/${pollId}/export?format=${format}`, {
+            headers: { 'Authorization': `Bearer ${authToken}` }
+        });
+        if (!response.ok) {
+            const err = await response.json().catch(() => ({}));
+            alert(err.error || 'Failed to export poll');
+            return;
+        }
+        const url = URL.createObjectURL(await response.blob());
+        const link = document.createElement('a');
+        link.href = url;
+        link.download = `poll-${pollId}.${format}`;
+        document.body.appendChild(link);
+        link.click();
+        link.remove();
+        URL.revokeObjectURL(url);
+    } catch (e) {
+        console.error('Poll export failed', e);
+        alert('Failed to export poll');
+    }
+}
+
+function displayQuizScores(data) {
+    if (!data.scores.length) {
+        alert('No closed quizzes in this group yet');
+        return;
+    }
+    const lines = data.scores.map((score, index) => `${index + 1}. ${score.username}: ${score.correct} / ${score.answered}`);
+    alert(`🏆 Quiz scores\n\n${lines.join('\n')}`);
+}
+
+function closePoll(pollId) {
+    if (!confirm('Close this poll now? No more votes will be accepted.')) return;
+    if (socket && socket.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify({ type: 'close_poll', poll_id: pollId }));
+    }
+}
+
+function handlePollClosed(poll) {
+    const open = document.querySelector(`.poll-modal[data-poll-id="${poll.id}"]`);
+    if (open) {
+        open.remove();
+        loadPollDetails(poll.id);
+    }
+}
+
+function submitVote(pollId, allowMultiple, pollType) {
+    let optionIds;
+    if (pollType === 'ranked') {
+        // Unnumbered options are left off the ballot
+        const ranks = Array.from(document.querySelectorAll('input[name="poll-rank"]'))
+            .filter(input => input.value)
+            .map(input => ({ rank: parseInt(input.value, 10), optionId: parseInt(input.dataset.optionId, 10) }));
+        if (new Set(ranks.map(r => r.rank)).size !== ranks.length) {
+            alert('Give each option a different rank');
+            return;
+        }
+        optionIds = ranks.sort((a, b) => a.rank - b.rank).map(r => r.optionId);
+    } else {
+        const checkedInputs = document.querySelectorAll('input[name="poll-vote"]:checked');
+        optionIds = Array.from(checkedInputs).map(input => parseInt(input.value));
+    }
     
     if (optionIds.length === 0) {
-        alert('Please select at least one option');
+        alert(pollType === 'ranked' ? 'Please rank at least one option' : 'Please select at least one option');
         return;
     }
     
-    if (!allowMultiple && optionIds.length > 1) {
+    if (pollType !== 'ranked' && !allowMultiple && optionIds.length > 1) {
         alert('Only one option allowed');
         return;
     }
@@ -1666,15 +2045,15 @@ async function handleLogin() {
         const data = await response.json();
 
         if (response.ok) {
-            authToken = data.token;
+            storeTokens(data);
             currentUser = username;
-            localStorage.setItem('authToken', authToken);
             localStorage.setItem('currentUser', currentUser);
             showMessengerInterface();
             loadContacts();
             loadGroups();
             loadNotes();
             showAIAssistant();
+            redeemInviteFromUrl();
         } else {
             showError(errorDiv, data.error || 'Login failed');
         }
@@ -1712,15 +2091,89 @@ async function handleRegister() {
     }
 }
 
-function handleLogout() {
+// Seconds-since-epoch expiry of a JWT, or 0 when it cannot be read
+function tokenExpiry(token) {
+    try {
+        const payload = JSON.parse(atob(token.split('.')[1].replace(/-/g, '+').replace(/_/g, '/')));
+        return payload.exp || 0;
+    } catch { return 0; }
+}
+
+function storeTokens(data) {
+    authToken = data.token;
+    localStorage.setItem('authToken', authToken);
+    if (data.refresh_token) {
+        refreshToken = data.refresh_token;
+        localStorage.setItem('refreshToken', refreshToken);
+    }
+    scheduleTokenRefresh();
+}
+
+// Refresh a minute before the access token runs out
+function scheduleTokenRefresh() {
+    if (refreshTimer) clearTimeout(refreshTimer);
+    refreshTimer = null;
+    if (!authToken || !refreshToken) return;
+    const delay = Math.max(5, tokenExpiry(authToken) - Date.now() / 1000 - 60) * 1000;
+    refreshTimer = setTimeout(refreshAccessToken, delay);
+}
+
+let refreshInFlight = null;
+async function refreshAccessToken() {
+    if (!refreshToken) return false;
+    // Refresh tokens are single use, so concurrent callers share one request
+    if (!refreshInFlight) {
+        refreshInFlight = (async () => {
+            try {
+                const response = await fetch('/refresh', {
+                    method: 'POST',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify({ refresh_token: refreshToken })
+                });
+                if (response.status === 401) { handleLogout({ local: true }); return false; }
+                if (!response.ok) { scheduleTokenRefresh(); return false; }
+                storeTokens(await response.json());
+                return true;
+            } catch {
+                // Offline: try again shortly
+                if (refreshTimer) clearTimeout(refreshTimer);
+                refreshTimer = setTimeout(refreshAccessToken, 15000);
+                return false;
+            } finally {
+                refreshInFlight = null;
+            }
+        })();
+    }
+    return refreshInFlight;
+}
+
+// Make sure the access token is good for at least another minute
+async function ensureFreshToken() {
+    if (authToken && tokenExpiry(authToken) - Date.now() / 1000 > 60) return true;
+    return refreshAccessToken();
+}
+
+// `local` skips telling the server (the session is already gone there);
+// `everywhere` ends every session of this user, not just this one.
+function handleLogout({ local = false, everywhere = false } = {}) {
+    if (!local && authToken) {
+        fetch(everywhere ? '/logout/all' : '/logout', {
+            method: 'POST',
+            headers: { 'Authorization': `Bearer ${authToken}` }
+        }).catch(() => {});
+    }
+    if (refreshTimer) clearTimeout(refreshTimer);
+    refreshTimer = null;
     localStorage.removeItem('authToken');
+    localStorage.removeItem('refreshToken');
     localStorage.removeItem('currentUser');
     authToken = null;
+    refreshToken = null;
     currentUser = null;
     currentConversation = null;
     currentGroup = null;
 
-    if (socket) { socket.close(); socket = null; }
+    if (socket) { socket.onclose = null; socket.close(); socket = null; }
 
     showAuthInterface();
     clearMessages();
@@ -1742,6 +2195,7 @@ async function loadContacts() {
             const data = await response.json();
             console.log('Contacts data received:', data);
             contacts = data.users || [];
+            contactPresence = data.presence || {};
             console.log('Contacts array now contains:', contacts);
             displayContacts();
         } else {
@@ -1758,23 +2212,25 @@ function displayContacts() {
     contactsList.innerHTML = '';
     const qEl = document.getElementById('contacts-search');
     const q = qEl ? qEl.value.trim().toLowerCase() : '';
-    const list = q ? contacts.filter(u => (u||'').toLowerCase().includes(q)) : contacts;
+    const list = sortByConversation(q ? contacts.filter(u => (u||'').toLowerCase().includes(q)) : contacts, u => directSummary(u));
     list.forEach(username => {
         const contactItem = document.createElement('div');
         contactItem.className = 'contact-item';
+        contactItem.dataset.peer = username;
 
-        // Randomly set online/offline for demo (in production, this would come from backend)
-        const isOnline = Math.random() > 0.5;
+        const presence = contactPresence[username] || { status: 'offline' };
+        const lastSeen = presence.status !== 'online' && presence.last_seen_at ? `Last seen ${formatLastSeen(presence.last_seen_at)}` : presence.status;
 
         contactItem.innerHTML = `
             <div class="contact-info">
-                <span class="user-status ${isOnline ? 'online' : 'offline'}"></span>
+                <span class="user-status ${presence.status}" title="${lastSeen}"></span>
                 <span class="contact-name">${username}</span>
             </div>
             <button class="contact-highlight-btn" title="Get chat highlights" onclick="event.stopPropagation(); generateChatHighlight('${username}', 'personal')">✨</button>
         `;
 
         contactItem.addEventListener('click', () => selectContact(username, contactItem));
+        renderConversationMeta(contactItem, directSummary(username), { receiver_username: username });
         contactsList.appendChild(contactItem);
     });
 }
@@ -1805,6 +2261,7 @@ async function selectContact(username, element) {
 
     currentConversation = username;
     currentGroup = null;
+    setReplyTarget(null);
     chatWithUsername.textContent = username;
     chatHeader.style.display = 'block';
     welcomeScreen.style.display = 'none';
@@ -1821,6 +2278,8 @@ async function selectContact(username, element) {
         dmLockBtn.style.display = 'inline-block';
         updateDMLockButton();
     }
+    const dmPollBtn = document.getElementById('dm-poll-btn');
+    if (dmPollBtn) dmPollBtn.style.display = 'inline-block';
     
     // Show game buttons for conversations
     const gameButtons = document.getElementById('game-buttons');
@@ -1868,8 +2327,14 @@ async function loadGroups() {
             
             memberGroups = data.member_groups || [];
             availableGroups = data.available_groups || [];
+            if (currentGroup) {
+                const fresh = memberGroups.find(g => g.id === currentGroup.id);
+                if (fresh) currentGroup.notifications_muted = fresh.notifications_muted;
+            }
             
             displayGroups();
+            refreshMentionsBadge();
+            requestConversations();
         } else {
             console.error('Failed to load groups, status:', response.status);
             const errorText = await response.text();
@@ -1887,12 +2352,13 @@ function displayGroups() {
     const agqEl = document.getElementById('available-groups-search');
     const gq = gqEl ? gqEl.value.trim().toLowerCase() : '';
     const agq = agqEl ? agqEl.value.trim().toLowerCase() : '';
-    const mg = gq ? memberGroups.filter(g => (g.name||'').toLowerCase().includes(gq)) : memberGroups;
+    const mg = sortByConversation(gq ? memberGroups.filter(g => (g.name||'').toLowerCase().includes(gq)) : memberGroups, g => groupSummary(g.id));
     const ag = agq ? availableGroups.filter(g => (g.name||'').toLowerCase().includes(agq)) : availableGroups;
 
     mg.forEach(group => {
         const groupItem = document.createElement('div');
         groupItem.className = 'group-item';
+        groupItem.dataset.groupId = group.id;
         
         groupItem.innerHTML = `
             <div class="contact-info">
@@ -1902,6 +2368,8 @@ function displayGroups() {
         `;
         
         groupItem.addEventListener('click', () => selectGroup(group, groupItem));
+        renderConversationMeta(groupItem, groupSummary(group.id), { group_id: group.id });
+        renderGroupMarkers(groupItem, group);
         groupsList.appendChild(groupItem);
     });
 
@@ -1916,11 +2384,18 @@ function displayGroups() {
             
             const joinBtn = document.createElement('button');
             joinBtn.className = 'join-group-btn';
-            joinBtn.textContent = 'Join';
-            joinBtn.addEventListener('click', (e) => {
-                e.stopPropagation();
-                joinGroup(group.id);
-            });
+            if (group.join_requested) {
+                joinBtn.textContent = 'Requested';
+                joinBtn.disabled = true;
+            } else {
+                joinBtn.textContent = group.join_policy === 'approval' ? 'Request' : 'Join';
+                joinBtn.addEventListener('click', (e) => {
+                    e.stopPropagation();
+                    const note = group.join_policy === 'approval' ? prompt('Add a note for the admins (optional):', '') : '';
+                    if (note === null) return;
+                    joinGroup(group.id, note);
+                });
+            }
             
             groupItem.appendChild(groupName);
             groupItem.appendChild(joinBtn);
@@ -1930,6 +2405,7 @@ function displayGroups() {
 }
 
 function selectGroup(group, element) {
+    setReplyTarget(null);
     console.log('Selecting group:', group.name);
     // Ensure split UI is not interfering
     try {
@@ -1966,8 +2442,15 @@ function selectGroup(group, element) {
     // Show group menu for group chats AND game buttons
     if (groupMenu) groupMenu.style.display = 'block';
     if (dmLockBtn) dmLockBtn.style.display = 'none';
+    const dmPollBtn = document.getElementById('dm-poll-btn');
+    if (dmPollBtn) dmPollBtn.style.display = 'none';
     removeDMLockUI();
     updateGhostToggleLabel();
+    updateNotificationsToggleLabel();
+    updateGroupMenuForRole();
+    unreadGroups.delete(group.id);
+    mentionedGroups.delete(group.id);
+    renderGroupMarkers(element, group);
     
     const gameButtons = document.getElementById('game-buttons');
     if (gameButtons) {
@@ -1975,11 +2458,428 @@ function selectGroup(group, element) {
         console.log('Game buttons should now be visible for group');
     }
 
-    clearMessages();
-    loadGroupConversation(group.id);
-    // Apply per-chat theme for group
-    (async () => { try { await loadAndApplyThemeForCurrent(); } catch {} })();
+    clearMessages();
+    loadGroupConversation(group.id);
+    // Apply per-chat theme for group
+    (async () => { try { await loadAndApplyThemeForCurrent(); } catch {} })();
+}
+function updateNotificationsToggleLabel() {
+    const btn = document.getElementById('toggle-notifications-btn');
+    if (!btn) return;
+    const muted = currentGroup && Boolean(currentGroup.notifications_muted);
+    btn.textContent = muted ? '🔔 Unmute Notifications' : '🔕 Mute Notifications';
+}
+
+async function toggleGroupNotifications() {
+    if (!currentGroup) return;
+    const muted = !currentGroup.notifications_muted;
+    const resp = await fetch(`/groups/${currentGroup.id}/notifications`, {
+        method: 'PUT',
+        headers: { 'Content-Type': 'application/json', 'Authorization': `Bearer ${authToken}` },
+        body: JSON.stringify({ muted })
+    });
+    if (!resp.ok) {
+        const data = await resp.json().catch(() => ({}));
+        alert('Failed to update notifications: ' + (data.error || 'Unknown error'));
+        return;
+    }
+    currentGroup.notifications_muted = muted;
+    const listed = memberGroups.find(g => g.id === currentGroup.id);
+    if (listed) listed.notifications_muted = muted;
+    updateNotificationsToggleLabel();
+    displayGroups();
+    showNotification(muted ? `Muted ${currentGroup.name}; you'll still hear about mentions` : `Unmuted ${currentGroup.name}`, 'info');
+}
+
+// ==== Conversation list ====
+function conversationKey(summary) {
+    return summary.kind === 'group' ? `group:${summary.group_id}` : `direct:${(summary.peer_username || '').toLowerCase()}`;
+}
+
+function directSummary(peer) {
+    return conversationSummaries.get(`direct:${(peer || '').toLowerCase()}`);
+}
+
+function groupSummary(groupId) {
+    return conversationSummaries.get(`group:${groupId}`);
+}
+
+function requestConversations() {
+    if (socket && socket.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify({ type: 'get_conversations' }));
+    }
+}
+
+// Pinned first, archived last, otherwise most recent activity; chats never used keep their order
+function sortByConversation(items, summaryOf) {
+    const rank = s => !s ? 2 : s.archived ? 3 : s.pinned ? 0 : 1;
+    return items
+        .map((item, index) => ({ item, index, summary: summaryOf(item) }))
+        .sort((a, b) => {
+            const byRank = rank(a.summary) - rank(b.summary);
+            if (byRank !== 0) return byRank;
+            const at = (a.summary && a.summary.last_activity) || '';
+            const bt = (b.summary && b.summary.last_activity) || '';
+            return bt.localeCompare(at) || a.index - b.index;
+        })
+        .map(entry => entry.item);
+}
+
+function applyConversations(list) {
+    conversationSummaries.clear();
+    list.forEach(summary => conversationSummaries.set(conversationKey(summary), summary));
+    displayContacts();
+    displayGroups();
+}
+
+function handleConversationUpdated(summary) {
+    conversationSummaries.set(conversationKey(summary), summary);
+    if (summary.kind === 'group') {
+        const group = memberGroups.find(g => g.id === summary.group_id);
+        if (group) group.notifications_muted = summary.muted;
+        if (currentGroup && currentGroup.id === summary.group_id) {
+            currentGroup.notifications_muted = summary.muted;
+            updateNotificationsToggleLabel();
+        }
+        displayGroups();
+    } else {
+        displayContacts();
+    }
+}
+
+// A message landed or changed: move the entry along without refetching it
+function handleConversationActivity(activity) {
+    const summary = conversationSummaries.get(conversationKey(activity));
+    if (!summary) {
+        // A conversation new to this list; fetch it with its settings
+        requestConversations();
+        return;
+    }
+    summary.last_message = activity.last_message;
+    if (activity.last_activity) summary.last_activity = activity.last_activity;
+    summary.unread_count = Math.max(0, (summary.unread_count || 0) + activity.unread_delta);
+    if (summary.kind === 'group') {
+        displayGroups();
+    } else {
+        displayContacts();
+    }
+}
+
+function conversationPreview(summary) {
+    const last = summary && summary.last_message;
+    if (!last) return '';
+    let text;
+    if (last.recalled) text = 'Message recalled';
+    else if (last.preview === undefined) text = '🔒 Hidden until reveal';
+    else if (!last.preview && last.attachment_count) text = '📎 Attachment';
+    else text = last.preview;
+    if (summary.kind === 'group' || last.sender_username === currentUser) {
+        text = `${last.sender_username === currentUser ? 'You' : last.sender_username}: ${text}`;
+    }
+    return text;
+}
+
+// Preview line, unread count and pin/mute/archive actions of a sidebar entry
+function renderConversationMeta(item, summary, target) {
+    const info = item.querySelector('.contact-info') || item;
+    item.classList.toggle('archived', Boolean(summary && summary.archived));
+    if (summary && summary.pinned) {
+        const pin = document.createElement('span');
+        pin.className = 'conversation-pin-marker';
+        pin.textContent = '📌';
+        pin.title = 'Pinned';
+        info.insertBefore(pin, info.firstChild);
+    }
+    if (summary && summary.kind === 'direct' && summary.muted) {
+        const muted = document.createElement('span');
+        muted.className = 'group-muted-marker';
+        muted.textContent = '🔕';
+        muted.title = 'Notifications muted';
+        info.appendChild(muted);
+    }
+    if (summary && summary.unread_count > 0) {
+        const badge = document.createElement('span');
+        badge.className = 'conversation-unread-count';
+        badge.textContent = summary.unread_count > 99 ? '99+' : String(summary.unread_count);
+        info.appendChild(badge);
+    }
+    const previewText = conversationPreview(summary);
+    if (previewText) {
+        const preview = document.createElement('div');
+        preview.className = 'conversation-preview';
+        preview.textContent = previewText;
+        info.appendChild(preview);
+    }
+
+    const actions = document.createElement('div');
+    actions.className = 'conversation-actions';
+    const pinned = Boolean(summary && summary.pinned);
+    const archived = Boolean(summary && summary.archived);
+    const muted = Boolean(summary && summary.muted);
+    [
+        ['📌', pinned ? 'Unpin' : 'Pin', { pinned: !pinned }],
+        ['🔕', muted ? 'Unmute' : 'Mute', { muted: !muted }],
+        ['🗄️', archived ? 'Unarchive' : 'Archive', { archived: !archived }],
+    ].forEach(([icon, title, change]) => {
+        const btn = document.createElement('button');
+        btn.className = 'contact-highlight-btn conversation-action-btn';
+        btn.textContent = icon;
+        btn.title = title;
+        btn.addEventListener('click', (e) => {
+            e.stopPropagation();
+            updateConversationSettings({ ...target, ...change });
+        });
+        actions.appendChild(btn);
+    });
+    const highlight = item.querySelector('.contact-highlight-btn');
+    if (highlight) actions.appendChild(highlight);
+    item.appendChild(actions);
+}
+
+async function updateConversationSettings(body) {
+    const resp = await fetch('/conversations/settings', {
+        method: 'PUT',
+        headers: { 'Content-Type': 'application/json', 'Authorization': `Bearer ${authToken}` },
+        body: JSON.stringify(body)
+    });
+    const data = await resp.json().catch(() => ({}));
+    if (!resp.ok) {
+        alert('Failed to update conversation: ' + (data.error || 'Unknown error'));
+        return;
+    }
+    handleConversationUpdated(data);
+}
+
+// ==== Mentions and group activity ====
+function renderGroupMarkers(groupItem, group) {
+    if (!groupItem) return;
+    groupItem.querySelectorAll('.group-unread-dot, .group-mention-marker, .group-muted-marker').forEach(el => el.remove());
+    const summary = groupSummary(group.id);
+    const info = groupItem.querySelector('.contact-info') || groupItem;
+    if (group.notifications_muted) {
+        const muted = document.createElement('span');
+        muted.className = 'group-muted-marker';
+        muted.textContent = '🔕';
+        muted.title = 'Notifications muted';
+        info.appendChild(muted);
+    }
+    if (mentionedGroups.has(group.id)) {
+        const mark = document.createElement('span');
+        mark.className = 'group-mention-marker';
+        mark.textContent = '@';
+        mark.title = 'You were mentioned';
+        info.appendChild(mark);
+    } else if (unreadGroups.has(group.id) && !(summary && summary.unread_count > 0)) {
+        const dot = document.createElement('span');
+        dot.className = 'group-unread-dot';
+        dot.title = 'New messages';
+        info.appendChild(dot);
+    }
+}
+
+function groupItemFor(groupId) {
+    return document.querySelector(`.group-item[data-group-id="${groupId}"]`);
+}
+
+// A message arrived in a group: flag it unless it's open or muted
+function noteGroupActivity(groupId) {
+    if (currentGroup && currentGroup.id === groupId) return;
+    const group = memberGroups.find(g => g.id === groupId);
+    if (!group || group.notifications_muted) return;
+    unreadGroups.add(groupId);
+    renderGroupMarkers(groupItemFor(groupId), group);
+}
+
+function updateMentionsBadge() {
+    const badge = document.getElementById('mentions-badge');
+    if (!badge) return;
+    badge.textContent = mentionsUnread > 99 ? '99+' : String(mentionsUnread);
+    badge.style.display = mentionsUnread > 0 ? '' : 'none';
+}
+
+async function refreshMentionsBadge() {
+    if (!authToken) return;
+    try {
+        const resp = await fetch('/mentions?limit=1&unread=true', { headers: { 'Authorization': `Bearer ${authToken}` } });
+        if (!resp.ok) return;
+        const data = await resp.json();
+        mentionsUnread = data.unread_count || 0;
+        updateMentionsBadge();
+    } catch (e) {
+        console.error('Failed to load mentions', e);
+    }
+}
+
+// Mentions come through even for muted groups
+function handleMentioned(mention) {
+    const viewing = currentGroup && currentGroup.id === mention.group_id && !document.hidden;
+    mentionsUnread += 1;
+    updateMentionsBadge();
+    if (viewing) return;
+    mentionedGroups.add(mention.group_id);
+    const group = memberGroups.find(g => g.id === mention.group_id);
+    if (group) renderGroupMarkers(groupItemFor(mention.group_id), group);
+    const how = mention.everyone ? 'mentioned everyone' : 'mentioned you';
+    showNotification(`${mention.mentioned_by} ${how} in ${mention.group_name}`, 'info');
+}
+
+async function openMentions(more = false) {
+    const list = document.getElementById('mentions-list');
+    const moreBtn = document.getElementById('more-mentions-btn');
+    if (!more) {
+        list.innerHTML = '';
+        oldestMentionId = null;
+    }
+    const params = new URLSearchParams({ limit: '30' });
+    if (oldestMentionId) params.set('before_id', oldestMentionId);
+    try {
+        const resp = await fetch(`/mentions?${params}`, { headers: { 'Authorization': `Bearer ${authToken}` } });
+        const data = await resp.json();
+        if (!resp.ok) {
+            showNotification(data.error || 'Failed to load mentions', 'error');
+            return;
+        }
+        mentionsUnread = data.unread_count || 0;
+        updateMentionsBadge();
+        if (!more && data.mentions.length === 0) {
+            list.innerHTML = '<div class="mention-meta">No mentions yet</div>';
+        }
+        data.mentions.forEach(mention => list.appendChild(mentionElement(mention)));
+        if (data.mentions.length) oldestMentionId = data.mentions[data.mentions.length - 1].id;
+        moreBtn.style.display = data.has_more ? '' : 'none';
+        document.getElementById('mentions-modal').style.display = 'block';
+    } catch (e) {
+        console.error('Failed to load mentions', e);
+    }
+}
+
+function mentionElement(mention) {
+    const item = document.createElement('div');
+    item.className = 'mention-item' + (mention.read_at ? '' : ' unread');
+    const meta = document.createElement('div');
+    meta.className = 'mention-meta';
+    const how = mention.everyone ? '@all' : '@you';
+    meta.textContent = `${mention.mentioned_by} • ${how} in ${mention.group_name} • ${mention.created_at}`;
+    const text = document.createElement('div');
+    text.textContent = mention.message !== undefined ? mention.message : 'Hidden until it is revealed';
+    item.append(meta, text);
+    item.addEventListener('click', () => jumpToMention(mention, item));
+    return item;
+}
+
+async function markMentionsRead(body) {
+    const resp = await fetch('/mentions/read', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json', 'Authorization': `Bearer ${authToken}` },
+        body: JSON.stringify(body)
+    });
+    if (!resp.ok) return;
+    const data = await resp.json();
+    mentionsUnread = data.unread_count || 0;
+    updateMentionsBadge();
+}
+
+async function jumpToMention(mention, item) {
+    if (!mention.read_at) {
+        await markMentionsRead({ ids: [mention.id] });
+        item.classList.remove('unread');
+    }
+    document.getElementById('mentions-modal').style.display = 'none';
+    const group = memberGroups.find(g => g.id === mention.group_id);
+    if (!group) return;
+    if (!currentGroup || currentGroup.id !== group.id) selectGroup(group, groupItemFor(group.id));
+    if (mention.thread_root) {
+        requestThread(mention.thread_root);
+    } else {
+        // Give the history a moment to load
+        setTimeout(() => highlightMessage('group', mention.message_id), 600);
+    }
+}
+
+// Pending and failed scheduled messages, by id
+const scheduledMessages = new Map();
+const localTimeZone = Intl.DateTimeFormat().resolvedOptions().timeZone;
+
+function requestScheduledMessages() {
+    if (socket && socket.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify({ type: 'list_scheduled_messages' }));
+    }
+}
+
+function applyScheduledMessages(list) {
+    scheduledMessages.clear();
+    list.forEach(entry => scheduledMessages.set(entry.id, entry));
+    renderScheduledMessages();
+}
+
+function handleScheduledMessageUpdated(entry) {
+    if (entry.status === 'pending' || entry.status === 'failed') {
+        scheduledMessages.set(entry.id, entry);
+    } else {
+        scheduledMessages.delete(entry.id);
+    }
+    if (entry.status === 'failed') {
+        showNotification(`Scheduled message failed: ${entry.last_error || 'Unknown'}`, 'error');
+    }
+    renderScheduledMessages();
+}
+
+function renderScheduledMessages() {
+    const list = document.getElementById('scheduled-list');
+    if (!list) return;
+    list.innerHTML = '';
+    const entries = [...scheduledMessages.values()].sort((a, b) => a.scheduled_at_epoch - b.scheduled_at_epoch);
+    if (entries.length === 0) {
+        list.innerHTML = '<div class="scheduled-meta">Nothing scheduled</div>';
+        return;
+    }
+    entries.forEach(entry => list.appendChild(scheduledElement(entry)));
+}
+
+function scheduledElement(entry) {
+    const item = document.createElement('div');
+    item.className = 'scheduled-item' + (entry.status === 'failed' ? ' failed' : '');
+    const body = document.createElement('div');
+    body.className = 'scheduled-body';
+    const text = document.createElement('div');
+    text.textContent = entry.message;
+    const meta = document.createElement('div');
+    meta.className = 'scheduled-meta';
+    const to = entry.group_id ? (memberGroups.find(g => g.id === entry.group_id) || {}).name || `group ${entry.group_id}` : entry.receiver_username;
+    const when = new Date(entry.scheduled_at_epoch * 1000).toLocaleString();
+    const parts = [`To ${to}`, when];
+    if (entry.recurrence) parts.push(`🔁 ${entry.recurrence}`);
+    if (entry.status === 'failed') parts.push(`Failed: ${entry.last_error || 'Unknown'}`);
+    else if (entry.last_error) parts.push(`Retrying: ${entry.last_error}`);
+    meta.textContent = parts.join(' • ');
+    body.append(text, meta);
+
+    const edit = document.createElement('button');
+    edit.className = 'message-action-btn';
+    edit.title = entry.status === 'failed' ? 'Edit and retry' : 'Edit';
+    edit.textContent = '✏️';
+    edit.addEventListener('click', () => editScheduledMessage(entry));
+    const cancel = document.createElement('button');
+    cancel.className = 'message-action-btn';
+    cancel.title = 'Cancel';
+    cancel.textContent = '🗑️';
+    cancel.addEventListener('click', () => {
+        if (!confirm('Cancel this scheduled message?')) return;
+        socket.send(JSON.stringify({ type: 'cancel_scheduled_message', id: entry.id }));
+    });
+    item.append(body, edit, cancel);
+    return item;
 }
+
+function editScheduledMessage(entry) {
+    const message = prompt('Edit scheduled message:', entry.message);
+    if (message === null) return;
+    const frame = { type: 'update_scheduled_message', id: entry.id };
+    if (message.trim() && message.trim() !== entry.message) frame.message = message.trim();
+    // An unchanged edit of a failed entry just retries it
+    socket.send(JSON.stringify(frame));
+}
+
 function updateGhostToggleLabel() {
     if (!toggleGhostBtn) return;
     const enabled = currentGroup && Boolean(currentGroup.ghost_mode);
@@ -1993,7 +2893,7 @@ function loadGroupConversation(groupId) {
     }
 }
 
-async function joinGroup(groupId) {
+async function joinGroup(groupId, message = '') {
     try {
         console.log(`Attempting to join group ${groupId}`);
         const response = await fetch('/groups/join', {
@@ -2004,7 +2904,8 @@ async function joinGroup(groupId) {
             },
             body: JSON.stringify({ 
                 group_id: groupId, 
-                username: currentUser 
+                username: currentUser,
+                message: message || null
             })
         });
         
@@ -2015,6 +2916,9 @@ async function joinGroup(groupId) {
             if (data.status === 'joined') {
                 alert('Successfully joined the group!');
                 loadGroups();
+            } else if (data.status === 'requested' || data.status === 'already_requested') {
+                alert('Your request to join was sent to the group admins');
+                loadGroups();
             } else if (data.status === 'already_member') {
                 alert('You are already a member of this group');
             } else {
@@ -2089,23 +2993,96 @@ async function createGroup() {
 // ==========================
 // Group Management Functions
 // ==========================
+const ROLE_RANK = { member: 0, moderator: 1, admin: 2, owner: 3 };
+
+// Only offer the actions the server will allow for this role
+function updateGroupMenuForRole() {
+    const rank = ROLE_RANK[(currentGroup && currentGroup.role) || 'member'];
+    if (addMembersBtn) addMembersBtn.style.display = rank >= ROLE_RANK.moderator ? '' : 'none';
+    const inviteLinkBtn = document.getElementById('invite-link-btn');
+    if (inviteLinkBtn) inviteLinkBtn.style.display = rank >= ROLE_RANK.moderator ? '' : 'none';
+    if (editGroupBtn) editGroupBtn.style.display = rank >= ROLE_RANK.admin ? '' : 'none';
+    if (toggleGhostBtn) toggleGhostBtn.style.display = rank >= ROLE_RANK.admin ? '' : 'none';
+}
+
 async function showGroupMembers() {
     if (!currentGroup) return;
     
     try {
+        const response = await fetch(`/groups/${currentGroup.id}/members`, {
+            headers: { 'Authorization': `Bearer ${authToken}` }
+        });
+        const data = await response.json();
+        if (!response.ok) { alert(data.error || 'Failed to load group members'); return; }
+
         membersList.innerHTML = '';
-        
-        if (currentGroup.members && currentGroup.members.length > 0) {
-            currentGroup.members.forEach(member => {
+        const me = data.members.find(m => m.username === currentUser);
+        const myRank = ROLE_RANK[me ? me.role : 'member'];
+
+        if (data.members.length > 0) {
+            data.members.forEach(member => {
                 const memberDiv = document.createElement('div');
                 memberDiv.style.padding = '10px';
                 memberDiv.style.borderBottom = '1px solid #eee';
-                memberDiv.textContent = member;
+                memberDiv.style.display = 'flex';
+                memberDiv.style.alignItems = 'center';
+                memberDiv.style.gap = '8px';
+
+                const name = document.createElement('span');
+                name.style.flex = '1';
+                name.textContent = member.username;
+                const badge = document.createElement('span');
+                badge.className = `role-badge role-${member.role}`;
+                badge.textContent = member.role;
+                memberDiv.append(name, badge);
+                if (member.muted_until) {
+                    const muted = document.createElement('span');
+                    muted.className = 'role-badge role-muted';
+                    muted.textContent = 'muted';
+                    muted.title = `Muted until ${new Date(member.muted_until).toLocaleString()}`;
+                    memberDiv.appendChild(muted);
+                }
+
+                // Admins and up manage roles of those below them; moderators and up remove them
+                const outranked = ROLE_RANK[member.role] < myRank;
+                if (outranked && myRank >= ROLE_RANK.admin) {
+                    const select = document.createElement('select');
+                    Object.keys(ROLE_RANK).filter(r => ROLE_RANK[r] < myRank).forEach(r => {
+                        const opt = document.createElement('option');
+                        opt.value = r; opt.textContent = r; opt.selected = r === member.role;
+                        select.appendChild(opt);
+                    });
+                    select.addEventListener('change', () => setMemberRole(member.username, select.value));
+                    memberDiv.appendChild(select);
+                }
+                if (outranked && myRank >= ROLE_RANK.moderator) {
+                    const mute = document.createElement('button');
+                    mute.textContent = member.muted_until ? 'Unmute' : 'Mute…';
+                    mute.addEventListener('click', () => member.muted_until ? unmuteGroupMember(member.username) : muteGroupMember(member.username));
+                    const remove = document.createElement('button');
+                    remove.textContent = 'Remove';
+                    remove.addEventListener('click', () => removeGroupMember(member.username));
+                    memberDiv.append(mute, remove);
+                }
+                if (outranked && myRank >= ROLE_RANK.admin) {
+                    const ban = document.createElement('button');
+                    ban.textContent = 'Ban…';
+                    ban.addEventListener('click', () => banGroupMember(member.username));
+                    memberDiv.appendChild(ban);
+                }
+                if (member.username !== currentUser && myRank === ROLE_RANK.owner) {
+                    const transfer = document.createElement('button');
+                    transfer.textContent = 'Make owner';
+                    transfer.addEventListener('click', () => transferGroupOwnership(member.username));
+                    memberDiv.appendChild(transfer);
+                }
                 membersList.appendChild(memberDiv);
             });
         } else {
             membersList.innerHTML = '<div style="padding: 20px; text-align: center; color: #666;">No members found</div>';
         }
+
+        if (myRank >= ROLE_RANK.moderator) await renderJoinRequests();
         
         viewMembersModal.style.display = 'block';
     } catch (err) {
@@ -2114,6 +3091,215 @@ async function showGroupMembers() {
     }
 }
 
+// Pending join requests, listed under the members for those who review them
+async function renderJoinRequests() {
+    const response = await fetch(`/groups/${currentGroup.id}/join_requests`, {
+        headers: { 'Authorization': `Bearer ${authToken}` }
+    });
+    if (!response.ok) return;
+    const { requests } = await response.json();
+    if (!requests.length) return;
+
+    const heading = document.createElement('h3');
+    heading.textContent = 'Join requests';
+    heading.style.margin = '16px 0 4px';
+    membersList.appendChild(heading);
+    requests.forEach(request => {
+        const row = document.createElement('div');
+        row.className = 'join-request-item';
+        const who = document.createElement('span');
+        who.style.flex = '1';
+        who.textContent = request.message ? `${request.username}: “${request.message}”` : request.username;
+        const approve = document.createElement('button');
+        approve.textContent = 'Approve';
+        approve.addEventListener('click', () => decideJoinRequest(request.id, true));
+        const reject = document.createElement('button');
+        reject.textContent = 'Reject';
+        reject.addEventListener('click', () => decideJoinRequest(request.id, false));
+        row.append(who, approve, reject);
+        membersList.appendChild(row);
+    });
+}
+
+async function decideJoinRequest(requestId, approve) {
+    const response = await fetch(`/groups/${currentGroup.id}/join_requests/${requestId}`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json', 'Authorization': `Bearer ${authToken}` },
+        body: JSON.stringify({ approve })
+    });
+    if (!response.ok) {
+        const data = await response.json().catch(() => ({}));
+        alert('Failed to decide request: ' + (data.error || 'Unknown error'));
+    }
+    showGroupMembers();
+    loadGroups();
+}
+
+async function createInviteLink() {
+    if (!currentGroup) return;
+    const hours = prompt('Invite expires after how many hours? (leave blank for never)', '24');
+    if (hours === null) return;
+    const uses = prompt('How many times can it be used? (leave blank for unlimited)', '');
+    if (uses === null) return;
+
+    const body = {};
+    if (hours.trim()) body.expires_in_secs = Math.round(parseFloat(hours) * 3600);
+    if (uses.trim()) body.max_uses = parseInt(uses, 10);
+    const response = await fetch(`/groups/${currentGroup.id}/invites`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json', 'Authorization': `Bearer ${authToken}` },
+        body: JSON.stringify(body)
+    });
+    const data = await response.json();
+    if (!response.ok) { alert('Failed to create invite: ' + (data.error || 'Unknown error')); return; }
+    prompt('Share this invite link:', `${window.location.origin}/#invite=${data.token}`);
+}
+
+// Offer to redeem an invite link the page was opened with
+async function redeemInviteFromUrl() {
+    const match = window.location.hash.match(/invite=([0-9a-f]+)/);
+    if (!match) return;
+    history.replaceState(null, '', window.location.pathname + window.location.search);
+    const token = match[1];
+
+    const preview = await fetch(`/invites/${token}`, { headers: { 'Authorization': `Bearer ${authToken}` } });
+    const info = await preview.json();
+    if (!preview.ok) { alert(info.error || 'This invite is no longer valid'); return; }
+    if (!confirm(`${info.invited_by} invited you to join "${info.name}" (${info.member_count} members). Join?`)) return;
+
+    const response = await fetch(`/invites/${token}/redeem`, {
+        method: 'POST',
+        headers: { 'Authorization': `Bearer ${authToken}` }
+    });
+    const data = await response.json();
+    if (!response.ok) { alert('Failed to join: ' + (data.error || 'Unknown error')); return; }
+    showNotification(data.status === 'already_member' ? `You are already in "${info.name}"` : `Joined "${info.name}"`, 'success');
+    loadGroups();
+}
+
+function handleJoinRequestEvent(data) {
+    const request = data.request;
+    if (data.type === 'join_requested') {
+        showNotification(`${request.username} asked to join "${request.group_name}"`);
+    } else if (request.username === currentUser) {
+        const approved = request.status === 'approved';
+        showNotification(approved ? `You can now chat in "${request.group_name}"` : `Your request to join "${request.group_name}" was declined`, approved ? 'success' : 'error');
+        loadGroups();
+        return;
+    }
+    if (currentGroup && currentGroup.id === request.group_id && viewMembersModal.style.display === 'block') {
+        showGroupMembers();
+    }
+}
+
+async function setMemberRole(username, role) {
+    const response = await fetch(`/groups/${currentGroup.id}/members/${encodeURIComponent(username)}/role`, {
+        method: 'PUT',
+        headers: { 'Content-Type': 'application/json', 'Authorization': `Bearer ${authToken}` },
+        body: JSON.stringify({ role })
+    });
+    if (!response.ok) {
+        const data = await response.json().catch(() => ({}));
+        alert('Failed to change role: ' + (data.error || 'Unknown error'));
+    }
+    showGroupMembers();
+}
+
+async function removeGroupMember(username) {
+    if (!confirm(`Remove ${username} from "${currentGroup.name}"?`)) return;
+    const response = await fetch(`/groups/${currentGroup.id}/members/${encodeURIComponent(username)}`, {
+        method: 'DELETE',
+        headers: { 'Authorization': `Bearer ${authToken}` }
+    });
+    if (!response.ok) {
+        const data = await response.json().catch(() => ({}));
+        alert('Failed to remove member: ' + (data.error || 'Unknown error'));
+    }
+    showGroupMembers();
+    loadGroups();
+}
+
+// Asks for an optional duration in hours; null when the prompt is cancelled
+function promptDurationSecs(message, fallback) {
+    const hours = prompt(message, fallback);
+    if (hours === null) return null;
+    return hours.trim() ? Math.round(parseFloat(hours) * 3600) : undefined;
+}
+
+async function moderateGroupMember(path, method, body, failure) {
+    const response = await fetch(`/groups/${currentGroup.id}/${path}`, {
+        method,
+        headers: { 'Content-Type': 'application/json', 'Authorization': `Bearer ${authToken}` },
+        body: body ? JSON.stringify(body) : undefined
+    });
+    if (!response.ok) {
+        const data = await response.json().catch(() => ({}));
+        alert(`${failure}: ` + (data.error || 'Unknown error'));
+    }
+    showGroupMembers();
+}
+
+async function muteGroupMember(username) {
+    const duration_secs = promptDurationSecs(`Mute ${username} for how many hours?`, '1');
+    if (!duration_secs) return;
+    const reason = prompt('Reason (optional):', '');
+    if (reason === null) return;
+    await moderateGroupMember(`members/${encodeURIComponent(username)}/mute`, 'POST', { duration_secs, reason }, 'Failed to mute member');
+}
+
+async function unmuteGroupMember(username) {
+    await moderateGroupMember(`members/${encodeURIComponent(username)}/mute`, 'DELETE', null, 'Failed to unmute member');
+}
+
+async function banGroupMember(username) {
+    const duration_secs = promptDurationSecs(`Ban ${username} for how many hours? (leave blank for a permanent ban)`, '');
+    if (duration_secs === null) return;
+    const reason = prompt('Reason (optional):', '');
+    if (reason === null) return;
+    await moderateGroupMember('bans', 'POST', { username, reason, duration_secs }, 'Failed to ban member');
+}
+
+async function transferGroupOwnership(username) {
+    if (!confirm(`Make ${username} the owner of "${currentGroup.name}"? You will become an admin.`)) return;
+    await moderateGroupMember('transfer', 'POST', { username }, 'Failed to transfer ownership');
+    loadGroups();
+}
+
+function closeCurrentGroup() {
+    currentGroup = null;
+    chatHeader.style.display = 'none';
+    welcomeScreen.style.display = 'block';
+    messagesContainer.style.display = 'none';
+    messageInputArea.style.display = 'none';
+    viewMembersModal.style.display = 'none';
+}
+
+function handleMemberChanged(change) {
+    const group = currentGroup && currentGroup.id === change.group_id ? currentGroup.name : 'a group';
+    if (change.username === currentUser) {
+        const until = change.until ? ` until ${new Date(change.until).toLocaleString()}` : '';
+        const reason = change.reason ? ` (${change.reason})` : '';
+        const notes = {
+            kicked: [`You were removed from ${group}${reason}`, 'error'],
+            banned: [`You were banned from ${group}${until}${reason}`, 'error'],
+            muted: [`You were muted in ${group}${until}${reason}`, 'error'],
+            unmuted: [`You can post in ${group} again`, 'success'],
+            role_changed: [`You are now a ${change.role} in ${group}`, 'success'],
+            ownership_transferred: [`You are now the owner of ${group}`, 'success'],
+        };
+        if (notes[change.action]) showNotification(...notes[change.action]);
+        if ((change.action === 'kicked' || change.action === 'banned') && currentGroup && currentGroup.id === change.group_id) {
+            closeCurrentGroup();
+        }
+        loadGroups();
+        return;
+    }
+    if (change.action === 'ownership_transferred') loadGroups();
+    if (currentGroup && currentGroup.id === change.group_id && viewMembersModal.style.display === 'block') {
+        showGroupMembers();
+    }
+}
+
 async function addMembersToGroup() {
     if (!currentGroup) {
         alert('No group selected');
@@ -2128,40 +3314,26 @@ async function addMembersToGroup() {
     }
     
     try {
-        let successCount = 0;
-        let errorCount = 0;
-        
-        for (const username of newMembers) {
-            console.log(`Adding user: ${username}`);
-            const response = await fetch('/groups/join', {
-                method: 'POST',
-                headers: { 
-                    'Content-Type': 'application/json',
-                    'Authorization': `Bearer ${authToken}`
-                },
-                body: JSON.stringify({ 
-                    group_id: currentGroup.id, 
-                    username: username 
-                })
-            });
-            
-            if (response.ok) {
-                successCount++;
-                console.log(`Successfully added ${username}`);
-            } else {
-                errorCount++;
-                console.error(`Failed to add member ${username}`, await response.text());
-            }
-        }
-        
-        if (successCount > 0 && errorCount === 0) {
-            alert(`Successfully added ${successCount} member(s) to the group`);
-        } else if (successCount > 0 && errorCount > 0) {
-            alert(`Added ${successCount} member(s) successfully, but failed to add ${errorCount} member(s)`);
-        } else {
-            alert('Failed to add any members');
+        const response = await fetch(`/groups/${currentGroup.id}/members`, {
+            method: 'POST',
+            headers: { 
+                'Content-Type': 'application/json',
+                'Authorization': `Bearer ${authToken}`
+            },
+            body: JSON.stringify({ usernames: newMembers })
+        });
+        const data = await response.json();
+        if (!response.ok) {
+            alert('Failed to add members: ' + (data.error || 'Unknown error'));
             return;
         }
+
+        const notes = [];
+        if (data.added.length) notes.push(`Added ${data.added.join(', ')}`);
+        if (data.already_members.length) notes.push(`Already members: ${data.already_members.join(', ')}`);
+        if (data.unknown.length) notes.push(`No such user: ${data.unknown.join(', ')}`);
+        if (data.banned.length) notes.push(`Banned: ${data.banned.join(', ')}`);
+        alert(notes.join('\n'));
         
         newMembersInput.value = '';
         addMembersModal.style.display = 'none';
@@ -2180,6 +3352,10 @@ async function editGroup() {
     if (!newName || newName.trim() === '') return;
     
     const newDescription = prompt('Enter new description:', currentGroup.description || '');
+    let joinPolicy = prompt('Who can join? open, approval or invite_only:', currentGroup.join_policy || 'open');
+    if (joinPolicy === null) return;
+    joinPolicy = joinPolicy.trim().toLowerCase();
+    if (!['open', 'approval', 'invite_only'].includes(joinPolicy)) { alert('Join policy must be open, approval or invite_only'); return; }
     
     try {
         const response = await fetch('/groups/update', {
@@ -2191,7 +3367,8 @@ async function editGroup() {
             body: JSON.stringify({ 
                 group_id: currentGroup.id,
                 name: newName.trim(),
-                description: newDescription ? newDescription.trim() : null
+                description: newDescription ? newDescription.trim() : null,
+                join_policy: joinPolicy
             })
         });
         
@@ -2233,12 +3410,7 @@ async function leaveGroup() {
         if (response.ok) {
             alert('Left group successfully');
             
-            currentGroup = null;
-            chatHeader.style.display = 'none';
-            welcomeScreen.style.display = 'block';
-            messagesContainer.style.display = 'none';
-            messageInputArea.style.display = 'none';
-            
+            closeCurrentGroup();
             loadGroups();
         } else {
             const errorData = await response.json();
@@ -2254,6 +3426,112 @@ async function leaveGroup() {
 // ==========================
 // WebSocket & Messaging
 // ==========================
+
+// Last-seen sequence per conversation, so a reconnect can replay what was missed
+function syncStateKey() { return `sync_state_${currentUser}`; }
+function loadSyncState() {
+    try {
+        const raw = localStorage.getItem(syncStateKey());
+        const state = raw ? JSON.parse(raw) : {};
+        return { direct: state.direct || {}, groups: state.groups || {} };
+    } catch { return { direct: {}, groups: {} }; }
+}
+function saveSyncState(state) { try { localStorage.setItem(syncStateKey(), JSON.stringify(state)); } catch {} }
+function recordSeenMessage(msg) {
+    if (!msg || !msg.seq) return;
+    const state = loadSyncState();
+    let map, key;
+    if (msg.group_id) {
+        map = state.groups; key = String(msg.group_id);
+    } else {
+        map = state.direct;
+        key = msg.sender_username === currentUser ? msg.receiver_username : msg.sender_username;
+    }
+    if (!key || (map[key] || 0) >= msg.seq) return;
+    map[key] = msg.seq;
+    saveSyncState(state);
+}
+function requestSync() {
+    if (!socket || socket.readyState !== WebSocket.OPEN) return;
+    const state = loadSyncState();
+    socket.send(JSON.stringify({ type: 'sync', direct: state.direct, groups: state.groups, request_id: 'sync' }));
+}
+// A sync naming a group we were removed from is refused; start those over
+function handleSyncRefused() {
+    const state = loadSyncState();
+    state.groups = {};
+    saveSyncState(state);
+    requestSync();
+}
+function handleSyncComplete(data) {
+    // Conversations we have never seen are loaded from history when opened
+    const state = loadSyncState();
+    Object.keys(state.groups).forEach(gid => { if (!(gid in (data.groups || {}))) delete state.groups[gid]; });
+    Object.entries(data.direct || {}).forEach(([peer, head]) => { if (!(peer in state.direct)) state.direct[peer] = head; });
+    Object.entries(data.groups || {}).forEach(([gid, head]) => { if (!(gid in state.groups)) state.groups[gid] = head; });
+    saveSyncState(state);
+    if (data.truncated) requestSync();
+}
+
+// Delivery/read receipts
+const readUpTo = {};
+let readFlushTimer = null;
+function markRead(message) {
+    if (!message.seq || document.hidden) return;
+    const key = message.group_id ? `g:${message.group_id}` : `d:${message.sender_username}`;
+    if ((readUpTo[key] || 0) >= message.seq) return;
+    readUpTo[key] = message.seq;
+    // Send once per batch of displayed messages (e.g. a whole history page)
+    if (!readFlushTimer) readFlushTimer = setTimeout(flushReadReceipts, 0);
+}
+const pendingReads = new Set();
+function flushReadReceipts() {
+    readFlushTimer = null;
+    if (!socket || socket.readyState !== WebSocket.OPEN) return;
+    Object.entries(readUpTo).forEach(([key, seq]) => {
+        if (pendingReads.has(`${key}:${seq}`)) return;
+        pendingReads.add(`${key}:${seq}`);
+        const frame = { type: 'read', up_to_seq: seq };
+        if (key.startsWith('g:')) frame.group_id = Number(key.slice(2));
+        else frame.receiver_username = key.slice(2);
+        socket.send(JSON.stringify(frame));
+        // Reading a group also reads its mentions
+        if (frame.group_id && mentionsUnread > 0) setTimeout(refreshMentionsBadge, 300);
+    });
+}
+function renderReceipt(el, message) {
+    if (message.group_id) {
+        const readers = message.read_by || [];
+        el.textContent = readers.length ? ` • Read by ${readers.length}` : '';
+        el.title = readers.join(', ');
+    } else {
+        el.textContent = message.read_at ? ' ✓✓' : (message.delivered_at ? ' ✓' : '');
+        el.title = message.read_at ? `Read ${message.read_at}` : (message.delivered_at ? `Delivered ${message.delivered_at}` : '');
+    }
+}
+function handleReceiptUpdate(data) {
+    data.message_ids.forEach(id => {
+        const msg = allMessages.find(m => m.id === id && !!m.group_id === !!data.group_id);
+        if (!msg) return;
+        if (data.type === 'read') {
+            if (data.group_id) {
+                msg.read_by = msg.read_by || [];
+                if (!msg.read_by.includes(data.username)) msg.read_by.push(data.username);
+            } else {
+                msg.read_at = data.timestamp;
+            }
+        }
+        if (!msg.delivered_at) msg.delivered_at = data.timestamp;
+        const msgEl = findMessageEl(messageKind(msg), id);
+        const el = msgEl && msgEl.querySelector('.message-receipt');
+        if (el) renderReceipt(el, msg);
+    });
+}
+document.addEventListener('visibilitychange', () => {
+    if (document.hidden) return;
+    allMessages.filter(m => m.sender_username !== currentUser).forEach(markRead);
+});
+
 function connectWebSocket() {
     if (socket) socket.close();
 
@@ -2274,7 +3552,8 @@ function connectWebSocket() {
                 if (mid && socket && socket.readyState === WebSocket.OPEN) {
                     socket.send(JSON.stringify({
                         type: 'get_reactions',
-                        message_id: mid
+                        message_id: mid,
+                        kind: el.dataset.messageKind
                     }));
                 }
             });
@@ -2283,6 +3562,10 @@ function connectWebSocket() {
             if (socket && socket.readyState === WebSocket.OPEN) {
                 socket.send(JSON.stringify({ type: 'get_pinned_messages' }));
             }
+
+            // Replay anything stored while we were disconnected
+            requestSync();
+            requestConversations();
         } catch (e) {
             console.warn('Failed to re-request reactions after connect:', e);
         }
@@ -2305,6 +3588,53 @@ function connectWebSocket() {
                     }
                 }
                 
+                if (data.type === 'chat_message') {
+                    recordSeenMessage(data);
+                    if (data.sender_username !== currentUser) hideTypingIndicator();
+                    if (data.thread_root) noteThreadReply(data);
+                    if (data.group_id && data.sender_username !== currentUser) noteGroupActivity(data.group_id);
+                    // Replies kept in their thread never reach the main chat
+                    if (data.thread_only) return;
+                } else if (data.type === 'thread') {
+                    renderThread(data);
+                    return;
+                } else if (data.type === 'mentioned') {
+                    handleMentioned(data);
+                    return;
+                } else if (data.type === 'conversations') {
+                    applyConversations(data.conversations || []);
+                    return;
+                } else if (data.type === 'conversation_updated') {
+                    handleConversationUpdated(data);
+                    return;
+                } else if (data.type === 'conversation_activity') {
+                    handleConversationActivity(data);
+                    return;
+                } else if (data.type === 'conversation_history' || data.type === 'group_conversation_history') {
+                    (data.messages || []).forEach(recordSeenMessage);
+                } else if (data.type === 'sync_complete') {
+                    handleSyncComplete(data);
+                    return;
+                } else if (data.type === 'error' && data.request_id === 'sync' && data.code === 'forbidden') {
+                    handleSyncRefused();
+                    return;
+                } else if (data.type === 'delivered' || data.type === 'read') {
+                    handleReceiptUpdate(data);
+                    return;
+                } else if (data.type === 'presence') {
+                    handlePresence(data);
+                    return;
+                } else if (data.type === 'join_requested' || data.type === 'join_request_decided') {
+                    handleJoinRequestEvent(data);
+                    return;
+                } else if (data.type === 'member_changed') {
+                    handleMemberChanged(data);
+                    return;
+                } else if (data.type === 'typing_start' || data.type === 'typing_stop') {
+                    handleTypingNotice(data);
+                    return;
+                }
+
                 // Handle WebRTC signaling first
                 if (data.type === 'call_offer' && data.to === currentUser) {
                     try {
@@ -2369,6 +3699,8 @@ function connectWebSocket() {
                 // Handle game-specific messages next
                 if (data.type === 'game_state' || data.type === 'game_update' || data.type === 'game_created' || data.type === 'game_joined') {
                     handleGameWebSocketMessage(data);
+                } else if (data.type === 'game_pgn') {
+                    downloadGamePgn(data);
                 } else if (data.type === 'game_error') {
                     console.error('Game error:', data.error);
                     alert('Game error: ' + data.error);
@@ -2427,6 +3759,10 @@ function connectWebSocket() {
                     displayConversationHistory(data);
                 } else if (data.type === 'poll_details') {
                     displayPollDetails(data.poll);
+                } else if (data.type === 'poll_closed') {
+                    handlePollClosed(data.poll);
+                } else if (data.type === 'quiz_scores') {
+                    displayQuizScores(data);
                 } else if (data.type === 'call_offer' && data.to === currentUser) {
                     try {
                         await ensureMedia();
@@ -2464,7 +3800,11 @@ function connectWebSocket() {
                 } else if (data.type === 'call_end' && data.to === currentUser) {
                     endCall(false);
                 } else if (data.sender_username && data.receiver_username && isMessageForCurrentConversation(data)) {
-                    displayMessage(data);
+                    if (data.message && data.message.includes('📊 Poll')) {
+                        displayPollMessage(data);
+                    } else {
+                        displayMessage(data);
+                    }
                 } else if (data.sender_username && data.receiver_username && currentConversation2 && (
                     (data.sender_username === currentUser && data.receiver_username === currentConversation2) ||
                     (data.sender_username === currentConversation2 && data.receiver_username === currentUser)
@@ -2490,10 +3830,6 @@ function connectWebSocket() {
                     }
                 } else if (data.group_id) {
                     console.log('Received message for different group:', data.group_id, 'current group:', currentGroup ? currentGroup.id : 'none');
-                } else if (data.type === 'file_ready' && pendingFile) {
-                    const arrayBuffer = await pendingFile.arrayBuffer();
-                    socket.send(arrayBuffer);
-                    pendingFile = null;
                 } else if (data.type === 'reaction_added') {
                     console.log('DEBUG: Received reaction_added message:', data);
                     handleReactionAdded(data);
@@ -2505,22 +3841,24 @@ function connectWebSocket() {
                     handleMessageUnpinned(data);
                 } else if (data.type === 'reactions_list') {
                     // If message element isn't rendered yet, stash and apply later
-                    const msgDiv = document.querySelector(`[data-message-id="${data.message_id}"]`);
+                    const msgDiv = findMessageEl(data.kind, data.message_id);
                     if (!msgDiv) {
-                        pendingReactions.set(data.message_id, data.reactions || {});
+                        pendingReactions.set(messageKey(data.kind, data.message_id), data.reactions || {});
                     } else {
                     handleReactionsList(data);
                     }
                 } else if (data.type === 'pinned_messages_list') {
                     handlePinnedMessagesList(data);
                 } else if (data.type === 'message_edited') {
-                    const m = document.querySelector(`[data-message-id="${data.message_id}"]`);
+                    const m = findMessageEl(data.kind, data.message_id);
                     if (m) {
                         const c = m.querySelector('.message-content');
                         if (c) { c.textContent = `${data.message} (edited)`; }
                     }
+                } else if (data.type === 'message_revealed') {
+                    handleMessageRevealed(data);
                 } else if (data.type === 'message_deleted') {
-                    const m = document.querySelector(`[data-message-id="${data.message_id}"]`);
+                    const m = findMessageEl(data.kind, data.message_id);
                     if (m) {
                         const c = m.querySelector('.message-content');
                         if (c) { c.textContent = 'Message recalled by sender'; }
@@ -2534,11 +3872,16 @@ function connectWebSocket() {
                 } else if (data.type === 'schedule_ack') {
                     if (data.ok) {
                         showNotification('Message scheduled ✅', 'success');
-                        const m = document.getElementById('schedule-modal');
-                        if (m) m.style.display = 'none';
+                        const txt = document.getElementById('schedule-text');
+                        if (txt) txt.value = '';
+                        requestScheduledMessages();
                     } else {
                         showNotification('Failed to schedule: ' + (data.error || 'Unknown'), 'error');
                     }
+                } else if (data.type === 'scheduled_messages') {
+                    applyScheduledMessages(data.scheduled || []);
+                } else if (data.type === 'scheduled_message_updated') {
+                    handleScheduledMessageUpdated(data);
                 } else {
                     console.log('Message not handled:', data);
                 }
@@ -2550,7 +3893,13 @@ function connectWebSocket() {
         }
     };
 
-    socket.onclose = () => setTimeout(() => { if (authToken) connectWebSocket(); }, 3000);
+    socket.onclose = (event) => {
+        // 4001: this session was logged out, here or on another device
+        if (event.code === 4001) { handleLogout({ local: true }); return; }
+        setTimeout(async () => {
+            if (authToken && await ensureFreshToken()) connectWebSocket();
+        }, 3000);
+    };
     socket.onerror = (err) => console.error('WebSocket error:', err);
 }
 
@@ -2582,6 +3931,12 @@ function displayConversationHistory(data) {
         }
     }
 
+    // An older page goes above what is already shown
+    if (data.before_id) {
+        prependOlderHistory(data);
+        return;
+    }
+
     console.log('Displaying conversation history:', data);
     clearMessages();
 
@@ -2589,6 +3944,7 @@ function displayConversationHistory(data) {
     allMessages = [];
 
     if (data.messages && data.messages.length > 0) {
+        if (data.has_more) addLoadOlderButton(data.messages[0].id);
         addHistorySeparator('--- Conversation History ---');
         data.messages.forEach(msg => {
             if (msg.message && msg.message.includes('🎮')) {
@@ -2624,7 +3980,8 @@ function displayConversationHistory(data) {
             if (mid && socket && socket.readyState === WebSocket.OPEN) {
                 socket.send(JSON.stringify({
                     type: 'get_reactions',
-                    message_id: mid
+                    message_id: mid,
+                    kind: el.dataset.messageKind
                 }));
             }
         });
@@ -2633,6 +3990,49 @@ function displayConversationHistory(data) {
     }
 }
 
+function renderHistoryMessage(msg) {
+    if (msg.message && msg.message.includes('🎮')) {
+        displayGameMessage(msg, true);
+    } else if (msg.message && msg.message.includes('📊 Poll')) {
+        displayPollMessage(msg, true);
+    } else {
+        displayMessage(msg, true);
+    }
+}
+
+function addLoadOlderButton(beforeId) {
+    const btn = document.createElement('button');
+    btn.className = 'load-older-btn';
+    btn.textContent = 'Load older messages';
+    btn.addEventListener('click', () => {
+        btn.disabled = true;
+        const frame = currentGroup && !currentConversation
+            ? { type: 'get_group_conversation', group_id: currentGroup.id, before_id: beforeId }
+            : { type: 'get_conversation', receiver_username: currentConversation, before_id: beforeId };
+        if (socket && socket.readyState === WebSocket.OPEN) socket.send(JSON.stringify(frame));
+    });
+    messagesDiv.appendChild(btn);
+}
+
+function prependOlderHistory(data) {
+    const oldBtn = messagesDiv.querySelector('.load-older-btn');
+    if (oldBtn) oldBtn.remove();
+    const firstExisting = messagesDiv.firstChild;
+    const mc = document.getElementById('messages-container');
+    const prevHeight = mc ? mc.scrollHeight : 0;
+
+    // Render at the end as usual, then move the new nodes to the top
+    const before = messagesDiv.childNodes.length;
+    if (data.has_more && data.messages.length > 0) addLoadOlderButton(data.messages[0].id);
+    (data.messages || []).forEach(renderHistoryMessage);
+    const added = Array.from(messagesDiv.childNodes).slice(before);
+    added.forEach(node => messagesDiv.insertBefore(node, firstExisting));
+    allMessages.sort((a, b) => a.id - b.id);
+
+    // Keep the view anchored where the user was reading
+    if (mc) mc.scrollTop += mc.scrollHeight - prevHeight;
+}
+
 function addHistorySeparator(text) {
     const sep = document.createElement('div');
     sep.className = 'history-separator';
@@ -2640,8 +4040,24 @@ function addHistorySeparator(text) {
     messagesDiv.appendChild(sep);
 }
 
+// The server withholds content until reveal_at and then sends it here
+function handleMessageRevealed(data) {
+    const stored = allMessages.find(m => m.id === data.message_id && messageKind(m) === data.kind);
+    if (stored) {
+        stored.message = data.message;
+        stored.attachments = data.attachments || [];
+        stored.withheld = false;
+    }
+    const m = findMessageEl(data.kind, data.message_id);
+    const content = m && m.querySelector('.message-content');
+    if (!content) return;
+    content.textContent = data.message;
+    if (data.attachments && data.attachments.length) renderAttachments(content, data.attachments);
+    content.classList.remove('blurred');
+}
+
 function displayMessage(message, historical = false) {
-    const existingMessage = document.querySelector(`[data-message-id="${message.id}"]`);
+    const existingMessage = findMessageEl(messageKind(message), message.id);
     if (existingMessage && message.id) {
         console.log('Duplicate message prevented (DOM check):', message.id);
         return;
@@ -2655,15 +4071,25 @@ function displayMessage(message, historical = false) {
     const msgDiv = document.createElement('div');
     msgDiv.className = `message ${message.sender_username === currentUser ? 'sent' : 'received'}`;
     msgDiv.dataset.messageId = message.id;
+    msgDiv.dataset.messageKind = messageKind(message);
     if (historical) msgDiv.classList.add('historical');
 
     const header = document.createElement('div');
     header.className = 'message-header';
     const displayName = (currentGroup && currentGroup.ghost_mode && message.group_id) ? 'Anonymous' : message.sender_username;
     header.textContent = `${displayName} • ${message.timestamp}`;
+    if (message.sender_username === currentUser) {
+        const receipt = document.createElement('span');
+        receipt.className = 'message-receipt';
+        header.appendChild(receipt);
+        renderReceipt(receipt, message);
+    } else {
+        markRead(message);
+    }
 
     const content = document.createElement('div');
     content.className = 'message-content';
+    const quote = message.quoted ? renderQuote(message.quoted, messageKind(message)) : null;
 
     // Check if message is deleted
     const isDeleted = message.deleted || message.message === 'Message recalled by sender';
@@ -2678,6 +4104,9 @@ function displayMessage(message, historical = false) {
     } else {
         content.textContent = message.message;
     }
+    if (message.attachments && message.attachments.length) {
+        renderAttachments(content, message.attachments);
+    }
 
     // Mark deleted messages
     if (isDeleted) {
@@ -2706,12 +4135,15 @@ function displayMessage(message, historical = false) {
                     overlay.appendChild(dot);
                 }
                 content.appendChild(overlay);
-                const delay = revealTime - now;
-                setTimeout(() => {
-                    content.classList.remove('blurred');
-                    const ov = content.querySelector('.reveal-overlay');
-                    if (ov) ov.remove();
-                }, delay);
+                // Withheld content arrives with message_revealed instead
+                if (!message.withheld) {
+                    const delay = revealTime - now;
+                    setTimeout(() => {
+                        content.classList.remove('blurred');
+                        const ov = content.querySelector('.reveal-overlay');
+                        if (ov) ov.remove();
+                    }, delay);
+                }
             }
         }
     } else if (message.sender_username === currentUser && pendingRevealISO) {
@@ -2774,7 +4206,8 @@ function displayMessage(message, historical = false) {
 
     // Apply any reactions that arrived before the element existed
     try {
-        const cached = pendingReactions.get(message.id);
+        const key = messageKey(messageKind(message), message.id);
+        const cached = pendingReactions.get(key);
         if (cached && Object.keys(cached).length > 0) {
             let reactionsDivLocal = reactionsDiv;
             if (!reactionsDivLocal) {
@@ -2795,7 +4228,7 @@ function displayMessage(message, historical = false) {
                 span.innerHTML = `${emoji} <span class="reaction-count">${count}</span>`;
                 reactionsDivLocal.appendChild(span);
             }
-            pendingReactions.delete(message.id);
+            pendingReactions.delete(key);
         }
     } catch (e) {
         console.warn('Failed to apply pending reactions:', e);
@@ -2823,8 +4256,28 @@ function displayMessage(message, historical = false) {
         showReactionPicker(msgDiv, message.id);
     });
 
+    const replyBtn = document.createElement('button');
+    replyBtn.className = 'message-action-btn';
+    replyBtn.textContent = '↩️';
+    replyBtn.title = 'Reply';
+    replyBtn.addEventListener('click', (e) => {
+        e.stopPropagation();
+        setReplyTarget(message);
+    });
+
+    const threadBtn = document.createElement('button');
+    threadBtn.className = 'message-action-btn';
+    threadBtn.textContent = '💬';
+    threadBtn.title = 'Reply in thread';
+    threadBtn.addEventListener('click', (e) => {
+        e.stopPropagation();
+        requestThread(message.thread_root || message.id);
+    });
+
     actionsDiv.appendChild(pinBtn);
     actionsDiv.appendChild(reactionBtn);
+    actionsDiv.appendChild(replyBtn);
+    actionsDiv.appendChild(threadBtn);
 
     // Edit/Delete only for own messages (and not deleted)
     if (message.sender_username === currentUser && !isDeleted) {
@@ -2837,7 +4290,7 @@ function displayMessage(message, historical = false) {
             const currentText = content.textContent || '';
             const newText = prompt('Edit message:', currentText);
             if (newText !== null && newText.trim() && socket && socket.readyState === WebSocket.OPEN) {
-                socket.send(JSON.stringify({ type: 'edit_message', message_id: message.id, message: newText.trim() }));
+                socket.send(JSON.stringify({ type: 'edit_message', message_id: message.id, kind: messageKind(message), message: newText.trim() }));
             }
         });
 
@@ -2849,7 +4302,7 @@ function displayMessage(message, historical = false) {
             e.stopPropagation();
             if (confirm('Recall this message?')) {
                 if (socket && socket.readyState === WebSocket.OPEN) {
-                    socket.send(JSON.stringify({ type: 'delete_message', message_id: message.id }));
+                    socket.send(JSON.stringify({ type: 'delete_message', message_id: message.id, kind: messageKind(message) }));
                 }
             }
         });
@@ -2859,7 +4312,9 @@ function displayMessage(message, historical = false) {
     }
 
     msgDiv.appendChild(header);
+    if (quote) msgDiv.appendChild(quote);
     msgDiv.appendChild(content);
+    if (!message.thread_root) msgDiv.appendChild(renderThreadSummary(message.id, message.thread));
     msgDiv.appendChild(reactionsDiv);
     msgDiv.appendChild(actionsDiv);
 
@@ -2875,7 +4330,7 @@ function displayMessage(message, historical = false) {
 
     // Load reactions and pin status for this message
     if (message.id) {
-        loadReactionsForMessage(message.id);
+        loadReactionsForMessage(messageKind(message), message.id);
     }
 }
 
@@ -2886,57 +4341,87 @@ function togglePinMessage(msgDiv, messageId, pinBtn) {
         if (wasPinned) {
             socket.send(JSON.stringify({
                 type: 'unpin_message',
-                message_id: messageId
+                message_id: messageId,
+                kind: msgDiv.dataset.messageKind
             }));
         } else {
             socket.send(JSON.stringify({
                 type: 'pin_message',
-                message_id: messageId
+                message_id: messageId,
+                kind: msgDiv.dataset.messageKind
             }));
         }
     }
 }
 
-async function searchMessages(query) {
+function escapeHtml(text) {
+    const div = document.createElement('div');
+    div.textContent = text == null ? '' : String(text);
+    return div.innerHTML;
+}
+
+// Supports from:user, in:group / in:@user, before:/after:YYYY-MM-DD and "phrases"
+async function searchMessages(query, cursor = null) {
     if (!query || query.trim().length === 0) {
         searchResults.style.display = 'none';
         return;
     }
     try {
-        const res = await fetch(`/search_messages?q=${encodeURIComponent(query)}`, { headers: { 'Authorization': `Bearer ${authToken}` } });
-        if (!res.ok) { searchResults.style.display = 'none'; return; }
+        let url = `/search_messages?q=${encodeURIComponent(query)}`;
+        if (cursor) url += `&cursor=${encodeURIComponent(cursor)}`;
+        const res = await fetch(url, { headers: { 'Authorization': `Bearer ${authToken}` } });
         const data = await res.json();
+        if (!res.ok) {
+            searchResults.innerHTML = `<div class="no-search-results">${escapeHtml(data.error || 'Search failed')}</div>`;
+            searchResults.style.display = 'block';
+            return;
+        }
         const results = Array.isArray(data.results) ? data.results : [];
-    if (results.length === 0) {
-        searchResults.innerHTML = '<div class="no-search-results">No messages found</div>';
-        searchResults.style.display = 'block';
-        return;
-    }
-    searchResults.innerHTML = '';
-        results.slice(0, 20).forEach(row => {
-        const resultItem = document.createElement('div');
-        resultItem.className = 'search-result-item';
-            const meta = row.type === 'group' ? `Group #${row.group_id}` : `${row.sender} → ${row.receiver}`;
-        resultItem.innerHTML = `
-                <div class="search-result-user">${meta}</div>
-                <div class="search-result-message">${row.message}</div>
-                <div class="search-result-time">${row.timestamp}</div>
+        if (!cursor) searchResults.innerHTML = '';
+        const previousMore = searchResults.querySelector('.search-load-more');
+        if (previousMore) previousMore.remove();
+        if (results.length === 0 && !cursor) {
+            searchResults.innerHTML = '<div class="no-search-results">No messages found</div>';
+            searchResults.style.display = 'block';
+            return;
+        }
+        results.forEach(row => {
+            const resultItem = document.createElement('div');
+            resultItem.className = 'search-result-item';
+            const meta = row.kind === 'group'
+                ? `${row.sender} in ${row.group_name || 'Group #' + row.group_id}`
+                : `${row.sender} → ${row.receiver}`;
+            // The snippet is escaped server-side, with matches in <mark>
+            resultItem.innerHTML = `
+                <div class="search-result-user">${escapeHtml(meta)}</div>
+                <div class="search-result-message">${row.snippet}</div>
+                <div class="search-result-time">${escapeHtml(row.timestamp)}</div>
             `;
-        resultItem.addEventListener('click', () => {
-                // Optional: jump to chat - for now just close results
-            searchResults.style.display = 'none';
-            searchInput.value = '';
+            resultItem.addEventListener('click', () => {
+                searchResults.style.display = 'none';
+                searchInput.value = '';
+                highlightMessage(row.kind, row.id);
+            });
+            searchResults.appendChild(resultItem);
         });
-        searchResults.appendChild(resultItem);
-    });
-    searchResults.style.display = 'block';
+        if (data.next_cursor) {
+            const more = document.createElement('button');
+            more.className = 'search-load-more load-older-btn';
+            more.textContent = 'More results';
+            more.addEventListener('click', (e) => {
+                e.stopPropagation();
+                searchMessages(query, data.next_cursor);
+            });
+            searchResults.appendChild(more);
+        }
+        searchResults.style.display = 'block';
     } catch {
         searchResults.style.display = 'none';
     }
 }
 
-function highlightMessage(messageId) {
-    const msgElement = document.querySelector(`[data-message-id="${messageId}"]`);
+function highlightMessage(kind, messageId) {
+    const msgElement = findMessageEl(kind, messageId);
     if (msgElement) {
         msgElement.scrollIntoView({ behavior: 'smooth', block: 'center' });
         msgElement.style.background = '#fff3cd';
@@ -2970,22 +4455,214 @@ function hideTypingIndicator() {
     }
 }
 
+function formatLastSeen(iso) {
+    const ms = Date.now() - parseISOToMs(iso);
+    if (isNaN(ms)) return '';
+    const mins = Math.floor(ms / 60000);
+    if (mins < 1) return 'just now';
+    if (mins < 60) return `${mins} min ago`;
+    const hours = Math.floor(mins / 60);
+    if (hours < 24) return `${hours} h ago`;
+    return new Date(parseISOToMs(iso)).toLocaleDateString();
+}
+
+function handlePresence(data) {
+    contactPresence[data.username] = { status: data.status, last_seen_at: data.last_seen_at };
+    displayContacts();
+}
+
+// Typing indicators: the server fans out starts and expires them on its own
+let typingSentAt = 0;
+function typingTarget() {
+    if (currentConversation) return { receiver_username: currentConversation };
+    if (currentGroup) return { group_id: currentGroup.id };
+    return null;
+}
+function notifyTyping() {
+    const target = typingTarget();
+    if (!target || !socket || socket.readyState !== WebSocket.OPEN) return;
+    if (!messageInput.value.trim()) { stopTyping(); return; }
+    if (Date.now() - typingSentAt < 2000) return;
+    typingSentAt = Date.now();
+    socket.send(JSON.stringify({ type: 'typing_start', ...target }));
+}
+function stopTyping() {
+    const target = typingTarget();
+    if (!typingSentAt || !target || !socket || socket.readyState !== WebSocket.OPEN) return;
+    typingSentAt = 0;
+    socket.send(JSON.stringify({ type: 'typing_stop', ...target }));
+}
+function handleTypingNotice(data) {
+    const here = data.group_id
+        ? (currentGroup && currentGroup.id === data.group_id)
+        : (currentConversation && currentConversation.toLowerCase() === data.username.toLowerCase());
+    if (!here) return;
+    if (data.type === 'typing_start') showTypingIndicator(data.username);
+    else hideTypingIndicator();
+}
+
 function sendMessage() {
     const text = messageInput.value.trim();
     if (!text || !socket || socket.readyState !== WebSocket.OPEN) return;
+    stopTyping();
 
+    // Replies from the main chat stay visible there
+    const reply = replyTarget ? { reply_to: replyTarget.id, also_send_to_channel: true } : {};
     if (currentConversation) {
-        const msg = { type: 'chat_message', receiver_username: currentConversation, message: text, timestamp: getCurrentTime() };
+        const msg = { type: 'chat_message', receiver_username: currentConversation, message: text, timestamp: getCurrentTime(), ...reply };
         if (revealAtISO) { msg.reveal_at = revealAtISO; pendingRevealISO = revealAtISO; }
         socket.send(JSON.stringify(msg));
     } else if (currentGroup) {
-        const msg = { type: 'group_message', group_id: currentGroup.id, message: text, timestamp: getCurrentTime() };
+        const msg = { type: 'group_message', group_id: currentGroup.id, message: text, timestamp: getCurrentTime(), ...reply };
         if (revealAtISO) { msg.reveal_at = revealAtISO; pendingRevealISO = revealAtISO; }
         socket.send(JSON.stringify(msg));
     }
 
     messageInput.value = '';
     revealAtISO = null;
+    setReplyTarget(null);
+}
+
+// ==== Replies and threads ====
+function renderQuote(quoted, kind) {
+    const quote = document.createElement('div');
+    quote.className = 'message-quote';
+    const who = document.createElement('strong');
+    who.textContent = quoted.sender_username;
+    const text = document.createElement('span');
+    if (quoted.deleted) {
+        text.textContent = 'Message recalled by sender';
+        quote.classList.add('quote-unavailable');
+    } else if (quoted.reveal_at) {
+        text.textContent = `Hidden until ${new Date(quoted.reveal_at).toLocaleString()}`;
+        quote.classList.add('quote-unavailable');
+    } else {
+        text.textContent = quoted.message + (quoted.edited ? ' (edited)' : '');
+    }
+    quote.append(who, text);
+    quote.addEventListener('click', () => highlightMessage(kind, quoted.id));
+    return quote;
+}
+
+function renderThreadSummary(rootId, thread) {
+    const summary = document.createElement('div');
+    summary.className = 'thread-summary';
+    summary.dataset.threadRoot = rootId;
+    summary.dataset.count = thread ? thread.reply_count : 0;
+    summary.addEventListener('click', () => requestThread(rootId));
+    updateThreadSummary(summary);
+    return summary;
+}
+
+function updateThreadSummary(summary) {
+    const count = parseInt(summary.dataset.count, 10) || 0;
+    summary.textContent = count ? `💬 ${count} ${count === 1 ? 'reply' : 'replies'}` : '';
+    summary.style.display = count ? '' : 'none';
+}
+
+function setReplyTarget(message) {
+    replyTarget = message;
+    let bar = document.getElementById('reply-bar');
+    if (!message) { if (bar) bar.remove(); return; }
+    if (!bar) {
+        bar = document.createElement('div');
+        bar.id = 'reply-bar';
+        bar.className = 'reply-bar';
+        messageInputArea.insertBefore(bar, messageInputArea.firstChild);
+    }
+    bar.innerHTML = '';
+    const label = document.createElement('span');
+    const name = (currentGroup && currentGroup.ghost_mode) ? 'Anonymous' : message.sender_username;
+    label.textContent = `Replying to ${name}: ${(message.message || '').slice(0, 80)}`;
+    const cancel = document.createElement('button');
+    cancel.textContent = '✕';
+    cancel.title = 'Cancel reply';
+    cancel.addEventListener('click', () => setReplyTarget(null));
+    bar.append(label, cancel);
+    messageInput.focus();
+}
+
+function requestThread(rootId) {
+    if (!socket || socket.readyState !== WebSocket.OPEN) return;
+    openThread = { rootId, groupId: currentGroup ? currentGroup.id : null, peer: currentConversation };
+    const frame = { type: 'get_thread', root_id: rootId, limit: 100 };
+    if (currentGroup) frame.group_id = currentGroup.id; else frame.receiver_username = currentConversation;
+    socket.send(JSON.stringify(frame));
+}
+
+function threadReplyElement(msg) {
+    const row = document.createElement('div');
+    row.className = 'thread-reply';
+    row.dataset.messageId = msg.id;
+    const header = document.createElement('div');
+    header.className = 'message-header';
+    header.textContent = `${msg.sender_username} • ${msg.timestamp}`;
+    row.appendChild(header);
+    if (msg.quoted && msg.quoted.id !== openThread.rootId) row.appendChild(renderQuote(msg.quoted, messageKind(msg)));
+    const body = document.createElement('div');
+    body.textContent = msg.message;
+    if (msg.attachments && msg.attachments.length) renderAttachments(body, msg.attachments);
+    row.appendChild(body);
+    return row;
+}
+
+function renderThread(data) {
+    if (!openThread) return;
+    openThread.rootId = data.root.id;
+    const rootDiv = document.getElementById('thread-root');
+    const replies = document.getElementById('thread-replies');
+    rootDiv.innerHTML = '';
+    rootDiv.appendChild(threadReplyElement(data.root));
+    replies.innerHTML = '';
+    if (data.has_more) {
+        const more = document.createElement('div');
+        more.className = 'thread-more';
+        more.textContent = 'Showing the latest replies';
+        replies.appendChild(more);
+    }
+    data.messages.forEach(msg => {
+        seenThreadReplies.add(`${msg.group_id || ''}:${msg.id}`);
+        replies.appendChild(threadReplyElement(msg));
+    });
+    document.getElementById('thread-modal').style.display = 'block';
+    replies.scrollTop = replies.scrollHeight;
+}
+
+// A reply arrived live: bump its root's counter and show it in an open thread
+function noteThreadReply(msg) {
+    const key = `${msg.group_id || ''}:${msg.id}`;
+    if (seenThreadReplies.has(key)) return;
+    seenThreadReplies.add(key);
+    const inView = msg.group_id ? (currentGroup && currentGroup.id === msg.group_id) : isMessageForCurrentConversation(msg);
+    if (!inView) return;
+    const summary = document.querySelector(`.thread-summary[data-thread-root="${msg.thread_root}"]`);
+    if (summary) {
+        summary.dataset.count = (parseInt(summary.dataset.count, 10) || 0) + 1;
+        updateThreadSummary(summary);
+    }
+    const modal = document.getElementById('thread-modal');
+    if (openThread && openThread.rootId === msg.thread_root && modal.style.display === 'block') {
+        const replies = document.getElementById('thread-replies');
+        replies.appendChild(threadReplyElement(msg));
+        replies.scrollTop = replies.scrollHeight;
+    }
+}
+
+function sendThreadReply() {
+    const input = document.getElementById('thread-input');
+    const text = input.value.trim();
+    if (!text || !openThread || !socket || socket.readyState !== WebSocket.OPEN) return;
+    const also = document.getElementById('thread-also-channel').checked;
+    const frame = openThread.groupId
+        ? { type: 'group_message', group_id: openThread.groupId }
+        : { type: 'chat_message', receiver_username: openThread.peer };
+    socket.send(JSON.stringify({ ...frame, message: text, reply_to: openThread.rootId, also_send_to_channel: also }));
+    input.value = '';
+}
+
+function closeThread() {
+    document.getElementById('thread-modal').style.display = 'none';
+    openThread = null;
 }
 
 // ==== Call UI wiring ====
@@ -2998,6 +4675,9 @@ document.addEventListener('click', (e) => {
         endCall(true);
     } else if (t.id === 'schedule-btn') {
         const m = document.getElementById('schedule-modal');
+        const zone = document.getElementById('schedule-time-zone');
+        if (zone) zone.textContent = `Times are in ${localTimeZone}`;
+        requestScheduledMessages();
         if (m) m.style.display = 'block';
     } else if (t.id === 'close-schedule-modal') {
         const m = document.getElementById('schedule-modal');
@@ -3010,9 +4690,10 @@ document.addEventListener('click', (e) => {
         const message = txt.value.trim();
         if (!when || !message) { showNotification('Pick time and enter message', 'error'); return; }
         if (!socket || socket.readyState !== WebSocket.OPEN) { showNotification('Not connected', 'error'); return; }
-        // Build payload
-        const localDate = new Date(when);
-        const payload = { type: 'schedule_message', message, scheduled_at: localDate.toISOString(), scheduled_at_epoch: Math.floor(localDate.getTime()/1000) };
+        // Build payload; the server resolves the wall-clock time in our zone so repeats survive DST
+        const recurrence = document.getElementById('schedule-recurrence');
+        const payload = { type: 'schedule_message', message, local_time: when, time_zone: localTimeZone };
+        if (recurrence && recurrence.value) payload.recurrence = recurrence.value;
         if (currentConversation) payload.receiver_username = currentConversation;
         if (currentGroup) payload.group_id = currentGroup.id;
         socket.send(JSON.stringify(payload));
@@ -3030,6 +4711,21 @@ document.addEventListener('click', (e) => {
         revealAtISO = d.toISOString();
         const m = document.getElementById('reveal-modal'); if (m) m.style.display = 'none';
         showNotification('Reveal time set for next message', 'info');
+    } else if (t.id === 'mentions-nav-btn' || (t.closest && t.closest('#mentions-nav-btn'))) {
+        openMentions();
+    } else if (t.id === 'close-mentions-modal') {
+        document.getElementById('mentions-modal').style.display = 'none';
+    } else if (t.id === 'more-mentions-btn') {
+        openMentions(true);
+    } else if (t.id === 'mark-mentions-read-btn') {
+        markMentionsRead({}).then(() => openMentions());
+    } else if (t.id === 'toggle-notifications-btn') {
+        groupMenuDropdown.classList.remove('show');
+        toggleGroupNotifications();
+    } else if (t.id === 'close-thread-modal') {
+        closeThread();
+    } else if (t.id === 'thread-send-btn') {
+        sendThreadReply();
     } else if (t.id === 'add-note-btn') {
         openNoteModal();
         setCurrentNote(null);
@@ -3126,34 +4822,66 @@ function displayNotes() {
 // Load notes after login
 // If you have a login success path, call loadNotes() there.
 
-function sendImage() {
+// Attachment downloads are authorized; <img> and links can't send headers
+function attachmentUrl(url) {
+    return `${url}?token=${encodeURIComponent(authToken)}`;
+}
+
+function renderAttachments(container, attachments) {
+    attachments.forEach(att => {
+        const wrapper = document.createElement('div');
+        wrapper.className = 'message-attachment';
+        if (att.mime_type.startsWith('image/')) {
+            const link = document.createElement('a');
+            link.href = attachmentUrl(att.url);
+            link.target = '_blank';
+            const img = document.createElement('img');
+            img.src = attachmentUrl(att.thumbnail_url || att.url);
+            img.alt = att.file_name;
+            link.appendChild(img);
+            wrapper.appendChild(link);
+        } else {
+            const link = document.createElement('a');
+            link.href = attachmentUrl(att.url);
+            link.textContent = `📎 ${att.file_name} (${Math.ceil(att.size_bytes / 1024)} KB)`;
+            wrapper.appendChild(link);
+        }
+        container.appendChild(wrapper);
+    });
+}
+
+// Upload the picked file for the open conversation, then send it as a message
+async function sendImage() {
     const file = imageInput.files[0];
+    imageInput.value = '';
     if (!file || !socket || socket.readyState !== WebSocket.OPEN) return;
+    if (!currentConversation && !currentGroup) return;
 
-    pendingFile = file;
-
-    const metaMessage = {
-        type: 'file_meta',
-        message: file.name
-    };
-
-    if (currentConversation) metaMessage.receiver_username = currentConversation;
-    else if (currentGroup) metaMessage.group_id = currentGroup.id;
+    const form = new FormData();
+    form.append('file', file);
+    if (currentConversation) form.append('receiver_username', currentConversation);
+    else form.append('group_id', currentGroup.id);
 
-    socket.send(JSON.stringify(metaMessage));
-
-    imageInput.value = '';
+    try {
+        const response = await fetch('/uploads', {
+            method: 'POST',
+            headers: { 'Authorization': `Bearer ${authToken}` },
+            body: form
+        });
+        const data = await response.json();
+        if (!response.ok) {
+            alert(data.error || 'Upload failed');
+            return;
+        }
 
-    const fakeUrl = URL.createObjectURL(file);
-    displayMessage({
-        id: Date.now(),
-        sender_username: currentUser,
-        receiver_username: currentConversation || null,
-        group_id: currentGroup ? currentGroup.id : null,
-        message: '',
-        file_url: fakeUrl,
-        timestamp: getCurrentTime()
-    });
+        const payload = currentConversation
+            ? { type: 'chat_message', receiver_username: currentConversation, message: '', attachment_ids: [data.id] }
+            : { type: 'group_message', group_id: currentGroup.id, message: '', attachment_ids: [data.id] };
+        socket.send(JSON.stringify(payload));
+    } catch (error) {
+        console.error('Upload failed:', error);
+        alert('Upload failed');
+    }
 }
 
 function showReactionPicker(messageDiv, messageId) {
@@ -3199,7 +4927,7 @@ function showReactionPicker(messageDiv, messageId) {
 
     picker.addEventListener('click', (e) => {
         if (e.target.classList.contains('emoji')) {
-            sendReaction(messageId, e.target.dataset.emoji);
+            sendReaction(messageDiv.dataset.messageKind, messageId, e.target.dataset.emoji);
             picker.remove();
         }
     });
@@ -3223,11 +4951,12 @@ function hideReactionPicker() {
     }, 2000);
 }
 
-function sendReaction(messageId, emoji) {
+function sendReaction(kind, messageId, emoji) {
     if (socket && socket.readyState === WebSocket.OPEN) {
         socket.send(JSON.stringify({
             type: 'add_reaction',
             message_id: messageId,
+            kind,
             emoji: emoji
         }));
     }
@@ -3235,7 +4964,7 @@ function sendReaction(messageId, emoji) {
 
 function handleReactionAdded(data) {
     console.log('DEBUG: handleReactionAdded called with data:', data);
-    const messageDiv = document.querySelector(`[data-message-id="${data.message_id}"]`);
+    const messageDiv = findMessageEl(data.kind, data.message_id);
     if (!messageDiv) {
         console.log('DEBUG: Message div not found for message_id:', data.message_id);
         return;
@@ -3282,7 +5011,7 @@ function handleReactionAdded(data) {
 }
 
 function handleReactionRemoved(data) {
-    const messageDiv = document.querySelector(`[data-message-id="${data.message_id}"]`);
+    const messageDiv = findMessageEl(data.kind, data.message_id);
     if (!messageDiv) return;
 
     const reactionsDiv = messageDiv.querySelector('.message-reactions');
@@ -3305,7 +5034,7 @@ function handleReactionRemoved(data) {
 }
 
 function handleMessagePinned(data) {
-    const messageDiv = document.querySelector(`[data-message-id="${data.message_id}"]`);
+    const messageDiv = findMessageEl(data.kind, data.message_id);
     if (messageDiv) {
         messageDiv.classList.add('pinned');
         const pinBtn = messageDiv.querySelector('.message-action-btn');
@@ -3317,7 +5046,7 @@ function handleMessagePinned(data) {
 }
 
 function handleMessageUnpinned(data) {
-    const messageDiv = document.querySelector(`[data-message-id="${data.message_id}"]`);
+    const messageDiv = findMessageEl(data.kind, data.message_id);
     if (messageDiv) {
         messageDiv.classList.remove('pinned');
         const pinBtn = messageDiv.querySelector('.message-action-btn');
@@ -3329,7 +5058,7 @@ function handleMessageUnpinned(data) {
 }
 
 function handleReactionsList(data) {
-    const messageDiv = document.querySelector(`[data-message-id="${data.message_id}"]`);
+    const messageDiv = findMessageEl(data.kind, data.message_id);
     if (!messageDiv) return;
 
     let reactionsDiv = messageDiv.querySelector('.message-reactions');
@@ -3393,7 +5122,7 @@ function handlePinnedMessagesList(data) {
         // Add pinned messages to the section
     data.pinned_messages.forEach(pinInfo => {
             // Mark message as pinned in main chat
-        const messageDiv = document.querySelector(`[data-message-id="${pinInfo.message_id}"]`);
+        const messageDiv = findMessageEl(pinInfo.kind, pinInfo.message_id);
         if (messageDiv) {
             messageDiv.classList.add('pinned');
             const pinBtn = messageDiv.querySelector('.message-action-btn');
@@ -3478,11 +5207,12 @@ function handlePinnedMessagesList(data) {
     }
 }
 
-function loadReactionsForMessage(messageId) {
+function loadReactionsForMessage(kind, messageId) {
     if (socket && socket.readyState === WebSocket.OPEN) {
         socket.send(JSON.stringify({
             type: 'get_reactions',
-            message_id: messageId
+            message_id: messageId,
+            kind
         }));
     }
 }
@@ -3869,6 +5599,9 @@ window.addEventListener('DOMContentLoaded', () => {
     addPollOptionBtn = document.getElementById('add-poll-option-btn');
     allowMultipleCheckbox = document.getElementById('allow-multiple-choices');
     pollExpiresInput = document.getElementById('poll-expires');
+    pollTypeSelect = document.getElementById('poll-type');
+    pollAnonymousCheckbox = document.getElementById('poll-anonymous');
+    pollCorrectOptionInput = document.getElementById('poll-correct-option');
     activePollsContainer = document.getElementById('active-polls-container');
     
     // DM lock elements
@@ -4268,26 +6001,35 @@ window.addEventListener('DOMContentLoaded', () => {
 
     const savedToken = localStorage.getItem('authToken');
     const savedUser = localStorage.getItem('currentUser');
+    const savedRefresh = localStorage.getItem('refreshToken');
 
-    if (savedToken && savedUser) {
+    // Tokens saved before refresh sessions existed are no longer accepted
+    if (savedToken && savedUser && savedRefresh) {
         authToken = savedToken;
+        refreshToken = savedRefresh;
         currentUser = savedUser;
-        showMessengerInterface();
-        loadContacts();
-        loadGroups();
-        loadNotes();
-        // Auto-enforce global lock on load if a PIN exists and session not yet unlocked
-        (async () => {
-            try {
-                const st = await fetchGlobalLockStatus();
-                if (st && st.has_pin) {
-                    try { await enableGlobalLock(); } catch {}
-                    if (sessionStorage.getItem('global_unlocked') !== '1') {
-                        applyGlobalLockOverlay();
+        scheduleTokenRefresh();
+        // A stored access token has usually expired; swap it before loading anything
+        ensureFreshToken().then(() => {
+            if (!authToken) return;
+            showMessengerInterface();
+            loadContacts();
+            loadGroups();
+            loadNotes();
+            redeemInviteFromUrl();
+            // Auto-enforce global lock on load if a PIN exists and session not yet unlocked
+            (async () => {
+                try {
+                    const st = await fetchGlobalLockStatus();
+                    if (st && st.has_pin) {
+                        try { await enableGlobalLock(); } catch {}
+                        if (sessionStorage.getItem('global_unlocked') !== '1') {
+                            applyGlobalLockOverlay();
+                        }
                     }
-                }
-            } catch {}
-        })();
+                } catch {}
+            })();
+        });
     } else {
         showAuthInterface();
         showLoginForm();
@@ -4340,7 +6082,6 @@ window.handleGameWebSocketMessage = handleGameWebSocketMessage;
 window.displayGameMessage = displayGameMessage;
 window.sendQuickMessage = sendQuickMessage;
 window.generateChatHighlight = generateChatHighlight;
-window.requestNextQuestion = requestNextQuestion;
 
 console.log('Game system integration loaded');
 
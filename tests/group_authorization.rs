@@ -0,0 +1,273 @@
+// tests/group_authorization.rs
+//! Group features seen from outside the group: every group-scoped WebSocket
+//! command answers a non-member with a `forbidden` error, and every group
+//! REST route with 403, without leaking anything from the group.
+//!
+//! Each test starts the server binary on a free port with a fresh database
+//! in its own temporary directory.
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+const PASSWORD: &str = "pw123456";
+
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    port: u16,
+    http: reqwest::Client,
+}
+
+impl Server {
+    async fn start() -> Server {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let dir = std::env::temp_dir().join(format!("chat_app_test_{}_{}", std::process::id(), port));
+        std::fs::create_dir_all(dir.join("db")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_chat_app"))
+            .current_dir(&dir)
+            .env("PORT", port.to_string())
+            .env("GEMINI_API_KEY", "test")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start the server");
+        let server = Server { child, dir, port, http: reqwest::Client::new() };
+
+        for _ in 0..150 {
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                return server;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("the server did not start listening on port {}", port);
+    }
+
+    async fn request(&self, method: Method, path: &str, token: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let mut request = self
+            .http
+            .request(method, format!("http://127.0.0.1:{}{}", self.port, path))
+            .bearer_auth(token);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await.unwrap();
+        let status = response.status();
+        let body = response.json().await.unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    /// Register `username` and return an access token for them.
+    async fn user(&self, username: &str) -> String {
+        let credentials = json!({ "username": username, "password": PASSWORD });
+        let url = |path: &str| format!("http://127.0.0.1:{}{}", self.port, path);
+        self.http.post(url("/register")).json(&credentials).send().await.unwrap();
+        let login: Value = self.http.post(url("/login")).json(&credentials).send().await.unwrap().json().await.unwrap();
+        login["token"].as_str().expect("login returned no token").to_string()
+    }
+
+    async fn socket(&self, token: &str) -> Socket {
+        let url = format!("ws://127.0.0.1:{}/ws?token={}", self.port, token);
+        let (stream, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        Socket { stream, seen: Vec::new() }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Socket {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    // Every event received, for checking nothing leaked
+    seen: Vec<Value>,
+}
+
+impl Socket {
+    async fn send(&mut self, frame: Value) {
+        self.stream.send(Message::Text(frame.to_string())).await.unwrap();
+    }
+
+    /// The next event matching `matches`, skipping any others.
+    async fn wait_for(&mut self, matches: impl Fn(&Value) -> bool) -> Value {
+        let wait = async {
+            loop {
+                let message = self.stream.next().await.expect("socket closed").unwrap();
+                let Ok(text) = message.to_text() else { continue };
+                let Ok(event) = serde_json::from_str::<Value>(text) else { continue };
+                self.seen.push(event.clone());
+                if matches(&event) {
+                    return event;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap_or_else(|_| panic!("timed out; events so far: {:?}", self.seen))
+    }
+
+    async fn wait_for_type(&mut self, event_type: &str) -> Value {
+        self.wait_for(|event| event["type"] == event_type).await
+    }
+}
+
+/// A group with a message, a pin, a poll and a game, owned by alice, and a
+/// token for mallory, who is not in it.
+struct Fixture {
+    server: Server,
+    outsider: String,
+    group_id: i64,
+    message_id: i64,
+    poll_id: i64,
+    option_id: i64,
+    game_id: i64,
+}
+
+async fn fixture() -> Fixture {
+    let server = Server::start().await;
+    let owner = server.user("alice").await;
+    let outsider = server.user("mallory").await;
+
+    let (status, group) = server
+        .request(Method::POST, "/groups", &owner, Some(json!({ "name": "secret", "members": [] })))
+        .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", group);
+    let group_id = group["id"].as_i64().unwrap();
+
+    let mut socket = server.socket(&owner).await;
+    socket.send(json!({ "type": "group_message", "group_id": group_id, "message": "private stuff" })).await;
+    let message = socket.wait_for(|event| event["type"] == "chat_message" && event["message"] == "private stuff").await;
+    let message_id = message["id"].as_i64().unwrap();
+
+    socket.send(json!({ "type": "pin_message", "message_id": message_id, "kind": "group" })).await;
+    socket.wait_for_type("message_pinned").await;
+
+    socket.send(json!({ "type": "create_game", "game_type": "tictactoe", "group_id": group_id })).await;
+    let game_id = socket.wait_for_type("game_created").await["game"]["id"].as_i64().unwrap();
+
+    let (status, poll) = server
+        .request(
+            Method::POST,
+            "/polls/create",
+            &owner,
+            Some(json!({ "group_id": group_id, "question": "Lunch?", "options": ["Soup", "Salad"] })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CREATED, "{}", poll);
+
+    Fixture {
+        outsider,
+        group_id,
+        message_id,
+        poll_id: poll["id"].as_i64().unwrap(),
+        option_id: poll["options"][0]["id"].as_i64().unwrap(),
+        game_id,
+        server,
+    }
+}
+
+#[tokio::test]
+async fn websocket_group_commands_are_forbidden_to_non_members() {
+    let Fixture { server, outsider, group_id, message_id, poll_id, option_id, game_id } = fixture().await;
+    let mut socket = server.socket(&outsider).await;
+
+    let frames = [
+        json!({ "type": "group_message", "group_id": group_id, "message": "hello" }),
+        json!({ "type": "get_group_conversation", "group_id": group_id }),
+        json!({ "type": "get_thread", "group_id": group_id, "root_id": message_id }),
+        json!({ "type": "read", "group_id": group_id, "up_to_seq": 100 }),
+        json!({ "type": "sync", "groups": { group_id.to_string(): 0 } }),
+        json!({ "type": "typing_start", "group_id": group_id }),
+        json!({ "type": "typing_stop", "group_id": group_id }),
+        json!({ "type": "schedule_message", "group_id": group_id, "message": "later", "scheduled_at_epoch": 4_000_000_000i64 }),
+        json!({ "type": "edit_message", "message_id": message_id, "kind": "group", "message": "edited" }),
+        json!({ "type": "delete_message", "message_id": message_id, "kind": "group" }),
+        json!({ "type": "create_poll", "group_id": group_id, "poll_question": "q", "poll_options": ["a", "b"] }),
+        json!({ "type": "vote_poll", "poll_id": poll_id, "poll_option_ids": [option_id] }),
+        json!({ "type": "get_poll_details", "poll_id": poll_id }),
+        json!({ "type": "close_poll", "poll_id": poll_id }),
+        json!({ "type": "get_quiz_scores", "group_id": group_id }),
+        json!({ "type": "create_game", "game_type": "tictactoe", "group_id": group_id }),
+        json!({ "type": "join_game", "game_id": game_id }),
+        json!({ "type": "game_move", "game_id": game_id, "game_move": "{\"row\":0,\"col\":0}" }),
+        json!({ "type": "get_game_state", "game_id": game_id }),
+        json!({ "type": "get_game_pgn", "game_id": game_id }),
+        json!({ "type": "add_reaction", "message_id": message_id, "kind": "group", "emoji": "👍" }),
+        json!({ "type": "remove_reaction", "message_id": message_id, "kind": "group", "emoji": "👍" }),
+        json!({ "type": "get_reactions", "message_id": message_id, "kind": "group" }),
+        json!({ "type": "pin_message", "message_id": message_id, "kind": "group" }),
+        json!({ "type": "unpin_message", "message_id": message_id, "kind": "group" }),
+    ];
+    for (request_id, mut frame) in frames.into_iter().enumerate() {
+        let command = frame["type"].as_str().unwrap().to_string();
+        frame["request_id"] = json!(request_id);
+        socket.send(frame).await;
+        let error = socket
+            .wait_for(|event| event["type"] == "error" && event["request_id"] == json!(request_id))
+            .await;
+        assert_eq!(error["code"], "forbidden", "{}: {}", command, error);
+    }
+
+    socket.send(json!({ "type": "get_pinned_messages" })).await;
+    let pinned = socket.wait_for_type("pinned_messages_list").await;
+    assert_eq!(pinned["pinned_messages"], json!([]));
+
+    let leaked = ["chat_message", "group_conversation_history", "thread_history", "poll_details", "game_state", "reactions"];
+    let leaks: Vec<&Value> = socket.seen.iter().filter(|event| leaked.iter().any(|t| event["type"] == *t)).collect();
+    assert!(leaks.is_empty(), "events leaked to a non-member: {:?}", leaks);
+}
+
+#[tokio::test]
+async fn rest_group_routes_are_forbidden_to_non_members() {
+    let Fixture { server, outsider, group_id, poll_id, option_id, .. } = fixture().await;
+
+    let g = group_id;
+    let routes = [
+        (Method::GET, format!("/groups/{}/messages", g), None),
+        (Method::PUT, "/groups/update".to_string(), Some(json!({ "group_id": g, "name": "mine" }))),
+        (Method::DELETE, format!("/groups/delete/{}", g), None),
+        (Method::GET, format!("/groups/{}/members", g), None),
+        (Method::POST, format!("/groups/{}/members", g), Some(json!({ "usernames": ["mallory"] }))),
+        (Method::DELETE, format!("/groups/{}/members/alice", g), None),
+        (Method::POST, format!("/groups/{}/members/alice/kick", g), Some(json!({}))),
+        (Method::POST, format!("/groups/{}/members/alice/mute", g), Some(json!({ "duration_secs": 60 }))),
+        (Method::DELETE, format!("/groups/{}/members/alice/mute", g), None),
+        (Method::PUT, format!("/groups/{}/members/mallory/role", g), Some(json!({ "role": "admin" }))),
+        (Method::POST, format!("/groups/{}/bans", g), Some(json!({ "username": "alice" }))),
+        (Method::GET, format!("/groups/{}/bans", g), None),
+        (Method::DELETE, format!("/groups/{}/bans/alice", g), None),
+        (Method::POST, format!("/groups/{}/transfer", g), Some(json!({ "username": "mallory" }))),
+        (Method::GET, format!("/groups/{}/audit", g), None),
+        (Method::PUT, format!("/groups/{}/notifications", g), Some(json!({ "muted": true }))),
+        (Method::POST, format!("/groups/{}/invites", g), Some(json!({}))),
+        (Method::GET, format!("/groups/{}/invites", g), None),
+        (Method::GET, format!("/groups/{}/join_requests", g), None),
+        (Method::GET, format!("/groups/{}/quiz_scores", g), None),
+        (Method::POST, "/polls/create".to_string(), Some(json!({ "group_id": g, "question": "q", "options": ["a", "b"] }))),
+        (Method::GET, format!("/polls/{}", poll_id), None),
+        (Method::POST, "/polls/vote".to_string(), Some(json!({ "poll_id": poll_id, "option_ids": [option_id] }))),
+        (Method::POST, format!("/polls/{}/close", poll_id), None),
+        (Method::GET, format!("/polls/{}/export", poll_id), None),
+        (
+            Method::POST,
+            "/highlights/generate".to_string(),
+            Some(json!({ "target_type": "group", "target_id": g, "type": "summary" })),
+        ),
+    ];
+    for (method, path, body) in routes {
+        let route = format!("{} {}", method, path);
+        let (status, body) = server.request(method, &path, &outsider, body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}: {}", route, body);
+    }
+}